@@ -1,38 +1,38 @@
 pub use crate::protos::{Block, BlockHeader, Transaction};
 use hex;
-use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// genesis must hash the same on every node, so its timestamp is fixed
+const GENESIS_TIMESTAMP: u64 = 1681171200;
+// compact target of the first blocks, until the first retarget. Hashes need 9
+// leading zero bits.
+pub const GENESIS_BITS: u32 = 0x1f7fffff;
+
 pub fn create_genesis_block() -> Block {
     let previous_hash = vec![];
     let transactions: Vec<Transaction> = vec![];
     let block_index = 0;
     let merkle_root: [u8; 32] = [0; 32];
-    let difficulty: u64 = 9;
-    return new_block(
+    let bits = GENESIS_BITS;
+    new_block_at(
         previous_hash,
         transactions,
         block_index,
         merkle_root,
-        difficulty,
-    );
+        bits,
+        GENESIS_TIMESTAMP,
+    )
 }
 
 pub fn next_block(
     last_block: &Block,
     transactions: Vec<Transaction>,
     merkle_root: [u8; 32],
-    difficulty: u64,
+    bits: u32,
 ) -> Block {
     let block_index = last_block.header.as_ref().unwrap().block_index + 1;
     let previous_hash = last_block.to_owned().block_hash;
-    return new_block(
-        previous_hash,
-        transactions,
-        block_index,
-        merkle_root,
-        difficulty,
-    );
+    return new_block(previous_hash, transactions, block_index, merkle_root, bits);
 }
 
 pub fn new_block(
@@ -40,19 +40,36 @@ pub fn new_block(
     transactions: Vec<Transaction>,
     block_index: u64,
     merkle_root: [u8; 32],
-    difficulty: u64,
+    bits: u32,
 ) -> Block {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
+    new_block_at(
+        previous_hash,
+        transactions,
+        block_index,
+        merkle_root,
+        bits,
+        timestamp,
+    )
+}
 
+fn new_block_at(
+    previous_hash: Vec<u8>,
+    transactions: Vec<Transaction>,
+    block_index: u64,
+    merkle_root: [u8; 32],
+    bits: u32,
+    timestamp: u64,
+) -> Block {
     let block_header = BlockHeader {
         timestamp,
         previous_hash,
         block_index,
-        merkle_root: merkle_root.try_into().unwrap(),
-        difficulty,
+        merkle_root: merkle_root.into(),
+        bits,
         nonce: 0,
     };
 
@@ -60,20 +77,13 @@ pub fn new_block(
     block.header = Some(block_header);
     block.transactions = transactions;
     block.block_hash = block.hash_block();
-    return block;
+    block
 }
 
 impl Block {
+    // block hash is the header hash, so peers can recompute it from the header
     fn hash_block(&self) -> Vec<u8> {
-        let header = self.header.to_owned().unwrap();
-        let mut hasher = Sha256::new();
-        hasher.update(header.timestamp.to_be_bytes());
-        hasher.update(header.difficulty.to_be_bytes());
-        hasher.update(header.nonce.to_be_bytes());
-        hasher.update(header.previous_hash);
-        hasher.update(header.block_index.to_be_bytes());
-        hasher.update(header.merkle_root);
-        hasher.finalize().to_vec()
+        self.header.as_ref().unwrap().hash()
     }
 }
 
@@ -129,7 +139,7 @@ Header:
 Transactions({})
 block_index:            {},
 merkle_root:            {}...,
-difficulty:             {},
+bits:                   {:#010x},
 block_hash:             {}...,
 ",
             title,
@@ -139,7 +149,7 @@ block_hash:             {}...,
             self.transactions.len(),
             header.block_index,
             hex::encode(shrunk_merkle_root),
-            self.header.to_owned().unwrap().difficulty,
+            self.header.to_owned().unwrap().bits,
             hex::encode(shrunk_hash),
         );
         return write!(f, "{}", block);
@@ -158,13 +168,19 @@ mod tests {
         assert_eq!(0, genesis.header.unwrap().block_index);
     }
 
+    #[test]
+    fn test_genesis_block_is_deterministic() {
+        let genesis: Block = create_genesis_block();
+        assert_eq!(create_genesis_block(), genesis);
+        assert_eq!(genesis.header.unwrap().hash(), genesis.block_hash);
+    }
+
     #[test]
     fn test_first_block() {
         let genesis: Block = create_genesis_block();
         let transactions: Vec<Transaction> = vec![];
         let merkle_root: [u8; 32] = [0; 32];
-        let difficulty: u64 = 0;
-        let new_block = next_block(&genesis, transactions, merkle_root, difficulty);
+        let new_block = next_block(&genesis, transactions, merkle_root, GENESIS_BITS);
         let header = new_block.header.unwrap();
         assert_eq!(genesis.block_hash, header.previous_hash);
         assert_eq!(1, header.block_index);
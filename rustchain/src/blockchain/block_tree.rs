@@ -0,0 +1,353 @@
+use crate::blockchain::target::block_work;
+use crate::protos::Block;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// orphans are held until their parent shows up, but only up to this many, the
+// oldest ones make room for new ones
+const MAX_ORPHANS: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockNode {
+    pub block: Block,
+    pub height: u64,
+    pub cumulative_work: u128,
+}
+
+// Every known block keyed by its hex hash, whether it is part of the active
+// chain or of a competing branch. Blocks whose parent is still unknown wait in
+// the orphan pool.
+#[derive(Debug, Default)]
+pub struct BlockTree {
+    nodes: HashMap<String, BlockNode>,
+    orphans: HashMap<String, Block>,
+    // orphan hashes, oldest first
+    orphan_order: VecDeque<String>,
+    invalid: HashSet<String>,
+    // blocks whose body failed validation, only their header is kept until a
+    // body matching it shows up
+    missing_bodies: HashSet<String>,
+}
+
+impl BlockTree {
+    pub fn new(genesis: Block) -> Self {
+        let mut tree = BlockTree::default();
+        let header = genesis.header.as_ref().unwrap();
+        let node = BlockNode {
+            height: header.block_index,
+            cumulative_work: block_work(header.bits),
+            block: genesis.clone(),
+        };
+        tree.nodes.insert(hex::encode(&genesis.block_hash), node);
+        tree
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&BlockNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn is_orphan(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn is_invalid(&self, hash: &str) -> bool {
+        self.invalid.contains(hash)
+    }
+
+    pub fn has_body(&self, hash: &str) -> bool {
+        self.nodes.contains_key(hash) && !self.missing_bodies.contains(hash)
+    }
+
+    pub fn has_missing_bodies(&self) -> bool {
+        !self.missing_bodies.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn orphans_len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    // links the block to its parent, which must already be in the tree
+    pub fn insert(&mut self, block: Block) -> Option<&BlockNode> {
+        let header = block.header.as_ref()?;
+        let parent = self.nodes.get(&hex::encode(&header.previous_hash))?;
+        let node = BlockNode {
+            height: parent.height + 1,
+            cumulative_work: parent
+                .cumulative_work
+                .saturating_add(block_work(header.bits)),
+            block: block.clone(),
+        };
+        let hash = hex::encode(&block.block_hash);
+        self.nodes.insert(hash.clone(), node);
+        self.nodes.get(&hash)
+    }
+
+    // Keeps the header of the block but forgets its transactions, so the block
+    // can still come again with a body that is valid.
+    pub fn drop_body(&mut self, hash: &str) {
+        if let Some(node) = self.nodes.get_mut(hash) {
+            node.block.transactions.clear();
+            self.missing_bodies.insert(hash.to_string());
+        }
+    }
+
+    // gives a block whose body was dropped the one just received
+    pub fn replace_body(&mut self, block: Block) {
+        let hash = hex::encode(&block.block_hash);
+        if let Some(node) = self.nodes.get_mut(&hash) {
+            node.block = block;
+            self.missing_bodies.remove(&hash);
+        }
+    }
+
+    // hashes of the blocks no other block builds on
+    pub fn tips(&self) -> Vec<String> {
+        let parents: HashSet<String> = self
+            .nodes
+            .values()
+            .filter_map(|node| node.block.header.as_ref())
+            .map(|header| hex::encode(&header.previous_hash))
+            .collect();
+        self.nodes
+            .keys()
+            .filter(|hash| !parents.contains(*hash))
+            .cloned()
+            .collect()
+    }
+
+    pub fn add_orphan(&mut self, block: Block) {
+        let hash = hex::encode(&block.block_hash);
+        if self.orphans.contains_key(&hash) {
+            return;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.orphan_order.pop_front() {
+                self.orphans.remove(&oldest);
+            }
+        }
+        self.orphan_order.push_back(hash.clone());
+        self.orphans.insert(hash, block);
+    }
+
+    fn remove_orphan(&mut self, hash: &str) -> Option<Block> {
+        let block = self.orphans.remove(hash)?;
+        self.orphan_order.retain(|orphan| orphan != hash);
+        Some(block)
+    }
+
+    // removes and returns the orphans that were waiting for `parent_hash`
+    pub fn take_orphans_of(&mut self, parent_hash: &str) -> Vec<Block> {
+        let children: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, block)| {
+                block
+                    .header
+                    .as_ref()
+                    .is_some_and(|h| hex::encode(&h.previous_hash) == parent_hash)
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        children
+            .iter()
+            .filter_map(|hash| self.remove_orphan(hash))
+            .collect()
+    }
+
+    // forgets the block and every descendant of it, so they are never
+    // considered for the active chain again
+    pub fn mark_invalid(&mut self, hash: &str) {
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            self.nodes.remove(&hash);
+            self.remove_orphan(&hash);
+            self.missing_bodies.remove(&hash);
+            for (child_hash, child) in self.nodes.iter() {
+                let parent = hex::encode(&child.block.header.as_ref().unwrap().previous_hash);
+                if parent == hash {
+                    pending.push(child_hash.clone());
+                }
+            }
+            self.invalid.insert(hash);
+        }
+    }
+
+    // the block at `height` on the branch ending at `hash`
+    pub fn ancestor(&self, hash: &str, height: u64) -> Option<&BlockNode> {
+        let mut node = self.nodes.get(hash)?;
+        while node.height > height {
+            let parent = hex::encode(&node.block.header.as_ref()?.previous_hash);
+            node = self.nodes.get(&parent)?;
+        }
+        (node.height == height).then_some(node)
+    }
+
+    // walks back from `hash` until `is_fork_point` matches and returns the
+    // blocks after the fork point, oldest first
+    pub fn branch(
+        &self,
+        hash: &str,
+        is_fork_point: impl Fn(&str, &BlockNode) -> bool,
+    ) -> Vec<Block> {
+        let mut branch = vec![];
+        let mut current = hash.to_string();
+        while let Some(node) = self.nodes.get(&current) {
+            if is_fork_point(&current, node) {
+                break;
+            }
+            branch.push(node.block.clone());
+            current = hex::encode(&node.block.header.as_ref().unwrap().previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::{create_genesis_block, GENESIS_BITS};
+    use crate::blockchain::validation::tests::mined_block;
+    use crate::protos::{Transaction, UtxoOutput};
+
+    fn tagged_block(parent: &Block, tag: &str) -> Block {
+        let tx = Transaction {
+            inputs: vec![],
+            outputs: vec![UtxoOutput {
+                to_addr: tag.to_string(),
+                amount: 1,
+            }],
+        };
+        mined_block(parent, vec![tx])
+    }
+
+    #[test]
+    fn test_insert_tracks_cumulative_work() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let node = tree.insert(block_1.clone()).unwrap();
+        assert_eq!(1, node.height);
+        assert_eq!(2 * block_work(GENESIS_BITS), node.cumulative_work);
+    }
+
+    #[test]
+    fn test_insert_without_parent() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2 = tagged_block(&block_1, "a");
+        assert!(tree.insert(block_2).is_none());
+        assert_eq!(1, tree.len());
+    }
+
+    #[test]
+    fn test_take_orphans_of() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2a = tagged_block(&block_1, "a");
+        let block_2b = tagged_block(&block_1, "b");
+        tree.add_orphan(block_2a.clone());
+        tree.add_orphan(block_2b.clone());
+        assert!(tree
+            .take_orphans_of(&hex::encode(&genesis.block_hash))
+            .is_empty());
+        let orphans = tree.take_orphans_of(&hex::encode(&block_1.block_hash));
+        assert_eq!(2, orphans.len());
+        assert_eq!(0, tree.orphans_len());
+    }
+
+    #[test]
+    fn test_oldest_orphans_are_evicted_first() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let orphans: Vec<Block> = (0..=MAX_ORPHANS)
+            .map(|i| tagged_block(&block_1, &i.to_string()))
+            .collect();
+        for orphan in &orphans {
+            tree.add_orphan(orphan.clone());
+        }
+        assert_eq!(MAX_ORPHANS, tree.orphans_len());
+        assert!(!tree.is_orphan(&hex::encode(&orphans[0].block_hash)));
+        assert!(tree.is_orphan(&hex::encode(&orphans[1].block_hash)));
+        assert!(tree.is_orphan(&hex::encode(&orphans[MAX_ORPHANS].block_hash)));
+        let taken = tree.take_orphans_of(&hex::encode(&block_1.block_hash));
+        assert_eq!(MAX_ORPHANS, taken.len());
+        assert!(tree.orphan_order.is_empty());
+    }
+
+    #[test]
+    fn test_mark_invalid_removes_descendants() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2 = tagged_block(&block_1, "a");
+        tree.insert(block_1.clone());
+        tree.insert(block_2.clone());
+        tree.mark_invalid(&hex::encode(&block_1.block_hash));
+        assert_eq!(1, tree.len());
+        assert!(tree.is_invalid(&hex::encode(&block_2.block_hash)));
+    }
+
+    #[test]
+    fn test_dropped_body_is_replaced() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2 = tagged_block(&block_1, "a");
+        let hash_1 = hex::encode(&block_1.block_hash);
+        tree.insert(block_1.clone());
+        tree.insert(block_2.clone());
+        tree.drop_body(&hash_1);
+        assert!(!tree.has_body(&hash_1));
+        assert!(!tree.is_invalid(&hash_1));
+        // the header stays, and so does the branch built on it
+        assert_eq!(1, tree.get(&hash_1).unwrap().height);
+        assert_eq!(vec![hex::encode(&block_2.block_hash)], tree.tips());
+        tree.replace_body(block_1.clone());
+        assert!(tree.has_body(&hash_1) && !tree.has_missing_bodies());
+        assert_eq!(block_1, tree.get(&hash_1).unwrap().block);
+    }
+
+    #[test]
+    fn test_ancestor() {
+        let genesis = create_genesis_block();
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2 = tagged_block(&block_1, "a");
+        tree.insert(block_1.clone());
+        tree.insert(block_2.clone());
+        let tip = hex::encode(&block_2.block_hash);
+        assert_eq!(block_1, tree.ancestor(&tip, 1).unwrap().block);
+        assert_eq!(genesis, tree.ancestor(&tip, 0).unwrap().block);
+        assert!(tree.ancestor(&tip, 3).is_none());
+    }
+
+    #[test]
+    fn test_branch() {
+        let genesis = create_genesis_block();
+        let genesis_hash = hex::encode(&genesis.block_hash);
+        let mut tree = BlockTree::new(genesis.clone());
+        let block_1 = tagged_block(&genesis, "a");
+        let block_2 = tagged_block(&block_1, "a");
+        tree.insert(block_1.clone());
+        tree.insert(block_2.clone());
+        let branch = tree.branch(&hex::encode(&block_2.block_hash), |hash, _| {
+            *hash == genesis_hash
+        });
+        assert_eq!(vec![block_1, block_2], branch);
+    }
+}
@@ -1,75 +1,968 @@
+use crate::blockchain::block::create_genesis_block;
+use crate::blockchain::block_tree::BlockTree;
+use crate::blockchain::consensus::ConsensusParams;
+use crate::blockchain::header_chain::locator_heights;
+use crate::blockchain::store::{BlockStore, MemoryBlockStore};
+use crate::blockchain::target::Target;
+use crate::blockchain::utxo_set::{BlockUndo, UtxoSet};
+use crate::blockchain::validation::{
+    validate_block, validate_difficulty, validate_header, validate_merkle_root,
+    validate_orphan_difficulty, validate_proof_of_work, validate_timestamp, BlockValidationError,
+};
 use crate::event_bus::event_bus::EventBus;
 use crate::event_bus::events::RustchainEvent;
-use crate::protos::Block;
+use crate::protos::{Block, BlockHeader, ChainTip, Transaction};
+use std::collections::HashMap;
+use std::iter::successors;
 use std::sync::Arc;
-use tokio::runtime::Handle;
 use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::RwLock;
 
+// where a block ended up after being added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStatus {
+    // extended the active chain, possibly by reorganizing onto its branch
+    Connected,
+    // stored on a branch with less cumulative work than the active chain, or
+    // one that can't be connected until a body dropped from it comes again
+    SideChain,
+    // parent unknown, held until it shows up
+    Orphan,
+    Duplicate,
+}
+
 #[derive(Debug)]
 pub struct Blockchain {
-    blocks: Arc<RwLock<Vec<Block>>>,
-    block_hashes: Arc<RwLock<Vec<String>>>,
+    tree: BlockTree,
+    // hashes of the active chain, indexed by height
+    block_hashes: Vec<String>,
+    // shared with wallets and RPCs. It's a std lock so it can be updated while
+    // connecting blocks; it's never held across an await.
+    utxos: Arc<std::sync::RwLock<UtxoSet>>,
+    // what each active block spent, to roll it back on a reorg
+    undo: HashMap<String, BlockUndo>,
+    // hash of the active block confirming each transaction
+    tx_index: HashMap<String, String>,
+    store: Box<dyn BlockStore>,
+    params: ConsensusParams,
+    event_bus: Arc<RwLock<EventBus>>,
 }
 
 impl Blockchain {
-    pub fn new(event_bus: Arc<RwLock<EventBus>>) -> Arc<RwLock<Self>> {
-        let handle = Handle::current();
-        let blockchain = Blockchain {
-            blocks: Arc::new(RwLock::new(Vec::new())),
-            block_hashes: Arc::new(RwLock::new(vec![])),
+    pub async fn new(event_bus: Arc<RwLock<EventBus>>) -> Arc<RwLock<Self>> {
+        Blockchain::with_store(event_bus, Box::new(MemoryBlockStore::new())).await
+    }
+
+    // Starts the chain from whatever `store` already holds: every stored block
+    // is added back, so the active chain ends up where it was before restarting.
+    pub async fn with_store(
+        event_bus: Arc<RwLock<EventBus>>,
+        store: Box<dyn BlockStore>,
+    ) -> Arc<RwLock<Self>> {
+        Blockchain::with_params(event_bus, store, ConsensusParams::default()).await
+    }
+
+    pub async fn with_params(
+        event_bus: Arc<RwLock<EventBus>>,
+        mut store: Box<dyn BlockStore>,
+        params: ConsensusParams,
+    ) -> Arc<RwLock<Self>> {
+        let genesis = create_genesis_block();
+        if let Err(e) = store.put(&genesis) {
+            println!("Could not store genesis block: {}", e);
+        }
+        let stored_blocks = store.blocks();
+        let mut utxos = UtxoSet::with_coinbase_maturity(params.coinbase_maturity);
+        let genesis_undo = utxos.connect_block(&genesis);
+        let mut blockchain = Blockchain {
+            block_hashes: vec![hex::encode(&genesis.block_hash)],
+            utxos: Arc::new(std::sync::RwLock::new(utxos)),
+            undo: HashMap::from([(hex::encode(&genesis.block_hash), genesis_undo)]),
+            tx_index: HashMap::new(),
+            tree: BlockTree::new(genesis),
+            store,
+            params,
+            event_bus: event_bus.clone(),
         };
+        for block in stored_blocks {
+            let block_hash = hex::encode(&block.block_hash);
+            if let Err(reason) = blockchain.process_block(block, &mut vec![]) {
+                println!("Ignoring stored block {}: {}", block_hash, reason);
+            }
+        }
         let blockchain_arc = Arc::new(RwLock::new(blockchain));
-        handle.block_on(async {
-            let event_receiver = event_bus.write().await.subscribe().await;
-            let blockchain_clone = blockchain_arc.clone();
-            spawn(async move { Blockchain::listen_for_events(blockchain_clone, event_receiver) });
-            blockchain_arc
+        let event_receiver = event_bus.write().await.subscribe().await;
+        let blockchain_clone = blockchain_arc.clone();
+        spawn(async move { Blockchain::listen_for_events(blockchain_clone, event_receiver).await });
+        blockchain_arc
+    }
+
+    // Adds the block to the block tree and switches the active chain to the
+    // branch with the most cumulative work. Orphans waiting for this block are
+    // added right after it. Returns the reason the block was rejected otherwise.
+    pub async fn add_block(&mut self, block: Block) -> Result<BlockStatus, BlockValidationError> {
+        let (status, events) = self.add_block_deferred(block);
+        let event_bus = self.event_bus.read().await;
+        for event in events {
+            event_bus.publish(event).await;
+        }
+        status
+    }
+
+    // Like add_block, but hands back the events instead of publishing them,
+    // so the caller can publish them once it released its lock on the chain.
+    pub fn add_block_deferred(
+        &mut self,
+        block: Block,
+    ) -> (
+        Result<BlockStatus, BlockValidationError>,
+        Vec<RustchainEvent>,
+    ) {
+        let mut events = vec![];
+        let hash = hex::encode(&block.block_hash);
+        let status = self.process_block(block, &mut events);
+        if status.is_ok() {
+            let mut pending = vec![hash];
+            while let Some(parent_hash) = pending.pop() {
+                for orphan in self.tree.take_orphans_of(&parent_hash) {
+                    let orphan_hash = hex::encode(&orphan.block_hash);
+                    match self.process_block(orphan, &mut events) {
+                        Ok(_) => pending.push(orphan_hash),
+                        Err(reason) => {
+                            println!("Rejected orphan block {}: {}", orphan_hash, reason)
+                        }
+                    }
+                }
+            }
+        }
+        (status, events)
+    }
+
+    fn process_block(
+        &mut self,
+        block: Block,
+        events: &mut Vec<RustchainEvent>,
+    ) -> Result<BlockStatus, BlockValidationError> {
+        let hash = hex::encode(&block.block_hash);
+        let missing_body = self.tree.contains(&hash) && !self.tree.has_body(&hash);
+        if (self.tree.contains(&hash) && !missing_body) || self.tree.is_orphan(&hash) {
+            return Ok(BlockStatus::Duplicate);
+        }
+        let header = block
+            .header
+            .as_ref()
+            .ok_or(BlockValidationError::MissingHeader)?;
+        let parent_hash = hex::encode(&header.previous_hash);
+        if self.tree.is_invalid(&hash) || self.tree.is_invalid(&parent_hash) {
+            self.tree.mark_invalid(&hash);
+            return Err(BlockValidationError::KnownInvalid { hash });
+        }
+        let parent = match self.tree.get(&parent_hash) {
+            Some(parent) => parent.block.clone(),
+            None => {
+                // cheap check so the pool can't be filled with junk, nor hold a
+                // mutated body under the hash of the genuine block
+                validate_orphan_difficulty(&block, self.easiest_orphan_bits())?;
+                validate_proof_of_work(&block)?;
+                validate_merkle_root(&block)?;
+                self.tree.add_orphan(block);
+                return Ok(BlockStatus::Orphan);
+            }
+        };
+        // the header checks include the merkle root, so the body is known to
+        // be the one the block hash commits to before it's stored
+        validate_header(&block, &parent)?;
+        validate_difficulty(&block, self.expected_bits(&parent_hash))?;
+        validate_timestamp(
+            &block,
+            self.median_time_past(&parent_hash),
+            self.params.latest_timestamp(),
+        )?;
+        let stored = match missing_body {
+            true => self.store.replace(&block),
+            false => self.store.put(&block),
+        };
+        if let Err(e) = stored {
+            println!("Could not store block {}: {}", hash, e);
+        }
+
+        let (candidate, work) = match missing_body {
+            true => {
+                self.tree.replace_body(block);
+                self.best_candidate()
+            }
+            false => {
+                let work = self.tree.insert(block).unwrap().cumulative_work;
+                match self.tree.has_missing_bodies() {
+                    true => self.best_candidate(),
+                    false => (hash.clone(), work),
+                }
+            }
+        };
+        if work <= self.cumulative_work() {
+            return Ok(BlockStatus::SideChain);
+        }
+        self.reorganize(&candidate, events)?;
+        match self.active_height(&hash) {
+            Some(_) => Ok(BlockStatus::Connected),
+            None => Ok(BlockStatus::SideChain),
+        }
+    }
+
+    // The block with the most cumulative work that can be connected, which is
+    // the tip of a branch or the last block before a body missing from it.
+    fn best_candidate(&self) -> (String, u128) {
+        let mut best = (
+            self.block_hashes.last().unwrap().clone(),
+            self.cumulative_work(),
+        );
+        for tip in self.tree.tips() {
+            let mut candidate = tip.clone();
+            let mut current = tip;
+            while self.active_height(&current).is_none() {
+                let Some(node) = self.tree.get(&current) else {
+                    break;
+                };
+                let parent = hex::encode(&node.block.header.as_ref().unwrap().previous_hash);
+                if !self.tree.has_body(&current) {
+                    candidate = parent.clone();
+                }
+                current = parent;
+            }
+            if let Some(node) = self.tree.get(&candidate) {
+                if node.cumulative_work > best.1 {
+                    best = (candidate, node.cumulative_work);
+                }
+            }
+        }
+        best
+    }
+
+    // Makes `hash` the new tip: disconnects the active chain down to the fork
+    // point and connects the branch, validating its transactions on the way.
+    // If a block of the branch is invalid the previous chain is restored and
+    // the block marked invalid, unless its body was mutated, which is dropped
+    // so the block can be fetched again.
+    fn reorganize(
+        &mut self,
+        hash: &str,
+        events: &mut Vec<RustchainEvent>,
+    ) -> Result<(), BlockValidationError> {
+        let branch = self.tree.branch(hash, |hash, node| {
+            self.block_hashes.get(node.height as usize) == Some(&hash.to_string())
+        });
+        let fork_height = branch[0].header.as_ref().unwrap().block_index - 1;
+        let disconnected = self.disconnect_to(fork_height);
+
+        let mut connected = vec![];
+        for block in branch {
+            let validation = validate_block(
+                &block,
+                &self.tip(),
+                &self.utxos.read().unwrap(),
+                &self.params,
+            );
+            if let Err(reason) = validation {
+                let block_hash = hex::encode(&block.block_hash);
+                match reason.is_mutated_body() {
+                    true => self.tree.drop_body(&block_hash),
+                    false => self.tree.mark_invalid(&block_hash),
+                }
+                self.disconnect_to(fork_height);
+                for block in disconnected.into_iter().rev() {
+                    self.connect(block);
+                }
+                return Err(reason);
+            }
+            self.connect(block.clone());
+            connected.push(block);
+        }
+
+        for block in disconnected {
+            events.push(RustchainEvent::BlockDisconnected(block));
+        }
+        for block in connected {
+            events.push(RustchainEvent::BlockConnected(block));
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, block: Block) {
+        let undo = self.utxos.write().unwrap().connect_block(&block);
+        let hash = hex::encode(&block.block_hash);
+        for tx in &block.transactions {
+            self.tx_index.insert(hex::encode(tx.hash()), hash.clone());
+        }
+        self.undo.insert(hash.clone(), undo);
+        self.block_hashes.push(hash);
+    }
+
+    // pops active blocks above `height`, tip first, rolling back their
+    // transactions
+    fn disconnect_to(&mut self, height: u64) -> Vec<Block> {
+        let mut disconnected = vec![];
+        while self.block_hashes.len() as u64 > height + 1 {
+            let hash = self.block_hashes.pop().unwrap();
+            let block = self.tree.get(&hash).unwrap().block.clone();
+            let undo = self.undo.remove(&hash).unwrap_or_default();
+            self.utxos.write().unwrap().disconnect_block(&block, undo);
+            for tx in &block.transactions {
+                self.tx_index.remove(&hex::encode(tx.hash()));
+            }
+            disconnected.push(block);
+        }
+        disconnected
+    }
+
+    // compact target the child of `parent_hash` must have, retargeted from the
+    // timestamps of the parent's branch every `retarget_interval` blocks
+    fn expected_bits(&self, parent_hash: &str) -> u32 {
+        let parent = self.tree.get(parent_hash).unwrap();
+        let parent_header = parent.block.header.as_ref().unwrap();
+        let height = parent.height + 1;
+        if !self.params.is_retarget_height(height) {
+            return parent_header.bits;
+        }
+        let first_height = height.saturating_sub(self.params.retarget_interval);
+        let first = self.tree.ancestor(parent_hash, first_height).unwrap();
+        self.params
+            .retarget(parent_header, first.block.header.as_ref().unwrap())
+    }
+
+    // median timestamp of the last `median_time_span` blocks of the branch
+    // ending at `hash`
+    fn median_time_past(&self, hash: &str) -> u64 {
+        let headers = successors(self.tree.get(hash), |node| {
+            let parent = &node.block.header.as_ref()?.previous_hash;
+            self.tree.get(&hex::encode(parent))
         })
+        .filter_map(|node| node.block.header.as_ref());
+        self.params
+            .median_time_past(headers.map(|header| header.timestamp))
+    }
+
+    // compact target of the next block on top of the tip
+    pub fn next_bits(&self) -> u32 {
+        self.expected_bits(self.block_hashes.last().unwrap())
+    }
+
+    // Easiest target an orphan may have: the next block's, eased as much as a
+    // single retarget could, and never easier than `pow_limit`.
+    fn easiest_orphan_bits(&self) -> u32 {
+        let pow_limit = self.params.pow_limit_target();
+        let next = Target::from_compact(self.next_bits()).unwrap_or(pow_limit);
+        next.scale(self.params.max_adjustment_factor.max(1), 1)
+            .min(pow_limit)
+            .to_compact()
+    }
+
+    pub fn tip(&self) -> Block {
+        let hash = self.block_hashes.last().unwrap();
+        self.tree.get(hash).unwrap().block.clone()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.block_hashes.len() as u64 - 1
+    }
+
+    pub fn cumulative_work(&self) -> u128 {
+        let hash = self.block_hashes.last().unwrap();
+        self.tree.get(hash).unwrap().cumulative_work
+    }
+
+    // what heartbeats advertise to peers
+    pub fn chain_tip(&self) -> ChainTip {
+        let tip = self.tree.get(self.block_hashes.last().unwrap()).unwrap();
+        ChainTip {
+            height: tip.height,
+            hash: tip.block.block_hash.clone(),
+            cumulative_work: tip.cumulative_work.to_be_bytes().to_vec(),
+        }
+    }
+
+    // hashes of the active chain to send with GetHeaders, newest first
+    pub fn locator(&self) -> Vec<String> {
+        locator_heights(self.height())
+            .into_iter()
+            .map(|height| self.block_hashes[height as usize].clone())
+            .collect()
+    }
+
+    // blocks of the active chain, genesis first
+    pub fn blocks(&self) -> Vec<Block> {
+        self.block_hashes
+            .iter()
+            .map(|hash| self.tree.get(hash).unwrap().block.clone())
+            .collect()
+    }
+
+    // unspent outputs of the active chain
+    pub fn utxo_set(&self) -> Arc<std::sync::RwLock<UtxoSet>> {
+        self.utxos.clone()
     }
 
-    pub async fn add_block(&mut self, block: Block) {
-        self.blocks.write().await.push(block);
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    pub fn block_hashes(&self) -> Vec<String> {
+        self.block_hashes.clone()
+    }
+
+    // looks up any known block, whether it is on the active chain or not,
+    // unless its body was dropped
+    pub fn get_block(&self, hash: &str) -> Option<Block> {
+        match self.tree.has_body(hash) {
+            true => self.tree.get(hash).map(|node| node.block.clone()),
+            false => None,
+        }
+    }
+
+    // header of any known block, even one whose body was dropped
+    pub fn get_header(&self, hash: &str) -> Option<BlockHeader> {
+        self.tree
+            .get(hash)
+            .and_then(|node| node.block.header.clone())
+    }
+
+    // height of the block if it is part of the active chain
+    fn active_height(&self, hash: &str) -> Option<u64> {
+        let node = self.tree.get(hash)?;
+        let active = self.block_hashes.get(node.height as usize)? == hash;
+        active.then_some(node.height)
+    }
+
+    // Headers of the active chain following the first locator hash that is on
+    // it, or following genesis if none is. Locators list the caller's hashes
+    // newest first, so the headers start right after the last block in common.
+    pub fn headers_after(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.active_height(hash))
+            .unwrap_or(0) as usize
+            + 1;
+        self.block_hashes
+            .iter()
+            .skip(start)
+            .take(max)
+            .map(|hash| self.tree.get(hash).unwrap().block.header.clone().unwrap())
+            .collect()
+    }
+
+    // a transaction confirmed on the active chain and the block confirming it
+    pub fn find_transaction(&self, tx_hash: &str) -> Option<(Transaction, Block)> {
+        let block = self.get_block(self.tx_index.get(tx_hash)?)?;
+        let tx = block
+            .transactions
+            .iter()
+            .find(|tx| hex::encode(tx.hash()) == tx_hash)?
+            .clone();
+        Some((tx, block))
     }
 
     async fn listen_for_events(
         b: Arc<RwLock<Blockchain>>,
         mut event_receiver: Receiver<RustchainEvent>,
     ) {
+        // heartbeats advertising a better chain are handled by net::sync
         while let Some(event) = event_receiver.recv().await {
-            match event {
-                RustchainEvent::NewHeartbeat(_heartbeat) => {
-                    // TO DO: check whether blockchain is up to date with all the
-                    // blocks or else request peers for all previous blocks
-                    // (maybe up to a certain block?)
-                    // let block_hashes = heartbeat.block_hashes;
-                    unimplemented!()
-                }
-                RustchainEvent::NewBlock(block) => {
-                    let block_header = block.clone().header.unwrap();
-                    let block_hash = hex::encode(block_header.hash());
-                    let mut b_lock = b.write().await;
-                    b_lock.block_hashes.write().await.push(block_hash);
-                    b_lock.add_block(block.clone()).await;
+            if let RustchainEvent::NewBlock(block) = event {
+                let block_hash = hex::encode(&block.block_hash);
+                let (status, mut events, event_bus) = {
+                    let mut lock = b.write().await;
+                    let (status, events) = lock.add_block_deferred(block.clone());
+                    (status, events, lock.event_bus.clone())
+                };
+                if let Err(reason) = status {
+                    println!("Rejected block {}: {}", block_hash, reason);
+                    events.push(RustchainEvent::BlockRejected(block));
                 }
-                _ => {
-                    unimplemented!();
+                // published without the lock, subscribers may need the chain
+                let event_bus = event_bus.read().await;
+                for event in events {
+                    event_bus.publish(event).await;
                 }
             }
         }
     }
 }
 
-// #[cfg(test)]
-// pub mod tests {
-//     use super::*;
-
-//     #[tokio::test]
-//     async fn test_blockchain_creation() {
-//         let event_bus = EventBus::new();
-//         let blockchain = Blockchain::new(event_bus);
-//         let genesis = blockchain.get_genesis().unwrap();
-//         assert_eq!(0, genesis.header.unwrap().block_index);
-//     }
-// }
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::block::{next_block, GENESIS_BITS};
+    use crate::blockchain::merkle::merkle_root;
+    use crate::blockchain::store::tests::temp_store_path;
+    use crate::blockchain::store::FileBlockStore;
+    use crate::blockchain::validation::tests::{
+        mined_block, mined_block_for, new_wallet, solve, spend,
+    };
+    use crate::protos::UtxoInput;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    // empty blocks mined in the same second would be identical, so every
+    // block pays its coinbase to `tag`
+    fn tagged_block(parent: &Block, tag: &str) -> Block {
+        mined_block_for(parent, tag, vec![])
+    }
+
+    async fn next_event(receiver: &mut Receiver<RustchainEvent>) -> RustchainEvent {
+        timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_blockchain_creation() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = blockchain.read().await.tip();
+        assert_eq!(create_genesis_block(), genesis);
+        assert_eq!(0, blockchain.read().await.height());
+    }
+
+    #[tokio::test]
+    async fn test_add_valid_block() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let block = mined_block(&create_genesis_block(), vec![]);
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            blockchain.write().await.add_block(block.clone()).await
+        );
+        assert_eq!(
+            Ok(BlockStatus::Duplicate),
+            blockchain.write().await.add_block(block.clone()).await
+        );
+        assert_eq!(block, blockchain.read().await.tip());
+    }
+
+    #[tokio::test]
+    async fn test_headers_after_locator() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let mut b = blockchain.write().await;
+        let mut blocks = vec![create_genesis_block()];
+        for _ in 0..3 {
+            let block = mined_block(blocks.last().unwrap(), vec![]);
+            b.add_block(block.clone()).await.unwrap();
+            blocks.push(block);
+        }
+        let header = |height: usize| blocks[height].header.clone().unwrap();
+        assert_eq!(
+            vec![header(1), header(2), header(3)],
+            b.headers_after(&[], 10)
+        );
+        // the first known hash of the locator wins
+        let locator = vec![String::from("unknown"), hex::encode(&blocks[1].block_hash)];
+        assert_eq!(vec![header(2)], b.headers_after(&locator, 1));
+        let locator = vec![hex::encode(&blocks[3].block_hash)];
+        assert!(b.headers_after(&locator, 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chain_tip_and_locator() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let mut b = blockchain.write().await;
+        let mut blocks = vec![create_genesis_block()];
+        for _ in 0..12 {
+            let block = mined_block(blocks.last().unwrap(), vec![]);
+            b.add_block(block.clone()).await.unwrap();
+            blocks.push(block);
+        }
+        let tip = b.chain_tip();
+        assert_eq!(12, tip.height);
+        assert_eq!(blocks[12].block_hash, tip.hash);
+        assert_eq!(b.cumulative_work(), tip.work());
+        let hash = |height: usize| hex::encode(&blocks[height].block_hash);
+        let expected: Vec<String> = [12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 1, 0]
+            .into_iter()
+            .map(hash)
+            .collect();
+        assert_eq!(expected, b.locator());
+    }
+
+    #[tokio::test]
+    async fn test_find_transaction_follows_active_chain() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = create_genesis_block();
+        let block_a = tagged_block(&genesis, "a");
+        let coinbase_a = hex::encode(block_a.transactions[0].hash());
+        let mut b = blockchain.write().await;
+        b.add_block(block_a.clone()).await.unwrap();
+        let (tx, block) = b.find_transaction(&coinbase_a).unwrap();
+        assert_eq!(block_a.transactions[0], tx);
+        assert_eq!(block_a, block);
+
+        // a longer branch drops block a
+        let block_b = tagged_block(&genesis, "b");
+        b.add_block(block_b.clone()).await.unwrap();
+        b.add_block(tagged_block(&block_b, "b")).await.unwrap();
+        assert_eq!(None, b.find_transaction(&coinbase_a));
+        assert!(b
+            .find_transaction(&hex::encode(block_b.transactions[0].hash()))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_header() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let mut block = mined_block(&create_genesis_block(), vec![]);
+        block.header.as_mut().unwrap().block_index = 2;
+        assert!(matches!(
+            blockchain.write().await.add_block(block).await,
+            Err(BlockValidationError::UnexpectedIndex { .. })
+        ));
+        assert_eq!(0, blockchain.read().await.height());
+    }
+
+    #[tokio::test]
+    async fn test_difficulty_is_retargeted() {
+        let event_bus = EventBus::new().await;
+        let params = ConsensusParams {
+            retarget_interval: 2,
+            target_block_time: 1000,
+            ..ConsensusParams::default()
+        };
+        let store = Box::new(MemoryBlockStore::new());
+        let blockchain = Blockchain::with_params(event_bus, store, params).await;
+        let mut b = blockchain.write().await;
+        let block_1 = mined_block(&b.tip(), vec![]);
+        b.add_block(block_1.clone()).await.unwrap();
+        // genesis is far older than the target block time, so the target
+        // grows as much as a retarget allows
+        assert_eq!(0x2001ffff, b.next_bits());
+
+        let stale = mined_block(&block_1, vec![]);
+        assert_eq!(
+            Err(BlockValidationError::UnexpectedDifficulty {
+                expected: 0x2001ffff,
+                found: GENESIS_BITS
+            }),
+            b.add_block(stale).await
+        );
+        let coinbase = &mined_block(&block_1, vec![]).transactions;
+        let merkle_root = merkle_root(coinbase);
+        let retargeted = solve(next_block(
+            &block_1,
+            coinbase.clone(),
+            merkle_root,
+            0x2001ffff,
+        ));
+        assert_eq!(Ok(BlockStatus::Connected), b.add_block(retargeted).await);
+    }
+
+    #[tokio::test]
+    async fn test_competing_block_is_kept_on_side_chain() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = create_genesis_block();
+        let block_a = tagged_block(&genesis, "a");
+        let block_b = tagged_block(&genesis, "b");
+        let mut b = blockchain.write().await;
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            b.add_block(block_a.clone()).await
+        );
+        assert_eq!(
+            Ok(BlockStatus::SideChain),
+            b.add_block(block_b.clone()).await
+        );
+        assert_eq!(block_a, b.tip());
+        assert_eq!(
+            Some(block_b.clone()),
+            b.get_block(&hex::encode(&block_b.block_hash))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reorg_to_heavier_branch() {
+        let event_bus = EventBus::new().await;
+        let mut receiver = event_bus.write().await.subscribe().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = create_genesis_block();
+        let block_a1 = tagged_block(&genesis, "a");
+        let block_b1 = tagged_block(&genesis, "b");
+        let block_b2 = tagged_block(&block_b1, "b");
+        let mut b = blockchain.write().await;
+        b.add_block(block_a1.clone()).await.unwrap();
+        b.add_block(block_b1.clone()).await.unwrap();
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            b.add_block(block_b2.clone()).await
+        );
+        assert_eq!(
+            vec![genesis, block_b1.clone(), block_b2.clone()],
+            b.blocks()
+        );
+        assert_eq!(0, b.utxos.read().unwrap().balance("a"));
+
+        assert!(
+            matches!(next_event(&mut receiver).await, RustchainEvent::BlockConnected(block) if block == block_a1)
+        );
+        assert!(
+            matches!(next_event(&mut receiver).await, RustchainEvent::BlockDisconnected(block) if block == block_a1)
+        );
+        assert!(
+            matches!(next_event(&mut receiver).await, RustchainEvent::BlockConnected(block) if block == block_b1)
+        );
+        assert!(
+            matches!(next_event(&mut receiver).await, RustchainEvent::BlockConnected(block) if block == block_b2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_branch_restores_chain() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = create_genesis_block();
+        let block_a1 = tagged_block(&genesis, "a");
+        let block_b1 = tagged_block(&genesis, "b");
+        let unknown_input = Transaction {
+            inputs: vec![UtxoInput {
+                prev_tx_hash: b"unknown".to_vec(),
+                ..UtxoInput::default()
+            }],
+            outputs: vec![],
+        };
+        let block_b2 = mined_block(&block_b1, vec![unknown_input]);
+        let block_b3 = tagged_block(&block_b2, "b");
+        let mut b = blockchain.write().await;
+        b.add_block(block_a1.clone()).await.unwrap();
+        b.add_block(block_b1.clone()).await.unwrap();
+        assert!(b.add_block(block_b2.clone()).await.is_err());
+        assert_eq!(vec![genesis.clone(), block_a1.clone()], b.blocks());
+        assert_eq!(50, b.utxos.read().unwrap().balance("a"));
+        assert_eq!(0, b.utxos.read().unwrap().balance("b"));
+        // the header commits to the body, so the branch is banned
+        assert!(b.get_header(&hex::encode(&block_b2.block_hash)).is_none());
+        assert!(matches!(
+            b.add_block(block_b3).await,
+            Err(BlockValidationError::KnownInvalid { .. })
+        ));
+        assert_eq!(vec![genesis, block_a1], b.blocks());
+    }
+
+    #[tokio::test]
+    async fn test_double_spend_marks_block_invalid() {
+        let event_bus = EventBus::new().await;
+        let params = ConsensusParams {
+            coinbase_maturity: 1,
+            ..ConsensusParams::default()
+        };
+        let store = Box::new(MemoryBlockStore::new());
+        let blockchain = Blockchain::with_params(event_bus.clone(), store, params).await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let mut b = blockchain.write().await;
+        let block_1 = mined_block_for(&b.tip(), &bob.get_address(), vec![]);
+        b.add_block(block_1.clone()).await.unwrap();
+        // the merkle root matches, the transactions spend the same output
+        let coinbase = &block_1.transactions[0];
+        let to_alice = spend(&bob, coinbase, String::from("alice"), 50);
+        let to_carol = spend(&bob, coinbase, String::from("carol"), 50);
+        let block_2 = mined_block(&block_1, vec![to_alice, to_carol]);
+        assert!(matches!(
+            b.add_block(block_2.clone()).await,
+            Err(BlockValidationError::DoubleSpend { .. })
+        ));
+        assert_eq!(block_1, b.tip());
+        assert!(!b.tree.has_missing_bodies());
+        // sent again it's refused without disconnecting anything
+        let (status, events) = b.add_block_deferred(block_2);
+        assert!(matches!(
+            status,
+            Err(BlockValidationError::KnownInvalid { .. })
+        ));
+        assert!(events.is_empty());
+        assert_eq!(block_1, b.tip());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_body_can_be_fetched_again() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let genesis = create_genesis_block();
+        let block_a1 = tagged_block(&genesis, "a");
+        let block_a2 = tagged_block(&block_a1, "a");
+        let block_b1 = tagged_block(&genesis, "b");
+        let block_b2 = tagged_block(&block_b1, "b");
+        let block_b3 = tagged_block(&block_b2, "b");
+        let mut b = blockchain.write().await;
+        for block in [&block_a1, &block_a2, &block_b1, &block_b2] {
+            b.add_block(block.clone()).await.unwrap();
+        }
+        // as if the body b2 came with had failed
+        b.tree.drop_body(&hex::encode(&block_b2.block_hash));
+        assert_eq!(
+            Ok(BlockStatus::SideChain),
+            b.add_block(block_b3.clone()).await
+        );
+        assert_eq!(block_a2, b.tip());
+        // the genuine body brings back the whole branch
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            b.add_block(block_b2.clone()).await
+        );
+        assert_eq!(
+            vec![genesis, block_b1, block_b2, block_b3.clone()],
+            b.blocks()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mutated_block_does_not_ban_the_genuine_one() {
+        let event_bus = EventBus::new().await;
+        let params = ConsensusParams {
+            coinbase_maturity: 1,
+            ..ConsensusParams::default()
+        };
+        let store = Box::new(MemoryBlockStore::new());
+        let blockchain = Blockchain::with_params(event_bus.clone(), store, params).await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let mut b = blockchain.write().await;
+        let block_1 = mined_block_for(&b.tip(), &bob.get_address(), vec![]);
+        // coinbase, and two transactions, so the last one is paired with itself
+        let first = spend(&bob, &block_1.transactions[0], bob.get_address(), 50);
+        let second = spend(&bob, &first, String::from("alice"), 50);
+        let block_2 = mined_block(&block_1, vec![first, second.clone()]);
+        let mut mutated = block_2.clone();
+        mutated.transactions.push(second);
+        assert_eq!(
+            hex::encode(&block_2.block_hash),
+            hex::encode(mutated.header.as_ref().unwrap().hash())
+        );
+        // refused as an orphan, and once its parent is known
+        for parent in [None, Some(block_1)] {
+            if let Some(parent) = parent {
+                b.add_block(parent).await.unwrap();
+            }
+            assert!(matches!(
+                b.add_block(mutated.clone()).await,
+                Err(BlockValidationError::DuplicateTransaction { .. })
+            ));
+        }
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            b.add_block(block_2.clone()).await
+        );
+        assert_eq!(block_2, b.tip());
+        assert_eq!(50, b.utxos.read().unwrap().balance("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_orphan_connects_when_parent_arrives() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let block_1 = tagged_block(&create_genesis_block(), "a");
+        let block_2 = tagged_block(&block_1, "a");
+        let block_3 = tagged_block(&block_2, "a");
+        let mut b = blockchain.write().await;
+        assert_eq!(Ok(BlockStatus::Orphan), b.add_block(block_3.clone()).await);
+        assert_eq!(Ok(BlockStatus::Orphan), b.add_block(block_2.clone()).await);
+        assert_eq!(0, b.height());
+        assert_eq!(Ok(BlockStatus::Connected), b.add_block(block_1).await);
+        assert_eq!(block_3, b.tip());
+        assert_eq!(0, b.tree.orphans_len());
+    }
+
+    #[tokio::test]
+    async fn test_orphan_with_easy_target_is_refused() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let block_1 = tagged_block(&create_genesis_block(), "a");
+        let coinbase = &tagged_block(&block_1, "a").transactions;
+        let pow_limit = ConsensusParams::default().pow_limit;
+        let easy = solve(next_block(
+            &block_1,
+            coinbase.clone(),
+            merkle_root(coinbase),
+            pow_limit,
+        ));
+        let mut b = blockchain.write().await;
+        assert!(matches!(
+            b.add_block(easy).await,
+            Err(BlockValidationError::TargetTooEasy { found, .. }) if found == pow_limit
+        ));
+        assert_eq!(0, b.tree.orphans_len());
+    }
+
+    #[tokio::test]
+    async fn test_block_timestamps_are_bounded() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let mut b = blockchain.write().await;
+        let genesis = b.tip();
+        let at = |timestamp: u64| {
+            let mut block = tagged_block(&genesis, "a");
+            block.header.as_mut().unwrap().timestamp = timestamp;
+            solve(block)
+        };
+        let genesis_time = genesis.header.as_ref().unwrap().timestamp;
+        assert!(matches!(
+            b.add_block(at(genesis_time - 1)).await,
+            Err(BlockValidationError::TimestampTooEarly { median_time_past, .. })
+                if median_time_past == genesis_time
+        ));
+        let latest = b.params().latest_timestamp();
+        assert!(matches!(
+            b.add_block(at(latest + 60)).await,
+            Err(BlockValidationError::TimestampTooFarAhead { .. })
+        ));
+        assert_eq!(
+            Ok(BlockStatus::Connected),
+            b.add_block(at(genesis_time)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_from_store() {
+        let path = temp_store_path("reload");
+        let genesis = create_genesis_block();
+        let block_a1 = tagged_block(&genesis, "a");
+        let block_b1 = tagged_block(&genesis, "b");
+        let block_b2 = tagged_block(&block_b1, "b");
+        {
+            let store = Box::new(FileBlockStore::open(&path).unwrap());
+            let blockchain = Blockchain::with_store(EventBus::new().await, store).await;
+            let mut b = blockchain.write().await;
+            for block in [&block_a1, &block_b1, &block_b2] {
+                b.add_block(block.clone()).await.unwrap();
+            }
+        }
+        let store = Box::new(FileBlockStore::open(&path).unwrap());
+        let blockchain = Blockchain::with_store(EventBus::new().await, store).await;
+        let b = blockchain.read().await;
+        assert_eq!(vec![genesis, block_b1, block_b2], b.blocks());
+        assert_eq!(
+            Some(block_a1.clone()),
+            b.get_block(&hex::encode(&block_a1.block_hash))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_block_event() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let block_1 = mined_block(&create_genesis_block(), vec![]);
+        let mut invalid = mined_block(&block_1, vec![]);
+        invalid.block_hash = vec![];
+        for block in [block_1.clone(), invalid] {
+            event_bus
+                .read()
+                .await
+                .publish(RustchainEvent::NewBlock(block))
+                .await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(block_1, blockchain.read().await.tip());
+    }
+}
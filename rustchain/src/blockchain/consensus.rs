@@ -0,0 +1,208 @@
+use crate::blockchain::target::Target;
+use crate::protos::{BlockHeader, Transaction, UtxoInput, UtxoOutput};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Rules every node of the network must agree on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsensusParams {
+    // reward of the first blocks, halved every `halving_interval` blocks
+    pub initial_subsidy: u32,
+    pub halving_interval: u64,
+    // blocks that have to be mined on top of a coinbase before its outputs
+    // can be spent
+    pub coinbase_maturity: u64,
+    // the target is recomputed every `retarget_interval` blocks so that blocks
+    // come every `target_block_time` seconds on average
+    pub retarget_interval: u64,
+    pub target_block_time: u64,
+    // a single retarget can't make blocks more than this many times harder
+    // or easier to mine
+    pub max_adjustment_factor: u64,
+    // compact form of the easiest target a retarget can reach
+    pub pow_limit: u32,
+    // a block can't be older than the median timestamp of the last
+    // `median_time_span` blocks, nor more than `max_future_drift` seconds
+    // ahead of the clock of the node validating it
+    pub median_time_span: u64,
+    pub max_future_drift: u64,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            initial_subsidy: 50,
+            halving_interval: 210_000,
+            coinbase_maturity: 100,
+            retarget_interval: 100,
+            target_block_time: 60,
+            max_adjustment_factor: 4,
+            pow_limit: 0x207fffff,
+            median_time_span: 11,
+            max_future_drift: 2 * 60 * 60,
+        }
+    }
+}
+
+impl ConsensusParams {
+    // newly created coins a block at `height` may pay to its miner
+    pub fn block_subsidy(&self, height: u64) -> u32 {
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= u32::BITS as u64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+
+    pub fn is_retarget_height(&self, height: u64) -> bool {
+        height > 0 && height.is_multiple_of(self.retarget_interval.max(1))
+    }
+
+    // Compact target of the block following `parent`, where `first` is the
+    // first block of the interval that just ended. The parent's target is
+    // scaled by how long the interval actually took over how long it should
+    // have, at most `max_adjustment_factor` times either way and never above
+    // `pow_limit`.
+    pub fn retarget(&self, parent: &BlockHeader, first: &BlockHeader) -> u32 {
+        let blocks = parent.block_index.saturating_sub(first.block_index).max(1);
+        let expected = self.target_block_time.max(1) * blocks;
+        let factor = self.max_adjustment_factor.max(1);
+        let actual = parent
+            .timestamp
+            .saturating_sub(first.timestamp)
+            .clamp(expected / factor, expected * factor);
+
+        let pow_limit = self.pow_limit_target();
+        let target = Target::from_compact(parent.bits).unwrap_or(pow_limit);
+        target.scale(actual, expected).min(pow_limit).to_compact()
+    }
+
+    // median of the last `median_time_span` of `timestamps`, newest first
+    pub fn median_time_past(&self, timestamps: impl Iterator<Item = u64>) -> u64 {
+        let mut timestamps: Vec<u64> = timestamps.take(self.median_time_span as usize).collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    // latest timestamp a block can have by the clock of this node
+    pub fn latest_timestamp(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        now + self.max_future_drift
+    }
+
+    pub fn pow_limit_target(&self) -> Target {
+        Target::from_compact(self.pow_limit).unwrap_or(Target::MAX)
+    }
+}
+
+// First transaction of every block, paying the subsidy plus the fees of the
+// block to the miner. Its single input spends nothing, it only carries the
+// height so that two coinbases paying the same address and amount still have
+// different hashes.
+pub fn coinbase_transaction(to_addr: String, height: u64, amount: u32) -> Transaction {
+    Transaction {
+        inputs: vec![UtxoInput {
+            output_index: height as u32,
+            ..UtxoInput::default()
+        }],
+        outputs: vec![UtxoOutput { to_addr, amount }],
+    }
+}
+
+pub fn is_coinbase(tx: &Transaction) -> bool {
+    tx.inputs.len() == 1 && tx.inputs[0].prev_tx_hash.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(block_index: u64, timestamp: u64, bits: u32) -> BlockHeader {
+        BlockHeader {
+            block_index,
+            timestamp,
+            bits,
+            ..BlockHeader::default()
+        }
+    }
+
+    fn retarget_params() -> ConsensusParams {
+        ConsensusParams {
+            retarget_interval: 10,
+            target_block_time: 60,
+            max_adjustment_factor: 4,
+            ..ConsensusParams::default()
+        }
+    }
+
+    #[test]
+    fn test_halving_schedule() {
+        let params = ConsensusParams {
+            initial_subsidy: 50,
+            halving_interval: 10,
+            ..ConsensusParams::default()
+        };
+        assert_eq!(50, params.block_subsidy(0));
+        assert_eq!(50, params.block_subsidy(9));
+        assert_eq!(25, params.block_subsidy(10));
+        assert_eq!(12, params.block_subsidy(20));
+        assert_eq!(0, params.block_subsidy(10 * 64));
+    }
+
+    #[test]
+    fn test_retarget_height() {
+        let params = retarget_params();
+        assert!(!params.is_retarget_height(0));
+        assert!(!params.is_retarget_height(9));
+        assert!(params.is_retarget_height(10));
+        assert!(params.is_retarget_height(20));
+    }
+
+    #[test]
+    fn test_retarget_on_time_keeps_target() {
+        let params = retarget_params();
+        let first = header(0, 1000, 0x1f7fffff);
+        let parent = header(9, 1000 + 9 * 60, 0x1f7fffff);
+        assert_eq!(0x1f7fffff, params.retarget(&parent, &first));
+    }
+
+    #[test]
+    fn test_retarget_fast_blocks_lower_target() {
+        let params = retarget_params();
+        let first = header(0, 1000, 0x1f7fffff);
+        // twice as fast
+        let parent = header(9, 1000 + 9 * 30, 0x1f7fffff);
+        assert_eq!(0x1f3fffff, params.retarget(&parent, &first));
+        // a third faster, which whole bits of difficulty couldn't express
+        let parent = header(9, 1000 + 9 * 40, 0x1f7fffff);
+        assert_eq!(0x1f555554, params.retarget(&parent, &first));
+        // way faster, clamped to 4x
+        let parent = header(9, 1001, 0x1f7fffff);
+        assert_eq!(0x1f1fffff, params.retarget(&parent, &first));
+    }
+
+    #[test]
+    fn test_retarget_slow_blocks_raise_target() {
+        let params = retarget_params();
+        let first = header(0, 1000, 0x1f7fffff);
+        let parent = header(9, 1000 + 9 * 120, 0x1f7fffff);
+        assert_eq!(0x2000ffff, params.retarget(&parent, &first));
+        // clamped to 4x
+        let parent = header(9, 1000 + 9 * 6000, 0x1f7fffff);
+        assert_eq!(0x2001ffff, params.retarget(&parent, &first));
+        // never easier than the limit
+        let parent = header(9, 1000 + 9 * 6000, 0x2040ffff);
+        assert_eq!(params.pow_limit, params.retarget(&parent, &first));
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_height() {
+        let first = coinbase_transaction(String::from("bob"), 1, 50);
+        let second = coinbase_transaction(String::from("bob"), 2, 50);
+        assert!(is_coinbase(&first));
+        assert_ne!(first.hash(), second.hash());
+        assert!(!is_coinbase(&Transaction::default()));
+    }
+}
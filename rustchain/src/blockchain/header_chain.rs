@@ -0,0 +1,246 @@
+use crate::blockchain::block::create_genesis_block;
+use crate::blockchain::consensus::ConsensusParams;
+use crate::blockchain::target::block_work;
+use crate::blockchain::validation::{
+    validate_chained_header, validate_header_timestamp, BlockValidationError,
+};
+use crate::protos::BlockHeader;
+
+// Chain of block headers kept by light clients, which never download the
+// transactions. Headers are checked for linkage, proof of work, the target
+// and timestamps consensus expects, and the branch with the most cumulative
+// work wins.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    // active chain, indexed by height
+    headers: Vec<BlockHeader>,
+    // cumulative work up to each height
+    work: Vec<u128>,
+    params: ConsensusParams,
+}
+
+impl Default for HeaderChain {
+    fn default() -> Self {
+        HeaderChain::new(ConsensusParams::default())
+    }
+}
+
+impl HeaderChain {
+    pub fn new(params: ConsensusParams) -> Self {
+        let genesis = create_genesis_block().header.unwrap();
+        HeaderChain {
+            work: vec![block_work(genesis.bits)],
+            headers: vec![genesis],
+            params,
+        }
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().unwrap()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64 - 1
+    }
+
+    pub fn cumulative_work(&self) -> u128 {
+        *self.work.last().unwrap()
+    }
+
+    pub fn get(&self, height: u64) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    // height of the header with this hash, if it is on the chain
+    pub fn position(&self, hash: &[u8]) -> Option<u64> {
+        self.headers
+            .iter()
+            .rposition(|header| header.hash() == hash)
+            .map(|height| height as u64)
+    }
+
+    // Hashes the chain is made of, newest first: the last ten, then every
+    // other step twice as far back, and genesis. A full node finds the last
+    // block in common with a few of them even after a long fork.
+    pub fn locator(&self) -> Vec<Vec<u8>> {
+        locator_heights(self.height())
+            .into_iter()
+            .map(|height| self.headers[height as usize].hash())
+            .collect()
+    }
+
+    // Validates a batch of consecutive headers and switches to them if they
+    // end up with more work than the current chain. The first one has to
+    // extend a header already on the chain. Returns whether the chain changed.
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<bool, BlockValidationError> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(false),
+        };
+        let fork = self.position(&first.previous_hash).ok_or_else(|| {
+            BlockValidationError::PreviousHashMismatch {
+                expected: hex::encode(self.tip().hash()),
+                found: hex::encode(&first.previous_hash),
+            }
+        })?;
+        let mut branch = self.headers[..=fork as usize].to_vec();
+        let mut work = self.work[..=fork as usize].to_vec();
+        let latest = self.params.latest_timestamp();
+        for header in headers {
+            validate_chained_header(header, branch.last().unwrap())?;
+            let timestamps = branch.iter().rev().map(|header| header.timestamp);
+            let median_time_past = self.params.median_time_past(timestamps);
+            validate_header_timestamp(header, median_time_past, latest)?;
+            let expected = self.expected_bits(&branch);
+            if header.bits != expected {
+                return Err(BlockValidationError::UnexpectedDifficulty {
+                    expected,
+                    found: header.bits,
+                });
+            }
+            work.push(work.last().unwrap().saturating_add(block_work(header.bits)));
+            branch.push(header.clone());
+        }
+        if *work.last().unwrap() <= self.cumulative_work() {
+            return Ok(false);
+        }
+        self.headers = branch;
+        self.work = work;
+        Ok(true)
+    }
+
+    // compact target of the header following the last one of `branch`
+    fn expected_bits(&self, branch: &[BlockHeader]) -> u32 {
+        let parent = branch.last().unwrap();
+        let height = parent.block_index + 1;
+        if !self.params.is_retarget_height(height) {
+            return parent.bits;
+        }
+        let first = &branch[height.saturating_sub(self.params.retarget_interval) as usize];
+        self.params.retarget(parent, first)
+    }
+}
+
+// Heights a locator is made of for a chain up to `tip`, newest first: the
+// last ten, then steps twice as far back each time, and genesis.
+pub fn locator_heights(tip: u64) -> Vec<u64> {
+    let mut heights = vec![];
+    let mut height = tip;
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            return heights;
+        }
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::target::hash_meets_target;
+    use crate::blockchain::validation::tests::{mined_block, mined_block_for, solve};
+    use crate::protos::Block;
+
+    fn headers(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks
+            .iter()
+            .map(|block| block.header.clone().unwrap())
+            .collect()
+    }
+
+    fn chain_of(length: usize, miner: &str) -> Vec<Block> {
+        let mut blocks = vec![create_genesis_block()];
+        for _ in 0..length {
+            let block = mined_block_for(blocks.last().unwrap(), miner, vec![]);
+            blocks.push(block);
+        }
+        blocks.split_off(1)
+    }
+
+    #[test]
+    fn test_add_headers() {
+        let mut chain = HeaderChain::default();
+        let blocks = chain_of(3, "miner");
+        assert_eq!(Ok(true), chain.add_headers(&headers(&blocks)));
+        assert_eq!(3, chain.height());
+        assert_eq!(blocks[2].block_hash, chain.tip().hash());
+        assert_eq!(Some(2), chain.position(&blocks[1].block_hash));
+        // already known
+        assert_eq!(Ok(false), chain.add_headers(&headers(&blocks)));
+    }
+
+    #[test]
+    fn test_rejects_unlinked_and_invalid_headers() {
+        let mut chain = HeaderChain::default();
+        let blocks = chain_of(2, "miner");
+        assert!(matches!(
+            chain.add_headers(&headers(&blocks[1..])),
+            Err(BlockValidationError::PreviousHashMismatch { .. })
+        ));
+        let mut header = blocks[0].header.clone().unwrap();
+        header.nonce += 1;
+        while hash_meets_target(&header.hash(), header.bits) {
+            header.nonce += 1;
+        }
+        assert!(matches!(
+            chain.add_headers(&[header]),
+            Err(BlockValidationError::InsufficientWork { .. })
+        ));
+        assert_eq!(0, chain.height());
+    }
+
+    #[test]
+    fn test_rejects_time_warped_headers() {
+        let mut chain = HeaderChain::default();
+        let genesis = create_genesis_block();
+        let at = |timestamp: u64| {
+            let mut block = mined_block(&genesis, vec![]);
+            block.header.as_mut().unwrap().timestamp = timestamp;
+            solve(block).header.unwrap()
+        };
+        let genesis_time = genesis.header.as_ref().unwrap().timestamp;
+        assert!(matches!(
+            chain.add_headers(&[at(genesis_time - 1)]),
+            Err(BlockValidationError::TimestampTooEarly { .. })
+        ));
+        let latest = ConsensusParams::default().latest_timestamp();
+        assert!(matches!(
+            chain.add_headers(&[at(latest + 60)]),
+            Err(BlockValidationError::TimestampTooFarAhead { .. })
+        ));
+        assert_eq!(0, chain.height());
+        assert_eq!(Ok(true), chain.add_headers(&[at(genesis_time)]));
+    }
+
+    #[test]
+    fn test_switches_to_branch_with_more_work() {
+        let mut chain = HeaderChain::default();
+        let short = chain_of(1, "a");
+        let long = chain_of(2, "b");
+        chain.add_headers(&headers(&short)).unwrap();
+        assert_eq!(Ok(true), chain.add_headers(&headers(&long)));
+        assert_eq!(long[1].block_hash, chain.tip().hash());
+        // a branch with as much work as the chain doesn't replace it
+        let other = mined_block(&long[0], vec![]);
+        assert_eq!(Ok(false), chain.add_headers(&headers(&[other])));
+        assert_eq!(long[1].block_hash, chain.tip().hash());
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut chain = HeaderChain::default();
+        let blocks = chain_of(15, "miner");
+        chain.add_headers(&headers(&blocks)).unwrap();
+        let locator = chain.locator();
+        let heights: Vec<u64> = locator
+            .iter()
+            .map(|hash| chain.position(hash).unwrap())
+            .collect();
+        assert_eq!(vec![15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 4, 0], heights);
+    }
+}
@@ -1,412 +1,341 @@
-// use serde::{Deserialize, Serialize};
-// use sha2::{Digest, Sha256};
-
-// trait IMerkleTree {
-//     // The Insert function takes a key and value as arguments. It will traverse
-//     // Merkle Tree, find the rightmost place to insert the entry. Entry is an object consisting of
-//     // ({key, value, etc..}). Merkle Tree consist of keys, where a key is hash of the JSON string
-//     // of the entry and value is the JSON string of the entry. Insert function will return a string
-//     // which will be the new Root hash. After every insert returned Root hash will correspond
-//     // to the latest state of the Merkle Tree.
-//     fn insert(&mut self, entry: MerkleEntry) -> Result<[u8; 32], String>;
-
-//     // The Delete function takes a key (Entry) as argument, traverses the Merkle
-//     // Tree and finds that key. If the key exists, delete the corresponding Entry and re-balance
-//     // the tree if necessary. Delete function will return updated root hash if the key was found
-//     // otherwise return empty string (or ‘’path_not_found”) if the key doesn't exist.
-//     fn delete(&mut self, entry: &MerkleEntry) -> Result<[u8; 32], String>;
-
-//     // The GenerateMerklePath function takes a key (Entry object) and return the
-//     // Merkle Path of this key in the form of the ordered list of hashes, starting from the leaf. If
-//     // the key does not exist, then return empty string (or ‘’path_not_found”).
-//     fn generate_merkle_path(&self, entry: MerkleEntry) -> Result<Vec<MerkleNode>, String>;
-
-//     // The VerifyMerklePath function takes a key (Entry) and its Merkle path, the
-//     // ordered list of sibling hashes as argument. It computes all the hashes on the path from
-//     // the given Entry to the root using the location and the MerklePath. The newly computed
-//     // root hash is compared to the stored root for verification. Function returns true if the
-//     // verification succeeds (if the newly computed root hash is equal to the stored root hash)
-//     // otherwise return false.
-//     fn verify_path(&self, key: [u8; 32], location: String, path: Vec<MerkleNode>) -> bool;
-// }
-
-// #[derive(Debug, Clone, PartialEq, Eq)]
-// pub struct MerkleTree {
-//     pub root_hash: [u8; 32],
-//     pub root_node: Option<Box<MerkleNode>>,
-//     pub depth: i32,
-//     pub leaves: Vec<Box<MerkleNode>>,
-// }
-
-// #[derive(Debug, Clone, PartialEq, Eq)]
-// pub struct MerkleNode {
-//     pub value: [u8; 32],
-//     pub left: Option<Box<MerkleNode>>,
-//     pub right: Option<Box<MerkleNode>>,
-//     pub leaf: Option<MerkleEntry>,
-// }
-
-// #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-// pub struct MerkleEntry {
-//     pub key: [u8; 32],
-//     pub value: Vec<u8>,
-// }
-
-// impl IMerkleTree for MerkleTree {
-//     fn insert(&mut self, entry: MerkleEntry) -> Result<[u8; 32], String> {
-//         let leaf = self.new_leaf(entry.clone());
-
-//         // empty tree
-//         if self.root_node.is_none() {
-//             self.root_node = Some(leaf);
-//             self.root_hash = leaf.value;
-//             self.depth = 1;
-//             self.leaves.push(leaf);
-//             return Ok(self.root_hash);
-//         }
-
-//         let num_leaves: f64 = self.leaves.len() as f64;
-//         let max_leaves: f64 = (2f64).powf(self.depth as f64 - 1.0);
-
-//         // merkle tree is full - add new root
-//         if num_leaves == max_leaves {
-//             let new_root = self.new_node(self.root_node.take(), Some(leaf.clone()));
-//             self.root_node = Some(new_root.clone());
-//             self.root_hash = new_root.value;
-//             self.depth += 1;
-
-//             let mut node = new_root;
-//             for _ in 0..(self.depth - 1) {
-//                 let temp_node = self.new_node(Some(leaf.clone()), None);
-//                 node.left.as_mut().unwrap().right = Some(temp_node.clone());
-//                 node = temp_node;
-//             }
-//             self.leaves.push(leaf);
-//             return Ok(self.root_hash);
-//         }
-
-//         // adding new leaves
-//         let mut parents = vec![];
-//         let mut current_node = self.root_node.as_ref().unwrap();
-//         while current_node.left.is_some() || current_node.right.is_some() {
-//             parents.push(current_node);
-//             if current_node.right.is_some() {
-//                 current_node = current_node.right.as_ref().unwrap();
-//             } else {
-//                 current_node = current_node.left.as_ref().unwrap();
-//             }
-//         }
-
-//         let is_even = self.leaves.len() % 2 == 0;
-//         let mut parent = parents.pop().unwrap();
-//         if is_even {
-//             let mut counter = 0;
-//             while parent.right.is_some() {
-//                 parent = parents.pop().unwrap();
-//                 counter += 1;
-//             }
-
-//             let mut current = parent;
-//             for _ in 0..counter {
-//                 let new_node = self.new_node(Some(leaf.clone()), None);
-//                 if current.right.is_none() {
-//                     current.right = Some(new_node.clone());
-//                 } else {
-//                     current.left = Some(new_node.clone());
-//                 }
-//                 current = &new_node;
-//             }
-//             current.left = Some(leaf.clone());
-//         } else {
-//             parent.right = Some(leaf.clone());
-//             parent.value = self.digest_nodes(parent.left.as_ref().unwrap().value, leaf.value);
-//         }
-//         let mut current_parent = parent;
-//         while let Some(parent) = parents.pop() {
-//             let left_hash = parent.left.as_ref().unwrap().value;
-//             let right_hash = parent.right.as_ref().unwrap().value;
-//             parent.value = self.digest_nodes(left_hash, right_hash);
-//             current_parent = parent;
-//         }
-
-//         self.root_hash = current_parent.value;
-//         self.leaves.push(leaf);
-//         Ok(self.root_hash)
-//     }
-
-//     fn delete(&mut self, entry: &MerkleEntry) -> Result<[u8; 32], String> {
-//         // only 1 node
-//         if self.leaves.len() == 1 {
-//             self.root_node = None;
-//             self.root_hash = [0; 32];
-//             self.depth = 0;
-//             self.leaves = Vec::new();
-//             return Ok(self.root_hash);
-//         }
-
-//         // case 2 nodes
-//         if self.leaves.len() == 2 {
-//             if self.entries_equal(
-//                 entry,
-//                 &self
-//                     .root_node
-//                     .as_ref()
-//                     .unwrap()
-//                     .left
-//                     .as_ref()
-//                     .unwrap()
-//                     .leaf
-//                     .unwrap(),
-//             ) {
-//                 self.root_node = self.root_node.as_ref().unwrap().right.clone();
-//                 self.root_hash = self.root_node.as_ref().unwrap().value;
-//                 self.depth = 1;
-//                 self.leaves = vec![self.root_node.as_ref().unwrap().clone()];
-//                 return Ok(self.root_hash);
-//             }
-//             self.root_node = self.root_node.as_ref().unwrap().left.clone();
-//             self.root_hash = self.root_node.as_ref().unwrap().value;
-//             self.depth = 1;
-//             self.leaves = vec![self.root_node.as_ref().unwrap().clone()];
-//             return Ok(self.root_hash);
-//         }
-
-//         // deleting the only node on the right side
-//         if ((2f64).powi(self.depth - 1) / 2.0).floor() as usize + 1 == self.leaves.len()
-//             && self.digest_entry(entry)
-//                 == self
-//                     .root_node
-//                     .as_ref()
-//                     .unwrap()
-//                     .right
-//                     .as_ref()
-//                     .unwrap()
-//                     .value
-//             && self.leaves.len() > 2
-//         {
-//             self.root_node = self.root_node.as_ref().unwrap().left.clone();
-//             self.root_hash = self
-//                 .root_node
-//                 .as_ref()
-//                 .unwrap()
-//                 .left
-//                 .as_ref()
-//                 .unwrap()
-//                 .value;
-//             self.depth -= 1;
-//             for (i, leaf) in self.leaves.iter().enumerate() {
-//                 if self.entries_equal(leaf.leaf.as_ref().unwrap(), entry) {
-//                     self.leaves.remove(i);
-//                     return Ok(self.root_hash);
-//                 }
-//             }
-//         }
-
-//         let mut leaves = Vec::new();
-//         let mut pending_leaves = Vec::new();
-//         let mut is_on_left_side = false;
-//         for (i, leaf) in self.leaves.iter().enumerate() {
-//             if self.entries_equal(leaf.leaf.as_ref().unwrap(), entry) {
-//                 leaves = self.leaves[..i].to_vec();
-//                 pending_leaves = self.leaves[i + 1..].to_vec();
-//                 if i < ((2f64).powi(self.depth - 1) / 2.0).floor() as usize {
-//                     is_on_left_side = true;
-//                 }
-//                 break;
-//             }
-//         }
-
-//         // let (path, found) = self.find_leaf(self.root_node.as_ref().unwrap(), entry);
-//         // if !found {
-//         //     return Err(String::from("Something went wrong searching entry"));
-//         // }
-//         // let location = path.clone().unwrap();
-//         // let target = location.last().unwrap();
-//         // for i in (0..location.len() - 1).rev() {
-//         //     let loc = location[i];
-//         //     let left = *loc.left.clone().unwrap();
-//         //     if left == **target {
-//         //         loc.left = loc.right.clone();
-//         //         loc.right = None;
-//         //     } else {
-//         //         loc.right = loc.left.clone();
-//         //         loc.left = None;
-//         //     }
-//         // }
-//         let (path, found) = self.find_leaf(self.root_node.as_ref().unwrap(), entry);
-//         if !found {
-//             return Err(String::from("Entry not found"));
-//         }
-//         let location = path.unwrap();
-//         let target = location.last().unwrap();
-//         for i in (0..location.len() - 1).rev() {
-//             let mut loc: MerkleNode = location[i].clone();
-//             let left = loc.left.as_ref().unwrap().clone();
-//             if left.value.as_ptr() == target.value.as_ptr() {
-//                 loc.left = loc.right.take();
-//                 loc.value = loc.left.as_ref().unwrap().value;
-//             } else {
-//                 loc.right = loc.left.take();
-//                 loc.value = loc.right.as_ref().unwrap().value;
-//             }
-//         }
-
-//         if is_on_left_side {
-//             self.root_node = self.root_node.as_ref().unwrap().left.clone();
-//             self.root_hash = self
-//                 .root_node
-//                 .as_ref()
-//                 .unwrap()
-//                 .left
-//                 .as_ref()
-//                 .unwrap()
-//                 .value;
-//             self.depth -= 1;
-//             self.leaves = leaves;
-
-//             for node in pending_leaves {
-//                 self.insert(node.leaf.unwrap())?;
-//             }
-//         } else {
-//             self.root_node = self.root_node.as_ref().unwrap().right.clone();
-//             self.root_hash = self
-//                 .root_node
-//                 .as_ref()
-//                 .unwrap()
-//                 .right
-//                 .as_ref()
-//                 .unwrap()
-//                 .value;
-//             self.depth -= 1;
-//             self.leaves = leaves;
-
-//             for node in pending_leaves {
-//                 self.insert(node.leaf.unwrap())?;
-//             }
-//         }
-
-//         Ok(self.root_hash)
-//     }
-
-//     // fn generate_merkle_path(&self, entry: MerkleEntry) -> Result<Vec<MerkleNode>, String> {
-//     //     Ok(vec![])
-//     // }
-
-//     fn generate_merkle_path(&self, entry: MerkleEntry) -> Result<Vec<MerkleNode>, String> {
-//         let (path, found) = self.find_leaf(&self.root_node.as_ref().unwrap(), &entry);
-//         if !found {
-//             return Err("Entry not found".to_string());
-//         }
-
-//         let locations = path.clone().unwrap();
-//         let mut merkle_path = vec![];
-//         let mut visited_node: &MerkleNode = locations[locations.len() - 1];
-//         for i in (0..locations.len() - 1).rev() {
-//             let node = locations[i];
-
-//             if node == visited_node || node.right.is_none() {
-//                 visited_node = node;
-//                 continue; // skip intermediate nodes
-//             }
-
-//             let right = *node.right.clone().unwrap();
-//             if right == *visited_node {
-//                 merkle_path.push(*node.left.as_ref().unwrap().clone());
-//             } else {
-//                 merkle_path.push(*node.right.as_ref().unwrap().clone());
-//             }
-//             visited_node = node;
-//         }
-
-//         Ok(merkle_path)
-//     }
-
-//     fn verify_path(&self, key: [u8; 32], location: String, path: Vec<MerkleNode>) -> bool {
-//         true
-//     }
-// }
-
-// impl MerkleTree {
-//     pub fn new() -> Self {
-//         Self {
-//             root_hash: [0; 32],
-//             root_node: None,
-//             depth: 0,
-//             leaves: vec![],
-//         }
-//     }
-
-//     fn new_node(
-//         &self,
-//         left: Option<Box<MerkleNode>>,
-//         right: Option<Box<MerkleNode>>,
-//     ) -> Box<MerkleNode> {
-//         let left_hash = left.as_ref().unwrap().value;
-//         let right_hash = if let Some(right_node) = right.as_ref() {
-//             right_node.value
-//         } else {
-//             [0; 32]
-//         };
-
-//         let value = self.digest_nodes(left_hash, right_hash);
-//         Box::new(MerkleNode {
-//             value,
-//             left,
-//             right,
-//             leaf: None,
-//         })
-//     }
-
-//     fn new_leaf(&self, entry: MerkleEntry) -> Box<MerkleNode> {
-//         let value = entry.key;
-//         Box::new(MerkleNode {
-//             value,
-//             left: None,
-//             right: None,
-//             leaf: Some(entry),
-//         })
-//     }
-
-//     fn digest_nodes(&self, left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
-//         let mut hasher = Sha256::new();
-//         hasher.update(left);
-//         hasher.update(right);
-//         let hash = hasher.finalize();
-//         let mut value = [0; 32];
-//         value.copy_from_slice(&hash[..]);
-//         value
-//     }
-
-//     fn digest_entry(&self, entry: &MerkleEntry) -> [u8; 32] {
-//         let serialized = serde_json::to_vec(entry).unwrap();
-//         let mut hasher = Sha256::new();
-//         hasher.update(serialized);
-//         let result = hasher.finalize();
-//         let mut hash = [0u8; 32];
-//         hash.copy_from_slice(&result[..]);
-//         hash
-//     }
-
-//     fn entries_equal(&self, entry1: &MerkleEntry, entry2: &MerkleEntry) -> bool {
-//         self.digest_entry(entry1) == self.digest_entry(entry2)
-//     }
-
-//     fn find_leaf<'a>(
-//         &'a self,
-//         node: &'a MerkleNode,
-//         entry: &'a MerkleEntry,
-//     ) -> (Option<Vec<&'a MerkleNode>>, bool) {
-//         if self.entries_equal(node.leaf.as_ref().unwrap(), entry) {
-//             return (Some(vec![node]), true);
-//         }
-//         let (left_path, found) = self.find_leaf(node.left.as_ref().unwrap(), entry);
-//         if found {
-//             return (Some([vec![node], left_path.unwrap()].concat()), true);
-//         }
-//         let (right_path, found) = self.find_leaf(node.right.as_ref().unwrap(), entry);
-//         if found {
-//             return (Some([vec![node], right_path.unwrap()].concat()), true);
-//         }
-//         (None, false)
-//     }
-// }
+use crate::protos::Transaction;
+use sha2::{Digest, Sha256};
+
+pub trait IMerkleTree {
+    // The Insert function takes an entry and appends it as the rightmost leaf of
+    // the tree. It returns the new Root hash, which corresponds to the latest
+    // state of the Merkle Tree.
+    fn insert(&mut self, entry: MerkleEntry) -> [u8; 32];
+
+    // The Delete function takes an entry, finds the leaf with the same key and
+    // removes it, rebalancing the tree. It returns the updated root hash, or an
+    // error if the key doesn't exist.
+    fn delete(&mut self, entry: &MerkleEntry) -> Result<[u8; 32], String>;
+
+    // The GenerateMerklePath function takes an entry and returns its Merkle Path:
+    // the position of its leaf and the ordered list of sibling hashes, starting
+    // from the leaf. Returns an error if the key doesn't exist.
+    fn generate_merkle_path(&self, entry: &MerkleEntry) -> Result<MerklePath, String>;
+
+    // The VerifyMerklePath function takes a key and its Merkle path. It computes
+    // all the hashes on the path from the key to the root using the location
+    // and the sibling hashes, and returns true if the computed root is equal to
+    // the stored root.
+    fn verify_path(&self, key: [u8; 32], path: &MerklePath) -> bool;
+}
+
+// Binary Merkle tree over a list of entries. A level with an odd number of
+// nodes pairs its last node with itself, so the root of the transactions of a
+// block is the same one bitcoin would compute. An empty tree has an all-zero
+// root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerkleTree {
+    pub root_hash: [u8; 32],
+    pub root_node: Option<Box<MerkleNode>>,
+    // levels above the leaves
+    pub depth: u32,
+    pub leaves: Vec<MerkleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleNode {
+    pub value: [u8; 32],
+    pub left: Option<Box<MerkleNode>>,
+    // None when the left node was paired with itself
+    pub right: Option<Box<MerkleNode>>,
+    pub leaf: Option<MerkleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleEntry {
+    // sha256 of the value, the hash of the leaf
+    pub key: [u8; 32],
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MerklePath {
+    // position of the leaf, its bits tell on which side each sibling goes
+    pub location: usize,
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl MerkleEntry {
+    pub fn new(value: Vec<u8>) -> Self {
+        MerkleEntry {
+            key: Sha256::digest(&value).into(),
+            value,
+        }
+    }
+}
+
+// a transaction's leaf is its hash
+impl From<&Transaction> for MerkleEntry {
+    fn from(tx: &Transaction) -> Self {
+        MerkleEntry::new(tx.to_bytes().unwrap().into_vec())
+    }
+}
+
+impl IMerkleTree for MerkleTree {
+    fn insert(&mut self, entry: MerkleEntry) -> [u8; 32] {
+        self.leaves.push(entry);
+        self.rebuild();
+        self.root_hash
+    }
+
+    fn delete(&mut self, entry: &MerkleEntry) -> Result<[u8; 32], String> {
+        let index = self
+            .position(&entry.key)
+            .ok_or_else(|| String::from("Entry not found"))?;
+        self.leaves.remove(index);
+        self.rebuild();
+        Ok(self.root_hash)
+    }
+
+    fn generate_merkle_path(&self, entry: &MerkleEntry) -> Result<MerklePath, String> {
+        let location = self
+            .position(&entry.key)
+            .ok_or_else(|| String::from("Entry not found"))?;
+        let mut hashes = vec![];
+        let mut node = self.root_node.as_ref().unwrap();
+        // walk down from the root, the bits of the location pick the side
+        for level in (0..self.depth).rev() {
+            let left = node.left.as_ref().unwrap();
+            if (location >> level) & 1 == 0 {
+                hashes.push(node.right.as_ref().unwrap_or(left).value);
+                node = left;
+            } else {
+                hashes.push(left.value);
+                node = node.right.as_ref().unwrap();
+            }
+        }
+        hashes.reverse();
+        Ok(MerklePath { location, hashes })
+    }
+
+    fn verify_path(&self, key: [u8; 32], path: &MerklePath) -> bool {
+        verify_merkle_path(&self.root_hash, key, path)
+    }
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_transactions(transactions: &[Transaction]) -> Self {
+        let mut tree = MerkleTree {
+            leaves: transactions.iter().map(MerkleEntry::from).collect(),
+            ..MerkleTree::default()
+        };
+        tree.rebuild();
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    // Whether two siblings anywhere in the tree have the same hash. The last
+    // node of an odd level is paired with itself, so repeating the last nodes
+    // of such a level gives the same root (CVE-2012-2459).
+    pub fn has_identical_siblings(&self) -> bool {
+        self.root_node
+            .as_ref()
+            .is_some_and(|root| root.has_identical_children())
+    }
+
+    fn position(&self, key: &[u8; 32]) -> Option<usize> {
+        self.leaves.iter().position(|leaf| leaf.key == *key)
+    }
+
+    // builds the nodes bottom-up from the leaves
+    fn rebuild(&mut self) {
+        let mut level: Vec<Box<MerkleNode>> =
+            self.leaves.iter().cloned().map(MerkleNode::leaf).collect();
+        self.depth = 0;
+        while level.len() > 1 {
+            let mut nodes = level.into_iter();
+            let mut parents = vec![];
+            while let Some(left) = nodes.next() {
+                parents.push(MerkleNode::parent(left, nodes.next()));
+            }
+            level = parents;
+            self.depth += 1;
+        }
+        self.root_node = level.pop();
+        self.root_hash = self.root_node.as_ref().map_or([0; 32], |root| root.value);
+    }
+}
+
+impl MerkleNode {
+    fn leaf(entry: MerkleEntry) -> Box<MerkleNode> {
+        Box::new(MerkleNode {
+            value: entry.key,
+            left: None,
+            right: None,
+            leaf: Some(entry),
+        })
+    }
+
+    fn has_identical_children(&self) -> bool {
+        match (&self.left, &self.right) {
+            (Some(left), Some(right)) => {
+                left.value == right.value
+                    || left.has_identical_children()
+                    || right.has_identical_children()
+            }
+            (Some(left), None) => left.has_identical_children(),
+            _ => false,
+        }
+    }
+
+    fn parent(left: Box<MerkleNode>, right: Option<Box<MerkleNode>>) -> Box<MerkleNode> {
+        let right_hash = right.as_ref().map_or(left.value, |right| right.value);
+        Box::new(MerkleNode {
+            value: digest_nodes(&left.value, &right_hash),
+            left: Some(left),
+            right,
+            leaf: None,
+        })
+    }
+}
+
+fn digest_nodes(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Recomputes the root from a leaf key and its path, so it can be checked by
+// someone who only knows the root, like a header.
+pub fn verify_merkle_path(root: &[u8], key: [u8; 32], path: &MerklePath) -> bool {
+    let mut hash = key;
+    let mut location = path.location;
+    for sibling in &path.hashes {
+        hash = if location & 1 == 0 {
+            digest_nodes(&hash, sibling)
+        } else {
+            digest_nodes(sibling, &hash)
+        };
+        location >>= 1;
+    }
+    location == 0 && hash.as_slice() == root
+}
+
+// merkle root the header of a block with these transactions commits to
+pub fn merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+    MerkleTree::from_transactions(transactions).root_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::UtxoOutput;
+
+    fn entries(count: usize) -> Vec<MerkleEntry> {
+        (0..count)
+            .map(|i| MerkleEntry::new(format!("entry {}", i).into_bytes()))
+            .collect()
+    }
+
+    fn tree_of(entries: &[MerkleEntry]) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for entry in entries {
+            tree.insert(entry.clone());
+        }
+        tree
+    }
+
+    #[test]
+    fn test_empty_and_single_leaf() {
+        assert_eq!([0; 32], MerkleTree::new().root_hash);
+        let entry = MerkleEntry::new(b"only".to_vec());
+        let tree = tree_of(std::slice::from_ref(&entry));
+        assert_eq!(entry.key, tree.root_hash);
+        let path = tree.generate_merkle_path(&entry).unwrap();
+        assert!(path.hashes.is_empty());
+        assert!(tree.verify_path(entry.key, &path));
+    }
+
+    #[test]
+    fn test_odd_level_pairs_last_node_with_itself() {
+        let entries = entries(3);
+        let tree = tree_of(&entries);
+        let left = digest_nodes(&entries[0].key, &entries[1].key);
+        let right = digest_nodes(&entries[2].key, &entries[2].key);
+        assert_eq!(digest_nodes(&left, &right), tree.root_hash);
+        assert_eq!(2, tree.depth);
+        assert!(!tree.has_identical_siblings());
+        // repeating the last entry doesn't change the root, but shows
+        let mutated = tree_of(&[entries.clone(), vec![entries[2].clone()]].concat());
+        assert_eq!(tree.root_hash, mutated.root_hash);
+        assert!(mutated.has_identical_siblings());
+    }
+
+    #[test]
+    fn test_every_path_verifies() {
+        for count in 1..10 {
+            let entries = entries(count);
+            let tree = tree_of(&entries);
+            for entry in &entries {
+                let path = tree.generate_merkle_path(entry).unwrap();
+                assert!(tree.verify_path(entry.key, &path));
+                assert!(verify_merkle_path(&tree.root_hash, entry.key, &path));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_path_fails() {
+        let entries = entries(5);
+        let tree = tree_of(&entries);
+        let mut path = tree.generate_merkle_path(&entries[3]).unwrap();
+        assert!(!tree.verify_path(entries[2].key, &path));
+        path.location = 2;
+        assert!(!tree.verify_path(entries[3].key, &path));
+        path.location = 3;
+        path.hashes[0] = [1; 32];
+        assert!(!tree.verify_path(entries[3].key, &path));
+    }
+
+    #[test]
+    fn test_delete() {
+        let entries = entries(4);
+        let mut tree = tree_of(&entries);
+        let root = tree.delete(&entries[1]).unwrap();
+        let expected = tree_of(&[entries[0].clone(), entries[2].clone(), entries[3].clone()]);
+        assert_eq!(expected.root_hash, root);
+        assert_eq!(expected, tree);
+        assert!(tree.generate_merkle_path(&entries[1]).is_err());
+        assert!(tree.delete(&entries[1]).is_err());
+        for entry in [&entries[0], &entries[2], &entries[3]] {
+            tree.delete(entry).unwrap();
+        }
+        assert_eq!([0; 32], tree.root_hash);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_transaction_leaves_are_their_hashes() {
+        let txs: Vec<Transaction> = (0..3)
+            .map(|amount| Transaction {
+                inputs: vec![],
+                outputs: vec![UtxoOutput {
+                    to_addr: String::from("bob"),
+                    amount,
+                }],
+            })
+            .collect();
+        let tree = MerkleTree::from_transactions(&txs);
+        for (leaf, tx) in tree.leaves.iter().zip(&txs) {
+            assert_eq!(tx.hash(), leaf.key.to_vec());
+        }
+        assert_eq!(tree.root_hash, merkle_root(&txs));
+    }
+}
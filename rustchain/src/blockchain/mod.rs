@@ -1,4 +1,11 @@
 pub mod block;
+pub mod block_tree;
 pub mod blockchain;
+pub mod consensus;
+pub mod header_chain;
 pub mod merkle;
+pub mod store;
+pub mod target;
+pub mod utxo_set;
+pub mod validation;
 pub mod wallet;
@@ -0,0 +1,415 @@
+use crate::protos::Block;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// length (u32) + checksum (first 4 bytes of the payload's sha256)
+const RECORD_HEADER_LEN: usize = 8;
+
+// Storage backend for every block the node knows about, indexed by hex hash
+// and by height. There can be several blocks per height when the chain forks.
+pub trait BlockStore: Debug + Send + Sync {
+    fn put(&mut self, block: &Block) -> io::Result<()>;
+    // stores the block over the one with the same hash, for a body that
+    // replaces one that failed validation
+    fn replace(&mut self, block: &Block) -> io::Result<()>;
+    fn get(&self, hash: &str) -> Option<Block>;
+    fn get_by_height(&self, height: u64) -> Vec<Block>;
+    fn contains(&self, hash: &str) -> bool;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // every stored block, lowest height first, so that parents always come
+    // before their children when the chain is reloaded
+    fn blocks(&self) -> Vec<Block>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBlockStore {
+    blocks: HashMap<String, Block>,
+    heights: BTreeMap<u64, Vec<String>>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn put(&mut self, block: &Block) -> io::Result<()> {
+        let hash = hex::encode(&block.block_hash);
+        if self.blocks.contains_key(&hash) {
+            return Ok(());
+        }
+        self.heights
+            .entry(block_height(block)?)
+            .or_default()
+            .push(hash.clone());
+        self.blocks.insert(hash, block.clone());
+        Ok(())
+    }
+
+    fn replace(&mut self, block: &Block) -> io::Result<()> {
+        let hash = hex::encode(&block.block_hash);
+        match self.blocks.get_mut(&hash) {
+            Some(stored) => *stored = block.clone(),
+            None => self.put(block)?,
+        }
+        Ok(())
+    }
+
+    fn get(&self, hash: &str) -> Option<Block> {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn get_by_height(&self, height: u64) -> Vec<Block> {
+        self.heights
+            .get(&height)
+            .map(|hashes| hashes.iter().filter_map(|h| self.get(h)).collect())
+            .unwrap_or_default()
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn blocks(&self) -> Vec<Block> {
+        self.heights
+            .values()
+            .flatten()
+            .filter_map(|hash| self.get(hash))
+            .collect()
+    }
+}
+
+// Append-only log of prost-encoded blocks. Every record is checksummed and
+// fsynced before `put` returns. On open the log is replayed to rebuild the
+// indexes, and a torn or corrupted tail left by a crash is truncated away.
+// A replaced block is appended again, the last record of a hash wins.
+#[derive(Debug)]
+pub struct FileBlockStore {
+    path: PathBuf,
+    file: File,
+    offsets: HashMap<String, u64>,
+    heights: BTreeMap<u64, Vec<String>>,
+}
+
+impl FileBlockStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut store = FileBlockStore {
+            path,
+            file: file.try_clone()?,
+            offsets: HashMap::new(),
+            heights: BTreeMap::new(),
+        };
+
+        let mut offset = 0;
+        file.seek(SeekFrom::Start(0))?;
+        while let Some((block, len)) = read_record(&mut file)? {
+            store.index(&block, offset)?;
+            offset += len;
+        }
+        if offset < store.file.metadata()?.len() {
+            println!(
+                "Block store {} has a corrupted tail, truncating it at {}",
+                store.path.display(),
+                offset
+            );
+            store.file.set_len(offset)?;
+            store.file.sync_all()?;
+        }
+        Ok(store)
+    }
+
+    fn index(&mut self, block: &Block, offset: u64) -> io::Result<()> {
+        let hash = hex::encode(&block.block_hash);
+        if !self.offsets.contains_key(&hash) {
+            self.heights
+                .entry(block_height(block)?)
+                .or_default()
+                .push(hash.clone());
+        }
+        self.offsets.insert(hash, offset);
+        Ok(())
+    }
+
+    fn append(&mut self, block: &Block) -> io::Result<()> {
+        let payload = block.encode_to_vec();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        let offset = self.file.metadata()?.len();
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.index(block, offset)
+    }
+
+    fn read_at(&self, offset: u64) -> io::Result<Option<Block>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(read_record(&mut file)?.map(|(block, _)| block))
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn put(&mut self, block: &Block) -> io::Result<()> {
+        let hash = hex::encode(&block.block_hash);
+        if self.offsets.contains_key(&hash) {
+            return Ok(());
+        }
+        self.append(block)
+    }
+
+    fn replace(&mut self, block: &Block) -> io::Result<()> {
+        self.append(block)
+    }
+
+    fn get(&self, hash: &str) -> Option<Block> {
+        let offset = self.offsets.get(hash)?;
+        self.read_at(*offset).ok().flatten()
+    }
+
+    fn get_by_height(&self, height: u64) -> Vec<Block> {
+        self.heights
+            .get(&height)
+            .map(|hashes| hashes.iter().filter_map(|h| self.get(h)).collect())
+            .unwrap_or_default()
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.offsets.contains_key(hash)
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    fn blocks(&self) -> Vec<Block> {
+        self.heights
+            .values()
+            .flatten()
+            .filter_map(|hash| self.get(hash))
+            .collect()
+    }
+}
+
+// Reads the record at the current position. Returns None at the end of the
+// log or when the record is incomplete or fails its checksum.
+fn read_record(file: &mut File) -> io::Result<Option<(Block, u64)>> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    if !read_full(file, &mut header)? {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    // a corrupted length isn't allocated for, it can't be longer than the log
+    let left = file
+        .metadata()?
+        .len()
+        .saturating_sub(file.stream_position()?);
+    if len as u64 > left {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len];
+    if !read_full(file, &mut payload)? || checksum(&payload) != header[4..] {
+        return Ok(None);
+    }
+    match Block::decode(payload.as_slice()) {
+        Ok(block) => Ok(Some((block, (RECORD_HEADER_LEN + len) as u64))),
+        Err(_) => Ok(None),
+    }
+}
+
+// like read_exact, but a short read is reported as false instead of an error
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    Sha256::digest(payload)[..4].try_into().unwrap()
+}
+
+fn block_height(block: &Block) -> io::Result<u64> {
+    block
+        .header
+        .as_ref()
+        .map(|header| header.block_index)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "block has no header"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::block::create_genesis_block;
+    use crate::blockchain::validation::tests::mined_block;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // unique file per test so tests can run in parallel
+    pub fn temp_store_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        temp_dir().join(format!("rustchain-{}-{}.blocks", name, nanos))
+    }
+
+    fn sample_chain() -> Vec<Block> {
+        let genesis = create_genesis_block();
+        let block_1 = mined_block(&genesis, vec![]);
+        let block_2 = mined_block(&block_1, vec![]);
+        vec![genesis, block_1, block_2]
+    }
+
+    fn check_store(store: &mut dyn BlockStore) {
+        let chain = sample_chain();
+        for block in chain.iter().rev() {
+            store.put(block).unwrap();
+        }
+        store.put(&chain[1]).unwrap();
+        assert_eq!(3, store.len());
+        assert_eq!(chain, store.blocks());
+        // put keeps what is stored, replace doesn't
+        let mut emptied = chain[1].clone();
+        emptied.transactions.clear();
+        store.put(&emptied).unwrap();
+        assert_eq!(
+            Some(chain[1].clone()),
+            store.get(&hex::encode(&emptied.block_hash))
+        );
+        store.replace(&emptied).unwrap();
+        assert_eq!(
+            Some(emptied.clone()),
+            store.get(&hex::encode(&emptied.block_hash))
+        );
+        store.replace(&chain[1]).unwrap();
+        assert_eq!(3, store.len());
+        assert_eq!(chain, store.blocks());
+        assert_eq!(vec![chain[1].clone()], store.get_by_height(1));
+        assert_eq!(
+            Some(chain[2].clone()),
+            store.get(&hex::encode(&chain[2].block_hash))
+        );
+        assert!(!store.contains("unknown"));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&mut MemoryBlockStore::new());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = temp_store_path("file-store");
+        check_store(&mut FileBlockStore::open(&path).unwrap());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_reopen() {
+        let path = temp_store_path("reopen");
+        let chain = sample_chain();
+        {
+            let mut store = FileBlockStore::open(&path).unwrap();
+            for block in &chain {
+                store.put(block).unwrap();
+            }
+        }
+        let store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(chain, store.blocks());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_truncates_torn_record() {
+        let path = temp_store_path("torn");
+        let chain = sample_chain();
+        {
+            let mut store = FileBlockStore::open(&path).unwrap();
+            for block in &chain {
+                store.put(block).unwrap();
+            }
+        }
+        // simulate a crash in the middle of the last write
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(chain[..2].to_vec(), store.blocks());
+        store.put(&chain[2]).unwrap();
+        let store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(chain, store.blocks());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_detects_corruption() {
+        let path = temp_store_path("corrupt");
+        let chain = sample_chain();
+        {
+            let mut store = FileBlockStore::open(&path).unwrap();
+            for block in &chain {
+                store.put(block).unwrap();
+            }
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(chain[..2].to_vec(), store.blocks());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_truncates_oversized_record() {
+        let path = temp_store_path("oversized");
+        let chain = sample_chain();
+        let offset = {
+            let mut store = FileBlockStore::open(&path).unwrap();
+            for block in &chain {
+                store.put(block).unwrap();
+            }
+            store.offsets[&hex::encode(&chain[2].block_hash)] as usize
+        };
+        // the length of the last record claims far more than the log holds
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[offset..offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let store = FileBlockStore::open(&path).unwrap();
+        assert_eq!(chain[..2].to_vec(), store.blocks());
+        assert_eq!(offset as u64, std::fs::metadata(&path).unwrap().len());
+        remove_file(path).unwrap();
+    }
+}
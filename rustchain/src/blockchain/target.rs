@@ -0,0 +1,365 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// 256-bit proof of work target. A block is valid when its header hash, read as
+// a big-endian integer, is lower or equal than the target, so the lower the
+// target the more hashes it takes to find a block.
+//
+// Headers carry it in the compact form bitcoin calls `nBits`: the high byte is
+// the length of the target in bytes and the low 3 bytes its most significant
+// bytes, so `target = mantissa * 256^(length - 3)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Target {
+    // least significant limb first
+    limbs: [u64; 4],
+}
+
+// the compact mantissa is signed, negative targets are invalid
+const COMPACT_SIGN_BIT: u32 = 0x0080_0000;
+
+impl Target {
+    pub const ZERO: Target = Target { limbs: [0; 4] };
+    pub const MAX: Target = Target {
+        limbs: [u64::MAX; 4],
+    };
+
+    // reads up to 32 big-endian bytes, like a header hash
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut target = Target::ZERO;
+        for byte in bytes.iter().skip(bytes.len().saturating_sub(32)) {
+            target = target.shl(8);
+            target.limbs[0] |= *byte as u64;
+        }
+        target
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (index, limb) in self.limbs.iter().rev().enumerate() {
+            bytes[index * 8..(index + 1) * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    // None if the compact form is negative or doesn't fit in 256 bits
+    pub fn from_compact(bits: u32) -> Option<Self> {
+        let length = bits >> 24;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & COMPACT_SIGN_BIT != 0 && mantissa != 0 {
+            return None;
+        }
+        let target = Target::from(mantissa as u64);
+        if length <= 3 {
+            return Some(target.shr(8 * (3 - length)));
+        }
+        let shift = 8 * (length - 3);
+        let shifted = target.shl(shift);
+        if shifted.shr(shift) != target {
+            return None;
+        }
+        Some(shifted)
+    }
+
+    // rounds down to the 3 most significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let mut length = self.bits().div_ceil(8);
+        let mut mantissa = if length <= 3 {
+            self.shl(8 * (3 - length)).low_u64() as u32
+        } else {
+            self.shr(8 * (length - 3)).low_u64() as u32
+        };
+        // keep the mantissa positive
+        if mantissa & COMPACT_SIGN_BIT != 0 {
+            mantissa >>= 8;
+            length += 1;
+        }
+        (length << 24) | mantissa
+    }
+
+    pub fn is_met_by(&self, hash: &[u8]) -> bool {
+        Target::from_be_bytes(hash) <= *self
+    }
+
+    // expected number of hashes needed to meet the target, 2^256 / (target + 1),
+    // saturating at u128::MAX
+    pub fn work(&self) -> u128 {
+        if *self == Target::MAX {
+            return 1;
+        }
+        // 2^256 doesn't fit, but 2^256 / d == (2^256 - 1 - d) / d + 1
+        let divisor = self.add_u64(1);
+        let work = self.not().div_rem(&divisor).0.add_u64(1);
+        if work.limbs[2] != 0 || work.limbs[3] != 0 {
+            return u128::MAX;
+        }
+        ((work.limbs[1] as u128) << 64) | work.limbs[0] as u128
+    }
+
+    // how many times harder than the easiest allowed target this one is
+    pub fn difficulty(&self, pow_limit: &Target) -> f64 {
+        if self.is_zero() {
+            return f64::INFINITY;
+        }
+        pow_limit.to_f64() / self.to_f64()
+    }
+
+    // target * numerator / denominator, saturating at `Target::MAX`
+    pub fn scale(&self, numerator: u64, denominator: u64) -> Target {
+        let denominator = Target::from(denominator.max(1));
+        if let Some(product) = self.mul_u64(numerator) {
+            return product.div_rem(&denominator).0;
+        }
+        // (q * d + r) * n / d == q * n + r * n / d, where r * n fits
+        let (quotient, remainder) = self.div_rem(&denominator);
+        let low = remainder
+            .mul_u64(numerator)
+            .map(|product| product.div_rem(&denominator).0);
+        match (quotient.mul_u64(numerator), low) {
+            (Some(high), Some(low)) => high.checked_add(&low).unwrap_or(Target::MAX),
+            _ => Target::MAX,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs == [0; 4]
+    }
+
+    // number of significant bits
+    fn bits(&self) -> u32 {
+        for (index, limb) in self.limbs.iter().enumerate().rev() {
+            if *limb != 0 {
+                return 64 * index as u32 + 64 - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    fn low_u64(&self) -> u64 {
+        self.limbs[0]
+    }
+
+    fn to_f64(self) -> f64 {
+        self.limbs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+    }
+
+    fn not(&self) -> Target {
+        Target {
+            limbs: self.limbs.map(|limb| !limb),
+        }
+    }
+
+    fn shl(&self, shift: u32) -> Target {
+        let mut limbs = [0; 4];
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        for index in (limb_shift..4).rev() {
+            limbs[index] = self.limbs[index - limb_shift] << bit_shift;
+            if bit_shift > 0 && index > limb_shift {
+                limbs[index] |= self.limbs[index - limb_shift - 1] >> (64 - bit_shift);
+            }
+        }
+        Target { limbs }
+    }
+
+    fn shr(&self, shift: u32) -> Target {
+        let mut limbs = [0; 4];
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        let kept = 4usize.saturating_sub(limb_shift);
+        for (index, limb) in limbs.iter_mut().enumerate().take(kept) {
+            *limb = self.limbs[index + limb_shift] >> bit_shift;
+            if bit_shift > 0 && index + limb_shift + 1 < 4 {
+                *limb |= self.limbs[index + limb_shift + 1] << (64 - bit_shift);
+            }
+        }
+        Target { limbs }
+    }
+
+    // wraps around on overflow
+    fn add_u64(&self, value: u64) -> Target {
+        let mut limbs = self.limbs;
+        let mut carry = value;
+        for limb in limbs.iter_mut() {
+            let (sum, overflow) = limb.overflowing_add(carry);
+            *limb = sum;
+            carry = overflow as u64;
+        }
+        Target { limbs }
+    }
+
+    fn checked_add(&self, other: &Target) -> Option<Target> {
+        let mut limbs = [0; 4];
+        let mut carry = false;
+        for (index, limb) in limbs.iter_mut().enumerate() {
+            let (sum, over) = self.limbs[index].overflowing_add(other.limbs[index]);
+            let (sum, over_carry) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = over || over_carry;
+        }
+        (!carry).then_some(Target { limbs })
+    }
+
+    fn sub(&self, other: &Target) -> Target {
+        let mut limbs = [0; 4];
+        let mut borrow = false;
+        for (index, limb) in limbs.iter_mut().enumerate() {
+            let (diff, under) = self.limbs[index].overflowing_sub(other.limbs[index]);
+            let (diff, under_borrow) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = under || under_borrow;
+        }
+        Target { limbs }
+    }
+
+    fn mul_u64(&self, value: u64) -> Option<Target> {
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for (index, limb) in limbs.iter_mut().enumerate() {
+            let product = self.limbs[index] as u128 * value as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(Target { limbs })
+    }
+
+    // shift-and-subtract long division, returns (quotient, remainder)
+    fn div_rem(&self, divisor: &Target) -> (Target, Target) {
+        assert!(!divisor.is_zero(), "division by zero");
+        let mut quotient = Target::ZERO;
+        let mut remainder = Target::ZERO;
+        for bit in (0..self.bits()).rev() {
+            remainder = remainder.shl(1);
+            remainder.limbs[0] |= (self.limbs[bit as usize / 64] >> (bit % 64)) & 1;
+            if remainder >= *divisor {
+                remainder = remainder.sub(divisor);
+                quotient.limbs[bit as usize / 64] |= 1 << (bit % 64);
+            }
+        }
+        (quotient, remainder)
+    }
+}
+
+impl From<u64> for Target {
+    fn from(value: u64) -> Self {
+        Target {
+            limbs: [value, 0, 0, 0],
+        }
+    }
+}
+
+impl Ord for Target {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.iter().rev().cmp(other.limbs.iter().rev())
+    }
+}
+
+impl PartialOrd for Target {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+// whether the hash meets the target encoded in `bits`. Invalid encodings are
+// never met.
+pub fn hash_meets_target(hash: &[u8], bits: u32) -> bool {
+    Target::from_compact(bits).is_some_and(|target| target.is_met_by(hash))
+}
+
+// expected number of hashes needed to find a block with the given compact
+// target, 0 for invalid encodings
+pub fn block_work(bits: u32) -> u128 {
+    Target::from_compact(bits).map_or(0, |target| target.work())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_round_trip() {
+        for bits in [0x1d00ffff, 0x1f7fffff, 0x207fffff, 0x03123456, 0x0412_3456] {
+            assert_eq!(bits, Target::from_compact(bits).unwrap().to_compact());
+        }
+        let target = Target::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(
+            "00000000ffff0000000000000000000000000000000000000000000000000000",
+            target.to_string()
+        );
+        assert_eq!(
+            Target::from(0x12),
+            Target::from_compact(0x01123456).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_compact() {
+        // negative
+        assert_eq!(None, Target::from_compact(0x04923456));
+        // more than 256 bits
+        assert_eq!(None, Target::from_compact(0x2300ffff));
+        assert!(!hash_meets_target(&[0; 32], 0x2300ffff));
+        assert_eq!(0, block_work(0x04923456));
+    }
+
+    #[test]
+    fn test_to_compact_keeps_mantissa_positive() {
+        let target = Target::from(0x80);
+        assert_eq!(0x02008000, target.to_compact());
+        assert_eq!(target, Target::from_compact(0x02008000).unwrap());
+    }
+
+    #[test]
+    fn test_hash_compared_as_big_endian_integer() {
+        let target = Target::from_compact(0x1f7fffff).unwrap();
+        let mut hash = [0; 32];
+        hash[1..4].copy_from_slice(&[0x7f, 0xff, 0xff]);
+        assert!(target.is_met_by(&hash));
+        // bigger in the least significant byte only
+        hash[31] = 0x01;
+        assert!(!target.is_met_by(&hash));
+        hash[3] = 0xfe;
+        hash[4..].fill(0xff);
+        assert!(target.is_met_by(&hash));
+    }
+
+    #[test]
+    fn test_work() {
+        assert_eq!(1, Target::MAX.work());
+        // one in two hashes meets it
+        assert_eq!(2, Target::MAX.shr(1).work());
+        assert_eq!(1 << 40, Target::MAX.shr(40).work());
+        assert_eq!(0x0100010001, block_work(0x1d00ffff));
+        assert_eq!(u128::MAX, Target::from(1).work());
+        // harder targets need strictly more work, not just whole powers of two
+        assert!(block_work(0x1f3fffff) > block_work(0x1f5fffff));
+        assert!(block_work(0x1f5fffff) > block_work(0x1f7fffff));
+    }
+
+    #[test]
+    fn test_difficulty() {
+        let limit = Target::from_compact(0x207fffff).unwrap();
+        assert_eq!(1.0, limit.difficulty(&limit));
+        let quarter = limit.scale(1, 4);
+        assert!((quarter.difficulty(&limit) - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_scale() {
+        let target = Target::from_compact(0x1f7fffff).unwrap();
+        assert_eq!(0x1f3fffff, target.scale(1, 2).to_compact());
+        assert_eq!(0x2000ffff, target.scale(2, 1).to_compact());
+        assert_eq!(Target::MAX, Target::MAX.scale(4, 1));
+        assert_eq!(Target::MAX.shr(2), Target::MAX.scale(1, 4));
+        // the product overflows but the result doesn't
+        let big = Target::MAX.shr(1);
+        assert_eq!(big, big.scale(540, 540));
+        assert_eq!(big.shr(1), big.scale(270, 540));
+    }
+}
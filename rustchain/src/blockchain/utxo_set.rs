@@ -0,0 +1,302 @@
+use crate::blockchain::consensus::{is_coinbase, ConsensusParams};
+use crate::protos::{Block, Transaction, UtxoOutput};
+use std::collections::{HashMap, HashSet};
+
+// (hex tx hash, output index), the same key wallets have always used
+pub type OutPoint = (String, u32);
+
+#[derive(Debug, Clone, PartialEq)]
+struct UtxoEntry {
+    output: UtxoOutput,
+    // height of the block that created the output
+    height: u64,
+    coinbase: bool,
+}
+
+// Outputs a block spent, so they can be put back if the block is disconnected
+// during a reorg.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockUndo {
+    // one entry per transaction, in block order
+    spent: Vec<Vec<(OutPoint, UtxoEntry)>>,
+}
+
+// Unspent outputs of the active chain. It's built only from confirmed blocks
+// and is the single source the node uses to validate and to answer balances.
+#[derive(Debug, Clone)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, UtxoEntry>,
+    by_address: HashMap<String, HashSet<OutPoint>>,
+    // height of the last connected block
+    height: u64,
+    coinbase_maturity: u64,
+}
+
+impl Default for UtxoSet {
+    fn default() -> Self {
+        UtxoSet::with_coinbase_maturity(ConsensusParams::default().coinbase_maturity)
+    }
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_coinbase_maturity(coinbase_maturity: u64) -> Self {
+        UtxoSet {
+            utxos: HashMap::new(),
+            by_address: HashMap::new(),
+            height: 0,
+            coinbase_maturity,
+        }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoOutput> {
+        self.utxos.get(outpoint).map(|entry| &entry.output)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    // whether the output can be spent by a transaction of the next block.
+    // Coinbase outputs have to wait `coinbase_maturity` blocks.
+    pub fn is_spendable(&self, outpoint: &OutPoint) -> bool {
+        self.utxos.get(outpoint).is_some_and(|entry| {
+            !entry.coinbase || self.height + 1 >= entry.height + self.coinbase_maturity
+        })
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn by_address(&self, address: &str) -> Vec<(OutPoint, UtxoOutput)> {
+        self.by_address
+            .get(address)
+            .map(|outpoints| {
+                outpoints
+                    .iter()
+                    .map(|outpoint| (outpoint.clone(), self.utxos[outpoint].output.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // like `by_address`, without the coinbase outputs that are not mature yet
+    pub fn spendable_by_address(&self, address: &str) -> Vec<(OutPoint, UtxoOutput)> {
+        self.by_address(address)
+            .into_iter()
+            .filter(|(outpoint, _)| self.is_spendable(outpoint))
+            .collect()
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.by_address(address)
+            .iter()
+            .map(|(_, utxo)| utxo.amount as u64)
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    // spends the block's inputs and adds its outputs, returning what is needed
+    // to undo it
+    pub fn connect_block(&mut self, block: &Block) -> BlockUndo {
+        self.height = block_height(block);
+        let mut undo = BlockUndo::default();
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let coinbase = index == 0 && is_coinbase(tx);
+            undo.spent.push(self.apply(tx, coinbase));
+        }
+        undo
+    }
+
+    // reverts `connect_block`: removes the block's outputs and restores the
+    // outputs it spent
+    pub fn disconnect_block(&mut self, block: &Block, undo: BlockUndo) {
+        let txs = block.transactions.iter().zip(undo.spent);
+        for (tx, spent) in txs.rev() {
+            let tx_hash = hex::encode(tx.hash());
+            for index in 0..tx.outputs.len() {
+                self.remove(&(tx_hash.clone(), index as u32));
+            }
+            for (outpoint, entry) in spent {
+                self.insert(outpoint, entry);
+            }
+        }
+        self.height = block_height(block).saturating_sub(1);
+    }
+
+    // applies a regular transaction at the current height
+    pub fn apply_transaction(&mut self, tx: &Transaction) {
+        self.apply(tx, false);
+    }
+
+    // returns the outputs the transaction spent
+    fn apply(&mut self, tx: &Transaction, coinbase: bool) -> Vec<(OutPoint, UtxoEntry)> {
+        let spent = if coinbase {
+            vec![]
+        } else {
+            tx.inputs
+                .iter()
+                .filter_map(|input| {
+                    let outpoint = input.outpoint();
+                    self.remove(&outpoint).map(|entry| (outpoint, entry))
+                })
+                .collect()
+        };
+        let tx_hash = hex::encode(tx.hash());
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let entry = UtxoEntry {
+                output: output.clone(),
+                height: self.height,
+                coinbase,
+            };
+            self.insert((tx_hash.clone(), index as u32), entry);
+        }
+        spent
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, entry: UtxoEntry) {
+        self.by_address
+            .entry(entry.output.to_addr.clone())
+            .or_default()
+            .insert(outpoint.clone());
+        self.utxos.insert(outpoint, entry);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        let entry = self.utxos.remove(outpoint)?;
+        if let Some(outpoints) = self.by_address.get_mut(&entry.output.to_addr) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.by_address.remove(&entry.output.to_addr);
+            }
+        }
+        Some(entry)
+    }
+}
+
+fn block_height(block: &Block) -> u64 {
+    block.header.as_ref().map_or(0, |header| header.block_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::consensus::coinbase_transaction;
+    use crate::protos::{BlockHeader, UtxoInput};
+
+    fn air_drop(to_addr: &str, amount: u32) -> Transaction {
+        Transaction {
+            inputs: vec![],
+            outputs: vec![UtxoOutput {
+                to_addr: to_addr.to_string(),
+                amount,
+            }],
+        }
+    }
+
+    fn spend(funding: &Transaction, to_addr: &str, amount: u32) -> Transaction {
+        Transaction {
+            inputs: vec![UtxoInput {
+                prev_tx_hash: hex::encode(funding.hash()).into_bytes(),
+                output_index: 0,
+                ..UtxoInput::default()
+            }],
+            outputs: vec![UtxoOutput {
+                to_addr: to_addr.to_string(),
+                amount,
+            }],
+        }
+    }
+
+    fn block_of(transactions: Vec<Transaction>) -> Block {
+        Block {
+            transactions,
+            ..Block::default()
+        }
+    }
+
+    fn block_at(height: u64, transactions: Vec<Transaction>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                block_index: height,
+                ..BlockHeader::default()
+            }),
+            transactions,
+            ..Block::default()
+        }
+    }
+
+    #[test]
+    fn test_query_by_outpoint_and_address() {
+        let funding = air_drop("bob", 10);
+        let mut utxos = UtxoSet::new();
+        utxos.connect_block(&block_of(vec![funding.clone(), air_drop("bob", 5)]));
+        let outpoint = (hex::encode(funding.hash()), 0);
+        assert_eq!(10, utxos.get(&outpoint).unwrap().amount);
+        assert_eq!(2, utxos.by_address("bob").len());
+        assert_eq!(15, utxos.balance("bob"));
+        assert_eq!(0, utxos.balance("alice"));
+    }
+
+    #[test]
+    fn test_connect_spends_inputs() {
+        let funding = air_drop("bob", 10);
+        let mut utxos = UtxoSet::new();
+        utxos.connect_block(&block_of(vec![funding.clone()]));
+        utxos.connect_block(&block_of(vec![spend(&funding, "alice", 10)]));
+        assert_eq!(0, utxos.balance("bob"));
+        assert_eq!(10, utxos.balance("alice"));
+        assert_eq!(1, utxos.len());
+    }
+
+    #[test]
+    fn test_disconnect_restores_spent_outputs() {
+        let funding = air_drop("bob", 10);
+        let mut utxos = UtxoSet::new();
+        utxos.connect_block(&block_of(vec![funding.clone()]));
+        let block = block_of(vec![spend(&funding, "alice", 10)]);
+        let undo = utxos.connect_block(&block);
+        utxos.disconnect_block(&block, undo);
+        assert_eq!(10, utxos.balance("bob"));
+        assert_eq!(0, utxos.balance("alice"));
+        assert!(utxos.contains(&(hex::encode(funding.hash()), 0)));
+    }
+
+    #[test]
+    fn test_disconnect_block_spending_its_own_outputs() {
+        let funding = air_drop("bob", 10);
+        let block = block_of(vec![funding.clone(), spend(&funding, "alice", 10)]);
+        let mut utxos = UtxoSet::new();
+        let undo = utxos.connect_block(&block);
+        utxos.disconnect_block(&block, undo);
+        assert!(utxos.is_empty());
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut utxos = UtxoSet::with_coinbase_maturity(2);
+        let coinbase = coinbase_transaction(String::from("bob"), 1, 50);
+        let outpoint = (hex::encode(coinbase.hash()), 0);
+        utxos.connect_block(&block_at(1, vec![coinbase]));
+        assert!(!utxos.is_spendable(&outpoint));
+        assert!(utxos.spendable_by_address("bob").is_empty());
+        assert_eq!(50, utxos.balance("bob"));
+
+        let block = block_at(2, vec![]);
+        let undo = utxos.connect_block(&block);
+        assert!(utxos.is_spendable(&outpoint));
+        utxos.disconnect_block(&block, undo);
+        assert!(!utxos.is_spendable(&outpoint));
+    }
+}
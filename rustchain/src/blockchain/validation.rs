@@ -0,0 +1,922 @@
+use crate::blockchain::consensus::{is_coinbase, ConsensusParams};
+use crate::blockchain::merkle::MerkleTree;
+use crate::blockchain::target::{hash_meets_target, Target};
+use crate::blockchain::utxo_set::{OutPoint, UtxoSet};
+use crate::blockchain::wallet::Wallet;
+use crate::protos::{Block, BlockHeader, Transaction, UtxoOutput};
+use openssl::pkey::PKey;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use tonic::Status;
+
+// Reason why a block was not accepted into the chain. It is meant to be logged
+// and sent back to the peer that relayed the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    MissingHeader,
+    KnownInvalid {
+        hash: String,
+    },
+    PreviousHashMismatch {
+        expected: String,
+        found: String,
+    },
+    UnexpectedIndex {
+        expected: u64,
+        found: u64,
+    },
+    BlockHashMismatch {
+        expected: String,
+        found: String,
+    },
+    InsufficientWork {
+        bits: u32,
+    },
+    UnexpectedDifficulty {
+        expected: u32,
+        found: u32,
+    },
+    TargetTooEasy {
+        easiest: u32,
+        found: u32,
+    },
+    TimestampTooEarly {
+        median_time_past: u64,
+        found: u64,
+    },
+    TimestampTooFarAhead {
+        latest: u64,
+        found: u64,
+    },
+    MerkleRootMismatch {
+        expected: String,
+        found: String,
+    },
+    DuplicateTransaction {
+        tx_hash: String,
+    },
+    MutatedMerkleTree,
+    InvalidSignature {
+        tx_hash: String,
+    },
+    UnknownInput {
+        tx_hash: String,
+        outpoint: (String, u32),
+    },
+    DoubleSpend {
+        tx_hash: String,
+        outpoint: (String, u32),
+    },
+    InputOwnerMismatch {
+        tx_hash: String,
+        outpoint: (String, u32),
+    },
+    OutputsExceedInputs {
+        tx_hash: String,
+        inputs: u64,
+        outputs: u64,
+    },
+    MissingCoinbase,
+    InvalidCoinbase {
+        tx_hash: String,
+    },
+    CoinbaseExceedsReward {
+        reward: u64,
+        found: u64,
+    },
+    UnexpectedCoinbase {
+        tx_hash: String,
+    },
+    MissingInputs {
+        tx_hash: String,
+    },
+    ImmatureCoinbase {
+        tx_hash: String,
+        outpoint: (String, u32),
+    },
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader => write!(f, "block has no header"),
+            Self::KnownInvalid { hash } => {
+                write!(f, "block {} is or descends from an invalid block", hash)
+            }
+            Self::PreviousHashMismatch { expected, found } => {
+                write!(f, "previous hash {} does not match tip {}", found, expected)
+            }
+            Self::UnexpectedIndex { expected, found } => {
+                write!(f, "block index {} but expected {}", found, expected)
+            }
+            Self::BlockHashMismatch { expected, found } => write!(
+                f,
+                "block hash {} does not match header hash {}",
+                found, expected
+            ),
+            Self::InsufficientWork { bits } => {
+                write!(f, "header hash does not meet target {:#010x}", bits)
+            }
+            Self::UnexpectedDifficulty { expected, found } => {
+                write!(f, "target {:#010x} but expected {:#010x}", found, expected)
+            }
+            Self::TargetTooEasy { easiest, found } => {
+                write!(f, "target {:#010x} is easier than {:#010x}", found, easiest)
+            }
+            Self::TimestampTooEarly {
+                median_time_past,
+                found,
+            } => write!(
+                f,
+                "timestamp {} is before the median time past {}",
+                found, median_time_past
+            ),
+            Self::TimestampTooFarAhead { latest, found } => {
+                write!(
+                    f,
+                    "timestamp {} is after the latest allowed {}",
+                    found, latest
+                )
+            }
+            Self::MerkleRootMismatch { expected, found } => write!(
+                f,
+                "merkle root {} does not match transactions root {}",
+                found, expected
+            ),
+            Self::DuplicateTransaction { tx_hash } => {
+                write!(f, "transaction {} is in the block more than once", tx_hash)
+            }
+            Self::MutatedMerkleTree => {
+                write!(f, "merkle tree has two identical sibling hashes")
+            }
+            Self::InvalidSignature { tx_hash } => {
+                write!(f, "transaction {} has an invalid signature", tx_hash)
+            }
+            Self::UnknownInput { tx_hash, outpoint } => write!(
+                f,
+                "transaction {} spends unknown output {}:{}",
+                tx_hash, outpoint.0, outpoint.1
+            ),
+            Self::DoubleSpend { tx_hash, outpoint } => write!(
+                f,
+                "transaction {} spends output {}:{} more than once",
+                tx_hash, outpoint.0, outpoint.1
+            ),
+            Self::InputOwnerMismatch { tx_hash, outpoint } => write!(
+                f,
+                "transaction {} spends output {}:{} it does not own",
+                tx_hash, outpoint.0, outpoint.1
+            ),
+            Self::OutputsExceedInputs {
+                tx_hash,
+                inputs,
+                outputs,
+            } => write!(
+                f,
+                "transaction {} spends {} but only has {} in inputs",
+                tx_hash, outputs, inputs
+            ),
+            Self::MissingCoinbase => write!(f, "block does not start with a coinbase"),
+            Self::InvalidCoinbase { tx_hash } => write!(
+                f,
+                "coinbase {} does not commit to the block height",
+                tx_hash
+            ),
+            Self::CoinbaseExceedsReward { reward, found } => write!(
+                f,
+                "coinbase pays {} but subsidy plus fees is {}",
+                found, reward
+            ),
+            Self::UnexpectedCoinbase { tx_hash } => {
+                write!(f, "transaction {} is a coinbase but not the first", tx_hash)
+            }
+            Self::MissingInputs { tx_hash } => {
+                write!(f, "transaction {} has no inputs", tx_hash)
+            }
+            Self::ImmatureCoinbase { tx_hash, outpoint } => write!(
+                f,
+                "transaction {} spends coinbase output {}:{} before it matures",
+                tx_hash, outpoint.0, outpoint.1
+            ),
+        }
+    }
+}
+
+impl BlockValidationError {
+    // A body mutated on the way, that doesn't match the merkle root the
+    // block hash commits to. Only these failures leave the block itself valid,
+    // as the genuine body can still come along.
+    pub fn is_mutated_body(&self) -> bool {
+        matches!(
+            self,
+            Self::MerkleRootMismatch { .. }
+                | Self::DuplicateTransaction { .. }
+                | Self::MutatedMerkleTree
+        )
+    }
+}
+
+impl std::error::Error for BlockValidationError {}
+
+impl From<BlockValidationError> for Status {
+    fn from(err: BlockValidationError) -> Self {
+        Status::invalid_argument(err.to_string())
+    }
+}
+
+// Runs every check a block must pass before being appended on top of `tip`.
+// `utxos` is the set of unspent outputs at `tip`.
+pub fn validate_block(
+    block: &Block,
+    tip: &Block,
+    utxos: &UtxoSet,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    validate_header(block, tip)?;
+    let (coinbase, transactions) = block
+        .transactions
+        .split_first()
+        .ok_or(BlockValidationError::MissingCoinbase)?;
+    let fees = validate_transactions(transactions, utxos)?;
+    let height = block.header.as_ref().unwrap().block_index;
+    validate_coinbase(coinbase, height, fees, params)
+}
+
+// The coinbase must commit to the height and can't pay more than the block
+// subsidy plus the fees of the block.
+pub fn validate_coinbase(
+    coinbase: &Transaction,
+    height: u64,
+    fees: u64,
+    params: &ConsensusParams,
+) -> Result<(), BlockValidationError> {
+    if !is_coinbase(coinbase) {
+        return Err(BlockValidationError::MissingCoinbase);
+    }
+    if coinbase.inputs[0].output_index != height as u32 {
+        return Err(BlockValidationError::InvalidCoinbase {
+            tx_hash: hex::encode(coinbase.hash()),
+        });
+    }
+    let reward = params.block_subsidy(height) as u64 + fees;
+    let found: u64 = coinbase.outputs.iter().map(|o| o.amount as u64).sum();
+    if found > reward {
+        return Err(BlockValidationError::CoinbaseExceedsReward { reward, found });
+    }
+    Ok(())
+}
+
+// Checks linkage to the tip, proof-of-work and the merkle root commitment.
+pub fn validate_header(block: &Block, tip: &Block) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    let tip_header = tip
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+
+    if header.previous_hash != tip.block_hash {
+        return Err(BlockValidationError::PreviousHashMismatch {
+            expected: hex::encode(&tip.block_hash),
+            found: hex::encode(&header.previous_hash),
+        });
+    }
+    if header.block_index != tip_header.block_index + 1 {
+        return Err(BlockValidationError::UnexpectedIndex {
+            expected: tip_header.block_index + 1,
+            found: header.block_index,
+        });
+    }
+    validate_proof_of_work(block)?;
+    validate_merkle_root(block)
+}
+
+// Checks that the header commits to the transactions. Bodies that repeat
+// transactions can have the genuine block's merkle root, and so its hash, so
+// they are rejected as well. It doesn't need any chain context, so it also
+// runs on orphans.
+pub fn validate_merkle_root(block: &Block) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    let mut tx_hashes = HashSet::new();
+    for tx in &block.transactions {
+        let tx_hash = hex::encode(tx.hash());
+        if !tx_hashes.insert(tx_hash.clone()) {
+            return Err(BlockValidationError::DuplicateTransaction { tx_hash });
+        }
+    }
+    let tree = MerkleTree::from_transactions(&block.transactions);
+    if tree.has_identical_siblings() {
+        return Err(BlockValidationError::MutatedMerkleTree);
+    }
+    if header.merkle_root != tree.root_hash {
+        return Err(BlockValidationError::MerkleRootMismatch {
+            expected: hex::encode(tree.root_hash),
+            found: hex::encode(&header.merkle_root),
+        });
+    }
+    Ok(())
+}
+
+// Header checks a light client can run without the transactions: the header
+// extends `parent` and its hash meets its target.
+pub fn validate_chained_header(
+    header: &BlockHeader,
+    parent: &BlockHeader,
+) -> Result<(), BlockValidationError> {
+    let parent_hash = parent.hash();
+    if header.previous_hash != parent_hash {
+        return Err(BlockValidationError::PreviousHashMismatch {
+            expected: hex::encode(&parent_hash),
+            found: hex::encode(&header.previous_hash),
+        });
+    }
+    if header.block_index != parent.block_index + 1 {
+        return Err(BlockValidationError::UnexpectedIndex {
+            expected: parent.block_index + 1,
+            found: header.block_index,
+        });
+    }
+    if !hash_meets_target(&header.hash(), header.bits) {
+        return Err(BlockValidationError::InsufficientWork { bits: header.bits });
+    }
+    Ok(())
+}
+
+// The target is a consensus rule, the header can't choose its own.
+pub fn validate_difficulty(block: &Block, expected: u32) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    if header.bits != expected {
+        return Err(BlockValidationError::UnexpectedDifficulty {
+            expected,
+            found: header.bits,
+        });
+    }
+    Ok(())
+}
+
+pub fn validate_timestamp(
+    block: &Block,
+    median_time_past: u64,
+    latest: u64,
+) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    validate_header_timestamp(header, median_time_past, latest)
+}
+
+// Timestamps drive retargets, so a header can't go back before the median time
+// past of its branch nor claim a time after `latest`. Blocks mined within the
+// same second share a timestamp, so the median itself is allowed.
+pub fn validate_header_timestamp(
+    header: &BlockHeader,
+    median_time_past: u64,
+    latest: u64,
+) -> Result<(), BlockValidationError> {
+    if header.timestamp < median_time_past {
+        return Err(BlockValidationError::TimestampTooEarly {
+            median_time_past,
+            found: header.timestamp,
+        });
+    }
+    if header.timestamp > latest {
+        return Err(BlockValidationError::TimestampTooFarAhead {
+            latest,
+            found: header.timestamp,
+        });
+    }
+    Ok(())
+}
+
+// The target an orphan should have depends on its unknown parent, but it can't
+// be easier than `easiest`, or orphans could be mined for next to nothing.
+pub fn validate_orphan_difficulty(block: &Block, easiest: u32) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    let too_easy = match (
+        Target::from_compact(header.bits),
+        Target::from_compact(easiest),
+    ) {
+        (Some(target), Some(easiest)) => target > easiest,
+        _ => true,
+    };
+    if too_easy {
+        return Err(BlockValidationError::TargetTooEasy {
+            easiest,
+            found: header.bits,
+        });
+    }
+    Ok(())
+}
+
+// Checks that the block hash commits to the header and meets its target.
+// It doesn't need any chain context, so it also runs on orphans.
+pub fn validate_proof_of_work(block: &Block) -> Result<(), BlockValidationError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(BlockValidationError::MissingHeader)?;
+    let hash = header.hash();
+    if hash != block.block_hash {
+        return Err(BlockValidationError::BlockHashMismatch {
+            expected: hex::encode(&hash),
+            found: hex::encode(&block.block_hash),
+        });
+    }
+    if !hash_meets_target(&hash, header.bits) {
+        return Err(BlockValidationError::InsufficientWork { bits: header.bits });
+    }
+    Ok(())
+}
+
+// Checks signatures, ownership and amounts of every non-coinbase transaction
+// against `utxos`. Outputs created by a transaction can be spent by later
+// transactions of the same block. Returns the fees the transactions pay.
+pub fn validate_transactions(
+    transactions: &[Transaction],
+    utxos: &UtxoSet,
+) -> Result<u64, BlockValidationError> {
+    let mut created = HashMap::new();
+    let mut spent = HashSet::new();
+    let mut fees = 0;
+    for tx in transactions {
+        let tx_hash = hex::encode(tx.hash());
+        // only the coinbase creates money
+        if tx.inputs.is_empty() {
+            return Err(BlockValidationError::MissingInputs { tx_hash });
+        }
+        if is_coinbase(tx) {
+            return Err(BlockValidationError::UnexpectedCoinbase { tx_hash });
+        }
+        fees += validate_inputs(tx, &tx_hash, utxos, &created, &mut spent)?;
+        for (index, output) in tx.outputs.iter().enumerate() {
+            created.insert((tx_hash.clone(), index as u32), output.clone());
+        }
+    }
+    Ok(fees)
+}
+
+// returns the fee of the transaction, what its inputs have left over
+fn validate_inputs(
+    tx: &Transaction,
+    tx_hash: &str,
+    utxos: &UtxoSet,
+    created: &HashMap<OutPoint, UtxoOutput>,
+    spent: &mut HashSet<OutPoint>,
+) -> Result<u64, BlockValidationError> {
+    let invalid_signature = || BlockValidationError::InvalidSignature {
+        tx_hash: tx_hash.to_string(),
+    };
+    if !Wallet::verify_transaction_signature(tx).map_err(|_| invalid_signature())? {
+        return Err(invalid_signature());
+    }
+
+    let mut inputs_total: u64 = 0;
+    for input in &tx.inputs {
+        let outpoint = input.outpoint();
+        if spent.contains(&outpoint) {
+            return Err(BlockValidationError::DoubleSpend {
+                tx_hash: tx_hash.to_string(),
+                outpoint,
+            });
+        }
+        let utxo = created
+            .get(&outpoint)
+            .or_else(|| utxos.get(&outpoint))
+            .ok_or_else(|| BlockValidationError::UnknownInput {
+                tx_hash: tx_hash.to_string(),
+                outpoint: outpoint.clone(),
+            })?;
+        if !created.contains_key(&outpoint) && !utxos.is_spendable(&outpoint) {
+            return Err(BlockValidationError::ImmatureCoinbase {
+                tx_hash: tx_hash.to_string(),
+                outpoint,
+            });
+        }
+        let owner = PKey::public_key_from_pem(&input.public_key)
+            .ok()
+            .and_then(|key| Wallet::compute_address(&key).ok());
+        if owner.as_ref() != Some(&utxo.to_addr) {
+            return Err(BlockValidationError::InputOwnerMismatch {
+                tx_hash: tx_hash.to_string(),
+                outpoint,
+            });
+        }
+        inputs_total += utxo.amount as u64;
+        spent.insert(outpoint);
+    }
+
+    let outputs_total: u64 = tx.outputs.iter().map(|o| o.amount as u64).sum();
+    if outputs_total > inputs_total {
+        return Err(BlockValidationError::OutputsExceedInputs {
+            tx_hash: tx_hash.to_string(),
+            inputs: inputs_total,
+            outputs: outputs_total,
+        });
+    }
+    Ok(inputs_total - outputs_total)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::block::{create_genesis_block, next_block};
+    use crate::blockchain::consensus::coinbase_transaction;
+    use crate::blockchain::merkle::merkle_root;
+    use crate::event_bus::event_bus::EventBus;
+    use crate::protos::UtxoInput;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    // 4 leading zero bits, a handful of hashes
+    const BITS: u32 = 0x200fffff;
+
+    // these tests only sign with the wallet, they never look at its balance
+    pub async fn new_wallet(event_bus: &Arc<RwLock<EventBus>>) -> Arc<RwLock<Wallet>> {
+        let utxo_set = Arc::new(std::sync::RwLock::new(UtxoSet::new()));
+        Wallet::new(event_bus.clone(), utxo_set).await
+    }
+
+    // brute-forces the nonce so that the block meets its own target
+    pub fn solve(mut block: Block) -> Block {
+        let mut header = block.header.take().unwrap();
+        while !hash_meets_target(&header.hash(), header.bits) {
+            header.nonce += 1;
+        }
+        block.block_hash = header.hash();
+        block.header = Some(header);
+        block
+    }
+
+    pub fn mined_block(tip: &Block, transactions: Vec<Transaction>) -> Block {
+        mined_block_for(tip, "miner", transactions)
+    }
+
+    // block whose coinbase pays the full subsidy to `miner`
+    pub fn mined_block_for(tip: &Block, miner: &str, transactions: Vec<Transaction>) -> Block {
+        let height = tip.header.as_ref().unwrap().block_index + 1;
+        let subsidy = ConsensusParams::default().block_subsidy(height);
+        let coinbase = coinbase_transaction(miner.to_string(), height, subsidy);
+        let transactions = [vec![coinbase], transactions].concat();
+        let merkle_root = merkle_root(&transactions);
+        let bits = tip.header.as_ref().unwrap().bits;
+        solve(next_block(tip, transactions, merkle_root, bits))
+    }
+
+    // output that is put straight into a utxo set to fund the tests
+    pub fn credit(to_addr: String, amount: u32) -> Transaction {
+        Transaction {
+            inputs: vec![],
+            outputs: vec![UtxoOutput { to_addr, amount }],
+        }
+    }
+
+    pub fn spend(
+        wallet: &Wallet,
+        funding: &Transaction,
+        to_addr: String,
+        amount: u32,
+    ) -> Transaction {
+        let mut tx = Transaction {
+            inputs: vec![UtxoInput {
+                from_addr: wallet.get_address(),
+                public_key: wallet.get_public_key().public_key_to_pem().unwrap(),
+                prev_tx_hash: hex::encode(funding.hash()).into_bytes(),
+                output_index: 0,
+                signature: vec![],
+            }],
+            outputs: vec![UtxoOutput { to_addr, amount }],
+        };
+        wallet.sign_tx(&mut tx).unwrap();
+        tx
+    }
+
+    fn utxos_of(tx: &Transaction) -> UtxoSet {
+        let mut utxos = UtxoSet::new();
+        utxos.apply_transaction(tx);
+        utxos
+    }
+
+    #[test]
+    fn test_valid_block() {
+        let genesis = create_genesis_block();
+        let block = mined_block(&genesis, vec![]);
+        let params = ConsensusParams::default();
+        assert_eq!(
+            Ok(()),
+            validate_block(&block, &genesis, &UtxoSet::new(), &params)
+        );
+    }
+
+    #[test]
+    fn test_missing_coinbase() {
+        let genesis = create_genesis_block();
+        let block = solve(next_block(&genesis, vec![], [0; 32], BITS));
+        assert_eq!(
+            Err(BlockValidationError::MissingCoinbase),
+            validate_block(
+                &block,
+                &genesis,
+                &UtxoSet::new(),
+                &ConsensusParams::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_coinbase_must_commit_to_height() {
+        let coinbase = coinbase_transaction(String::from("miner"), 7, 50);
+        assert!(matches!(
+            validate_coinbase(&coinbase, 1, 0, &ConsensusParams::default()),
+            Err(BlockValidationError::InvalidCoinbase { .. })
+        ));
+    }
+
+    #[test]
+    fn test_coinbase_exceeds_reward() {
+        let coinbase = coinbase_transaction(String::from("miner"), 1, 61);
+        assert_eq!(
+            Err(BlockValidationError::CoinbaseExceedsReward {
+                reward: 60,
+                found: 61
+            }),
+            validate_coinbase(&coinbase, 1, 10, &ConsensusParams::default())
+        );
+    }
+
+    #[test]
+    fn test_input_less_transaction() {
+        let tx = credit(String::from("bob"), 10);
+        assert_eq!(
+            Err(BlockValidationError::MissingInputs {
+                tx_hash: hex::encode(tx.hash())
+            }),
+            validate_transactions(&[tx], &UtxoSet::new())
+        );
+    }
+
+    #[test]
+    fn test_coinbase_after_first_transaction() {
+        let coinbase = coinbase_transaction(String::from("miner"), 1, 50);
+        assert!(matches!(
+            validate_transactions(&[coinbase], &UtxoSet::new()),
+            Err(BlockValidationError::UnexpectedCoinbase { .. })
+        ));
+    }
+
+    #[test]
+    fn test_previous_hash_mismatch() {
+        let genesis = create_genesis_block();
+        let block = mined_block(&genesis, vec![]);
+        let orphan = mined_block(&block, vec![]);
+        assert!(matches!(
+            validate_header(&orphan, &genesis),
+            Err(BlockValidationError::PreviousHashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unexpected_index() {
+        let genesis = create_genesis_block();
+        let mut block = next_block(&genesis, vec![], [0; 32], BITS);
+        block.header.as_mut().unwrap().block_index = 5;
+        let block = solve(block);
+        assert_eq!(
+            Err(BlockValidationError::UnexpectedIndex {
+                expected: 1,
+                found: 5
+            }),
+            validate_header(&block, &genesis)
+        );
+    }
+
+    #[test]
+    fn test_block_hash_mismatch() {
+        let genesis = create_genesis_block();
+        let mut block = mined_block(&genesis, vec![]);
+        block.block_hash = vec![0; 32];
+        assert!(matches!(
+            validate_header(&block, &genesis),
+            Err(BlockValidationError::BlockHashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_insufficient_work() {
+        let genesis = create_genesis_block();
+        // only a zero or one hash would meet it
+        let mut block = next_block(&genesis, vec![], [0; 32], 0x01010000);
+        block.block_hash = block.header.as_ref().unwrap().hash();
+        assert_eq!(
+            Err(BlockValidationError::InsufficientWork { bits: 0x01010000 }),
+            validate_header(&block, &genesis)
+        );
+    }
+
+    #[test]
+    fn test_unexpected_difficulty() {
+        let genesis = create_genesis_block();
+        let block = solve(next_block(&genesis, vec![], [0; 32], BITS));
+        assert_eq!(Ok(()), validate_difficulty(&block, BITS));
+        assert_eq!(
+            Err(BlockValidationError::UnexpectedDifficulty {
+                expected: 0x1f7fffff,
+                found: BITS
+            }),
+            validate_difficulty(&block, 0x1f7fffff)
+        );
+    }
+
+    #[test]
+    fn test_timestamp_bounds() {
+        let genesis = create_genesis_block();
+        let mut block = next_block(&genesis, vec![], [0; 32], BITS);
+        block.header.as_mut().unwrap().timestamp = 100;
+        assert_eq!(Ok(()), validate_timestamp(&block, 100, 100));
+        assert_eq!(
+            Err(BlockValidationError::TimestampTooEarly {
+                median_time_past: 101,
+                found: 100
+            }),
+            validate_timestamp(&block, 101, 200)
+        );
+        assert_eq!(
+            Err(BlockValidationError::TimestampTooFarAhead {
+                latest: 99,
+                found: 100
+            }),
+            validate_timestamp(&block, 0, 99)
+        );
+    }
+
+    #[test]
+    fn test_merkle_root_mismatch() {
+        let genesis = create_genesis_block();
+        let block = solve(next_block(
+            &genesis,
+            vec![credit(String::from("bob"), 10)],
+            [1; 32],
+            BITS,
+        ));
+        assert!(matches!(
+            validate_header(&block, &genesis),
+            Err(BlockValidationError::MerkleRootMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_transactions() {
+        let genesis = create_genesis_block();
+        let block = mined_block(
+            &genesis,
+            vec![
+                credit(String::from("bob"), 1),
+                credit(String::from("bob"), 2),
+            ],
+        );
+        assert_eq!(Ok(()), validate_merkle_root(&block));
+        // three transactions, so repeating the last one keeps the root and hash
+        let mut mutated = block.clone();
+        mutated.transactions.push(block.transactions[2].clone());
+        assert_eq!(
+            merkle_root(&block.transactions),
+            merkle_root(&mutated.transactions)
+        );
+        assert_eq!(
+            Err(BlockValidationError::DuplicateTransaction {
+                tx_hash: hex::encode(block.transactions[2].hash())
+            }),
+            validate_header(&mutated, &genesis)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_valid_spend() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let tx = spend(&bob, &funding, String::from("alice"), 60);
+        assert_eq!(Ok(40), validate_transactions(&[tx], &utxos_of(&funding)));
+    }
+
+    #[tokio::test]
+    async fn test_spend_output_of_same_block() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let first = spend(&bob, &funding, bob.get_address(), 100);
+        let second = spend(&bob, &first, String::from("alice"), 60);
+        assert_eq!(
+            Ok(40),
+            validate_transactions(&[first, second], &utxos_of(&funding))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spend_immature_coinbase() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let coinbase = coinbase_transaction(bob.get_address(), 1, 50);
+        let block = mined_block_for(&create_genesis_block(), &bob.get_address(), vec![]);
+        let mut utxos = UtxoSet::with_coinbase_maturity(2);
+        utxos.connect_block(&block);
+        let tx = spend(&bob, &coinbase, String::from("alice"), 50);
+        assert!(matches!(
+            validate_transactions(std::slice::from_ref(&tx), &utxos),
+            Err(BlockValidationError::ImmatureCoinbase { .. })
+        ));
+
+        utxos.connect_block(&mined_block(&block, vec![]));
+        assert_eq!(Ok(0), validate_transactions(&[tx], &utxos));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signature() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let mut tx = spend(&bob, &funding, String::from("alice"), 60);
+        tx.outputs[0].amount = 100; // tampered after signing
+        assert!(matches!(
+            validate_transactions(&[tx], &utxos_of(&funding)),
+            Err(BlockValidationError::InvalidSignature { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_input() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let tx = spend(&bob, &funding, String::from("alice"), 60);
+        assert!(matches!(
+            validate_transactions(&[tx], &UtxoSet::new()),
+            Err(BlockValidationError::UnknownInput { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_double_spend_in_block() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let first = spend(&bob, &funding, String::from("alice"), 60);
+        let second = spend(&bob, &funding, String::from("carol"), 60);
+        assert!(matches!(
+            validate_transactions(&[first, second], &utxos_of(&funding)),
+            Err(BlockValidationError::DoubleSpend { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_spend_someone_elses_output() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let mallory = new_wallet(&event_bus).await;
+        let funding = credit(bob.read().await.get_address(), 100);
+        let tx = spend(
+            &*mallory.read().await,
+            &funding,
+            String::from("mallory"),
+            60,
+        );
+        assert!(matches!(
+            validate_transactions(&[tx], &utxos_of(&funding)),
+            Err(BlockValidationError::InputOwnerMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_outputs_exceed_inputs() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 100);
+        let tx = spend(&bob, &funding, String::from("alice"), 160);
+        assert_eq!(
+            Err(BlockValidationError::OutputsExceedInputs {
+                tx_hash: hex::encode(tx.hash()),
+                inputs: 100,
+                outputs: 160,
+            }),
+            validate_transactions(&[tx], &utxos_of(&funding))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::blockchain::utxo_set::{OutPoint, UtxoSet};
 use crate::event_bus::event_bus::EventBus;
 use crate::event_bus::events::RustchainEvent;
 use crate::protos::{Block, Transaction};
@@ -15,21 +16,34 @@ use ripemd::{Digest, Ripemd160};
 use sha2::Sha256;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::RwLock;
 
+// how long outputs stay locked by a transaction that doesn't confirm, after
+// which it's taken as dropped, evicted or rejected, and they can be spent again
+pub const PENDING_TTL: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Clone, Debug)]
 pub struct Wallet {
     address: String,
     private_key: PKey<Private>,
     public_key: PKey<Public>,
-    utxos: HashMap<(String, u32), UtxoOutput>,
+    // the node's utxo set, the wallet only owns the outputs sent to its address
+    utxo_set: Arc<std::sync::RwLock<UtxoSet>>,
+    // outputs already spent by transactions that are not confirmed yet, and
+    // when they were sent
+    pending_spent: HashMap<OutPoint, Instant>,
+    pending_ttl: Duration,
     event_bus: Arc<RwLock<EventBus>>,
 }
 
 impl Wallet {
-    pub async fn new(event_bus: Arc<RwLock<EventBus>>) -> Arc<RwLock<Wallet>> {
+    pub async fn new(
+        event_bus: Arc<RwLock<EventBus>>,
+        utxo_set: Arc<std::sync::RwLock<UtxoSet>>,
+    ) -> Arc<RwLock<Wallet>> {
         let rsa = Rsa::generate(2048).expect("Failed to generate RSA key pair");
         let private_key = PKey::from_rsa(rsa.clone()).expect("Failed to create private key");
         let public_key = PKey::from_rsa(
@@ -46,7 +60,9 @@ impl Wallet {
             address,
             private_key,
             public_key,
-            utxos: HashMap::new(),
+            utxo_set,
+            pending_spent: HashMap::new(),
+            pending_ttl: PENDING_TTL,
             event_bus: event_bus.clone(),
         };
         let wallet_arc = Arc::new(RwLock::new(wallet));
@@ -63,34 +79,50 @@ impl Wallet {
         mut event_receiver: Receiver<RustchainEvent>,
     ) {
         while let Some(event) = event_receiver.recv().await {
-            match event {
-                RustchainEvent::NewBlock(block) => {
-                    Wallet::on_block_received(wallet.clone(), block).await;
-                }
-                RustchainEvent::NewTransaction(tx) => {
-                    Wallet::on_tx_received(wallet.clone(), tx).await;
-                }
-                _ => {
-                    unimplemented!();
-                }
+            if let RustchainEvent::BlockConnected(block) = event {
+                Wallet::on_block_connected(wallet.clone(), block).await;
             }
         }
     }
 
-    async fn on_block_received(_: Arc<RwLock<Wallet>>, _: Block) {}
+    pub fn use_pending_ttl(&mut self, ttl: Duration) {
+        self.pending_ttl = ttl;
+    }
 
-    async fn on_tx_received(wallet: Arc<RwLock<Wallet>>, tx: Transaction) {
+    // once a spend is confirmed its inputs are gone from the utxo set, so
+    // there is no need to keep them locked anymore, nor once it expired
+    async fn on_block_connected(wallet: Arc<RwLock<Wallet>>, _: Block) {
         let mut w = wallet.write().await;
-        for (index, utxo_output) in tx.clone().outputs.into_iter().enumerate() {
-            if utxo_output.to_addr != w.address {
-                continue;
-            }
-            let tx_hash = hex::encode(tx.hash());
-            w.utxos.insert((tx_hash, index as u32), utxo_output.clone());
-        }
+        let utxo_set = w.utxo_set.clone();
+        let utxo_set = utxo_set.read().unwrap();
+        let ttl = w.pending_ttl;
+        w.pending_spent
+            .retain(|outpoint, sent| utxo_set.contains(outpoint) && sent.elapsed() < ttl);
+    }
+
+    fn is_pending(&self, outpoint: &OutPoint) -> bool {
+        self.pending_spent
+            .get(outpoint)
+            .is_some_and(|sent| sent.elapsed() < self.pending_ttl)
+    }
+
+    // confirmed outputs of this wallet not used by a pending transaction yet
+    fn spendable_utxos(&self) -> Vec<(OutPoint, UtxoOutput)> {
+        let mut utxos: Vec<(OutPoint, UtxoOutput)> = self
+            .utxo_set
+            .read()
+            .unwrap()
+            .spendable_by_address(&self.address)
+            .into_iter()
+            .filter(|(outpoint, _)| !self.is_pending(outpoint))
+            .collect();
+        utxos.sort_by(|a, b| a.0.cmp(&b.0));
+        utxos
     }
 
-    fn compute_address(public_key: &PKey<Public>) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn compute_address(
+        public_key: &PKey<Public>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let pem_data = public_key.public_key_to_pem()?;
         let sha256_hash = hash(MessageDigest::sha256(), &pem_data)?;
 
@@ -110,20 +142,6 @@ impl Wallet {
         Ok(address)
     }
 
-    pub async fn air_drop(&self, amount: u32) {
-        let mut tx = Transaction::default();
-        let utxo_output = UtxoOutput {
-            to_addr: self.address.clone(),
-            amount,
-        };
-        tx.outputs.push(utxo_output);
-        self.event_bus
-            .read()
-            .await
-            .publish(RustchainEvent::NewTransaction(tx))
-            .await;
-    }
-
     pub fn sign_transaction(
         &self,
         input: &mut UtxoInput,
@@ -135,6 +153,16 @@ impl Wallet {
         Ok(())
     }
 
+    // signs the content of the whole transaction (inputs and outputs) and sets
+    // the signature on every input, which is what validation verifies
+    pub fn sign_tx(&self, tx: &mut Transaction) -> Result<(), Box<dyn std::error::Error>> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        signer.update(&tx.hashable_content()?)?;
+        let signature = signer.sign_to_vec()?;
+        tx.set_signature(signature);
+        Ok(())
+    }
+
     pub async fn send_transaction(
         &mut self,
         to_addr: String,
@@ -148,17 +176,16 @@ impl Wallet {
         let mut total_utxo_value: u32 = 0;
         let mut used_utxos: Vec<(String, u32)> = vec![];
 
-        for (utxo_key, utxo) in self.utxos.iter() {
+        for (utxo_key, utxo) in self.spendable_utxos().iter() {
             // Create a new Utxo input using the selected UTXO
             let (prev_tx_hash, output_index) = utxo_key;
-            let mut input = UtxoInput {
+            let input = UtxoInput {
                 from_addr: self.address.clone(),
                 public_key: self.public_key.public_key_to_pem()?,
                 prev_tx_hash: prev_tx_hash.clone().into_bytes(),
                 output_index: *output_index,
                 signature: vec![],
             };
-            self.sign_transaction(&mut input)?;
             tx.inputs.push(input);
             used_utxos.push((prev_tx_hash.clone(), *output_index));
             // Added to list of utxo's that should be removed on transaction complete
@@ -179,6 +206,7 @@ impl Wallet {
                 amount: change,
             });
         }
+        self.sign_tx(&mut tx)?;
 
         // wait for tx to be dispatched
         self.event_bus
@@ -187,10 +215,10 @@ impl Wallet {
             .publish(RustchainEvent::NewTransaction(tx.clone()))
             .await;
 
-        // if tx OK then remove used utxo's
-        for utxo in used_utxos {
-            self.utxos.remove(&utxo);
-        }
+        // if tx OK then lock used utxo's until the tx is confirmed or expires
+        let sent = Instant::now();
+        self.pending_spent
+            .extend(used_utxos.into_iter().map(|outpoint| (outpoint, sent)));
         Ok(tx)
     }
 
@@ -210,7 +238,7 @@ impl Wallet {
     }
 
     pub fn get_balance(&self) -> u32 {
-        self.utxos.values().map(|x| x.amount).sum()
+        self.spendable_utxos().iter().map(|(_, x)| x.amount).sum()
     }
 
     pub fn get_public_key(&self) -> PKey<Public> {
@@ -232,15 +260,67 @@ impl Wallet {
 pub mod tests {
     use std::time::Duration;
 
-    use tokio::time::sleep;
+    use tokio::time::{sleep, timeout};
 
     use super::*;
+    use crate::blockchain::blockchain::Blockchain;
+    use crate::blockchain::consensus::ConsensusParams;
+    use crate::blockchain::store::MemoryBlockStore;
+    use crate::blockchain::validation::tests::mined_block_for;
+
+    // coinbases can be spent once one more block is mined on top
+    async fn setup() -> (Arc<RwLock<EventBus>>, Arc<RwLock<Blockchain>>) {
+        let event_bus = EventBus::new().await;
+        let params = ConsensusParams {
+            coinbase_maturity: 2,
+            ..ConsensusParams::default()
+        };
+        let store = Box::new(MemoryBlockStore::new());
+        let blockchain = Blockchain::with_params(event_bus.clone(), store, params).await;
+        (event_bus, blockchain)
+    }
+
+    async fn new_wallet(
+        event_bus: &Arc<RwLock<EventBus>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
+    ) -> Arc<RwLock<Wallet>> {
+        let utxo_set = blockchain.read().await.utxo_set();
+        Wallet::new(event_bus.clone(), utxo_set).await
+    }
+
+    async fn mine(blockchain: &Arc<RwLock<Blockchain>>, miner: &str, txs: Vec<Transaction>) {
+        let block = mined_block_for(&blockchain.read().await.tip(), miner, txs);
+        blockchain.write().await.add_block(block).await.unwrap();
+    }
+
+    // mines a coinbase paying `wallet` and a block on top so it matures
+    async fn fund(wallet: &Arc<RwLock<Wallet>>, blockchain: &Arc<RwLock<Blockchain>>) {
+        mine(blockchain, &wallet.read().await.get_address(), vec![]).await;
+        mine(blockchain, "miner", vec![]).await;
+    }
+
+    // waits for the next transaction published on the bus and mines it
+    async fn confirm_next_tx(
+        receiver: &mut Receiver<RustchainEvent>,
+        blockchain: &Arc<RwLock<Blockchain>>,
+    ) {
+        let tx = loop {
+            let event = timeout(Duration::from_secs(1), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let RustchainEvent::NewTransaction(tx) = event {
+                break tx;
+            }
+        };
+        mine(blockchain, "miner", vec![tx]).await;
+    }
 
     #[tokio::test]
     async fn not_enough_balance() {
-        let event_bus = EventBus::new().await;
-        let bob = Wallet::new(event_bus.clone()).await;
-        let alice = Wallet::new(event_bus.clone()).await;
+        let (event_bus, blockchain) = setup().await;
+        let bob = new_wallet(&event_bus, &blockchain).await;
+        let alice = new_wallet(&event_bus, &blockchain).await;
         let alice_key = alice.read().await.public_key_string().unwrap();
         assert!(matches!(
             bob.write().await.send_transaction(alice_key, 500).await,
@@ -249,27 +329,69 @@ pub mod tests {
     }
 
     #[tokio::test]
-    async fn test_air_drop() {
-        let amount = 500;
-        let event_bus = EventBus::new().await;
-        let bob = Wallet::new(event_bus).await;
-        bob.read().await.air_drop(amount).await;
-        sleep(Duration::from_millis(100)).await;
-        let balance = bob.read().await.get_balance();
-        assert_eq!(amount, balance);
+    async fn test_coinbase_matures() {
+        let (event_bus, blockchain) = setup().await;
+        let bob = new_wallet(&event_bus, &blockchain).await;
+        mine(&blockchain, &bob.read().await.get_address(), vec![]).await;
+        // not spendable until it matures
+        assert_eq!(0, bob.read().await.get_balance());
+        mine(&blockchain, "miner", vec![]).await;
+        assert_eq!(50, bob.read().await.get_balance());
     }
 
     #[tokio::test]
     async fn test_transaction() {
-        let event_bus = EventBus::new().await;
-        let alice = Wallet::new(event_bus.clone()).await;
+        let (event_bus, blockchain) = setup().await;
+        let mut receiver = event_bus.write().await.subscribe().await;
+        let alice = new_wallet(&event_bus, &blockchain).await;
+        let alice_addr = alice.read().await.address.clone();
+        let bob = new_wallet(&event_bus, &blockchain).await;
+        fund(&bob, &blockchain).await;
+
+        bob.write()
+            .await
+            .send_transaction(alice_addr, 30)
+            .await
+            .unwrap();
+        // the spent output is locked while the tx is pending
+        assert_eq!(0, bob.read().await.get_balance());
+        confirm_next_tx(&mut receiver, &blockchain).await;
+        assert_eq!(20, bob.read().await.get_balance());
+        assert_eq!(30, alice.read().await.get_balance());
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_spend_expires() {
+        let (event_bus, blockchain) = setup().await;
+        let bob = new_wallet(&event_bus, &blockchain).await;
+        fund(&bob, &blockchain).await;
+        bob.write()
+            .await
+            .use_pending_ttl(Duration::from_millis(100));
+        // never mined, as if the mempool dropped it
+        bob.write()
+            .await
+            .send_transaction(String::from("alice"), 30)
+            .await
+            .unwrap();
+        assert_eq!(0, bob.read().await.get_balance());
+        sleep(Duration::from_millis(150)).await;
+        assert_eq!(50, bob.read().await.get_balance());
+    }
+
+    #[tokio::test]
+    async fn test_sent_transaction_is_signed() {
+        let (event_bus, blockchain) = setup().await;
+        let alice = new_wallet(&event_bus, &blockchain).await;
         let alice_addr = alice.read().await.address.clone();
-        let bob = Wallet::new(event_bus.clone()).await;
-        bob.read().await.air_drop(1000).await;
-        sleep(Duration::from_millis(100)).await;
-        let _ = bob.write().await.send_transaction(alice_addr, 500).await;
-        sleep(Duration::from_millis(100)).await;
-        assert_eq!(500, bob.read().await.get_balance());
-        assert_eq!(500, alice.read().await.get_balance());
+        let bob = new_wallet(&event_bus, &blockchain).await;
+        fund(&bob, &blockchain).await;
+        let tx = bob
+            .write()
+            .await
+            .send_transaction(alice_addr, 30)
+            .await
+            .unwrap();
+        assert!(Wallet::verify_transaction_signature(&tx).unwrap());
     }
 }
@@ -24,7 +24,7 @@ impl EventBus {
         let event_bus_clone = Arc::clone(&event_bus);
         spawn(async move {
             while let Some(event) = receiver.recv().await {
-                (event_bus_clone.write().await).dispatch(event).await;
+                (event_bus_clone.read().await).dispatch(event).await;
             }
         });
         event_bus
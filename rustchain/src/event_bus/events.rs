@@ -1,14 +1,25 @@
-use crate::protos::{Block, Heartbeat, PeerList, Transaction};
+use crate::net::middleware::Client;
+use crate::protos::{Block, Heartbeat, Inventory, Peer, PeerList, Transaction};
 
 #[derive(Clone)]
 pub enum RustchainEvent {
     NewBlock(Block),
+    // published by the blockchain when a block joins or leaves the active chain
+    BlockConnected(Block),
+    BlockDisconnected(Block),
+    // published by the blockchain when a new block fails validation
+    BlockRejected(Block),
     NewTransaction(Transaction),
+    // published by the miner once a transaction passed validation and entered
+    // the mempool
+    TransactionAccepted(Transaction),
+    // blocks and transactions a peer announced, and the client it came from
+    NewInventory(Inventory, Client),
     NewPeers(PeerList),
     NewHeartbeat(Heartbeat),
-}
-
-pub enum P2pEvent {
-    NewPeers(PeerList),
-    NewHeartbeat(Heartbeat),
+    // published when the failure detector evicts a dead peer
+    PeerRemoved(Peer),
+    // a peer announced itself joining or leaving over the P2P service
+    PeerJoined(Peer),
+    PeerLeft(Peer),
 }
@@ -0,0 +1,330 @@
+use crate::blockchain::consensus::is_coinbase;
+use crate::blockchain::utxo_set::{OutPoint, UtxoSet};
+use crate::blockchain::validation::{validate_transactions, BlockValidationError};
+use crate::protos::{Block, Transaction};
+use prost::Message;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+// total encoded size of the transactions the pool holds before evicting
+pub const MAX_MEMPOOL_BYTES: usize = 5_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    AlreadyKnown,
+    Invalid(BlockValidationError),
+    // an input is already spent by a transaction of the pool
+    Conflict { outpoint: OutPoint },
+    // the pool is full of transactions paying at least as much
+    FeeTooLow { fee_rate: u64 },
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyKnown => write!(f, "transaction is already in the mempool"),
+            Self::Invalid(reason) => write!(f, "{}", reason),
+            Self::Conflict { outpoint } => write!(
+                f,
+                "output {}:{} is already spent by a pending transaction",
+                outpoint.0, outpoint.1
+            ),
+            Self::FeeTooLow { fee_rate } => {
+                write!(f, "fee rate {} is too low for a full mempool", fee_rate)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    // inputs minus outputs
+    pub fee: u64,
+    pub size: usize,
+}
+
+impl MempoolEntry {
+    // fee paid per 1000 bytes
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+// Transactions waiting to be mined, ordered by fee rate. Every transaction
+// only spends confirmed outputs and no two of them spend the same one, so any
+// subset of the pool can go in a block.
+#[derive(Debug)]
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    // (fee rate, tx hash), lowest paying first
+    by_fee_rate: BTreeSet<(u64, String)>,
+    // outpoints spent by pool transactions and who spends them
+    spent: HashMap<OutPoint, String>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(MAX_MEMPOOL_BYTES)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_bytes: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            by_fee_rate: BTreeSet::new(),
+            spent: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, tx_hash: &str) -> bool {
+        self.entries.contains_key(tx_hash)
+    }
+
+    pub fn get(&self, tx_hash: &str) -> Option<&MempoolEntry> {
+        self.entries.get(tx_hash)
+    }
+
+    // Validates the transaction against the confirmed outputs and adds it,
+    // evicting lower paying transactions if the pool is full. Returns the fee.
+    pub fn add(&mut self, tx: Transaction, utxos: &UtxoSet) -> Result<u64, MempoolError> {
+        let tx_hash = hex::encode(tx.hash());
+        if self.entries.contains_key(&tx_hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if let Some(outpoint) = tx
+            .inputs
+            .iter()
+            .map(|input| input.outpoint())
+            .find(|outpoint| self.spent.contains_key(outpoint))
+        {
+            return Err(MempoolError::Conflict { outpoint });
+        }
+        let fee = validate_transactions(std::slice::from_ref(&tx), utxos)
+            .map_err(MempoolError::Invalid)?;
+        let entry = MempoolEntry {
+            size: tx.encoded_len(),
+            tx,
+            fee,
+        };
+        self.make_room(&entry)?;
+
+        self.bytes += entry.size;
+        self.by_fee_rate.insert((entry.fee_rate(), tx_hash.clone()));
+        for input in &entry.tx.inputs {
+            self.spent.insert(input.outpoint(), tx_hash.clone());
+        }
+        self.entries.insert(tx_hash, entry);
+        Ok(fee)
+    }
+
+    // evicts the lowest paying transactions until `entry` fits, as long as
+    // they pay less than it
+    fn make_room(&mut self, entry: &MempoolEntry) -> Result<(), MempoolError> {
+        let fee_rate = entry.fee_rate();
+        let mut freed = 0;
+        let mut evicted = vec![];
+        for (rate, hash) in self.by_fee_rate.iter() {
+            if self.bytes - freed + entry.size <= self.max_bytes {
+                break;
+            }
+            if *rate >= fee_rate {
+                return Err(MempoolError::FeeTooLow { fee_rate });
+            }
+            freed += self.entries[hash].size;
+            evicted.push(hash.clone());
+        }
+        if self.bytes - freed + entry.size > self.max_bytes {
+            return Err(MempoolError::FeeTooLow { fee_rate });
+        }
+        for hash in evicted {
+            self.remove(&hash);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, tx_hash: &str) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(tx_hash)?;
+        self.bytes -= entry.size;
+        self.by_fee_rate
+            .remove(&(entry.fee_rate(), tx_hash.to_string()));
+        for input in &entry.tx.inputs {
+            self.spent.remove(&input.outpoint());
+        }
+        Some(entry)
+    }
+
+    // drops the transactions a block confirmed and the ones that conflict
+    // with them
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.remove(&hex::encode(tx.hash()));
+            if is_coinbase(tx) {
+                continue;
+            }
+            for input in &tx.inputs {
+                if let Some(conflict) = self.spent.get(&input.outpoint()).cloned() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    // Highest fee rate first, skipping what doesn't fit in `max_bytes`.
+    // Returns the transactions and the fees they pay.
+    pub fn block_template(&self, max_bytes: usize) -> (Vec<Transaction>, u64) {
+        let mut transactions = vec![];
+        let mut fees = 0;
+        let mut bytes = 0;
+        for (_, hash) in self.by_fee_rate.iter().rev() {
+            let entry = &self.entries[hash];
+            if bytes + entry.size > max_bytes {
+                continue;
+            }
+            bytes += entry.size;
+            fees += entry.fee;
+            transactions.push(entry.tx.clone());
+        }
+        (transactions, fees)
+    }
+}
+
+fn fee_rate(fee: u64, size: usize) -> u64 {
+    fee * 1000 / size.max(1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::validation::tests::{credit, new_wallet, spend};
+    use crate::blockchain::wallet::Wallet;
+    use crate::event_bus::event_bus::EventBus;
+
+    async fn funded_wallet(amounts: &[u32]) -> (Wallet, Vec<Transaction>, UtxoSet) {
+        let event_bus = EventBus::new().await;
+        let wallet = new_wallet(&event_bus).await.read().await.clone();
+        let mut utxos = UtxoSet::new();
+        let funding: Vec<Transaction> = amounts
+            .iter()
+            .map(|amount| credit(wallet.get_address(), *amount))
+            .collect();
+        for tx in &funding {
+            utxos.apply_transaction(tx);
+        }
+        (wallet, funding, utxos)
+    }
+
+    #[tokio::test]
+    async fn test_add_computes_fee() {
+        let (bob, funding, utxos) = funded_wallet(&[100]).await;
+        let mut mempool = Mempool::default();
+        let tx = spend(&bob, &funding[0], String::from("alice"), 90);
+        assert_eq!(Ok(10), mempool.add(tx.clone(), &utxos));
+        assert_eq!(
+            Err(MempoolError::AlreadyKnown),
+            mempool.add(tx.clone(), &utxos)
+        );
+        let entry = mempool.get(&hex::encode(tx.hash())).unwrap();
+        assert_eq!(10, entry.fee);
+        assert_eq!(tx.encoded_len(), entry.size);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_and_conflicting() {
+        let (bob, funding, utxos) = funded_wallet(&[100]).await;
+        let mut mempool = Mempool::default();
+        let overspend = spend(&bob, &funding[0], String::from("alice"), 160);
+        assert!(matches!(
+            mempool.add(overspend, &utxos),
+            Err(MempoolError::Invalid(
+                BlockValidationError::OutputsExceedInputs { .. }
+            ))
+        ));
+        mempool
+            .add(spend(&bob, &funding[0], String::from("alice"), 90), &utxos)
+            .unwrap();
+        let double_spend = spend(&bob, &funding[0], String::from("carol"), 50);
+        assert_eq!(
+            Err(MempoolError::Conflict {
+                outpoint: (hex::encode(funding[0].hash()), 0)
+            }),
+            mempool.add(double_spend, &utxos)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_orders_by_fee_rate() {
+        let (bob, funding, utxos) = funded_wallet(&[100, 200, 300]).await;
+        let mut mempool = Mempool::default();
+        let low = spend(&bob, &funding[0], String::from("alice"), 99);
+        let high = spend(&bob, &funding[1], String::from("alice"), 150);
+        let mid = spend(&bob, &funding[2], String::from("alice"), 290);
+        for tx in [&low, &high, &mid] {
+            mempool.add(tx.clone(), &utxos).unwrap();
+        }
+        let (txs, fees) = mempool.block_template(usize::MAX);
+        assert_eq!(vec![high.clone(), mid.clone(), low], txs);
+        assert_eq!(61, fees);
+
+        // only room for one transaction
+        let (txs, fees) = mempool.block_template(high.encoded_len());
+        assert_eq!(vec![high], txs);
+        assert_eq!(50, fees);
+    }
+
+    #[tokio::test]
+    async fn test_full_pool_evicts_lowest_fee_rate() {
+        let (bob, funding, utxos) = funded_wallet(&[100, 200, 300]).await;
+        let low = spend(&bob, &funding[0], String::from("alice"), 99);
+        let high = spend(&bob, &funding[1], String::from("alice"), 150);
+        let lower = spend(&bob, &funding[2], String::from("alice"), 300);
+        let mut mempool = Mempool::new(low.encoded_len() + 10);
+        mempool.add(low.clone(), &utxos).unwrap();
+
+        assert!(matches!(
+            mempool.add(lower, &utxos),
+            Err(MempoolError::FeeTooLow { .. })
+        ));
+        assert_eq!(Ok(50), mempool.add(high.clone(), &utxos));
+        assert!(!mempool.contains(&hex::encode(low.hash())));
+        assert!(mempool.contains(&hex::encode(high.hash())));
+        // the evicted transaction's input can be spent again
+        assert!(mempool.spent.len() == 1);
+    }
+
+    #[tokio::test]
+    async fn test_remove_confirmed_drops_conflicts() {
+        let (bob, funding, utxos) = funded_wallet(&[100]).await;
+        let mut mempool = Mempool::default();
+        mempool
+            .add(spend(&bob, &funding[0], String::from("alice"), 90), &utxos)
+            .unwrap();
+        let confirmed = spend(&bob, &funding[0], String::from("carol"), 80);
+        let block = Block {
+            transactions: vec![confirmed],
+            ..Block::default()
+        };
+        mempool.remove_confirmed(&block);
+        assert!(mempool.is_empty());
+        assert_eq!(0, mempool.bytes());
+    }
+}
@@ -1,254 +1,456 @@
+use crate::blockchain::block::next_block;
+use crate::blockchain::blockchain::Blockchain;
+use crate::blockchain::consensus::{coinbase_transaction, is_coinbase};
+use crate::blockchain::merkle::merkle_root;
+use crate::blockchain::utxo_set::UtxoSet;
+use crate::blockchain::validation::validate_transactions;
 use crate::event_bus::event_bus::EventBus;
 use crate::event_bus::events::RustchainEvent;
-use crate::protos::BlockHeader;
-use crate::protos::Transaction;
-use openssl::hash::MessageDigest;
-use openssl::pkey::{PKey, Public};
-use openssl::sign::Verifier;
-use sha2::{Digest, Sha256};
-use std::io::Error;
-use std::io::ErrorKind;
+use crate::miner::mempool::Mempool;
+use crate::miner::pow::PowEngine;
+use crate::protos::{Block, Transaction};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::runtime::Handle;
 use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::RwLock;
+use tokio::task::{spawn_blocking, JoinHandle};
+
+// room for the transactions of a block template, the coinbase aside
+const MAX_BLOCK_BYTES: usize = 1_000_000;
+
+// block being mined right now, so it can be dropped when a competing block for
+// its height shows up
+#[derive(Debug, Clone)]
+struct Attempt {
+    height: u64,
+    cancel: Arc<AtomicBool>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Miner {
-    pending_transactions: Arc<RwLock<Vec<Transaction>>>,
+    mempool: Arc<RwLock<Mempool>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    // confirmed outputs, used to validate incoming transactions without
+    // waiting on the chain lock
+    utxo_set: Arc<std::sync::RwLock<UtxoSet>>,
     event_bus: Arc<RwLock<EventBus>>,
+    // where the coinbase of mined blocks pays to
+    address: String,
+    attempt: Option<Attempt>,
+    running: Arc<AtomicBool>,
+    engine: Arc<PowEngine>,
 }
-// mining_reward: f64, do I need to pass mining
-// reward or should it be notified by the system
 
 impl Miner {
-    pub fn new(event_bus: Arc<RwLock<EventBus>>) -> Arc<RwLock<Miner>> {
+    // mines with one worker thread per core
+    pub async fn new(
+        event_bus: Arc<RwLock<EventBus>>,
+        blockchain: Arc<RwLock<Blockchain>>,
+        address: String,
+    ) -> Arc<RwLock<Miner>> {
+        Miner::with_engine(event_bus, blockchain, address, PowEngine::default()).await
+    }
+
+    pub async fn with_engine(
+        event_bus: Arc<RwLock<EventBus>>,
+        blockchain: Arc<RwLock<Blockchain>>,
+        address: String,
+        engine: PowEngine,
+    ) -> Arc<RwLock<Miner>> {
+        let utxo_set = blockchain.read().await.utxo_set();
         let miner = Miner {
-            pending_transactions: Arc::new(RwLock::new(vec![])),
+            mempool: Arc::new(RwLock::new(Mempool::default())),
+            blockchain,
+            utxo_set,
             event_bus: event_bus.clone(),
+            address,
+            attempt: None,
+            running: Arc::new(AtomicBool::new(false)),
+            engine: Arc::new(engine),
         };
         let miner_arc = Arc::new(RwLock::new(miner));
-        let handle = Handle::current();
-        handle.block_on(async {
-            let event_receiver = event_bus.write().await.subscribe().await;
-            let miner_clone = miner_arc.clone();
-            spawn(async move {
-                miner_clone
-                    .write()
-                    .await
-                    .listen_for_events(event_receiver)
-                    .await;
-            });
-        });
+        let event_receiver = event_bus.write().await.subscribe().await;
+        let miner_clone = miner_arc.clone();
+        spawn(async move { Miner::listen_for_events(miner_clone, event_receiver).await });
         miner_arc
     }
 
-    async fn listen_for_events(&mut self, mut event_receiver: Receiver<RustchainEvent>) {
+    async fn listen_for_events(
+        miner: Arc<RwLock<Miner>>,
+        mut event_receiver: Receiver<RustchainEvent>,
+    ) {
         while let Some(event) = event_receiver.recv().await {
             match event {
-                RustchainEvent::NewBlock(block) => {
-                    unimplemented!()
+                // only once the chain took it, a block that's merely announced
+                // could be invalid, or never connect
+                RustchainEvent::BlockConnected(block) => {
+                    Miner::on_block_received(miner.clone(), block).await;
                 }
-                RustchainEvent::NewTransaction(transaction) => {
-                    unimplemented!()
+                RustchainEvent::BlockDisconnected(block) => {
+                    Miner::on_block_disconnected(miner.clone(), block).await;
                 }
-                _ => {
-                    unimplemented!()
+                RustchainEvent::NewTransaction(transaction) => {
+                    Miner::on_transaction_received(miner.clone(), transaction).await;
                 }
+                _ => {}
             }
         }
     }
 
-    fn mine(
-        self,
-        prev_block_index: u64,
-        prev_hash: &Vec<u8>,
-        transactions: &Vec<Transaction>,
-        difficulty: u64,
-    ) -> Result<(Vec<u8>, u64), Box<dyn std::error::Error>> {
-        for transaction in transactions {
-            for input in transaction.inputs.iter() {
-                let public_key = PKey::public_key_from_pem(&input.public_key).unwrap();
-                if !verify_signature(
-                    &public_key,
-                    &input.signature,
-                    &transaction.to_bytes().unwrap(),
-                ) {
-                    return Err(Box::new(Error::new(
-                        ErrorKind::Other,
-                        "Error verifying tx using public key",
-                    )));
-                }
+    // accepted transactions are published again so they can be relayed
+    async fn on_transaction_received(miner: Arc<RwLock<Miner>>, tx: Transaction) {
+        let m = miner.read().await;
+        let tx_hash = hex::encode(tx.hash());
+        let utxo_set = m.utxo_set.clone();
+        let added = m
+            .mempool
+            .write()
+            .await
+            .add(tx.clone(), &utxo_set.read().unwrap());
+        match added {
+            Ok(_) => {
+                m.event_bus
+                    .read()
+                    .await
+                    .publish(RustchainEvent::TransactionAccepted(tx))
+                    .await
             }
+            Err(reason) => println!("Rejected transaction {}: {}", tx_hash, reason),
         }
-        let mut nonce = 0;
-        let mut hash: Vec<u8> = vec![];
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        // Verify the digital signatures of the transactions
-
-        loop {
-            nonce = nonce + 1;
-            let header = BlockHeader {
-                timestamp,
-                nonce,
-                difficulty,
-                previous_hash: prev_hash.to_owned(),
-                block_index: prev_block_index + 1,
-                merkle_root: calculate_merkle_root(&transactions.to_owned()),
-            };
-
-            hash = header.hash();
-
-            if satisfies_difficulty(&hash, header.difficulty.to_owned()) {
-                return Ok((hash.to_vec(), nonce));
+    }
+
+    // a block connected at the height being mined makes the current attempt
+    // stale, and its transactions leave the mempool
+    async fn on_block_received(miner: Arc<RwLock<Miner>>, block: Block) {
+        let height = block.header.as_ref().map_or(0, |h| h.block_index);
+        let m = miner.read().await;
+        if let Some(attempt) = &m.attempt {
+            if height >= attempt.height {
+                attempt.cancel.store(true, Ordering::Relaxed);
             }
         }
+        m.mempool.write().await.remove_confirmed(&block);
     }
-}
 
-fn verify_signature(public_key: &PKey<Public>, signature: &[u8], message: &[u8]) -> bool {
-    let digest = MessageDigest::sha256();
-    let mut verifier = Verifier::new(digest, public_key).unwrap();
-    verifier.update(message).unwrap();
-    verifier.verify(signature).unwrap()
-}
+    // transactions of a block dropped by a reorg go back to the mempool, if
+    // they are still valid on the new chain
+    async fn on_block_disconnected(miner: Arc<RwLock<Miner>>, block: Block) {
+        let m = miner.read().await;
+        if let Some(attempt) = &m.attempt {
+            attempt.cancel.store(true, Ordering::Relaxed);
+        }
+        let mut mempool = m.mempool.write().await;
+        let utxo_set = m.utxo_set.read().unwrap();
+        for tx in block.transactions {
+            if !is_coinbase(&tx) {
+                let _ = mempool.add(tx, &utxo_set);
+            }
+        }
+    }
 
-// checks whether the hash has at least the difficulty number of leading zeroes
-fn satisfies_difficulty(hash: &Vec<u8>, difficulty: u64) -> bool {
-    let mut counter = 0;
-    for &byte in hash {
-        for i in (0..8).rev() {
-            if byte & (1 << i) == 0 {
-                counter += 1;
-            } else {
-                if counter >= difficulty {
-                    return true;
-                } else {
-                    return false;
+    // Keeps mining blocks on top of the current tip until `stop` is called.
+    // Every block found is published as a `NewBlock` event.
+    pub async fn start(miner: Arc<RwLock<Miner>>) -> JoinHandle<()> {
+        let (running, engine) = {
+            let m = miner.read().await;
+            (m.running.clone(), m.engine.clone())
+        };
+        running.store(true, Ordering::Relaxed);
+        spawn(async move {
+            while running.load(Ordering::Relaxed) {
+                let (candidate, cancel) = Miner::next_attempt(miner.clone()).await;
+                let search = engine.clone();
+                let found = spawn_blocking(move || search.mine(candidate, &cancel)).await;
+                miner.write().await.attempt = None;
+                if let Ok(Some(block)) = found {
+                    let height = block.header.as_ref().unwrap().block_index;
+                    println!(
+                        "Mined block {} at height {} ({:.0} H/s)",
+                        hex::encode(&block.block_hash),
+                        height,
+                        engine.hashrate()
+                    );
+                    let m = miner.read().await;
+                    m.event_bus
+                        .read()
+                        .await
+                        .publish(RustchainEvent::NewBlock(block))
+                        .await;
                 }
             }
+        })
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(attempt) = &self.attempt {
+            attempt.cancel.store(true, Ordering::Relaxed);
         }
     }
-    if counter >= difficulty {
-        return true;
-    } else {
-        return false;
+
+    pub fn mempool(&self) -> Arc<RwLock<Mempool>> {
+        self.mempool.clone()
+    }
+
+    // hashes per second of the proof of work search
+    pub fn hashrate(&self) -> f64 {
+        self.engine.hashrate()
+    }
+
+    // builds the candidate block on the current tip and registers it as the
+    // current attempt
+    async fn next_attempt(miner: Arc<RwLock<Miner>>) -> (Block, Arc<AtomicBool>) {
+        // the miner lock is not held while waiting on the chain, the chain may
+        // be publishing events the miner has to handle
+        let (blockchain, mempool, address) = {
+            let m = miner.read().await;
+            (m.blockchain.clone(), m.mempool.clone(), m.address.clone())
+        };
+        let (tip, utxo_set, params, bits) = {
+            let blockchain = blockchain.read().await;
+            (
+                blockchain.tip(),
+                blockchain.utxo_set(),
+                blockchain.params().clone(),
+                blockchain.next_bits(),
+            )
+        };
+        let (transactions, fees) = {
+            let mut mempool = mempool.write().await;
+            let utxo_set = utxo_set.read().unwrap();
+            select_transactions(&mut mempool, &utxo_set)
+        };
+        let height = tip.header.as_ref().unwrap().block_index + 1;
+        let reward = params.block_subsidy(height) as u64 + fees;
+        let coinbase = coinbase_transaction(address, height, reward.min(u32::MAX as u64) as u32);
+        let transactions = [vec![coinbase], transactions].concat();
+        let merkle_root = merkle_root(&transactions);
+        let candidate = next_block(&tip, transactions, merkle_root, bits);
+        let cancel = Arc::new(AtomicBool::new(false));
+        miner.write().await.attempt = Some(Attempt {
+            height: candidate.header.as_ref().unwrap().block_index,
+            cancel: cancel.clone(),
+        });
+        (candidate, cancel)
     }
 }
 
-// Calculate the Merkle root of the transactions
-fn calculate_merkle_root(transactions: &[Transaction]) -> Vec<u8> {
-    let mut hashes: Vec<Vec<u8>> = transactions.iter().map(|tx| tx.hash()).collect();
-    while hashes.len() > 1 {
-        if hashes.len() % 2 != 0 {
-            hashes.push(hashes.last().unwrap().clone());
-        }
-        let mut new_hashes: Vec<Vec<u8>> = vec![];
-        for i in (0..hashes.len()).step_by(2) {
-            let mut hasher = Sha256::new();
-            hasher.update(&hashes[i]);
-            hasher.update(&hashes[i + 1]);
-            new_hashes.push(hasher.finalize().to_vec());
+// Takes the best paying transactions of the mempool that fit in a block. The
+// mempool only holds transactions valid when they arrived, the ones the chain
+// has invalidated since then are dropped from it. The room they free is filled
+// once more, with transactions checked in turn but not replaced if they fail.
+fn select_transactions(mempool: &mut Mempool, utxo_set: &UtxoSet) -> (Vec<Transaction>, u64) {
+    // fee of every transaction checked, None when it's invalid
+    let mut checked = HashMap::new();
+    let (transactions, fees, dropped) = checked_template(mempool, utxo_set, &mut checked);
+    if !dropped {
+        return (transactions, fees);
+    }
+    let (transactions, fees, _) = checked_template(mempool, utxo_set, &mut checked);
+    (transactions, fees)
+}
+
+// the block template of the mempool without the invalid transactions, which
+// are dropped from it, and whether there were any
+fn checked_template(
+    mempool: &mut Mempool,
+    utxo_set: &UtxoSet,
+    checked: &mut HashMap<String, Option<u64>>,
+) -> (Vec<Transaction>, u64, bool) {
+    let (template, _) = mempool.block_template(MAX_BLOCK_BYTES);
+    let mut transactions = vec![];
+    let mut fees = 0;
+    let mut dropped = false;
+    for tx in template {
+        let tx_hash = hex::encode(tx.hash());
+        let fee = *checked
+            .entry(tx_hash.clone())
+            .or_insert_with(|| validate_transactions(std::slice::from_ref(&tx), utxo_set).ok());
+        match fee {
+            Some(fee) => {
+                fees += fee;
+                transactions.push(tx);
+            }
+            None => {
+                println!("Dropping pending transaction {}", tx_hash);
+                mempool.remove(&tx_hash);
+                dropped = true;
+            }
         }
-        hashes = new_hashes;
     }
-    hashes[0].clone()
+    (transactions, fees, dropped)
+}
+
+// single threaded search, for callers that mine a block now and then
+pub fn proof_of_work(block: Block, cancel: &AtomicBool) -> Option<Block> {
+    PowEngine::new(1).mine(block, cancel)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockchain::block::create_genesis_block;
+    use crate::blockchain::consensus::ConsensusParams;
+    use crate::blockchain::store::MemoryBlockStore;
+    use crate::blockchain::validation::tests::{
+        credit, mined_block, mined_block_for, new_wallet, spend,
+    };
+    use crate::blockchain::validation::validate_proof_of_work;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
 
     #[test]
-    fn test_leading_zeroes_difficulty_12() {
-        let hash = vec![0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00]; // Three leading zeroes
-        assert!(satisfies_difficulty(&hash, 12));
+    fn test_proof_of_work() {
+        let genesis = create_genesis_block();
+        let candidate = next_block(&genesis, vec![], [0; 32], 0x2000ffff);
+        let block = proof_of_work(candidate, &AtomicBool::new(false)).unwrap();
+        assert!(validate_proof_of_work(&block).is_ok());
     }
 
     #[test]
-    fn test_leading_zeroes_difficulty_48() {
-        let hash = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]; // Eight leading zeroes
-        assert!(satisfies_difficulty(&hash, 48));
+    fn test_proof_of_work_cancelled() {
+        let genesis = create_genesis_block();
+        // target of 1, never met
+        let candidate = next_block(&genesis, vec![], [0; 32], 0x01010000);
+        assert!(proof_of_work(candidate, &AtomicBool::new(true)).is_none());
     }
 
-    #[test]
-    fn test_leading_zeroes_difficulty_4() {
-        let hash = vec![0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]; // Eight leading zeroes
-        assert!(satisfies_difficulty(&hash, 4));
+    #[tokio::test]
+    async fn test_mines_pending_transactions() {
+        let event_bus = EventBus::new().await;
+        let params = ConsensusParams {
+            coinbase_maturity: 1,
+            ..ConsensusParams::default()
+        };
+        let store = Box::new(MemoryBlockStore::new());
+        let blockchain = Blockchain::with_params(event_bus.clone(), store, params).await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = mined_block_for(&create_genesis_block(), &bob.get_address(), vec![]);
+        blockchain
+            .write()
+            .await
+            .add_block(funding.clone())
+            .await
+            .unwrap();
+        // pays a fee of 10
+        let tx = spend(&bob, &funding.transactions[0], String::from("alice"), 40);
+
+        let miner = Miner::new(event_bus.clone(), blockchain.clone(), String::from("miner")).await;
+        let mut receiver = event_bus.write().await.subscribe().await;
+        event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::NewTransaction(tx.clone()))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        let handle = Miner::start(miner.clone()).await;
+
+        let connected = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(RustchainEvent::BlockConnected(block)) = receiver.recv().await {
+                    if block.transactions.contains(&tx) {
+                        return block;
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        miner.read().await.stop();
+        handle.await.unwrap();
+
+        assert_eq!(2, connected.header.unwrap().block_index);
+        // the coinbase collects the subsidy and the fee
+        let coinbase = &connected.transactions[0];
+        assert!(is_coinbase(coinbase));
+        assert_eq!("miner", coinbase.outputs[0].to_addr);
+        assert_eq!(60, coinbase.outputs[0].amount);
+        let utxo_set = blockchain.read().await.utxo_set();
+        assert_eq!(40, utxo_set.read().unwrap().balance("alice"));
+        sleep(Duration::from_millis(100)).await;
+        let mempool = miner.read().await.mempool();
+        assert!(mempool.read().await.is_empty());
     }
 
-    #[test]
-    fn test_leading_zeroes_difficulty_4_fails() {
-        let hash = vec![0b00010000, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]; // Eight leading zeroes
-        assert!(!satisfies_difficulty(&hash, 4));
+    #[tokio::test]
+    async fn test_competing_block_cancels_attempt() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let miner = Miner::new(event_bus.clone(), blockchain, String::from("miner")).await;
+        let cancel = Arc::new(AtomicBool::new(false));
+        miner.write().await.attempt = Some(Attempt {
+            height: 1,
+            cancel: cancel.clone(),
+        });
+        let competing = mined_block(&create_genesis_block(), vec![]);
+        event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::NewBlock(competing))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(cancel.load(Ordering::Relaxed));
     }
 
-    #[test]
-    fn test_leading_zeroes_difficulty_0() {
-        let hash = vec![0xa1, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]; // No leading zeroes
-        assert!(satisfies_difficulty(&hash, 0));
-    }
-
-    // #[test]
-    // fn test_proof_of_work() {
-    //     let difficulty = 15;
-    //     let timestamp = SystemTime::now()
-    //         .duration_since(UNIX_EPOCH)
-    //         .unwrap()
-    //         .as_secs();
-    //     let genesis = create_genesis_block();
-    //     let mut header = BlockHeader {
-    //         timestamp,
-    //         previous_hash: genesis.block_hash,
-    //         block_index: 1,
-    //         merkle_root: vec![],
-    //         difficulty,
-    //         nonce: 0,
-    //     };
-    //     let (hash, nonce) = proof_of_work(&mut header);
-    //     assert!(satisfies_difficulty(&hash, difficulty));
-    //     assert_eq!(nonce, header.nonce);
-    // }
-
-    // #[test]
-    // fn test_proof_of_work_2_blocks() {
-    //     let difficulty = 15;
-    //     let mut timestamp = SystemTime::now()
-    //         .duration_since(UNIX_EPOCH)
-    //         .unwrap()
-    //         .as_secs();
-    //     let genesis = create_genesis_block();
-    //     let mut header_1 = BlockHeader {
-    //         timestamp,
-    //         previous_hash: genesis.block_hash,
-    //         block_index: 1,
-    //         merkle_root: vec![],
-    //         difficulty,
-    //         nonce: 0,
-    //     };
-    //     let (hash_1, nonce_1) = proof_of_work(&mut header_1);
-    //     timestamp = SystemTime::now()
-    //         .duration_since(UNIX_EPOCH)
-    //         .unwrap()
-    //         .as_secs();
-
-    //     let mut header_2 = BlockHeader {
-    //         timestamp,
-    //         previous_hash: hash_1.to_owned(),
-    //         block_index: 2,
-    //         merkle_root: vec![],
-    //         difficulty,
-    //         nonce: 0,
-    //     };
-    //     let (hash_2, nonce_2) = proof_of_work(&mut header_2);
-    //     assert!(satisfies_difficulty(&hash_1, difficulty));
-    //     assert_eq!(nonce_1, header_1.nonce);
-    //     assert!(satisfies_difficulty(&hash_2, difficulty));
-    //     assert_eq!(nonce_2, header_2.nonce);
-    // }
+    #[tokio::test]
+    async fn test_block_that_does_not_connect_is_ignored() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let miner = Miner::new(event_bus.clone(), blockchain, String::from("miner")).await;
+        let cancel = Arc::new(AtomicBool::new(false));
+        miner.write().await.attempt = Some(Attempt {
+            height: 1,
+            cancel: cancel.clone(),
+        });
+        // its parent is unknown, so it's only held as an orphan
+        let block_1 = mined_block(&create_genesis_block(), vec![]);
+        let orphan = mined_block(&block_1, vec![]);
+        event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::NewBlock(orphan))
+            .await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(!cancel.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_select_transactions_drops_invalid() {
+        let event_bus = EventBus::new().await;
+        let bob = new_wallet(&event_bus).await;
+        let bob = bob.read().await;
+        let funding = credit(bob.get_address(), 10);
+        let other_funding = credit(bob.get_address(), 20);
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.apply_transaction(&funding);
+        let tx = spend(&bob, &funding, String::from("alice"), 7);
+        let mut mempool = Mempool::default();
+        mempool.add(tx.clone(), &utxo_set).unwrap();
+        assert_eq!(
+            (vec![tx.clone()], 3),
+            select_transactions(&mut mempool, &utxo_set)
+        );
+
+        // a funding output is gone, e.g. after a reorg, and the transaction
+        // spending it is dropped while the valid one stays
+        utxo_set.apply_transaction(&other_funding);
+        let other = spend(&bob, &other_funding, String::from("alice"), 10);
+        mempool.add(other, &utxo_set).unwrap();
+        let mut after_reorg = UtxoSet::new();
+        after_reorg.apply_transaction(&funding);
+        assert_eq!(
+            (vec![tx], 3),
+            select_transactions(&mut mempool, &after_reorg)
+        );
+        assert_eq!(1, mempool.len());
+        // and all of them at once
+        assert_eq!(
+            (vec![], 0),
+            select_transactions(&mut mempool, &UtxoSet::new())
+        );
+        assert!(mempool.is_empty());
+    }
 }
@@ -1 +1,3 @@
+pub mod mempool;
 pub mod miner;
+pub mod pow;
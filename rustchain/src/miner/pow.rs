@@ -0,0 +1,229 @@
+use crate::blockchain::target::Target;
+use crate::protos::{Block, BlockHeader};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// how many nonces a worker tries between checks of the cancel flag
+const NONCES_PER_CHECK: u64 = 1024;
+
+// Hashes a header for any nonce. Everything but the nonce is fixed for a
+// search, so the timestamp is hashed once and the bytes after the nonce,
+// merkle root included, are only serialized once.
+#[derive(Clone)]
+pub struct HeaderHasher {
+    // state after the timestamp, the field hashed before the nonce
+    prefix: Sha256,
+    suffix: Vec<u8>,
+}
+
+impl HeaderHasher {
+    // must hash the same fields in the same order as `BlockHeader::hash`
+    pub fn new(header: &BlockHeader) -> Self {
+        let mut prefix = Sha256::new();
+        prefix.update(header.timestamp.to_be_bytes());
+        let mut suffix = vec![];
+        suffix.extend(header.bits.to_be_bytes());
+        suffix.extend(&header.previous_hash);
+        suffix.extend(header.block_index.to_be_bytes());
+        suffix.extend(&header.merkle_root);
+        HeaderHasher { prefix, suffix }
+    }
+
+    pub fn hash(&self, nonce: u64) -> [u8; 32] {
+        let mut hasher = self.prefix.clone();
+        hasher.update(nonce.to_be_bytes());
+        hasher.update(&self.suffix);
+        hasher.finalize().into()
+    }
+}
+
+enum Search {
+    Found(u64),
+    Exhausted,
+    Cancelled,
+}
+
+// Parallel proof of work search. The nonce space is split in as many ranges as
+// worker threads. When every range is exhausted the timestamp is rolled
+// forward, which gives a whole new nonce space.
+#[derive(Debug)]
+pub struct PowEngine {
+    threads: usize,
+    // highest nonce tried before rolling the timestamp
+    max_nonce: u64,
+    hashes: AtomicU64,
+    // time spent searching, in microseconds
+    busy: AtomicU64,
+}
+
+impl Default for PowEngine {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        PowEngine::new(threads)
+    }
+}
+
+impl PowEngine {
+    pub fn new(threads: usize) -> Self {
+        PowEngine::with_max_nonce(threads, u64::MAX)
+    }
+
+    pub fn with_max_nonce(threads: usize, max_nonce: u64) -> Self {
+        PowEngine {
+            threads: threads.max(1),
+            max_nonce,
+            hashes: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // headers hashed since the engine was created
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    // hashes per second while searching
+    pub fn hashrate(&self) -> f64 {
+        let busy = self.busy.load(Ordering::Relaxed);
+        if busy == 0 {
+            return 0.0;
+        }
+        self.hashes() as f64 * 1_000_000.0 / busy as f64
+    }
+
+    // Looks for a nonce, and timestamp if needed, that makes the block hash
+    // meet its target. Gives up and returns None as soon as `cancel` is set.
+    pub fn mine(&self, mut block: Block, cancel: &AtomicBool) -> Option<Block> {
+        let mut header = block.header.take()?;
+        let target = Target::from_compact(header.bits)?;
+        let started = Instant::now();
+        let found = loop {
+            match self.search(&header, &target, cancel) {
+                Search::Found(nonce) => {
+                    header.nonce = nonce;
+                    break true;
+                }
+                Search::Exhausted => roll_timestamp(&mut header),
+                Search::Cancelled => break false,
+            }
+        };
+        let busy = started.elapsed().as_micros() as u64;
+        self.busy.fetch_add(busy, Ordering::Relaxed);
+        if !found {
+            return None;
+        }
+        block.block_hash = header.hash();
+        block.header = Some(header);
+        Some(block)
+    }
+
+    // searches the whole nonce space of the header with every worker
+    fn search(&self, header: &BlockHeader, target: &Target, cancel: &AtomicBool) -> Search {
+        let hasher = HeaderHasher::new(header);
+        let target = target.to_be_bytes();
+        let done = AtomicBool::new(false);
+        let solution = Mutex::new(None);
+        let space = self.max_nonce as u128 + 1;
+        thread::scope(|scope| {
+            for worker in 0..self.threads as u128 {
+                let start = (space * worker / self.threads as u128) as u64;
+                let end = space * (worker + 1) / self.threads as u128;
+                let (hasher, done, solution) = (&hasher, &done, &solution);
+                scope.spawn(move || {
+                    let mut nonce = start as u128;
+                    while nonce < end {
+                        if cancel.load(Ordering::Relaxed) || done.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let batch_end = (nonce + NONCES_PER_CHECK as u128).min(end);
+                        for candidate in nonce..batch_end {
+                            if hasher.hash(candidate as u64) <= target {
+                                self.hashes
+                                    .fetch_add((candidate - nonce + 1) as u64, Ordering::Relaxed);
+                                done.store(true, Ordering::Relaxed);
+                                *solution.lock().unwrap() = Some(candidate as u64);
+                                return;
+                            }
+                        }
+                        self.hashes
+                            .fetch_add((batch_end - nonce) as u64, Ordering::Relaxed);
+                        nonce = batch_end;
+                    }
+                });
+            }
+        });
+        match solution.into_inner().unwrap() {
+            Some(nonce) => Search::Found(nonce),
+            None if cancel.load(Ordering::Relaxed) => Search::Cancelled,
+            None => Search::Exhausted,
+        }
+    }
+}
+
+// moves the timestamp to now, or a second later if it is already ahead
+fn roll_timestamp(header: &mut BlockHeader) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    header.timestamp = now.max(header.timestamp + 1);
+    header.nonce = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::{create_genesis_block, next_block};
+    use crate::blockchain::validation::validate_proof_of_work;
+
+    #[test]
+    fn test_hasher_matches_header_hash() {
+        let genesis = create_genesis_block();
+        let mut header = next_block(&genesis, vec![], [7; 32], 0x1f7fffff)
+            .header
+            .unwrap();
+        let hasher = HeaderHasher::new(&header);
+        for nonce in [0, 1, 12345, u64::MAX] {
+            header.nonce = nonce;
+            assert_eq!(header.hash(), hasher.hash(nonce).to_vec());
+        }
+    }
+
+    #[test]
+    fn test_workers_find_valid_block() {
+        let genesis = create_genesis_block();
+        let engine = PowEngine::new(4);
+        let candidate = next_block(&genesis, vec![], [0; 32], 0x1f7fffff);
+        let block = engine.mine(candidate, &AtomicBool::new(false)).unwrap();
+        assert!(validate_proof_of_work(&block).is_ok());
+        assert!(engine.hashes() > 0);
+        assert!(engine.hashrate() > 0.0);
+    }
+
+    #[test]
+    fn test_rolls_timestamp_when_nonces_run_out() {
+        let genesis = create_genesis_block();
+        // 4 nonces per timestamp, when a hash meets the target 1 in 512 times
+        let engine = PowEngine::with_max_nonce(2, 3);
+        let candidate = next_block(&genesis, vec![], [0; 32], 0x1f7fffff);
+        let block = engine.mine(candidate, &AtomicBool::new(false)).unwrap();
+        assert!(validate_proof_of_work(&block).is_ok());
+        assert!(block.header.unwrap().nonce <= 3);
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let genesis = create_genesis_block();
+        let engine = PowEngine::new(4);
+        // target of 1, never met
+        let candidate = next_block(&genesis, vec![], [0; 32], 0x01010000);
+        assert!(engine.mine(candidate, &AtomicBool::new(true)).is_none());
+    }
+}
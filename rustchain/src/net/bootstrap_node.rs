@@ -1,98 +1,474 @@
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashSet,
+    error::Error,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    net::p2p::print_membership_table,
+    net::{
+        identity::{verify_registration, verify_request, ReplayCache},
+        middleware::{
+            ClientAddressInterceptor, PeerGuard, MAX_CONCURRENT_STREAMS, MAX_MESSAGE_SIZE,
+        },
+        p2p::print_membership_table,
+        peer_cache::PeerCache,
+        server_stubs::{refuse_admin, wrong_peer},
+        tls::TlsConfig,
+    },
     protos::{
         bootstrap_server::{Bootstrap, BootstrapServer},
-        Peer, PeerList, RegisterResponse,
+        AdminRequest, Challenge, KeepAliveRequest, KeepAliveResponse, Null, Peer, PeerList,
+        RegisterRequest, RegisterResponse,
     },
 };
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use tokio::spawn;
 use tokio::sync::RwLock;
-use tonic::transport::server::Router;
+use tokio::time::interval;
+use tonic::service::interceptor::InterceptedService;
 pub use tonic::{transport::Server, Request, Response, Status};
 
+// how long a registration challenge can be answered for
+const CHALLENGE_TTL: Duration = Duration::from_secs(30);
+// registrations not kept alive for this long are dropped
+pub const REGISTRATION_TTL: Duration = Duration::from_secs(90);
+// most peers handed to a registrant
+pub const SAMPLE_SIZE: usize = 16;
+// how often changes to the registry are written to its file
+const REGISTRY_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// actions signed in keepalive and admin requests
+pub const KEEPALIVE: &str = "keepalive";
+pub const LIST_REGISTRATIONS: &str = "list-registrations";
+pub const REMOVE_REGISTRATION: &str = "remove-registration";
+
+// expiry (u64 millis) + salt + hmac-sha256
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 8 + SALT_LEN + 32;
+
+// Registration challenges are stateless. A nonce carries when it expires and
+// a MAC binding it to the id and address it was issued for, under a key only
+// this node knows, so nothing is stored per caller: nobody can overwrite the
+// challenge of another node, nor grow the node's memory by asking for many.
+// Answering a challenge twice only registers the same address again.
+#[derive(Debug)]
+struct Challenger {
+    key: Vec<u8>,
+}
+
+impl Challenger {
+    fn new() -> Challenger {
+        let mut key = vec![0; 32];
+        rand_bytes(&mut key).expect("Failed to generate a challenge key");
+        Challenger { key }
+    }
+
+    fn issue(&self, peer: &Peer) -> Vec<u8> {
+        let expires = now_millis() + CHALLENGE_TTL.as_millis() as u64;
+        let mut salt = [0; SALT_LEN];
+        rand_bytes(&mut salt).expect("Failed to generate a challenge");
+        let mut nonce = expires.to_be_bytes().to_vec();
+        nonce.extend_from_slice(&salt);
+        nonce.extend(self.mac(peer, &nonce));
+        nonce
+    }
+
+    // whether `nonce` was issued to `peer` by this node and is still fresh
+    fn verify(&self, nonce: &[u8], peer: &Peer) -> bool {
+        if nonce.len() != NONCE_LEN {
+            return false;
+        }
+        let (issued, mac) = nonce.split_at(8 + SALT_LEN);
+        let expires = u64::from_be_bytes(issued[..8].try_into().unwrap());
+        memcmp::eq(&self.mac(peer, issued), mac) && now_millis() < expires
+    }
+
+    fn mac(&self, peer: &Peer, issued: &[u8]) -> Vec<u8> {
+        let key = PKey::hmac(&self.key).expect("Failed to load the challenge key");
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("Failed to sign");
+        for part in [
+            peer.id.as_bytes(),
+            peer.ip.as_bytes(),
+            &peer.port.to_be_bytes(),
+            issued,
+        ] {
+            // length prefixed, so fields can't run into each other
+            signer
+                .update(&(part.len() as u32).to_be_bytes())
+                .expect("Failed to sign");
+            signer.update(part).expect("Failed to sign");
+        }
+        signer.sign_to_vec().expect("Failed to sign")
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone)]
+struct Registration {
+    peer: Peer,
+    last_seen: Instant,
+}
+
+// Registered peers in registration order. Entries that aren't kept alive
+// within the ttl expire. Changes are written to the registry file, if there is
+// one, every now and then rather than on every registration, so a restarted
+// bootstrap node still knows the network.
+#[derive(Debug)]
+struct Registry {
+    entries: Vec<Registration>,
+    ttl: Duration,
+    file: Option<PeerCache>,
+    // changed since it was last written
+    dirty: bool,
+}
+
+impl Registry {
+    fn new(ttl: Duration) -> Registry {
+        Registry {
+            entries: vec![],
+            ttl,
+            file: None,
+            dirty: false,
+        }
+    }
+
+    // entries read back get a whole ttl to send their next keepalive
+    fn open(ttl: Duration, file: PeerCache) -> Registry {
+        let entries = file
+            .load()
+            .into_iter()
+            .map(|peer| Registration {
+                peer,
+                last_seen: Instant::now(),
+            })
+            .collect();
+        Registry {
+            entries,
+            ttl,
+            file: Some(file),
+            dirty: false,
+        }
+    }
+
+    fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // live peers, oldest registration first
+    fn peers(&self) -> Vec<Peer> {
+        self.entries
+            .iter()
+            .filter(|r| r.last_seen.elapsed() < self.ttl)
+            .map(|r| r.peer.clone())
+            .collect()
+    }
+
+    // a node registering again, from wherever it is now
+    fn register(&mut self, peer: Peer) {
+        self.entries.retain(|r| r.peer.id != peer.id);
+        self.entries.push(Registration {
+            peer,
+            last_seen: Instant::now(),
+        });
+        self.dirty = true;
+    }
+
+    // false when `peer` isn't registered, or expired already
+    fn keep_alive(&mut self, peer: &Peer) -> bool {
+        self.expire();
+        match self.entries.iter_mut().find(|r| r.peer == *peer) {
+            Some(registration) => {
+                registration.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|r| r.peer.id != id);
+        let removed = self.entries.len() != len;
+        self.dirty |= removed;
+        removed
+    }
+
+    fn expire(&mut self) {
+        let len = self.entries.len();
+        let ttl = self.ttl;
+        self.entries.retain(|r| r.last_seen.elapsed() < ttl);
+        self.dirty |= self.entries.len() != len;
+    }
+
+    // up to `size` live peers other than `exclude`, picked at random
+    fn sample(&self, exclude: &str, size: usize) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self
+            .peers()
+            .into_iter()
+            .filter(|p| p.id != exclude)
+            .collect();
+        // the first `size` steps of a Fisher-Yates shuffle
+        for i in 0..peers.len().min(size) {
+            let j = i + random_index(peers.len() - i);
+            peers.swap(i, j);
+        }
+        peers.truncate(size);
+        peers
+    }
+
+    // the file and what to write to it, if anything changed since the last
+    // time
+    fn take_changes(&mut self) -> Option<(PeerCache, Vec<Peer>)> {
+        if !self.dirty {
+            return None;
+        }
+        let file = self.file.clone()?;
+        self.dirty = false;
+        let peers = self.entries.iter().map(|r| r.peer.clone()).collect();
+        Some((file, peers))
+    }
+
+    // writes the changes out without holding the lock while doing so
+    async fn flush(registry: &RwLock<Registry>) {
+        let changes = registry.write().await.take_changes();
+        if let Some((file, peers)) = changes {
+            if let Err(e) = file.save(&peers) {
+                println!("Could not save registry {}: {}", file.path().display(), e);
+            }
+        }
+    }
+}
+
+// uniform in 0..bound, near enough for bounds this small
+fn random_index(bound: usize) -> usize {
+    let mut bytes = [0; 8];
+    rand_bytes(&mut bytes).expect("Failed to generate random bytes");
+    (u64::from_be_bytes(bytes) % bound as u64) as usize
+}
+
+// A directory of registered peers. Nodes pick their own ids, derived from
+// their keys, and prove they own them by signing a one-time challenge.
+// Registrations are then kept alive with signed keepalives, and admins can
+// list and remove them.
 #[derive(Debug)]
 pub struct BootstrapService {
-    peers: Arc<RwLock<Vec<Peer>>>,
-    id_counter: Arc<RwLock<u64>>,
+    registry: Arc<RwLock<Registry>>,
+    challenger: Arc<Challenger>,
+    // ids allowed to use the admin rpcs
+    admins: Arc<HashSet<String>>,
+    // signed keepalives and admin requests accepted lately
+    replays: Arc<Mutex<ReplayCache>>,
 }
 
 pub struct BootstrapNode {
     addr: SocketAddr,
-    router: Router,
-    peers: Arc<RwLock<Vec<Peer>>>,
-    id_counter: Arc<RwLock<u64>>,
+    registry: Registry,
+    admins: HashSet<String>,
+    tls: Option<TlsConfig>,
 }
 
 impl BootstrapNode {
     pub fn new(addr: SocketAddr) -> Self {
-        let peers = Arc::new(RwLock::new(Vec::new()));
-        let id_counter = Arc::new(RwLock::new(0));
-        let bootstrap_service = BootstrapService {
-            peers: Arc::clone(&peers),
-            id_counter: Arc::clone(&id_counter),
-        };
-        let mut server = Server::builder();
-        let router = server.add_service(BootstrapServer::new(bootstrap_service));
-        return Self {
+        Self {
             addr,
-            router,
-            peers,
-            id_counter,
-        };
+            registry: Registry::new(REGISTRATION_TTL),
+            admins: HashSet::new(),
+            tls: None,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.registry.ttl = ttl;
+        self
+    }
+
+    // keeps the registry in `path`, starting from whatever is saved there
+    pub fn with_registry(mut self, path: impl AsRef<Path>) -> Self {
+        self.registry = Registry::open(self.registry.ttl, PeerCache::new(path));
+        self
+    }
+
+    // lets node `id` list and remove registrations
+    pub fn with_admin(mut self, id: String) -> Self {
+        self.admins.insert(id);
+        self
+    }
+
+    // serves over mutual TLS, registrants need a certificate for their id
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
     }
 
     pub async fn serve(self) -> Result<(), Box<dyn Error + Send>> {
-        self.router
-            .serve(self.addr)
-            .await
-            .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        let registry = Arc::new(RwLock::new(self.registry));
+        let flushed = registry.clone();
+        let flusher = spawn(async move {
+            let mut ticks = interval(REGISTRY_FLUSH_INTERVAL);
+            loop {
+                ticks.tick().await;
+                Registry::flush(&flushed).await;
+            }
+        });
+        let bootstrap_service = BootstrapService {
+            registry: registry.clone(),
+            challenger: Arc::new(Challenger::new()),
+            admins: Arc::new(self.admins),
+            replays: ReplayCache::new().shared(),
+        };
+        // registrants are held to request budgets like peers are
+        let bootstrap_service = InterceptedService::new(
+            BootstrapServer::new(bootstrap_service).max_decoding_message_size(MAX_MESSAGE_SIZE),
+            ClientAddressInterceptor::new(PeerGuard::new().shared()),
+        );
+        let mut server = Server::builder().max_concurrent_streams(MAX_CONCURRENT_STREAMS);
+        if let Some(tls) = &self.tls {
+            server = server
+                .tls_config(tls.server())
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        }
+        let served = server.add_service(bootstrap_service).serve(self.addr).await;
+        flusher.abort();
+        Registry::flush(&registry).await;
+        served.map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
         Ok(())
     }
 
     pub async fn get_peer_list(&self) -> Vec<Peer> {
-        return self.peers.read().await.clone();
+        self.registry.peers()
     }
 }
 
 impl Default for BootstrapService {
     fn default() -> Self {
         Self {
-            peers: Arc::new(RwLock::new(vec![])),
-            id_counter: Arc::new(RwLock::new(0)),
+            registry: Arc::new(RwLock::new(Registry::new(REGISTRATION_TTL))),
+            challenger: Arc::new(Challenger::new()),
+            admins: Arc::new(HashSet::new()),
+            replays: ReplayCache::new().shared(),
         }
     }
 }
 
+fn ttl_millis(ttl: Duration) -> u64 {
+    ttl.as_millis() as u64
+}
+
 #[tonic::async_trait]
 impl Bootstrap for BootstrapService {
-    async fn register(&self, req: Request<Peer>) -> Result<Response<RegisterResponse>, Status> {
-        let mut peer = req.into_inner();
-        let id = {
-            let mut id_counter = self.id_counter.write().await;
-            *id_counter += 1;
-            *id_counter
-        };
-        peer.id = id.to_string();
-        self.peers.write().await.push(peer.clone());
-        let peers = PeerList::from(self.peers.read().await.clone());
+    async fn get_challenge(&self, req: Request<Peer>) -> Result<Response<Challenge>, Status> {
+        let nonce = self.challenger.issue(req.get_ref());
+        Ok(Response::new(Challenge { nonce }))
+    }
+
+    async fn register(
+        &self,
+        req: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        if let Some(status) = wrong_peer(&req, req.get_ref().peer.as_ref()) {
+            return Err(status);
+        }
+        let req = req.into_inner();
+        let peer = req.peer.unwrap_or_default();
+        if !self.challenger.verify(&req.nonce, &peer) {
+            return Err(Status::failed_precondition(
+                "no challenge issued to this id and address",
+            ));
+        }
+        verify_registration(&req.nonce, &peer, req.proof.as_ref())
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        let id = peer.id.clone();
+        let mut registry = self.registry.write().await;
+        registry.expire();
+        registry.register(peer.clone());
+        let peers = PeerList::from(registry.sample(&id, SAMPLE_SIZE));
         let resp = RegisterResponse {
             peers: Some(peers),
             peer: Some(peer.clone()),
+            ttl_millis: ttl_millis(registry.ttl()),
         };
         println!("New Peer registration with id: {}", id);
         // un-comment to visualize bootstrap membership table
-        // print_membership_table(String::from("BOOTSTRAP"), self.peers.read().await.clone());
+        // print_membership_table(String::from("BOOTSTRAP"), registry.peers());
         Ok(Response::new(resp))
     }
+
+    async fn keep_alive(
+        &self,
+        req: Request<KeepAliveRequest>,
+    ) -> Result<Response<KeepAliveResponse>, Status> {
+        if let Some(status) = wrong_peer(&req, req.get_ref().peer.as_ref()) {
+            return Err(status);
+        }
+        let req = req.into_inner();
+        let peer = req.peer.unwrap_or_default();
+        verify_request(
+            &peer.id,
+            KEEPALIVE,
+            &peer,
+            req.proof.as_ref(),
+            &self.replays,
+        )
+        .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        let mut registry = self.registry.write().await;
+        // expired, or registered somewhere else, the node has to register again
+        if !registry.keep_alive(&peer) {
+            return Err(Status::not_found("not registered"));
+        }
+        Ok(Response::new(KeepAliveResponse {
+            ttl_millis: ttl_millis(registry.ttl()),
+        }))
+    }
+
+    async fn list_registrations(
+        &self,
+        req: Request<AdminRequest>,
+    ) -> Result<Response<PeerList>, Status> {
+        if let Some(status) = refuse_admin(&self.admins, &req, LIST_REGISTRATIONS, &self.replays) {
+            return Err(status);
+        }
+        let mut registry = self.registry.write().await;
+        registry.expire();
+        Ok(Response::new(PeerList::from(registry.peers())))
+    }
+
+    async fn remove_registration(
+        &self,
+        req: Request<AdminRequest>,
+    ) -> Result<Response<Null>, Status> {
+        if let Some(status) = refuse_admin(&self.admins, &req, REMOVE_REGISTRATION, &self.replays) {
+            return Err(status);
+        }
+        let req = req.into_inner();
+        let id = req.peer.map(|p| p.id).unwrap_or_default();
+        if !self.registry.write().await.remove(&id) {
+            return Err(Status::not_found("not registered"));
+        }
+        println!("Removed registration of {}", id);
+        Ok(Response::new(Null {}))
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::time::Duration;
+    use std::{env::temp_dir, fs, time::Duration};
 
-    use crate::net::{client_stubs::PeerClient, networking::get_addr};
+    use crate::net::{client_stubs::PeerClient, identity::NodeIdentity, networking::get_addr};
     use tokio::{spawn, task::JoinHandle, time::sleep};
+    use tonic::Code;
 
     use super::*;
 
@@ -115,20 +491,22 @@ pub mod tests {
         let client_ip = "127.0.0.1";
         let client_port = 7989;
         let client_addr = get_addr(client_ip, client_port);
+        let identity = NodeIdentity::generate();
         let mut peer_client: PeerClient = PeerClient::new(SERVER_IP, SERVER_PORT).await.unwrap();
-        let resp = peer_client.register(client_addr).await.unwrap();
+        let resp = peer_client.register(&identity, client_addr).await.unwrap();
         sleep(Duration::from_millis(100)).await; // buffer time
         let peers: Vec<Peer> = resp.peers.unwrap().peers;
-        // bootstrap node and peer should have sames nodes
-        assert_eq!(peers.len(), 1);
+        // the first registrant is handed no one but is registered itself
+        assert!(peers.is_empty());
         assert_eq!(
             Peer {
-                id: 1.to_string(),
+                id: identity.id(),
                 ip: client_ip.to_string(),
                 port: client_port as u32,
             },
-            *peers.get(0).unwrap()
+            resp.peer.unwrap()
         );
+        assert_eq!(REGISTRATION_TTL.as_millis() as u64, resp.ttl_millis);
         serve_handle.abort();
         ()
     }
@@ -142,11 +520,12 @@ pub mod tests {
         let client_ip = "127.0.0.1";
         let client_port = 7989;
         let client_addr = get_addr(client_ip, client_port);
-        for _ in 0..10 {
+        let identities: Vec<NodeIdentity> = (0..10).map(|_| NodeIdentity::generate()).collect();
+        for identity in identities.iter() {
             let mut peer_client: PeerClient =
                 PeerClient::new(SERVER_IP, SERVER_PORT).await.unwrap();
             let peer_list = peer_client
-                .register(client_addr)
+                .register(identity, client_addr)
                 .await
                 .unwrap()
                 .peers
@@ -154,20 +533,230 @@ pub mod tests {
                 .peers;
             peer_lists.push(peer_list);
         }
-        // - checks that each peer is receiving i number of register clients. Meanning that the third
-        // registered client should receive a list of 3 registered clients.
+        // - checks that each peer is receiving the i clients registered before it. Meanning that
+        // the third registered client should receive a list of 2 registered clients.
         // - checks that the registered clients have the expected registered ids
-        for i in 0..peer_lists.len() {
-            let len = peer_lists.get(i).unwrap().len();
-            assert_eq!(i + 1, len);
-            for j in 0..len - 1 {
-                assert_eq!(
-                    (j + 1).to_string(),
-                    peer_lists.get(i).unwrap().get(j).unwrap().id
-                );
-            }
+        for (i, peer_list) in peer_lists.iter().enumerate() {
+            assert_eq!(i, peer_list.len());
+            let mut ids: Vec<String> = peer_list.iter().map(|p| p.id.clone()).collect();
+            let mut expected: Vec<String> = identities[..i].iter().map(|id| id.id()).collect();
+            ids.sort();
+            expected.sort();
+            assert_eq!(expected, ids);
         }
         server_handler.abort();
         ()
     }
+
+    #[tokio::test]
+    async fn test_rejects_unproven_registration() {
+        let service = BootstrapService::default();
+        let identity = NodeIdentity::generate();
+        let peer = Peer {
+            id: identity.id(),
+            ip: String::from("127.0.0.1"),
+            port: 7990,
+        };
+        let register = |nonce: &[u8], proof| {
+            Request::new(RegisterRequest {
+                peer: Some(peer.clone()),
+                proof: Some(proof),
+                nonce: nonce.to_vec(),
+            })
+        };
+        // without asking for a challenge first
+        let proof = identity.prove_registration(b"guess", &peer);
+        let status = service
+            .register(register(b"guess", proof))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::FailedPrecondition, status.code());
+        // with a key the id isn't derived from
+        let challenge = service.get_challenge(Request::new(peer.clone())).await;
+        let nonce = challenge.unwrap().into_inner().nonce;
+        let impostor = NodeIdentity::generate();
+        let proof = impostor.prove_registration(&nonce, &peer);
+        let status = service.register(register(&nonce, proof)).await.unwrap_err();
+        assert_eq!(Code::Unauthenticated, status.code());
+        // with a challenge issued for another address
+        let mut elsewhere = peer.clone();
+        elsewhere.port = 7991;
+        let challenge = service.get_challenge(Request::new(elsewhere)).await;
+        let other_nonce = challenge.unwrap().into_inner().nonce;
+        let proof = identity.prove_registration(&other_nonce, &peer);
+        let status = service
+            .register(register(&other_nonce, proof))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::FailedPrecondition, status.code());
+        assert!(service.registry.read().await.peers().is_empty());
+        // asking for another challenge doesn't void the one issued before
+        let proof = identity.prove_registration(&nonce, &peer);
+        service.register(register(&nonce, proof)).await.unwrap();
+        assert_eq!(vec![peer], service.registry.read().await.peers());
+    }
+
+    #[test]
+    fn test_challenges_are_bound_and_expire() {
+        let challenger = Challenger::new();
+        let peer = Peer {
+            id: NodeIdentity::generate().id(),
+            ip: String::from("127.0.0.1"),
+            port: 7990,
+        };
+        let nonce = challenger.issue(&peer);
+        assert!(challenger.verify(&nonce, &peer));
+        assert!(!Challenger::new().verify(&nonce, &peer));
+        let mut tampered = nonce.clone();
+        tampered[NONCE_LEN - 1] ^= 1;
+        assert!(!challenger.verify(&tampered, &peer));
+        // pushing back the expiry breaks the mac
+        let mut extended = nonce.clone();
+        extended[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(!challenger.verify(&extended, &peer));
+        // a valid mac over an expiry that passed
+        let mut expired = 0u64.to_be_bytes().to_vec();
+        expired.extend_from_slice(&nonce[8..8 + SALT_LEN]);
+        expired.extend(challenger.mac(&peer, &expired));
+        assert!(!challenger.verify(&expired, &peer));
+    }
+
+    fn peer(identity: &NodeIdentity, port: u32) -> Peer {
+        Peer {
+            id: identity.id(),
+            ip: String::from("127.0.0.1"),
+            port,
+        }
+    }
+
+    async fn register(service: &BootstrapService, identity: &NodeIdentity, peer: &Peer) {
+        let challenge = service.get_challenge(Request::new(peer.clone())).await;
+        let nonce = challenge.unwrap().into_inner().nonce;
+        let register = RegisterRequest {
+            peer: Some(peer.clone()),
+            proof: Some(identity.prove_registration(&nonce, peer)),
+            nonce,
+        };
+        service.register(Request::new(register)).await.unwrap();
+    }
+
+    fn keep_alive(identity: &NodeIdentity, peer: &Peer) -> Request<KeepAliveRequest> {
+        Request::new(KeepAliveRequest {
+            peer: Some(peer.clone()),
+            proof: Some(identity.prove_request(KEEPALIVE, peer)),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_registrations_expire_without_keepalives() {
+        let service = BootstrapService {
+            registry: Arc::new(RwLock::new(Registry::new(Duration::from_millis(200)))),
+            ..BootstrapService::default()
+        };
+        let (alive, silent) = (NodeIdentity::generate(), NodeIdentity::generate());
+        let (alive_peer, silent_peer) = (peer(&alive, 1), peer(&silent, 2));
+        register(&service, &alive, &alive_peer).await;
+        register(&service, &silent, &silent_peer).await;
+        for _ in 0..3 {
+            sleep(Duration::from_millis(100)).await;
+            let resp = service.keep_alive(keep_alive(&alive, &alive_peer)).await;
+            assert_eq!(200, resp.unwrap().into_inner().ttl_millis);
+        }
+        // a captured keepalive can't be sent again
+        let captured = keep_alive(&alive, &alive_peer).into_inner();
+        service
+            .keep_alive(Request::new(captured.clone()))
+            .await
+            .unwrap();
+        let replayed = service.keep_alive(Request::new(captured)).await;
+        assert_eq!(Code::Unauthenticated, replayed.unwrap_err().code());
+        assert_eq!(vec![alive_peer], service.registry.read().await.peers());
+        // the expired node has to register again
+        let status = service
+            .keep_alive(keep_alive(&silent, &silent_peer))
+            .await
+            .unwrap_err();
+        assert_eq!(Code::NotFound, status.code());
+        // and keepalives for someone else's registration aren't taken
+        let forged = service
+            .keep_alive(keep_alive(&silent, &peer(&alive, 1)))
+            .await;
+        assert_eq!(Code::Unauthenticated, forged.unwrap_err().code());
+    }
+
+    #[test]
+    fn test_sample_is_bounded_and_excludes_the_registrant() {
+        let mut registry = Registry::new(REGISTRATION_TTL);
+        let identities: Vec<NodeIdentity> = (0..40).map(|_| NodeIdentity::generate()).collect();
+        for (port, identity) in identities.iter().enumerate() {
+            registry.register(peer(identity, port as u32));
+        }
+        let own_id = identities[0].id();
+        let sample = registry.sample(&own_id, SAMPLE_SIZE);
+        assert_eq!(SAMPLE_SIZE, sample.len());
+        assert!(sample.iter().all(|p| p.id != own_id));
+        let ids: HashSet<String> = sample.iter().map(|p| p.id.clone()).collect();
+        assert_eq!(SAMPLE_SIZE, ids.len());
+        assert_eq!(39, registry.sample(&own_id, 100).len());
+    }
+
+    #[tokio::test]
+    async fn test_registry_survives_restarts() {
+        let path = temp_dir().join(format!(
+            "rustchain-{}.registry",
+            NodeIdentity::generate().id()
+        ));
+        let identity = NodeIdentity::generate();
+        let mut registry = Registry::open(REGISTRATION_TTL, PeerCache::new(&path));
+        registry.register(peer(&identity, 1));
+        registry.register(peer(&NodeIdentity::generate(), 2));
+        registry.remove(&identity.id());
+        // nothing is written until the changes are flushed
+        assert!(!path.exists());
+        let registry = RwLock::new(registry);
+        Registry::flush(&registry).await;
+        assert!(registry.write().await.take_changes().is_none());
+        let reloaded = Registry::open(REGISTRATION_TTL, PeerCache::new(&path));
+        assert_eq!(registry.read().await.peers(), reloaded.peers());
+        assert_eq!(1, reloaded.peers().len());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_rpcs() {
+        let admin = NodeIdentity::generate();
+        let service = BootstrapService {
+            admins: Arc::new(HashSet::from([admin.id()])),
+            ..BootstrapService::default()
+        };
+        let registrant = NodeIdentity::generate();
+        let registered = peer(&registrant, 1);
+        register(&service, &registrant, &registered).await;
+        let request = |signer: &NodeIdentity, action: &str, peer: Option<Peer>| {
+            let proof = signer.prove_request(action, &peer.clone().unwrap_or_default());
+            Request::new(AdminRequest {
+                admin_id: admin.id(),
+                peer,
+                proof: Some(proof),
+            })
+        };
+        let listed = service
+            .list_registrations(request(&admin, LIST_REGISTRATIONS, None))
+            .await;
+        assert_eq!(vec![registered.clone()], listed.unwrap().into_inner().peers);
+        // only the admin can sign admin requests
+        let forged = request(&registrant, REMOVE_REGISTRATION, Some(registered.clone()));
+        let status = service.remove_registration(forged).await.unwrap_err();
+        assert_eq!(Code::Unauthenticated, status.code());
+        let mut impostor = request(&registrant, LIST_REGISTRATIONS, None);
+        impostor.get_mut().admin_id = registrant.id();
+        let status = service.list_registrations(impostor).await.unwrap_err();
+        assert_eq!(Code::PermissionDenied, status.code());
+        // removing
+        let remove = || request(&admin, REMOVE_REGISTRATION, Some(registered.clone()));
+        service.remove_registration(remove()).await.unwrap();
+        assert!(service.registry.read().await.peers().is_empty());
+        let status = service.remove_registration(remove()).await.unwrap_err();
+        assert_eq!(Code::NotFound, status.code());
+    }
 }
@@ -0,0 +1,533 @@
+use crate::net::connections::ConnectionManager;
+use crate::net::kademlia::{peer_id, NodeId, ID_BITS, ID_BYTES};
+use crate::protos::{ChordHop, ChordNeighbours, Peer};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout};
+
+// successors each node keeps, so the ring survives that many failures in a row
+const SUCCESSOR_LIST_LEN: usize = 4;
+// how long a ring neighbour may take to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+// a lookup halves the distance to the key on every hop, more hops than bits
+// means the ring is broken
+const MAX_HOPS: usize = ID_BITS;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChordError {
+    Unreachable { peer: Peer, reason: String },
+    TooManyHops { key: NodeId },
+}
+
+impl fmt::Display for ChordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChordError::Unreachable { peer, reason } => {
+                write!(f, "Ring node {} is unreachable: {}", peer.id, reason)
+            }
+            ChordError::TooManyHops { key } => {
+                write!(f, "Lookup of key {} took more than {} hops", key, MAX_HOPS)
+            }
+        }
+    }
+}
+
+impl Error for ChordError {}
+
+// one step of a lookup
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hop {
+    // the node responsible for the key
+    Found(Peer),
+    // the closest node preceding the key this node knows, to be asked next
+    Next(Peer),
+}
+
+impl From<Hop> for ChordHop {
+    fn from(hop: Hop) -> Self {
+        match hop {
+            Hop::Found(peer) => ChordHop {
+                found: true,
+                peer: Some(peer),
+            },
+            Hop::Next(peer) => ChordHop {
+                found: false,
+                peer: Some(peer),
+            },
+        }
+    }
+}
+
+// the ring position of arbitrary data, such as a block hash or a tx id
+pub fn key(data: &[u8]) -> NodeId {
+    NodeId::from_key(data)
+}
+
+// id + 2^i on the ring, where finger i starts
+pub fn finger_start(id: &NodeId, i: usize) -> NodeId {
+    let mut bytes = id.as_bytes().to_vec();
+    let mut index = ID_BYTES - 1 - i / 8;
+    let mut carry = 1u16 << (i % 8);
+    loop {
+        let sum = bytes[index] as u16 + carry;
+        bytes[index] = sum as u8;
+        carry = sum >> 8;
+        if carry == 0 || index == 0 {
+            break;
+        }
+        index -= 1;
+    }
+    NodeId::from_bytes(&bytes).unwrap()
+}
+
+// whether `x` is in the open interval (a, b) going clockwise; (a, a) is the
+// whole ring but a
+pub fn between(x: &NodeId, a: &NodeId, b: &NodeId) -> bool {
+    if a < b {
+        a < x && x < b
+    } else {
+        a < x || x < b
+    }
+}
+
+// (a, b]
+fn between_right_inclusive(x: &NodeId, a: &NodeId, b: &NodeId) -> bool {
+    x == b || between(x, a, b)
+}
+
+// Chord ring membership of a node: every node is responsible for the keys
+// between its predecessor and itself, and knows its successors plus a finger
+// table of nodes at power of two distances, so lookups take O(log n) hops.
+// Ring neighbours are kept up to date by stabilize, notify, fix_fingers and
+// check_predecessor rounds.
+#[derive(Debug, Clone)]
+pub struct Chord {
+    node: Peer,
+    id: NodeId,
+    predecessor: Option<Peer>,
+    // the first one is the successor, the node itself while it's alone
+    successors: Vec<Peer>,
+    // finger i is the first node succeeding id + 2^i
+    fingers: Vec<Option<Peer>>,
+    // next finger fix_fingers refreshes
+    next_finger: usize,
+    connections: Arc<RwLock<ConnectionManager>>,
+}
+
+impl Chord {
+    // a ring of one, `node` is how other nodes reach this one
+    pub fn new(id: NodeId, node: Peer) -> Chord {
+        Chord {
+            node: node.clone(),
+            id,
+            predecessor: None,
+            successors: vec![node],
+            fingers: vec![None; ID_BITS],
+            next_finger: 0,
+            connections: ConnectionManager::new(None).shared(),
+        }
+    }
+
+    // ring requests share the node's connections to its peers
+    pub fn with_connections(mut self, connections: Arc<RwLock<ConnectionManager>>) -> Chord {
+        self.connections = connections;
+        self
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn successor(&self) -> Peer {
+        self.successors[0].clone()
+    }
+
+    pub fn predecessor(&self) -> Option<Peer> {
+        self.predecessor.clone()
+    }
+
+    pub fn neighbours(&self) -> ChordNeighbours {
+        ChordNeighbours {
+            predecessor: self.predecessor.clone(),
+            successors: self.successors.clone(),
+        }
+    }
+
+    // whether this node is the one responsible for `key`
+    pub fn owns(&self, key: &NodeId) -> bool {
+        match self.predecessor.as_ref().and_then(peer_id) {
+            Some(predecessor) => between_right_inclusive(key, &predecessor, &self.id),
+            None => self.successor().id == self.node.id,
+        }
+    }
+
+    pub fn next_hop(&self, key: &NodeId) -> Hop {
+        let successor = self.successor();
+        match peer_id(&successor) {
+            Some(successor_id) if !between_right_inclusive(key, &self.id, &successor_id) => {
+                Hop::Next(self.closest_preceding(key))
+            }
+            _ => Hop::Found(successor),
+        }
+    }
+
+    // the finger or successor closest to `key` without passing it
+    fn closest_preceding(&self, key: &NodeId) -> Peer {
+        let candidates = self.fingers.iter().rev().flatten();
+        for peer in candidates.chain(self.successors.iter().rev()) {
+            if let Some(id) = peer_id(peer) {
+                if between(&id, &self.id, key) {
+                    return peer.clone();
+                }
+            }
+        }
+        self.node.clone()
+    }
+
+    // `peer` thinks it might be this node's predecessor
+    pub fn notify(&mut self, peer: Peer) -> bool {
+        let id = match peer_id(&peer) {
+            Some(id) if id != self.id => id,
+            _ => return false,
+        };
+        let closer = match self.predecessor.as_ref().and_then(peer_id) {
+            Some(predecessor) => between(&id, &predecessor, &self.id),
+            None => true,
+        };
+        if closer {
+            self.predecessor = Some(peer);
+        }
+        closer
+    }
+
+    // drops a node that stopped answering from every table
+    fn forget(&mut self, peer: &Peer) {
+        if self.predecessor.as_ref() == Some(peer) {
+            self.predecessor = None;
+        }
+        for finger in self.fingers.iter_mut() {
+            if finger.as_ref() == Some(peer) {
+                *finger = None;
+            }
+        }
+        self.successors.retain(|s| s != peer);
+        if self.successors.is_empty() {
+            self.successors.push(self.node.clone());
+        }
+    }
+
+    // Looks the key up starting from this node's own tables, asking the next
+    // hop for its own until the responsible node is found. Nodes that don't
+    // answer are forgotten, so later lookups route around them.
+    pub async fn lookup(chord: Arc<RwLock<Chord>>, key: NodeId) -> Result<Peer, ChordError> {
+        let hop = chord.read().await.next_hop(&key);
+        Chord::resolve(chord, key, hop).await
+    }
+
+    async fn resolve(
+        chord: Arc<RwLock<Chord>>,
+        key: NodeId,
+        mut hop: Hop,
+    ) -> Result<Peer, ChordError> {
+        let (node, connections) = {
+            let lock = chord.read().await;
+            (lock.node.clone(), lock.connections.clone())
+        };
+        for _ in 0..MAX_HOPS {
+            let next = match hop {
+                Hop::Found(peer) => return Ok(peer),
+                Hop::Next(peer) if peer == node => return Ok(chord.read().await.successor()),
+                Hop::Next(peer) => peer,
+            };
+            hop = match find_successor(&next, &key, &connections).await {
+                Ok(hop) => hop,
+                Err(reason) => {
+                    chord.write().await.forget(&next);
+                    return Err(ChordError::Unreachable { peer: next, reason });
+                }
+            };
+        }
+        Err(ChordError::TooManyHops { key })
+    }
+
+    // joins the ring `known` is part of, the rest is set up by stabilize
+    pub async fn join(chord: Arc<RwLock<Chord>>, known: Peer) -> Result<(), ChordError> {
+        let id = chord.read().await.id;
+        let successor = Chord::resolve(chord.clone(), id, Hop::Next(known)).await?;
+        let mut lock = chord.write().await;
+        lock.predecessor = None;
+        lock.successors = vec![successor];
+        Ok(())
+    }
+
+    // Asks the successor for its predecessor, which becomes this node's
+    // successor if it joined in between, then lets the successor know about
+    // this node. The successor list is refreshed along the way.
+    pub async fn stabilize(chord: Arc<RwLock<Chord>>) {
+        let (node, id, successor, connections) = {
+            let lock = chord.read().await;
+            (
+                lock.node.clone(),
+                lock.id,
+                lock.successor(),
+                lock.connections.clone(),
+            )
+        };
+        let neighbours = if successor == node {
+            chord.read().await.neighbours()
+        } else {
+            match get_neighbours(&successor, &connections).await {
+                Ok(neighbours) => neighbours,
+                Err(e) => {
+                    println!("Ring successor {} is unreachable: {}", successor.id, e);
+                    chord.write().await.forget(&successor);
+                    return;
+                }
+            }
+        };
+        let mut successors = vec![successor.clone()];
+        successors.extend(neighbours.successors);
+        if let Some(candidate) = neighbours.predecessor {
+            let closer = match (peer_id(&candidate), peer_id(&successor)) {
+                (Some(candidate_id), Some(successor_id)) => {
+                    between(&candidate_id, &id, &successor_id)
+                }
+                _ => false,
+            };
+            if closer {
+                successors.insert(0, candidate);
+            }
+        }
+        successors.truncate(SUCCESSOR_LIST_LEN);
+        let successor = successors[0].clone();
+        chord.write().await.successors = successors;
+        if successor == node {
+            return;
+        }
+        if let Err(e) = notify(&successor, node, &connections).await {
+            println!("Could not notify ring successor {}: {}", successor.id, e);
+        }
+    }
+
+    // Refreshes the next finger. Fingers that start before the node found
+    // have the same node, so those are filled in without asking.
+    pub async fn fix_fingers(chord: Arc<RwLock<Chord>>) {
+        let (id, i) = {
+            let lock = chord.read().await;
+            (lock.id, lock.next_finger)
+        };
+        let found = match Chord::lookup(chord.clone(), finger_start(&id, i)).await {
+            Ok(found) => found,
+            Err(e) => {
+                println!("Could not fix finger {}: {}", i, e);
+                return;
+            }
+        };
+        let mut lock = chord.write().await;
+        let found_id = match peer_id(&found) {
+            Some(found_id) => found_id,
+            None => return,
+        };
+        let mut next = i;
+        loop {
+            lock.fingers[next] = Some(found.clone());
+            next = (next + 1) % ID_BITS;
+            let start = finger_start(&id, next);
+            if next == 0 || !between_right_inclusive(&start, &id, &found_id) {
+                break;
+            }
+        }
+        lock.next_finger = next;
+    }
+
+    // clears the predecessor once it stops answering, so a live node can
+    // take its place
+    pub async fn check_predecessor(chord: Arc<RwLock<Chord>>) {
+        let (predecessor, connections) = {
+            let lock = chord.read().await;
+            (lock.predecessor.clone(), lock.connections.clone())
+        };
+        let predecessor = match predecessor {
+            Some(predecessor) => predecessor,
+            None => return,
+        };
+        if get_neighbours(&predecessor, &connections).await.is_err() {
+            println!("Ring predecessor {} is unreachable", predecessor.id);
+            chord.write().await.forget(&predecessor);
+        }
+    }
+
+    // runs the maintenance rounds every `interval`
+    pub async fn maintain(chord: Arc<RwLock<Chord>>, interval: Duration) {
+        loop {
+            Chord::stabilize(chord.clone()).await;
+            Chord::fix_fingers(chord.clone()).await;
+            Chord::check_predecessor(chord.clone()).await;
+            sleep(interval).await;
+        }
+    }
+}
+
+async fn find_successor(
+    peer: &Peer,
+    key: &NodeId,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<Hop, String> {
+    let key = *key;
+    let request = ConnectionManager::call(connections, peer, |mut client| async move {
+        client.find_successor(&key).await
+    });
+    let hop = timeout(REQUEST_TIMEOUT, request)
+        .await
+        .unwrap_or_else(|_| Err(String::from("timed out")))?;
+    let next = hop.peer.ok_or_else(|| String::from("empty answer"))?;
+    Ok(if hop.found {
+        Hop::Found(next)
+    } else {
+        Hop::Next(next)
+    })
+}
+
+async fn get_neighbours(
+    peer: &Peer,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<ChordNeighbours, String> {
+    let request = ConnectionManager::call(connections, peer, |mut client| async move {
+        client.get_neighbours().await
+    });
+    timeout(REQUEST_TIMEOUT, request)
+        .await
+        .unwrap_or_else(|_| Err(String::from("timed out")))
+}
+
+async fn notify(
+    peer: &Peer,
+    node: Peer,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<(), String> {
+    let request = ConnectionManager::call(connections, peer, |mut client| async move {
+        client.notify(node).await
+    });
+    timeout(REQUEST_TIMEOUT, request)
+        .await
+        .unwrap_or_else(|_| Err(String::from("timed out")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_bus::event_bus::EventBus;
+    use crate::net::networking::get_addr;
+    use crate::net::server_stubs::PeerServer;
+    use tokio::spawn;
+
+    fn id(first_byte: u8) -> NodeId {
+        let mut id = [0; ID_BYTES];
+        id[0] = first_byte;
+        NodeId::from_bytes(&id).unwrap()
+    }
+
+    fn peer(id: &NodeId) -> Peer {
+        Peer {
+            id: id.to_hex(),
+            ip: String::from("127.0.0.1"),
+            port: 0,
+        }
+    }
+
+    #[test]
+    fn test_ring_arithmetic() {
+        let zero = NodeId::default();
+        assert_eq!(id(0x80), finger_start(&zero, ID_BITS - 1));
+        // wraps around the ring
+        let max = NodeId::from_bytes(&[0xff; ID_BYTES]).unwrap();
+        assert_eq!(zero, finger_start(&max, 0));
+        assert!(between(&id(2), &id(1), &id(3)));
+        assert!(!between(&id(3), &id(1), &id(3)));
+        assert!(between(&id(0), &id(0xf0), &id(3)));
+        assert!(between(&id(5), &id(1), &id(1)));
+        assert!(!between(&id(1), &id(1), &id(1)));
+    }
+
+    #[test]
+    fn test_next_hop_and_notify() {
+        let mut chord = Chord::new(id(0x10), peer(&id(0x10)));
+        // alone, it owns every key
+        assert_eq!(Hop::Found(peer(&id(0x10))), chord.next_hop(&id(0x90)));
+        assert!(chord.owns(&id(0x90)));
+        chord.successors = vec![peer(&id(0x20))];
+        chord.fingers[ID_BITS - 2] = Some(peer(&id(0x50)));
+        chord.fingers[ID_BITS - 1] = Some(peer(&id(0x90)));
+        assert_eq!(Hop::Found(peer(&id(0x20))), chord.next_hop(&id(0x18)));
+        assert_eq!(Hop::Next(peer(&id(0x50))), chord.next_hop(&id(0x80)));
+        assert_eq!(Hop::Next(peer(&id(0x90))), chord.next_hop(&id(0x05)));
+        // predecessors only get closer
+        assert!(chord.notify(peer(&id(0x80))));
+        assert!(!chord.notify(peer(&id(0x50))));
+        assert!(chord.notify(peer(&id(0x08))));
+        assert!(!chord.notify(peer(&id(0x10))));
+        assert!(chord.owns(&id(0x09)));
+        assert!(!chord.owns(&id(0x05)));
+    }
+
+    #[tokio::test]
+    async fn test_ring_forms_and_routes_keys() {
+        let event_bus = EventBus::new().await;
+        let mut nodes = vec![];
+        for port in 5017..5021 {
+            let addr = get_addr("127.0.0.1", port);
+            let node_id = key(&port.to_be_bytes());
+            let node = Peer {
+                id: node_id.to_hex(),
+                ip: addr.ip().to_string(),
+                port: port as u32,
+            };
+            let chord = Arc::new(RwLock::new(Chord::new(node_id, node)));
+            let server = PeerServer::new(event_bus.clone(), addr).with_chord(chord.clone());
+            spawn(async move { server.serve().await });
+            nodes.push(chord);
+        }
+        sleep(Duration::from_millis(100)).await;
+        let first = nodes[0].read().await.node.clone();
+        for chord in nodes.iter().skip(1) {
+            Chord::join(chord.clone(), first.clone()).await.unwrap();
+        }
+        for _ in 0..nodes.len() * 2 {
+            for chord in nodes.iter() {
+                Chord::stabilize(chord.clone()).await;
+            }
+        }
+        for _ in 0..8 {
+            for chord in nodes.iter() {
+                Chord::fix_fingers(chord.clone()).await;
+            }
+        }
+        // successors follow the ids around the ring
+        let mut ring = vec![];
+        for chord in nodes.iter() {
+            ring.push((chord.read().await.node.clone(), chord.clone()));
+        }
+        ring.sort_by_key(|(node, _)| peer_id(node).unwrap());
+        for (i, (node, chord)) in ring.iter().enumerate() {
+            let (next, next_chord) = &ring[(i + 1) % ring.len()];
+            assert_eq!(*next, chord.read().await.successor());
+            assert_eq!(Some(node.clone()), next_chord.read().await.predecessor());
+        }
+        // every node finds the same owner for a key
+        for data in [b"block".as_slice(), b"tx"] {
+            let key = key(data);
+            let owner = ring
+                .iter()
+                .map(|(node, _)| node)
+                .find(|node| peer_id(node).unwrap() >= key)
+                .unwrap_or(&ring[0].0);
+            for chord in nodes.iter() {
+                assert_eq!(*owner, Chord::lookup(chord.clone(), key).await.unwrap());
+            }
+        }
+    }
+}
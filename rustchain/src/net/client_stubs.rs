@@ -1,10 +1,23 @@
+use crate::net::bootstrap_node::{KEEPALIVE, LIST_REGISTRATIONS, REMOVE_REGISTRATION};
+use crate::net::identity::NodeIdentity;
+use crate::net::kademlia::NodeId;
+use crate::net::server_stubs::{ADD_PEER, LIST_BANS, REMOVE_PEER};
+use crate::net::tls::TlsConfig;
 use crate::protos::bootstrap_client::BootstrapClient;
+use crate::protos::chord_client::ChordClient;
 use crate::protos::p2p_client::P2pClient;
 use crate::protos::rustchain_client::RustchainClient;
-use crate::protos::{GetPeersRequest, Heartbeat, Null, Peer, PeerList, RegisterResponse};
-use crate::protos::{Response as ProtoResponse, Transaction};
+use crate::protos::{AddPeerResponse, RemovePeerResponse};
+use crate::protos::{AdminRequest, BanList, KeepAliveRequest, KeepAliveResponse};
+use crate::protos::{BlockList, BlocksRequest, FindNodeRequest, Inventory};
+use crate::protos::{ChordHop, ChordKey, ChordNeighbours};
+use crate::protos::{GetPeersRequest, Heartbeat, Null, Peer, PeerList, PeerRequest};
+use crate::protos::{HeaderList, HeadersRequest, MerkleProof, MerkleProofRequest};
+use crate::protos::{RegisterRequest, RegisterResponse};
+use crate::protos::{Response as ProtoResponse, Transaction, UtxoList, UtxoQuery};
+use crate::protos::{TransactionList, TransactionsRequest};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tonic::transport::Channel;
 use tonic::transport::Endpoint;
 use tonic::Request;
@@ -13,31 +26,115 @@ pub struct PeerClient {
     bootstrap: BootstrapClient<Channel>,
     rustchain: RustchainClient<Channel>,
     p2p: P2pClient<Channel>,
+    chord: ChordClient<Channel>,
 }
 
 impl PeerClient {
+    // a plaintext connection
     pub async fn new(to_ip: &str, to_port: u16) -> Result<PeerClient, Box<dyn Error>> {
-        let target = format!("https://{}:{}", to_ip, to_port);
-        let endpoint = Endpoint::from_shared(target)?;
-        let channel = endpoint.connect().await?;
+        let endpoint = Endpoint::from_shared(uri("http", to_ip, to_port))?;
+        Ok(PeerClient::from_channel(endpoint.connect().await?))
+    }
+
+    // Connects to `peer` over mutual TLS when `tls` is given, making sure it
+    // is the node its id says.
+    pub async fn connect(
+        peer: &Peer,
+        tls: Option<&TlsConfig>,
+    ) -> Result<PeerClient, Box<dyn Error>> {
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return PeerClient::new(&peer.ip, peer.port as u16).await,
+        };
+        let endpoint = Endpoint::from_shared(uri("https", &peer.ip, peer.port as u16))?
+            .tls_config(tls.client(&peer.id))?;
+        Ok(PeerClient::from_channel(endpoint.connect().await?))
+    }
+
+    // over a channel the ConnectionManager shares
+    pub(crate) fn from_channel(channel: Channel) -> PeerClient {
         let rustchain = RustchainClient::new(channel.clone());
         let bootstrap = BootstrapClient::new(channel.clone());
         let p2p = P2pClient::new(channel.clone());
-        Ok(PeerClient {
+        let chord = ChordClient::new(channel.clone());
+        PeerClient {
             bootstrap,
             rustchain,
             p2p,
-        })
+            chord,
+        }
     }
 
-    pub async fn register(&mut self, addr: SocketAddr) -> Result<RegisterResponse, Box<dyn Error>> {
-        // peer id will be assigned upon registration
-        let peer = Peer {
-            id: String::from(""),
-            ip: addr.clone().ip().to_string(),
-            port: addr.clone().port().into(),
+    // registers this node under the id derived from its key, proving it owns
+    // the key by signing the challenge the bootstrap node hands out
+    pub async fn register(
+        &mut self,
+        identity: &NodeIdentity,
+        addr: SocketAddr,
+    ) -> Result<RegisterResponse, Box<dyn Error>> {
+        let peer = own_peer(identity, addr);
+        let challenge = self
+            .bootstrap
+            .get_challenge(Request::new(peer.clone()))
+            .await?;
+        let nonce = challenge.into_inner().nonce;
+        let register = RegisterRequest {
+            proof: Some(identity.prove_registration(&nonce, &peer)),
+            peer: Some(peer),
+            nonce,
         };
-        let req = self.bootstrap.register(Request::new(peer)).await;
+        let req = self.bootstrap.register(Request::new(register)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // refreshes the registration of this node at `addr`, which expires
+    // otherwise. Fails with NotFound once it has expired already.
+    pub async fn keep_alive(
+        &mut self,
+        identity: &NodeIdentity,
+        addr: SocketAddr,
+    ) -> Result<KeepAliveResponse, Box<dyn Error>> {
+        let peer = own_peer(identity, addr);
+        let proof = identity.prove_request(KEEPALIVE, &peer);
+        let keep_alive = KeepAliveRequest {
+            peer: Some(peer),
+            proof: Some(proof),
+        };
+        let req = self.bootstrap.keep_alive(Request::new(keep_alive)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // the live registrations, for an admin of the bootstrap node
+    pub async fn list_registrations(
+        &mut self,
+        admin: &NodeIdentity,
+    ) -> Result<PeerList, Box<dyn Error>> {
+        let req = self
+            .bootstrap
+            .list_registrations(Request::new(admin_request(admin, LIST_REGISTRATIONS, None)))
+            .await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn remove_registration(
+        &mut self,
+        admin: &NodeIdentity,
+        peer: Peer,
+    ) -> Result<Null, Box<dyn Error>> {
+        let remove = admin_request(admin, REMOVE_REGISTRATION, Some(peer));
+        let req = self
+            .bootstrap
+            .remove_registration(Request::new(remove))
+            .await;
         match req {
             Ok(resp) => Ok(resp.into_inner()),
             Err(e) => Err(Box::new(e)),
@@ -45,7 +142,7 @@ impl PeerClient {
     }
 
     pub async fn send_transaction(
-        mut self,
+        &mut self,
         transaction: Transaction,
     ) -> Result<ProtoResponse, Box<dyn Error>> {
         let tx = Request::new(transaction);
@@ -56,7 +153,72 @@ impl PeerClient {
         }
     }
 
-    pub async fn get_peers(mut self) -> Result<PeerList, Box<dyn Error>> {
+    pub async fn get_utxos(&mut self, query: UtxoQuery) -> Result<UtxoList, Box<dyn Error>> {
+        let req = self.rustchain.get_utxos(Request::new(query)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_headers(
+        &mut self,
+        locator: Vec<Vec<u8>>,
+        max_headers: u32,
+    ) -> Result<HeaderList, Box<dyn Error>> {
+        let headers = HeadersRequest {
+            locator,
+            max_headers,
+        };
+        let req = self.rustchain.get_headers(Request::new(headers)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_blocks(&mut self, hashes: Vec<Vec<u8>>) -> Result<BlockList, Box<dyn Error>> {
+        let blocks = BlocksRequest { hashes };
+        let req = self.rustchain.get_blocks(Request::new(blocks)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_transactions(
+        &mut self,
+        hashes: Vec<Vec<u8>>,
+    ) -> Result<TransactionList, Box<dyn Error>> {
+        let txs = TransactionsRequest { hashes };
+        let req = self.rustchain.get_transactions(Request::new(txs)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn announce(&mut self, inventory: Inventory) -> Result<Null, Box<dyn Error>> {
+        let req = self.rustchain.announce(Request::new(inventory)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_merkle_proof(
+        &mut self,
+        tx_hash: String,
+    ) -> Result<MerkleProof, Box<dyn Error>> {
+        let proof = MerkleProofRequest { tx_hash };
+        let req = self.rustchain.get_merkle_proof(Request::new(proof)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_peers(&mut self) -> Result<PeerList, Box<dyn Error>> {
         let req = self
             .p2p
             .get_peers(Request::new(GetPeersRequest::default()))
@@ -67,21 +229,137 @@ impl PeerClient {
         }
     }
 
-    pub async fn send_heartbeat(
+    // announces this node at `addr` to the peer, which adds it to its
+    // routing table
+    pub async fn add_peer(
         &mut self,
-        peers: PeerList,
-        peer: Peer,
-        block_hashes: Vec<String>,
-    ) -> Result<Null, Box<dyn Error>> {
-        let heartbeat = Heartbeat {
-            peers: Some(peers),
-            peer: Some(peer),
-            block_hashes,
-        };
+        identity: &NodeIdentity,
+        addr: SocketAddr,
+    ) -> Result<AddPeerResponse, Box<dyn Error>> {
+        let add = peer_request(identity, ADD_PEER, addr);
+        let req = self.p2p.add_peer(Request::new(add)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // tells the peer this node is leaving, it is dropped from its routing
+    // table until it announces itself again
+    pub async fn remove_peer(
+        &mut self,
+        identity: &NodeIdentity,
+        addr: SocketAddr,
+    ) -> Result<RemovePeerResponse, Box<dyn Error>> {
+        let remove = peer_request(identity, REMOVE_PEER, addr);
+        let req = self.p2p.remove_peer(Request::new(remove)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // the clients the peer refuses for misbehaving, for an admin of the peer
+    pub async fn list_bans(&mut self, admin: &NodeIdentity) -> Result<BanList, Box<dyn Error>> {
+        let req = self
+            .p2p
+            .list_bans(Request::new(admin_request(admin, LIST_BANS, None)))
+            .await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // heartbeats are signed by the sender, see NodeIdentity::prove_heartbeat
+    pub async fn send_heartbeat(&mut self, heartbeat: Heartbeat) -> Result<Null, Box<dyn Error>> {
         let req = self.p2p.send_heartbeat(Request::new(heartbeat)).await;
         match req {
             Ok(resp) => Ok(resp.into_inner()),
             Err(e) => Err(Box::new(e)),
         }
     }
+
+    // the peers the remote node knows closest to `target`, `sender` is this node
+    pub async fn find_node(
+        &mut self,
+        target: &NodeId,
+        sender: Peer,
+    ) -> Result<PeerList, Box<dyn Error>> {
+        let find_node = FindNodeRequest {
+            target: target.as_bytes().to_vec(),
+            sender: Some(sender),
+        };
+        let req = self.p2p.find_node(Request::new(find_node)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn find_successor(&mut self, key: &NodeId) -> Result<ChordHop, Box<dyn Error>> {
+        let key = ChordKey {
+            key: key.as_bytes().to_vec(),
+        };
+        let req = self.chord.find_successor(Request::new(key)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    pub async fn get_neighbours(&mut self) -> Result<ChordNeighbours, Box<dyn Error>> {
+        let req = self
+            .chord
+            .get_neighbours(Request::new(Null::default()))
+            .await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // tells a ring node this one might be its predecessor
+    pub async fn notify(&mut self, node: Peer) -> Result<Null, Box<dyn Error>> {
+        let req = self.chord.notify(Request::new(node)).await;
+        match req {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+fn admin_request(admin: &NodeIdentity, action: &str, peer: Option<Peer>) -> AdminRequest {
+    let proof = admin.prove_request(action, &peer.clone().unwrap_or_default());
+    AdminRequest {
+        admin_id: admin.id(),
+        peer,
+        proof: Some(proof),
+    }
+}
+
+// this node's entry, as others list it
+fn own_peer(identity: &NodeIdentity, addr: SocketAddr) -> Peer {
+    Peer {
+        id: identity.id(),
+        ip: addr.ip().to_string(),
+        port: addr.port().into(),
+    }
+}
+
+fn peer_request(identity: &NodeIdentity, action: &str, addr: SocketAddr) -> PeerRequest {
+    let peer = own_peer(identity, addr);
+    let proof = identity.prove_request(action, &peer);
+    PeerRequest {
+        peer: Some(peer),
+        proof: Some(proof),
+    }
+}
+
+// ipv6 addresses are bracketed in uris, peers may advertise them bare
+pub(crate) fn uri(scheme: &str, ip: &str, port: u16) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("{}://[{}]:{}", scheme, ip, port),
+        _ => format!("{}://{}:{}", scheme, ip, port),
+    }
 }
@@ -0,0 +1,280 @@
+use crate::net::client_stubs::{uri, PeerClient};
+use crate::net::tls::TlsConfig;
+use crate::protos::Peer;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+// deadline of every call, and of connecting before the first one
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// a peer that can't be reached isn't dialed again for this long, doubling on
+// every failure in a row up to the max
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionError {
+    BadAddress(String),
+    // the peer failed lately and isn't dialed again until the backoff is over
+    BackingOff { peer: String, retry_in: Duration },
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::BadAddress(e) => write!(f, "Bad peer address: {}", e),
+            ConnectionError::BackingOff { peer, retry_in } => {
+                write!(
+                    f,
+                    "Peer {} is unreachable, retrying in {:?}",
+                    peer, retry_in
+                )
+            }
+        }
+    }
+}
+
+impl Error for ConnectionError {}
+
+// failures in a row and when the peer may be dialed again
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+// Node-wide connections to peers. Every peer gets one channel, shared by
+// heartbeats, gossip, sync and ring maintenance. Channels connect on their
+// first call, and a peer whose connection broke is dialed again only once
+// its backoff is over.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    // mutual TLS for every connection, plaintext without
+    tls: Option<TlsConfig>,
+    call_timeout: Duration,
+    channels: HashMap<String, Channel>,
+    backoffs: HashMap<String, Backoff>,
+}
+
+impl ConnectionManager {
+    pub fn new(tls: Option<TlsConfig>) -> ConnectionManager {
+        ConnectionManager {
+            tls,
+            call_timeout: CALL_TIMEOUT,
+            channels: HashMap::new(),
+            backoffs: HashMap::new(),
+        }
+    }
+
+    pub fn with_call_timeout(mut self, call_timeout: Duration) -> ConnectionManager {
+        self.call_timeout = call_timeout;
+        self
+    }
+
+    pub fn shared(self) -> Arc<RwLock<ConnectionManager>> {
+        Arc::new(RwLock::new(self))
+    }
+
+    // A client over the peer's channel, made on first use. It doesn't dial
+    // anything yet, the first call does.
+    pub fn client(&mut self, peer: &Peer) -> Result<PeerClient, ConnectionError> {
+        let key = peer_key(peer);
+        if let Some(backoff) = self.backoffs.get(&key) {
+            let now = Instant::now();
+            if backoff.retry_at > now {
+                return Err(ConnectionError::BackingOff {
+                    peer: key,
+                    retry_in: backoff.retry_at - now,
+                });
+            }
+        }
+        if let Some(channel) = self.channels.get(&key) {
+            return Ok(PeerClient::from_channel(channel.clone()));
+        }
+        let channel = self.channel(peer)?;
+        self.channels.insert(key, channel.clone());
+        Ok(PeerClient::from_channel(channel))
+    }
+
+    fn channel(&self, peer: &Peer) -> Result<Channel, ConnectionError> {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let mut endpoint = Endpoint::from_shared(uri(scheme, &peer.ip, peer.port as u16))
+            .map_err(|e| ConnectionError::BadAddress(e.to_string()))?
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(self.call_timeout);
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.client(&peer.id))
+                .map_err(|e| ConnectionError::BadAddress(e.to_string()))?;
+        }
+        Ok(endpoint.connect_lazy())
+    }
+
+    // the peer answered, it is dialed right away next time it fails
+    pub fn succeeded(&mut self, peer: &Peer) {
+        self.backoffs.remove(&peer_key(peer));
+    }
+
+    // the connection to the peer broke, it is dropped and dialed again once
+    // the backoff is over
+    pub fn failed(&mut self, peer: &Peer) {
+        let key = peer_key(peer);
+        self.channels.remove(&key);
+        let failures = self.backoffs.get(&key).map_or(0, |b| b.failures) + 1;
+        self.backoffs.insert(
+            key,
+            Backoff {
+                failures,
+                retry_at: Instant::now() + backoff(failures),
+            },
+        );
+    }
+
+    // drops everything about a peer that left or was evicted
+    pub fn forget(&mut self, peer: &Peer) {
+        let key = peer_key(peer);
+        self.channels.remove(&key);
+        self.backoffs.remove(&key);
+    }
+
+    // Makes `call` with a client for `peer`, keeping track of whether the
+    // peer could be reached. Errors the peer answered with don't count as
+    // failures of the connection.
+    pub async fn call<T, F, Fut>(
+        connections: &Arc<RwLock<ConnectionManager>>,
+        peer: &Peer,
+        call: F,
+    ) -> Result<T, String>
+    where
+        F: FnOnce(PeerClient) -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn Error>>>,
+    {
+        let client = connections
+            .write()
+            .await
+            .client(peer)
+            .map_err(|e| e.to_string())?;
+        let result = match call(client).await {
+            Ok(response) => Ok(response),
+            Err(e) => Err((is_unreachable(e.as_ref()), e.to_string())),
+        };
+        let mut lock = connections.write().await;
+        match result {
+            Ok(response) => {
+                lock.succeeded(peer);
+                Ok(response)
+            }
+            Err((unreachable, e)) => {
+                if unreachable {
+                    lock.failed(peer);
+                } else {
+                    lock.succeeded(peer);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+// the id is part of the key, with TLS the channel only talks to that node
+fn peer_key(peer: &Peer) -> String {
+    format!("{}@{}:{}", peer.id, peer.ip, peer.port)
+}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+// transport errors and deadlines, as opposed to errors the peer answered with
+fn is_unreachable(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<Status>() {
+        Some(status) => matches!(
+            status.code(),
+            Code::Unavailable | Code::Cancelled | Code::DeadlineExceeded
+        ),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_bus::event_bus::EventBus;
+    use crate::net::networking::get_addr;
+    use crate::net::p2p::{P2p, P2pConfig};
+    use tokio::time::sleep;
+
+    fn peer(port: u32) -> Peer {
+        Peer {
+            id: String::from("peer"),
+            ip: String::from("127.0.0.1"),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        assert_eq!(MIN_BACKOFF, backoff(1));
+        assert_eq!(MIN_BACKOFF * 2, backoff(2));
+        assert_eq!(MIN_BACKOFF * 8, backoff(4));
+        assert_eq!(MAX_BACKOFF, backoff(20));
+        assert_eq!(MAX_BACKOFF, backoff(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peers_back_off() {
+        // nothing listens there
+        let peer = peer(5037);
+        let connections = ConnectionManager::new(None).shared();
+        let get_peers = |mut client: PeerClient| async move { client.get_peers().await };
+        let e = ConnectionManager::call(&connections, &peer, get_peers)
+            .await
+            .unwrap_err();
+        assert!(!e.contains("retrying"));
+        // not dialed again until the backoff is over
+        let e = ConnectionManager::call(&connections, &peer, get_peers)
+            .await
+            .unwrap_err();
+        assert!(e.contains("retrying"));
+        assert!(connections.read().await.channels.is_empty());
+        sleep(MIN_BACKOFF).await;
+        let e = ConnectionManager::call(&connections, &peer, get_peers)
+            .await
+            .unwrap_err();
+        assert!(!e.contains("retrying"));
+        assert_eq!(
+            2,
+            connections.read().await.backoffs[&peer_key(&peer)].failures
+        );
+        connections.write().await.forget(&peer);
+        assert!(connections.write().await.client(&peer).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_channels_are_reused() {
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5038), Duration::from_secs(1));
+        let _p2p = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(100)).await;
+        let peer = peer(5038);
+        let connections = ConnectionManager::new(None).shared();
+        for _ in 0..3 {
+            let peers = ConnectionManager::call(&connections, &peer, |mut client| async move {
+                client.get_peers().await
+            })
+            .await;
+            assert!(peers.unwrap().peers.is_empty());
+        }
+        let lock = connections.read().await;
+        assert_eq!(1, lock.channels.len());
+        assert!(lock.backoffs.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// heartbeat rounds in a row a peer can't be reached before it is suspected
+const MISSED_BEFORE_SUSPECT: u32 = 2;
+// and before it is declared dead and evicted
+const MISSED_BEFORE_DEAD: u32 = 4;
+// heartbeat intervals a dead peer is kept out of membership lists for
+const QUARANTINE_HEARTBEATS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Alive,
+    // missed a few heartbeats, still in the membership table
+    Suspect,
+    Dead,
+}
+
+// Missed heartbeat counter per peer. A round where a heartbeat can't be
+// delivered to a peer counts as missed, hearing from the peer in any way
+// resets it. Dead peers are quarantined for a while: membership lists other
+// peers pass around don't bring them back, only a heartbeat of their own does.
+#[derive(Debug, Clone)]
+pub struct FailureDetector {
+    // by peer id
+    missed: HashMap<String, u32>,
+    // when each dead peer was evicted
+    dead: HashMap<String, Instant>,
+    quarantine: Duration,
+}
+
+impl FailureDetector {
+    pub fn new(heartbeat_interval: Duration) -> Self {
+        FailureDetector {
+            missed: HashMap::new(),
+            dead: HashMap::new(),
+            quarantine: heartbeat_interval * QUARANTINE_HEARTBEATS,
+        }
+    }
+
+    pub fn state(&self, id: &str) -> PeerState {
+        if self.dead.contains_key(id) {
+            return PeerState::Dead;
+        }
+        match self.missed.get(id) {
+            Some(missed) if *missed >= MISSED_BEFORE_SUSPECT => PeerState::Suspect,
+            _ => PeerState::Alive,
+        }
+    }
+
+    // the peer answered or sent a heartbeat, which also brings a dead peer back
+    pub fn heard_from(&mut self, id: &str) {
+        self.missed.remove(id);
+        self.dead.remove(id);
+    }
+
+    // counts a heartbeat the peer didn't get and returns its state
+    pub fn missed_heartbeat(&mut self, id: &str) -> PeerState {
+        let missed = self.missed.entry(id.to_string()).or_insert(0);
+        *missed += 1;
+        if *missed >= MISSED_BEFORE_DEAD {
+            self.missed.remove(id);
+            self.dead.insert(id.to_string(), Instant::now());
+        }
+        self.state(id)
+    }
+
+    // a peer that left on its own is kept out like a dead one
+    pub fn quarantine(&mut self, id: &str) {
+        self.missed.remove(id);
+        self.dead.insert(id.to_string(), Instant::now());
+    }
+
+    // whether a peer evicted lately should stay out of the membership table
+    pub fn is_quarantined(&mut self, id: &str) -> bool {
+        self.dead
+            .retain(|_, evicted| evicted.elapsed() < self.quarantine);
+        self.dead.contains_key(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missed_heartbeats() {
+        let mut detector = FailureDetector::new(Duration::from_secs(1));
+        assert_eq!(PeerState::Alive, detector.state("1"));
+        assert_eq!(PeerState::Alive, detector.missed_heartbeat("1"));
+        assert_eq!(PeerState::Suspect, detector.missed_heartbeat("1"));
+        // hearing from it clears the suspicion
+        detector.heard_from("1");
+        assert_eq!(PeerState::Alive, detector.state("1"));
+        for _ in 1..MISSED_BEFORE_DEAD {
+            detector.missed_heartbeat("1");
+        }
+        assert_eq!(PeerState::Dead, detector.missed_heartbeat("1"));
+        assert_eq!(PeerState::Alive, detector.state("2"));
+    }
+
+    #[test]
+    fn test_dead_peers_come_back() {
+        let mut detector = FailureDetector::new(Duration::from_secs(1));
+        for _ in 0..MISSED_BEFORE_DEAD {
+            detector.missed_heartbeat("1");
+        }
+        assert!(detector.is_quarantined("1"));
+        // a heartbeat of its own brings a peer back right away
+        detector.heard_from("1");
+        assert_eq!(PeerState::Alive, detector.state("1"));
+        assert!(!detector.is_quarantined("1"));
+        // others once the quarantine is over
+        let mut detector = FailureDetector::new(Duration::from_millis(1));
+        for _ in 0..MISSED_BEFORE_DEAD {
+            detector.missed_heartbeat("2");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!detector.is_quarantined("2"));
+    }
+}
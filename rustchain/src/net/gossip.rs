@@ -0,0 +1,646 @@
+use crate::event_bus::event_bus::EventBus;
+use crate::event_bus::events::RustchainEvent;
+use crate::net::connections::ConnectionManager;
+use crate::net::middleware::{Client, Misbehaviour, PeerGuard};
+use crate::net::reputation::{Interaction, Reputation};
+use crate::protos::inventory_item::Kind;
+use crate::protos::{Block, Inventory, InventoryItem, Peer, Transaction};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::RwLock;
+use tokio::time::timeout;
+
+// hashes remembered as seen by this node
+const SEEN_CACHE_SIZE: usize = 10_000;
+// hashes remembered as known by each peer
+const KNOWN_PER_PEER: usize = 1_000;
+// relayed transactions kept around for peers fetching them
+const RELAYED_TRANSACTIONS: usize = 1_000;
+// how long a peer gets to answer a fetch
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+// peers an item is announced to, the most trusted ones
+const MAX_FANOUT: usize = 8;
+// fetched blocks remembered with who relayed them, until validated
+const RELAYERS: usize = 1_000;
+
+// Set of the last hashes inserted, the oldest are forgotten past `capacity`.
+#[derive(Debug, Clone, Default)]
+pub struct SeenCache {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity,
+            ..SeenCache::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    // returns whether the hash wasn't in the cache yet
+    pub fn insert(&mut self, hash: String) -> bool {
+        if !self.hashes.insert(hash.clone()) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn remove(&mut self, hash: &str) {
+        if self.hashes.remove(hash) {
+            self.order.retain(|seen| seen != hash);
+        }
+    }
+}
+
+// Relays blocks and transactions once this node validated them. Peers are
+// sent an inventory of hashes and fetch what they don't have yet from the
+// announcer, so every item crosses each link about once. Items are announced
+// once, to the most trusted peers not known to have them already, and peers
+// that relayed a block are trusted more or less once it was validated. Those
+// whose block was rejected are reported to the guard as well.
+#[derive(Debug)]
+pub struct Gossip {
+    event_bus: Arc<RwLock<EventBus>>,
+    // where peers fetch what this node announces
+    addr: SocketAddr,
+    // membership table of the node
+    peers: Arc<RwLock<Vec<Peer>>>,
+    // hashes fetched, being fetched or announced by this node
+    seen: SeenCache,
+    announced: SeenCache,
+    // hashes each member announced or was announced, by peer address
+    known: HashMap<String, SeenCache>,
+    // transactions announced lately, until peers have fetched them
+    relayed: HashMap<String, Transaction>,
+    relayed_order: VecDeque<String>,
+    // id and client of the peer each fetched or pushed block came from
+    relayers: HashMap<String, (String, Client)>,
+    relayers_order: VecDeque<String>,
+    connections: Arc<RwLock<ConnectionManager>>,
+    reputation: Arc<RwLock<Reputation>>,
+    guard: Arc<Mutex<PeerGuard>>,
+}
+
+impl Gossip {
+    pub async fn new(
+        event_bus: Arc<RwLock<EventBus>>,
+        addr: SocketAddr,
+        peers: Arc<RwLock<Vec<Peer>>>,
+    ) -> Arc<RwLock<Gossip>> {
+        let gossip = Arc::new(RwLock::new(Gossip {
+            event_bus: event_bus.clone(),
+            addr,
+            peers,
+            seen: SeenCache::new(SEEN_CACHE_SIZE),
+            announced: SeenCache::new(SEEN_CACHE_SIZE),
+            known: HashMap::new(),
+            relayed: HashMap::new(),
+            relayed_order: VecDeque::new(),
+            relayers: HashMap::new(),
+            relayers_order: VecDeque::new(),
+            connections: ConnectionManager::new(None).shared(),
+            reputation: Reputation::new(String::new()).shared(),
+            guard: PeerGuard::new().shared(),
+        }));
+        let event_receiver = event_bus.write().await.subscribe().await;
+        let gossip_clone = gossip.clone();
+        spawn(async move { Gossip::listen_for_events(gossip_clone, event_receiver).await });
+        gossip
+    }
+
+    // announcements and fetches share the node's connections to its peers
+    pub fn use_connections(&mut self, connections: Arc<RwLock<ConnectionManager>>) {
+        self.connections = connections;
+    }
+
+    // fan-out targets are picked by trust, and relayers are judged by their
+    // blocks
+    pub fn use_reputation(&mut self, reputation: Arc<RwLock<Reputation>>) {
+        self.reputation = reputation;
+    }
+
+    // relayers of rejected blocks are reported to the guard of the node's
+    // server
+    pub fn use_guard(&mut self, guard: Arc<Mutex<PeerGuard>>) {
+        self.guard = guard;
+    }
+
+    // a block pushed to this node, judged like a fetched one once validated
+    pub fn pushed_by(&mut self, block: &Block, client: Client) {
+        let id = client.node.clone().unwrap_or_default();
+        self.remember_relayer(hex::encode(&block.block_hash), id, client);
+    }
+
+    // a transaction relayed lately, for peers fetching an announcement
+    pub fn relayed_transaction(&self, tx_hash: &str) -> Option<Transaction> {
+        self.relayed.get(tx_hash).cloned()
+    }
+
+    async fn listen_for_events(
+        gossip: Arc<RwLock<Gossip>>,
+        mut event_receiver: Receiver<RustchainEvent>,
+    ) {
+        while let Some(event) = event_receiver.recv().await {
+            match event {
+                RustchainEvent::BlockConnected(block) => {
+                    gossip
+                        .write()
+                        .await
+                        .judge_relayer(&block, Interaction::ValidBlock)
+                        .await;
+                    let item = item(Kind::Block, block.block_hash);
+                    Gossip::announce(gossip.clone(), item).await;
+                }
+                RustchainEvent::BlockRejected(block) => {
+                    gossip
+                        .write()
+                        .await
+                        .judge_relayer(&block, Interaction::InvalidBlock)
+                        .await;
+                }
+                RustchainEvent::TransactionAccepted(tx) => {
+                    let item = item(Kind::Transaction, tx.hash());
+                    gossip.write().await.remember_transaction(tx);
+                    Gossip::announce(gossip.clone(), item).await;
+                }
+                RustchainEvent::NewInventory(inventory, client) => {
+                    Gossip::on_inventory(gossip.clone(), inventory, client).await;
+                }
+                RustchainEvent::PeerRemoved(peer) => {
+                    gossip.write().await.known.remove(&peer_key(&peer));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn remember_transaction(&mut self, tx: Transaction) {
+        let tx_hash = hex::encode(tx.hash());
+        if self.relayed.insert(tx_hash.clone(), tx).is_some() {
+            return;
+        }
+        self.relayed_order.push_back(tx_hash);
+        if self.relayed_order.len() > RELAYED_TRANSACTIONS {
+            let oldest = self.relayed_order.pop_front().unwrap();
+            self.relayed.remove(&oldest);
+        }
+    }
+
+    fn remember_relayer(&mut self, block_hash: String, peer_id: String, client: Client) {
+        if self
+            .relayers
+            .insert(block_hash.clone(), (peer_id, client))
+            .is_some()
+        {
+            return;
+        }
+        self.relayers_order.push_back(block_hash);
+        if self.relayers_order.len() > RELAYERS {
+            let oldest = self.relayers_order.pop_front().unwrap();
+            self.relayers.remove(&oldest);
+        }
+    }
+
+    // the peer that relayed the block, if this node fetched it or had it
+    // pushed, is trusted according to whether it was valid
+    async fn judge_relayer(&mut self, block: &Block, interaction: Interaction) {
+        let hash = hex::encode(&block.block_hash);
+        if let Some((peer_id, client)) = self.relayers.remove(&hash) {
+            self.relayers_order.retain(|relayed| relayed != &hash);
+            self.reputation.write().await.record(&peer_id, interaction);
+            if interaction == Interaction::InvalidBlock {
+                self.guard
+                    .lock()
+                    .unwrap()
+                    .report(&client, Misbehaviour::InvalidBlock);
+            }
+        }
+    }
+
+    fn known_by(&mut self, peer: &Peer) -> &mut SeenCache {
+        self.known
+            .entry(peer_key(peer))
+            .or_insert_with(|| SeenCache::new(KNOWN_PER_PEER))
+    }
+
+    // the most trusted peers that don't know the item yet, which from now on do
+    async fn announce_targets(&mut self, hash: &str) -> Vec<Peer> {
+        if !self.announced.insert(hash.to_string()) {
+            return vec![];
+        }
+        self.seen.insert(hash.to_string());
+        let self_key = self.addr.to_string();
+        let peers = self.peers.read().await.clone();
+        // members that left without notice aren't kept track of any longer
+        let members: HashSet<String> = peers.iter().map(peer_key).collect();
+        self.known.retain(|key, _| members.contains(key));
+        let mut targets: Vec<Peer> = peers
+            .into_iter()
+            .filter(|peer| peer_key(peer) != self_key)
+            .filter(|peer| !self.known_by(peer).contains(hash))
+            .collect();
+        self.reputation.read().await.rank(&mut targets);
+        targets.truncate(MAX_FANOUT);
+        for peer in &targets {
+            self.known_by(peer).insert(hash.to_string());
+        }
+        targets
+    }
+
+    async fn announce(gossip: Arc<RwLock<Gossip>>, item: InventoryItem) {
+        let hash = hex::encode(&item.hash);
+        let (targets, addr, connections) = {
+            let mut g = gossip.write().await;
+            (
+                g.announce_targets(&hash).await,
+                g.addr,
+                g.connections.clone(),
+            )
+        };
+        let inventory = Inventory {
+            items: vec![item],
+            peer: Some(Peer {
+                id: String::from(""),
+                ip: addr.ip().to_string(),
+                port: addr.port() as u32,
+            }),
+        };
+        for peer in targets {
+            let (inventory, connections) = (inventory.clone(), connections.clone());
+            spawn(async move {
+                let announced =
+                    ConnectionManager::call(&connections, &peer, |mut client| async move {
+                        client.announce(inventory).await
+                    })
+                    .await;
+                if let Err(e) = announced {
+                    println!("Announcement to {}:{} failed: {}", peer.ip, peer.port, e);
+                }
+            });
+        }
+    }
+
+    // The peer behind an announcement, by the connection it came over rather
+    // than the address it claims: the member certified with the client's id,
+    // or else the member at the client's ip and the advertised port. Others
+    // are fetched from at that ip and port, credited only if certified.
+    async fn announcer(&self, inventory: &Inventory, client: &Client) -> Option<Peer> {
+        let ip = client.ip?;
+        let port = inventory.peer.as_ref().map_or(0, |peer| peer.port);
+        let at_ip = |member: &&Peer| {
+            member.ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical()) == Some(ip)
+        };
+        let peers = self.peers.read().await;
+        let member = match &client.node {
+            Some(id) => peers.iter().find(|member| &member.id == id),
+            None => peers
+                .iter()
+                .filter(at_ip)
+                .find(|member| member.port == port),
+        };
+        member.cloned().or_else(|| {
+            (port != 0).then(|| Peer {
+                id: client.node.clone().unwrap_or_default(),
+                ip: ip.to_string(),
+                port,
+            })
+        })
+    }
+
+    // fetches the announced items this node hasn't seen from the announcer
+    async fn on_inventory(gossip: Arc<RwLock<Gossip>>, inventory: Inventory, client: Client) {
+        let peer = match gossip.read().await.announcer(&inventory, &client).await {
+            Some(peer) => peer,
+            None => return,
+        };
+        let mut wanted = vec![];
+        let connections = {
+            let mut g = gossip.write().await;
+            // only members are announced to, so only their items are tracked,
+            // or anyone could make up peers to fill the map
+            let member = g.peers.read().await.iter().any(|member| member == &peer);
+            for item in inventory.items {
+                let hash = hex::encode(&item.hash);
+                if member {
+                    g.known_by(&peer).insert(hash.clone());
+                }
+                if g.seen.insert(hash) {
+                    wanted.push(item);
+                }
+            }
+            g.connections.clone()
+        };
+        if wanted.is_empty() {
+            return;
+        }
+        spawn(async move {
+            let hashes: Vec<String> = wanted.iter().map(|item| hex::encode(&item.hash)).collect();
+            let fetched = timeout(FETCH_TIMEOUT, fetch(&peer, wanted, &connections)).await;
+            let events = match fetched {
+                Ok(Ok(events)) => events,
+                Ok(Err(e)) => {
+                    println!("Fetching from {}:{} failed: {}", peer.ip, peer.port, e);
+                    vec![]
+                }
+                Err(_) => {
+                    println!("Fetching from {}:{} timed out", peer.ip, peer.port);
+                    vec![]
+                }
+            };
+            let mut g = gossip.write().await;
+            // what didn't arrive can be fetched from the next announcer
+            let received: HashSet<String> = events.iter().map(event_hash).collect();
+            for hash in hashes.iter().filter(|hash| !received.contains(*hash)) {
+                g.seen.remove(hash);
+            }
+            for event in &events {
+                if let RustchainEvent::NewBlock(block) = event {
+                    let hash = hex::encode(&block.block_hash);
+                    g.remember_relayer(hash, peer.id.clone(), client.clone());
+                }
+            }
+            let event_bus = g.event_bus.clone();
+            drop(g);
+            for event in events {
+                event_bus.read().await.publish(event).await;
+            }
+        });
+    }
+}
+
+fn item(kind: Kind, hash: Vec<u8>) -> InventoryItem {
+    InventoryItem {
+        kind: kind as i32,
+        hash,
+    }
+}
+
+fn peer_key(peer: &Peer) -> String {
+    format!("{}:{}", peer.ip, peer.port)
+}
+
+fn event_hash(event: &RustchainEvent) -> String {
+    match event {
+        RustchainEvent::NewBlock(block) => hex::encode(&block.block_hash),
+        RustchainEvent::NewTransaction(tx) => hex::encode(tx.hash()),
+        _ => String::new(),
+    }
+}
+
+// asks the peer for the items, which come back as the events announcing them
+// locally, for the blockchain and the miner to validate
+async fn fetch(
+    peer: &Peer,
+    items: Vec<InventoryItem>,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<Vec<RustchainEvent>, String> {
+    ConnectionManager::call(connections, peer, |mut client| async move {
+        let (blocks, txs): (Vec<InventoryItem>, Vec<InventoryItem>) = items
+            .into_iter()
+            .partition(|item| item.kind() == Kind::Block);
+        let mut events = vec![];
+        if !blocks.is_empty() {
+            let hashes: Vec<Vec<u8>> = blocks.into_iter().map(|item| item.hash).collect();
+            let blocks = client.get_blocks(hashes.clone()).await?.blocks;
+            events.extend(
+                blocks
+                    .into_iter()
+                    .filter(|block| is_block_asked_for(block, &hashes))
+                    .map(RustchainEvent::NewBlock),
+            );
+        }
+        if !txs.is_empty() {
+            let hashes: Vec<Vec<u8>> = txs.into_iter().map(|item| item.hash).collect();
+            let txs = client.get_transactions(hashes.clone()).await?.transactions;
+            events.extend(
+                txs.into_iter()
+                    .filter(|tx| hashes.contains(&tx.hash()))
+                    .map(RustchainEvent::NewTransaction),
+            );
+        }
+        Ok::<_, Box<dyn Error>>(events)
+    })
+    .await
+}
+
+// the hash a block claims has to be the one of its header
+fn is_block_asked_for(block: &Block, hashes: &[Vec<u8>]) -> bool {
+    let hash = block.header.as_ref().map(|header| header.hash());
+    hash.as_ref() == Some(&block.block_hash) && hashes.contains(&block.block_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::middleware::Offender;
+    use crate::net::networking::get_addr;
+
+    fn peer(port: u32) -> Peer {
+        Peer {
+            id: port.to_string(),
+            ip: String::from("127.0.0.1"),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_seen_cache_forgets_oldest() {
+        let mut cache = SeenCache::new(2);
+        assert!(cache.insert(String::from("a")));
+        assert!(!cache.insert(String::from("a")));
+        assert!(cache.insert(String::from("b")));
+        assert!(cache.insert(String::from("c")));
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b") && cache.contains("c"));
+        cache.remove("b");
+        assert_eq!(1, cache.len());
+        assert!(cache.insert(String::from("a")));
+    }
+
+    #[tokio::test]
+    async fn test_announces_once_to_peers_that_need_it() {
+        let event_bus = EventBus::new().await;
+        let addr = get_addr("127.0.0.1", 1);
+        let peers = Arc::new(RwLock::new(vec![peer(1), peer(2), peer(3)]));
+        let gossip = Gossip::new(event_bus, addr, peers).await;
+        let mut g = gossip.write().await;
+        // peer 2 announced the item itself
+        g.known_by(&peer(2)).insert(String::from("item"));
+        let targets: Vec<u32> = g
+            .announce_targets("item")
+            .await
+            .iter()
+            .map(|peer| peer.port)
+            .collect();
+        assert_eq!(vec![3], targets);
+        assert!(g.announce_targets("item").await.is_empty());
+        assert!(g.seen.contains("item"));
+    }
+
+    #[tokio::test]
+    async fn test_announces_to_the_most_trusted_peers() {
+        let event_bus = EventBus::new().await;
+        let addr = get_addr("127.0.0.1", 1);
+        let peers: Vec<Peer> = (2..20).map(peer).collect();
+        let gossip = Gossip::new(event_bus, addr, Arc::new(RwLock::new(peers))).await;
+        let mut reputation = Reputation::new(String::from("self"));
+        reputation.record("19", Interaction::ValidBlock);
+        reputation.record("18", Interaction::Up);
+        reputation.compute();
+        let mut g = gossip.write().await;
+        g.use_reputation(reputation.shared());
+        let targets: Vec<u32> = g
+            .announce_targets("item")
+            .await
+            .iter()
+            .map(|peer| peer.port)
+            .collect();
+        assert_eq!(MAX_FANOUT, targets.len());
+        assert_eq!(vec![19, 18, 2], targets[..3]);
+        // the peers left out weren't told about the item
+        assert!(!g.known_by(&peer(10)).contains("item"));
+
+        // relayers of blocks are trusted by their blocks
+        let block = Block {
+            block_hash: vec![1],
+            ..Block::default()
+        };
+        g.remember_relayer(
+            hex::encode(&block.block_hash),
+            String::from("10"),
+            Client::peer(&peer(10)),
+        );
+        g.judge_relayer(&block, Interaction::InvalidBlock).await;
+        g.judge_relayer(&block, Interaction::ValidBlock).await;
+        let mut reputation = g.reputation.write().await;
+        reputation.compute();
+        assert_eq!(0.0, reputation.trust("10"));
+        assert!(g.relayers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_announcer_is_the_client_not_the_claimed_peer() {
+        let event_bus = EventBus::new().await;
+        let addr = get_addr("127.0.0.1", 1);
+        let member = Peer {
+            id: String::from("member"),
+            ip: String::from("10.0.0.2"),
+            port: 2,
+        };
+        let peers = Arc::new(RwLock::new(vec![peer(3), member.clone()]));
+        let gossip = Gossip::new(event_bus, addr, peers).await;
+        let g = gossip.read().await;
+        let claiming = |claimed: Peer| Inventory {
+            items: vec![],
+            peer: Some(claimed),
+        };
+        let from = |ip: &str, node: Option<&str>| Client {
+            ip: ip.parse().ok(),
+            node: node.map(String::from),
+        };
+        // the address claimed doesn't make the announcer a member
+        let announcer = g
+            .announcer(&claiming(member.clone()), &from("10.0.0.9", None))
+            .await;
+        assert_eq!(
+            Some(Peer {
+                id: String::new(),
+                ip: String::from("10.0.0.9"),
+                port: 2,
+            }),
+            announcer
+        );
+        // the member at the client's address and the advertised port
+        let announcer = g
+            .announcer(&claiming(peer(2)), &from("10.0.0.2", None))
+            .await;
+        assert_eq!(Some(member.clone()), announcer);
+        // a certified client is the member with its id
+        let announcer = g
+            .announcer(&claiming(peer(3)), &from("10.0.0.9", Some("member")))
+            .await;
+        assert_eq!(Some(member), announcer);
+        assert_eq!(
+            None,
+            g.announcer(&claiming(peer(3)), &Client::default()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracks_known_items_of_members_only() {
+        let event_bus = EventBus::new().await;
+        let addr = get_addr("127.0.0.1", 1);
+        let peers = Arc::new(RwLock::new(vec![peer(2), peer(3)]));
+        let gossip = Gossip::new(event_bus, addr, peers.clone()).await;
+        let announce = |port: u32, hash: u8| Inventory {
+            items: vec![item(Kind::Block, vec![hash])],
+            peer: Some(peer(port)),
+        };
+        let from = Client {
+            ip: "127.0.0.1".parse().ok(),
+            node: None,
+        };
+        Gossip::on_inventory(gossip.clone(), announce(2, 1), from.clone()).await;
+        for port in 10..20 {
+            Gossip::on_inventory(gossip.clone(), announce(port, 2), from.clone()).await;
+        }
+        assert_eq!(1, gossip.read().await.known.len());
+        // nor kept once the member is gone
+        peers.write().await.retain(|member| member.port != 2);
+        let mut g = gossip.write().await;
+        g.announce_targets("item").await;
+        assert_eq!(
+            vec![peer_key(&peer(3))],
+            g.known.keys().cloned().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reports_relayers_of_rejected_blocks() {
+        let event_bus = EventBus::new().await;
+        let addr = get_addr("127.0.0.1", 1);
+        let gossip = Gossip::new(event_bus, addr, Arc::new(RwLock::new(vec![]))).await;
+        let guard = PeerGuard::new().shared();
+        let mut g = gossip.write().await;
+        g.use_guard(guard.clone());
+        let valid = Block {
+            block_hash: vec![1],
+            ..Block::default()
+        };
+        let rejected = Block {
+            block_hash: vec![2],
+            ..Block::default()
+        };
+        g.pushed_by(&valid, Client::peer(&peer(2)));
+        g.pushed_by(&rejected, Client::peer(&peer(3)));
+        g.judge_relayer(&valid, Interaction::ValidBlock).await;
+        g.judge_relayer(&rejected, Interaction::InvalidBlock).await;
+        let guard = guard.lock().unwrap();
+        assert_eq!(0, guard.score(&Offender::Node(String::from("2"))));
+        assert!(guard.score(&Offender::Node(String::from("3"))) > 0);
+    }
+}
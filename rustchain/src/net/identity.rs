@@ -0,0 +1,427 @@
+use crate::net::kademlia::NodeId;
+use crate::protos::{Heartbeat, Peer, Proof};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use prost::Message;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// heartbeats and requests signed longer ago than this, or this far in the
+// future, are refused, so a captured heartbeat can't be replayed for long
+pub const MAX_PROOF_AGE: Duration = Duration::from_secs(60);
+
+// last timestamp a request was signed with, so that two identical requests
+// made in the same millisecond aren't taken for a replay
+static LAST_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    MissingProof,
+    MalformedKey,
+    // the public key doesn't hash to the claimed id
+    IdMismatch { id: String },
+    BadSignature { id: String },
+    Expired { id: String },
+    // a request already accepted, sent again
+    Replayed { id: String },
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::MissingProof => write!(f, "No proof of identity"),
+            IdentityError::MalformedKey => write!(f, "Malformed public key"),
+            IdentityError::IdMismatch { id } => {
+                write!(f, "Public key does not belong to node {}", id)
+            }
+            IdentityError::BadSignature { id } => write!(f, "Bad signature from node {}", id),
+            IdentityError::Expired { id } => write!(f, "Proof from node {} is too old", id),
+            IdentityError::Replayed { id } => {
+                write!(f, "Request from node {} was already accepted", id)
+            }
+        }
+    }
+}
+
+impl Error for IdentityError {}
+
+// The long-lived P-256 key pair of a node. Its id is derived from the public
+// key, so owning an id means being able to sign with the matching key.
+#[derive(Debug, Clone)]
+pub struct NodeIdentity {
+    key: PKey<Private>,
+    public_key: Vec<u8>,
+    node_id: NodeId,
+}
+
+impl NodeIdentity {
+    pub fn generate() -> NodeIdentity {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("Failed to get curve");
+        let key = EcKey::generate(&group).expect("Failed to generate node key");
+        let key = PKey::from_ec_key(key).expect("Failed to create node key");
+        NodeIdentity::from_key(key).expect("Failed to encode node key")
+    }
+
+    fn from_key(key: PKey<Private>) -> Result<NodeIdentity, ErrorStack> {
+        let public_key = key.public_key_to_der()?;
+        let node_id = NodeId::from_key(&public_key);
+        Ok(NodeIdentity {
+            key,
+            public_key,
+            node_id,
+        })
+    }
+
+    // Reads the PEM key at `path`, generating and saving one the first time,
+    // so the node keeps its id across restarts.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<NodeIdentity> {
+        let path = path.as_ref();
+        if let Ok(pem) = fs::read(path) {
+            return PKey::private_key_from_pem(&pem)
+                .and_then(NodeIdentity::from_key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let identity = NodeIdentity::generate();
+        let pem = identity
+            .key
+            .private_key_to_pem_pkcs8()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // only the owner may read the key
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(&pem)?;
+        file.sync_all()?;
+        Ok(identity)
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn id(&self) -> String {
+        self.node_id.to_hex()
+    }
+
+    // DER encoded
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    // PKCS#8 PEM, for the TLS certificate of the node
+    pub(crate) fn private_key_pem(&self) -> Vec<u8> {
+        self.key
+            .private_key_to_pem_pkcs8()
+            .expect("Failed to encode node key")
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &self.key).expect("Failed to create signer");
+        signer.update(message).expect("Failed to sign");
+        signer.sign_to_vec().expect("Failed to sign")
+    }
+
+    // answers a registration challenge for `peer`, this node's own entry
+    pub fn prove_registration(&self, nonce: &[u8], peer: &Peer) -> Proof {
+        Proof {
+            public_key: self.public_key(),
+            timestamp: 0,
+            signature: self.sign(&registration_message(nonce, peer)),
+        }
+    }
+
+    // signs a request to do `action` on `subject`, such as a keepalive for
+    // this node's own registration or an admin removing another one
+    pub fn prove_request(&self, action: &str, subject: &Peer) -> Proof {
+        let timestamp = request_timestamp();
+        Proof {
+            public_key: self.public_key(),
+            timestamp,
+            signature: self.sign(&request_message(action, subject, timestamp)),
+        }
+    }
+
+    // signs the whole heartbeat, so its peer list and tip can't be tampered
+    // with on the way either
+    pub fn prove_heartbeat(&self, heartbeat: &mut Heartbeat) {
+        let timestamp = now_millis();
+        heartbeat.proof = Some(Proof {
+            public_key: self.public_key(),
+            timestamp,
+            signature: self.sign(&heartbeat_message(heartbeat, timestamp)),
+        });
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// now, or a millisecond after the last request if that was signed just now
+fn request_timestamp() -> u64 {
+    let now = now_millis();
+    let last = LAST_REQUEST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap();
+    now.max(last + 1)
+}
+
+// the challenge is bound to the address being registered, so it can't be used
+// to register the id somewhere else
+fn registration_message(nonce: &[u8], peer: &Peer) -> Vec<u8> {
+    let mut message = b"register".to_vec();
+    message.extend_from_slice(nonce);
+    message.extend_from_slice(peer.id.as_bytes());
+    message.extend_from_slice(peer.ip.as_bytes());
+    message.extend_from_slice(&peer.port.to_be_bytes());
+    message
+}
+
+fn request_message(action: &str, subject: &Peer, timestamp: u64) -> Vec<u8> {
+    let mut message = action.as_bytes().to_vec();
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&subject.encode_to_vec());
+    message
+}
+
+fn heartbeat_message(heartbeat: &Heartbeat, timestamp: u64) -> Vec<u8> {
+    let unsigned = Heartbeat {
+        proof: None,
+        ..heartbeat.clone()
+    };
+    let mut message = b"heartbeat".to_vec();
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&unsigned.encode_to_vec());
+    message
+}
+
+// checks that `proof` was made with the key `id` is derived from
+fn verify(id: &str, proof: &Proof, message: &[u8]) -> Result<(), IdentityError> {
+    if NodeId::from_key(&proof.public_key).to_hex() != id {
+        return Err(IdentityError::IdMismatch { id: id.to_string() });
+    }
+    let public_key =
+        PKey::public_key_from_der(&proof.public_key).map_err(|_| IdentityError::MalformedKey)?;
+    let valid = Verifier::new(MessageDigest::sha256(), &public_key)
+        .and_then(|mut verifier| {
+            verifier.update(message)?;
+            verifier.verify(&proof.signature)
+        })
+        .unwrap_or(false);
+    if !valid {
+        return Err(IdentityError::BadSignature { id: id.to_string() });
+    }
+    Ok(())
+}
+
+pub fn verify_registration(
+    nonce: &[u8],
+    peer: &Peer,
+    proof: Option<&Proof>,
+) -> Result<(), IdentityError> {
+    let proof = proof.ok_or(IdentityError::MissingProof)?;
+    verify(&peer.id, proof, &registration_message(nonce, peer))
+}
+
+fn check_age(id: &str, proof: &Proof) -> Result<(), IdentityError> {
+    let age = now_millis().abs_diff(proof.timestamp);
+    if age > MAX_PROOF_AGE.as_millis() as u64 {
+        return Err(IdentityError::Expired { id: id.to_string() });
+    }
+    Ok(())
+}
+
+// Requests accepted while their proof is recent enough to pass, so a captured
+// one can't be sent again. They are told apart by the message signed rather
+// than the signature, which can be altered and still verify.
+#[derive(Debug, Default)]
+pub struct ReplayCache {
+    // digests of the requests of each signer, and when they were signed
+    seen: HashMap<String, HashMap<[u8; 32], u64>>,
+}
+
+impl ReplayCache {
+    pub fn new() -> ReplayCache {
+        ReplayCache::default()
+    }
+
+    pub fn shared(self) -> Arc<Mutex<ReplayCache>> {
+        Arc::new(Mutex::new(self))
+    }
+
+    // false when `signer` already had `message` accepted
+    fn insert(&mut self, signer: &str, message: &[u8], timestamp: u64) -> bool {
+        // older requests are refused as expired anyway
+        let oldest = now_millis().saturating_sub(MAX_PROOF_AGE.as_millis() as u64);
+        self.seen.retain(|_, requests| {
+            requests.retain(|_, signed| *signed >= oldest);
+            !requests.is_empty()
+        });
+        let requests = self.seen.entry(signer.to_string()).or_default();
+        requests.insert(sha256(message), timestamp).is_none()
+    }
+}
+
+// checks that node `signer` asked for `action` on `subject` lately, and that
+// the request wasn't accepted before
+pub fn verify_request(
+    signer: &str,
+    action: &str,
+    subject: &Peer,
+    proof: Option<&Proof>,
+    replays: &Mutex<ReplayCache>,
+) -> Result<(), IdentityError> {
+    let proof = proof.ok_or(IdentityError::MissingProof)?;
+    check_age(signer, proof)?;
+    let message = request_message(action, subject, proof.timestamp);
+    verify(signer, proof, &message)?;
+    if !replays
+        .lock()
+        .unwrap()
+        .insert(signer, &message, proof.timestamp)
+    {
+        return Err(IdentityError::Replayed {
+            id: signer.to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn verify_heartbeat(heartbeat: &Heartbeat) -> Result<(), IdentityError> {
+    let proof = heartbeat
+        .proof
+        .as_ref()
+        .ok_or(IdentityError::MissingProof)?;
+    let id = heartbeat
+        .peer
+        .as_ref()
+        .map(|p| p.id.clone())
+        .unwrap_or_default();
+    check_age(&id, proof)?;
+    verify(&id, proof, &heartbeat_message(heartbeat, proof.timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::PeerList;
+    use std::env::temp_dir;
+
+    fn peer(identity: &NodeIdentity) -> Peer {
+        Peer {
+            id: identity.id(),
+            ip: String::from("127.0.0.1"),
+            port: 5000,
+        }
+    }
+
+    #[test]
+    fn test_registration_proof() {
+        let identity = NodeIdentity::generate();
+        let peer = peer(&identity);
+        let proof = identity.prove_registration(b"nonce", &peer);
+        assert_eq!(Ok(()), verify_registration(b"nonce", &peer, Some(&proof)));
+        // another challenge, or another address
+        assert!(verify_registration(b"other", &peer, Some(&proof)).is_err());
+        let moved = Peer {
+            port: 5001,
+            ..peer.clone()
+        };
+        assert!(verify_registration(b"nonce", &moved, Some(&proof)).is_err());
+        assert_eq!(
+            Err(IdentityError::MissingProof),
+            verify_registration(b"nonce", &peer, None)
+        );
+        // someone else's id can't be claimed with one's own key
+        let impostor = NodeIdentity::generate();
+        let proof = impostor.prove_registration(b"nonce", &peer);
+        assert_eq!(
+            Err(IdentityError::IdMismatch {
+                id: peer.id.clone()
+            }),
+            verify_registration(b"nonce", &peer, Some(&proof))
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_proof() {
+        let identity = NodeIdentity::generate();
+        let mut heartbeat = Heartbeat {
+            peer: Some(peer(&identity)),
+            peers: Some(PeerList::default()),
+            ..Heartbeat::default()
+        };
+        identity.prove_heartbeat(&mut heartbeat);
+        assert_eq!(Ok(()), verify_heartbeat(&heartbeat));
+        // tampering with the content breaks the signature
+        let mut tampered = heartbeat.clone();
+        tampered.block_hashes.push(String::from("00"));
+        assert_eq!(
+            Err(IdentityError::BadSignature { id: identity.id() }),
+            verify_heartbeat(&tampered)
+        );
+        let mut old = heartbeat.clone();
+        old.proof.as_mut().unwrap().timestamp -= 2 * MAX_PROOF_AGE.as_millis() as u64;
+        assert_eq!(
+            Err(IdentityError::Expired { id: identity.id() }),
+            verify_heartbeat(&old)
+        );
+    }
+
+    #[test]
+    fn test_request_proof() {
+        let identity = NodeIdentity::generate();
+        let subject = peer(&NodeIdentity::generate());
+        let proof = identity.prove_request("remove", &subject);
+        let id = identity.id();
+        let replays = Mutex::new(ReplayCache::new());
+        // a proof is only good for the action and subject it was made for
+        assert!(verify_request(&id, "list", &subject, Some(&proof), &replays).is_err());
+        assert!(verify_request(&id, "remove", &peer(&identity), Some(&proof), &replays).is_err());
+        assert!(verify_request(&subject.id, "remove", &subject, Some(&proof), &replays).is_err());
+        assert_eq!(
+            Ok(()),
+            verify_request(&id, "remove", &subject, Some(&proof), &replays)
+        );
+        // and only once
+        assert_eq!(
+            Err(IdentityError::Replayed { id: id.clone() }),
+            verify_request(&id, "remove", &subject, Some(&proof), &replays)
+        );
+        let again = identity.prove_request("list", &subject);
+        assert_eq!(
+            Ok(()),
+            verify_request(&id, "list", &subject, Some(&again), &replays)
+        );
+    }
+
+    #[test]
+    fn test_identity_survives_restarts() {
+        let path = temp_dir().join(format!("rustchain-{}.key", NodeIdentity::generate().id()));
+        let identity = NodeIdentity::load_or_generate(&path).unwrap();
+        let reloaded = NodeIdentity::load_or_generate(&path).unwrap();
+        assert_eq!(identity.node_id(), reloaded.node_id());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use crate::protos::Peer;
+use openssl::rand::rand_bytes;
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+pub const ID_BYTES: usize = 32;
+pub const ID_BITS: usize = ID_BYTES * 8;
+// contacts kept per bucket, also how many peers a lookup returns
+pub const K: usize = 20;
+// FIND_NODE requests a lookup keeps in flight
+pub const ALPHA: usize = 3;
+
+// 256-bit node id, the sha256 of the node's public key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct NodeId([u8; ID_BYTES]);
+
+impl NodeId {
+    pub fn from_key(public_key: &[u8]) -> NodeId {
+        NodeId(Sha256::digest(public_key).into())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<NodeId> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    pub fn from_hex(id: &str) -> Option<NodeId> {
+        NodeId::from_bytes(&hex::decode(id).ok()?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; ID_BYTES];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeId(distance)
+    }
+
+    // Bucket `other` falls in from this node's point of view: the position
+    // of the highest bit the two ids differ in. A node has no bucket for
+    // itself.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let leading_zeros = leading_zeros(&distance.0);
+        if leading_zeros == ID_BITS {
+            return None;
+        }
+        Some(ID_BITS - 1 - leading_zeros)
+    }
+
+    // random id that falls in the given bucket, looked up to refresh it
+    pub fn random_in_bucket(&self, index: usize) -> NodeId {
+        let mut random = [0; ID_BYTES];
+        rand_bytes(&mut random).expect("Failed to generate random bytes");
+        let mut id = self.0;
+        for (i, byte) in id.iter_mut().enumerate() {
+            // bits above the bucket's are shared with this node, bit `index`
+            // differs and the ones below are random
+            for bit in 0..8 {
+                let position = ID_BITS - 1 - (i * 8 + bit);
+                let mask = 0x80 >> bit;
+                if position == index {
+                    *byte ^= mask;
+                } else if position < index {
+                    *byte = (*byte & !mask) | (random[i] & mask);
+                }
+            }
+        }
+        NodeId(id)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+fn leading_zeros(bytes: &[u8]) -> usize {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros() as usize;
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+// peers advertise their node id hex encoded
+pub fn peer_id(peer: &Peer) -> Option<NodeId> {
+    NodeId::from_hex(&peer.id)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insertion {
+    Added,
+    // already known, left as it was
+    Known,
+    // already known, given the address it came from and moved to the tail of
+    // its bucket
+    Updated,
+    // the bucket is full and keeps its oldest contacts, which are evicted by
+    // the failure detector once they stop answering
+    BucketFull,
+    // own id or not a valid node id
+    Ignored,
+}
+
+#[derive(Debug, Clone)]
+struct KBucket {
+    // least recently seen first
+    contacts: VecDeque<Peer>,
+    // last time a lookup went through the bucket
+    refreshed: Instant,
+}
+
+// Kademlia routing table: contacts are kept in one bucket per bit of XOR
+// distance to this node, so a node knows many peers close to it and a few
+// far away ones.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<KBucket>,
+    bucket_size: usize,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> RoutingTable {
+        RoutingTable::with_bucket_size(own_id, K)
+    }
+
+    pub fn with_bucket_size(own_id: NodeId, bucket_size: usize) -> RoutingTable {
+        let bucket = KBucket {
+            contacts: VecDeque::new(),
+            refreshed: Instant::now(),
+        };
+        RoutingTable {
+            own_id,
+            buckets: vec![bucket; ID_BITS],
+            bucket_size,
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.contacts.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        match self.own_id.bucket_index(id) {
+            Some(index) => self.buckets[index]
+                .contacts
+                .iter()
+                .any(|p| peer_id(p) == Some(*id)),
+            None => false,
+        }
+    }
+
+    // Adds a new contact. The address of a known one is kept, whoever claims
+    // it moved could be lying.
+    pub fn insert(&mut self, peer: Peer) -> Insertion {
+        let index = match peer_id(&peer).and_then(|id| self.own_id.bucket_index(&id)) {
+            Some(index) => index,
+            None => return Insertion::Ignored,
+        };
+        let bucket = &mut self.buckets[index];
+        if bucket.contacts.iter().any(|p| p.id == peer.id) {
+            return Insertion::Known;
+        }
+        if bucket.contacts.len() >= self.bucket_size {
+            return Insertion::BucketFull;
+        }
+        bucket.contacts.push_back(peer);
+        Insertion::Added
+    }
+
+    // Like insert, but a known contact takes the address of `peer` and becomes
+    // the most recently seen. Only for a peer that proved it owns its id.
+    pub fn update(&mut self, peer: Peer) -> Insertion {
+        let index = match peer_id(&peer).and_then(|id| self.own_id.bucket_index(&id)) {
+            Some(index) => index,
+            None => return Insertion::Ignored,
+        };
+        let bucket = &mut self.buckets[index];
+        match bucket.contacts.iter().position(|p| p.id == peer.id) {
+            Some(position) => {
+                bucket.contacts.remove(position);
+                bucket.contacts.push_back(peer);
+                Insertion::Updated
+            }
+            None => self.insert(peer),
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> bool {
+        let index = match self.own_id.bucket_index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let contacts = &mut self.buckets[index].contacts;
+        let len = contacts.len();
+        contacts.retain(|p| peer_id(p) != Some(*id));
+        contacts.len() != len
+    }
+
+    // the contacts sharing a bucket with `id`, least recently seen first
+    pub fn bucket_of(&self, id: &NodeId) -> Vec<Peer> {
+        match self.own_id.bucket_index(id) {
+            Some(index) => self.buckets[index].contacts.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    // every contact, closest buckets first
+    pub fn peers(&self) -> Vec<Peer> {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.contacts.iter().cloned())
+            .collect()
+    }
+
+    // the `count` known contacts closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Peer> {
+        let mut peers = self.peers();
+        sort_by_distance(&mut peers, target);
+        peers.truncate(count);
+        peers
+    }
+
+    pub fn refreshed(&mut self, target: &NodeId) {
+        if let Some(index) = self.own_id.bucket_index(target) {
+            self.buckets[index].refreshed = Instant::now();
+        }
+    }
+
+    // Buckets no lookup went through for `max_age`. Empty buckets are left
+    // out, with 256 of them most would never have peers to find.
+    pub fn stale_buckets(&self, max_age: Duration) -> Vec<usize> {
+        (0..ID_BITS)
+            .filter(|i| !self.buckets[*i].contacts.is_empty())
+            .filter(|i| self.buckets[*i].refreshed.elapsed() >= max_age)
+            .collect()
+    }
+}
+
+// peers without a valid id sort last
+fn sort_by_distance(peers: &mut [Peer], target: &NodeId) {
+    peers.sort_by_key(|p| peer_id(p).map(|id| id.distance(target)).ok_or(()));
+}
+
+// Iterative FIND_NODE: asks the ALPHA closest peers not asked yet for the
+// peers they know closest to `target`, until the K closest peers seen have
+// all been asked. Returns the closest peers that answered. Peers that fail
+// to answer are dropped from the lookup.
+pub async fn lookup<F, Fut>(own_id: NodeId, target: NodeId, seeds: Vec<Peer>, query: F) -> Vec<Peer>
+where
+    F: Fn(Peer) -> Fut,
+    Fut: Future<Output = Result<Vec<Peer>, String>> + Send + 'static,
+{
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut shortlist: Vec<Peer> = vec![];
+    let mut queried: HashSet<String> = HashSet::new();
+    let mut answered: Vec<Peer> = vec![];
+    for peer in seeds {
+        if peer_id(&peer).is_some_and(|id| id != own_id) && seen.insert(peer.id.clone()) {
+            shortlist.push(peer);
+        }
+    }
+    loop {
+        sort_by_distance(&mut shortlist, &target);
+        shortlist.truncate(K);
+        let batch: Vec<Peer> = shortlist
+            .iter()
+            .filter(|p| !queried.contains(&p.id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+        let mut requests = JoinSet::new();
+        for peer in batch {
+            queried.insert(peer.id.clone());
+            let request = query(peer.clone());
+            requests.spawn(async move { (peer, request.await) });
+        }
+        while let Some(Ok((peer, found))) = requests.join_next().await {
+            match found {
+                Ok(found) => {
+                    for found_peer in found {
+                        let valid = peer_id(&found_peer).is_some_and(|id| id != own_id);
+                        if valid && seen.insert(found_peer.id.clone()) {
+                            shortlist.push(found_peer);
+                        }
+                    }
+                    answered.push(peer);
+                }
+                Err(e) => {
+                    println!("FIND_NODE to peer {} failed: {}", peer.id, e);
+                    shortlist.retain(|p| p.id != peer.id);
+                }
+            }
+        }
+    }
+    sort_by_distance(&mut answered, &target);
+    answered.truncate(K);
+    answered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn id(first_byte: u8) -> NodeId {
+        let mut id = [0; ID_BYTES];
+        id[0] = first_byte;
+        NodeId(id)
+    }
+
+    fn peer(id: &NodeId) -> Peer {
+        Peer {
+            id: id.to_hex(),
+            ip: String::from("127.0.0.1"),
+            port: 0,
+        }
+    }
+
+    #[test]
+    fn test_distance_and_buckets() {
+        let a = NodeId::from_key(b"a");
+        let b = NodeId::from_key(b"b");
+        assert_eq!(a.distance(&b), b.distance(&a));
+        assert_eq!(NodeId::default(), a.distance(&a));
+        assert_eq!(None, a.bucket_index(&a));
+        assert_eq!(Some(ID_BITS - 1), id(0).bucket_index(&id(0x80)));
+        assert_eq!(Some(ID_BITS - 8), id(0).bucket_index(&id(0x01)));
+        assert_eq!(Some(a), NodeId::from_hex(&a.to_hex()));
+        for index in [0, 7, 100, ID_BITS - 1] {
+            assert_eq!(Some(index), a.bucket_index(&a.random_in_bucket(index)));
+        }
+    }
+
+    #[test]
+    fn test_full_bucket_keeps_old_contacts() {
+        let mut table = RoutingTable::with_bucket_size(id(0), 2);
+        // all in the farthest bucket
+        assert_eq!(Insertion::Added, table.insert(peer(&id(0x80))));
+        assert_eq!(Insertion::Added, table.insert(peer(&id(0x81))));
+        assert_eq!(Insertion::BucketFull, table.insert(peer(&id(0x82))));
+        // only the peer itself can move its contact
+        let mut moved = peer(&id(0x80));
+        moved.port = 6000;
+        assert_eq!(Insertion::Known, table.insert(moved.clone()));
+        assert_eq!(
+            vec![peer(&id(0x80)), peer(&id(0x81))],
+            table.bucket_of(&id(0x80))
+        );
+        assert_eq!(Insertion::Updated, table.update(moved.clone()));
+        assert_eq!(
+            vec![peer(&id(0x81)), moved.clone()],
+            table.bucket_of(&id(0x80))
+        );
+        assert_eq!(Insertion::Updated, table.update(peer(&id(0x80))));
+        assert_eq!(Insertion::Added, table.insert(peer(&id(0x01))));
+        assert_eq!(Insertion::Ignored, table.insert(peer(&id(0))));
+        assert_eq!(Insertion::Ignored, table.insert(Peer::default()));
+        assert_eq!(3, table.len());
+        assert_eq!(
+            vec![peer(&id(0x81)), peer(&id(0x80))],
+            table.bucket_of(&id(0x82))
+        );
+        // room is made once a contact is removed
+        assert!(table.remove(&id(0x81)));
+        assert!(!table.contains(&id(0x81)));
+        assert_eq!(Insertion::Added, table.insert(peer(&id(0x82))));
+    }
+
+    #[test]
+    fn test_closest_and_stale_buckets() {
+        let mut table = RoutingTable::new(id(0));
+        for first_byte in [0x80, 0x40, 0x41, 0x02] {
+            table.insert(peer(&id(first_byte)));
+        }
+        let closest = table.closest(&id(0x43), 2);
+        assert_eq!(vec![peer(&id(0x41)), peer(&id(0x40))], closest);
+        let mut stale = table.stale_buckets(Duration::ZERO);
+        stale.sort();
+        assert_eq!(vec![ID_BITS - 7, ID_BITS - 2, ID_BITS - 1], stale);
+        table.refreshed(&id(0x80));
+        assert!(!table
+            .stale_buckets(Duration::from_secs(60))
+            .contains(&(ID_BITS - 1)));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_finds_peers_beyond_seeds() {
+        // a line of nodes, each only knows the next one
+        let ids: Vec<NodeId> = (0..10u8).map(|i| NodeId::from_key(&[i])).collect();
+        let mut network: HashMap<String, Vec<Peer>> = HashMap::new();
+        for pair in ids.windows(2) {
+            network.insert(pair[0].to_hex(), vec![peer(&pair[1])]);
+        }
+        let network = Arc::new(network);
+        let target = ids[9];
+        let found = lookup(ids[0], target, vec![peer(&ids[1])], |p| {
+            let network = network.clone();
+            async move {
+                match network.get(&p.id) {
+                    Some(peers) => Ok(peers.clone()),
+                    // the last one is down
+                    None => Err(String::from("unreachable")),
+                }
+            }
+        })
+        .await;
+        assert_eq!(8, found.len());
+        assert!(!found.contains(&peer(&ids[9])));
+        assert!(found.contains(&peer(&ids[8])));
+        assert!(!found.contains(&peer(&ids[0])));
+    }
+}
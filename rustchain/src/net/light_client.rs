@@ -0,0 +1,194 @@
+use crate::blockchain::consensus::ConsensusParams;
+use crate::blockchain::header_chain::HeaderChain;
+use crate::blockchain::merkle::{verify_merkle_path, MerklePath};
+use crate::net::client_stubs::PeerClient;
+use crate::net::server_stubs::MAX_HEADERS;
+use crate::protos::{MerkleProof, Transaction};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpvError {
+    MissingTransaction,
+    // the proof is for a block that is not on the header chain
+    UnknownBlock { hash: String },
+    // the path doesn't lead from the transaction to the block's merkle root
+    InvalidPath { tx_hash: String },
+    // the node answered with another transaction than the one asked for
+    UnexpectedTransaction { expected: String, found: String },
+}
+
+impl fmt::Display for SpvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingTransaction => write!(f, "merkle proof without a transaction"),
+            Self::UnknownBlock { hash } => {
+                write!(f, "block {} is not on the header chain", hash)
+            }
+            Self::InvalidPath { tx_hash } => write!(
+                f,
+                "merkle path does not include transaction {} in its block",
+                tx_hash
+            ),
+            Self::UnexpectedTransaction { expected, found } => {
+                write!(f, "asked for transaction {} but got {}", expected, found)
+            }
+        }
+    }
+}
+
+impl Error for SpvError {}
+
+// Light client mode for nodes that can't download full blocks. It only keeps
+// the header chain, and trusts a transaction once a full node shows a merkle
+// path from it to the merkle root of one of those headers.
+#[derive(Debug, Clone, Default)]
+pub struct LightClient {
+    headers: HeaderChain,
+}
+
+impl LightClient {
+    pub fn new(params: ConsensusParams) -> Self {
+        LightClient {
+            headers: HeaderChain::new(params),
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    // Downloads and validates headers from a full node until it has no more
+    // to give. Returns the height of the header chain.
+    pub async fn sync(&mut self, client: &mut PeerClient) -> Result<u64, Box<dyn Error>> {
+        loop {
+            let headers = client
+                .get_headers(self.headers.locator(), MAX_HEADERS)
+                .await?
+                .headers;
+            let changed = self.headers.add_headers(&headers)?;
+            if !changed || headers.len() < MAX_HEADERS as usize {
+                return Ok(self.headers.height());
+            }
+        }
+    }
+
+    // Asks a full node for a confirmed transaction and checks its inclusion.
+    // Returns the transaction and how many blocks confirm it.
+    pub async fn get_transaction(
+        &self,
+        client: &mut PeerClient,
+        tx_hash: &str,
+    ) -> Result<(Transaction, u64), Box<dyn Error>> {
+        let proof = client.get_merkle_proof(tx_hash.to_string()).await?;
+        let (tx, confirmations) = self.verify_proof(&proof)?;
+        let found = hex::encode(tx.hash());
+        if found != tx_hash {
+            return Err(Box::new(SpvError::UnexpectedTransaction {
+                expected: tx_hash.to_string(),
+                found,
+            }));
+        }
+        Ok((tx, confirmations))
+    }
+
+    // checks that the proof's transaction is under the merkle root of a header
+    // of the chain
+    pub fn verify_proof(&self, proof: &MerkleProof) -> Result<(Transaction, u64), SpvError> {
+        let tx = proof
+            .transaction
+            .clone()
+            .ok_or(SpvError::MissingTransaction)?;
+        let header = self
+            .headers
+            .get(proof.block_index)
+            .filter(|header| header.hash() == proof.block_hash)
+            .ok_or_else(|| SpvError::UnknownBlock {
+                hash: hex::encode(&proof.block_hash),
+            })?;
+        let tx_hash = tx.hash();
+        let invalid_path = || SpvError::InvalidPath {
+            tx_hash: hex::encode(&tx_hash),
+        };
+        let hashes = proof
+            .hashes
+            .iter()
+            .map(|hash| hash.as_slice().try_into().ok())
+            .collect::<Option<Vec<[u8; 32]>>>()
+            .ok_or_else(invalid_path)?;
+        let path = MerklePath {
+            location: proof.location as usize,
+            hashes,
+        };
+        let key = tx_hash.as_slice().try_into().unwrap();
+        if !verify_merkle_path(&header.merkle_root, key, &path) {
+            return Err(invalid_path());
+        }
+        let confirmations = self.headers.height() - proof.block_index + 1;
+        Ok((tx, confirmations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::create_genesis_block;
+    use crate::blockchain::merkle::{IMerkleTree, MerkleEntry, MerkleTree};
+    use crate::blockchain::validation::tests::{credit, mined_block, mined_block_for};
+    use crate::protos::Block;
+
+    fn proof_for(block: &Block, tx: &Transaction) -> MerkleProof {
+        let path = MerkleTree::from_transactions(&block.transactions)
+            .generate_merkle_path(&MerkleEntry::from(tx))
+            .unwrap();
+        MerkleProof {
+            transaction: Some(tx.clone()),
+            block_hash: block.block_hash.clone(),
+            block_index: block.header.as_ref().unwrap().block_index,
+            location: path.location as u64,
+            hashes: path.hashes.iter().map(|hash| hash.to_vec()).collect(),
+        }
+    }
+
+    fn synced_client(blocks: &[Block]) -> LightClient {
+        let mut client = LightClient::default();
+        let headers: Vec<_> = blocks.iter().map(|b| b.header.clone().unwrap()).collect();
+        client.headers.add_headers(&headers).unwrap();
+        client
+    }
+
+    #[test]
+    fn test_verify_proof() {
+        let txs: Vec<Transaction> = (1..4).map(|i| credit(String::from("bob"), i)).collect();
+        let block = mined_block(&create_genesis_block(), txs.clone());
+        let next = mined_block(&block, vec![]);
+        let client = synced_client(&[block.clone(), next]);
+        for tx in &txs {
+            let proof = proof_for(&block, tx);
+            assert_eq!(Ok((tx.clone(), 2)), client.verify_proof(&proof));
+        }
+    }
+
+    #[test]
+    fn test_rejects_forged_proofs() {
+        let txs: Vec<Transaction> = (1..4).map(|i| credit(String::from("bob"), i)).collect();
+        let block = mined_block(&create_genesis_block(), txs.clone());
+        let client = synced_client(std::slice::from_ref(&block));
+
+        // a transaction that isn't in the block
+        let mut proof = proof_for(&block, &txs[0]);
+        proof.transaction = Some(credit(String::from("mallory"), 1000));
+        assert!(matches!(
+            client.verify_proof(&proof),
+            Err(SpvError::InvalidPath { .. })
+        ));
+
+        // a block the header chain doesn't have
+        let other = mined_block_for(&create_genesis_block(), "other", txs.clone());
+        let proof = proof_for(&other, &txs[0]);
+        assert!(matches!(
+            client.verify_proof(&proof),
+            Err(SpvError::UnknownBlock { .. })
+        ));
+    }
+}
@@ -1,19 +1,428 @@
-// use std::net::SocketAddr;
+use crate::net::tls::peer_id;
+use crate::protos::Peer;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tonic::{service::Interceptor, Request, Status};
 
+// largest message a node decodes, enough for a full GetBlocks answer
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// requests a single connection may have in flight
+pub const MAX_CONCURRENT_STREAMS: u32 = 64;
+// requests per second each address, and each node over TLS, may make, and
+// how many it may make at once after being quiet for a while
+const IP_BUDGET: Budget = Budget::new(200, 400);
+const PEER_BUDGET: Budget = Budget::new(100, 200);
+// misbehaviour score at which a client is banned, and how fast it is
+// forgiven otherwise
+const BAN_SCORE: u32 = 100;
+const FORGIVEN_PER_SECOND: u32 = 1;
+const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+// clients tracked before the ones with a full budget are forgotten
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    per_second: u32,
+    burst: u32,
+}
+
+impl Budget {
+    pub const fn new(per_second: u32, burst: u32) -> Budget {
+        Budget { per_second, burst }
+    }
+}
+
+// whoever a request is from, by address or, over TLS, by node id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Offender {
+    Ip(IpAddr),
+    Node(String),
+}
+
+impl fmt::Display for Offender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offender::Ip(ip) => write!(f, "{}", ip),
+            Offender::Node(id) => write!(f, "node {}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    InvalidBlock,
+    MalformedTransaction,
+    // forged heartbeats, announcements and the like
+    BadSignature,
+    // requests over budget
+    Flooding,
+}
+
+impl Misbehaviour {
+    fn score(&self) -> u32 {
+        match self {
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::MalformedTransaction => 20,
+            Misbehaviour::BadSignature => 20,
+            Misbehaviour::Flooding => 1,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misbehaviour::InvalidBlock => write!(f, "sent an invalid block"),
+            Misbehaviour::MalformedTransaction => write!(f, "sent a malformed transaction"),
+            Misbehaviour::BadSignature => write!(f, "sent a bad signature"),
+            Misbehaviour::Flooding => write!(f, "flooded the node with requests"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuardError {
+    Banned {
+        offender: Offender,
+        remaining: Duration,
+    },
+    OverBudget {
+        offender: Offender,
+    },
+}
+
+impl fmt::Display for GuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardError::Banned {
+                offender,
+                remaining,
+            } => write!(f, "{} is banned for another {:?}", offender, remaining),
+            GuardError::OverBudget { offender } => write!(f, "{} is over its budget", offender),
+        }
+    }
+}
+
+impl Error for GuardError {}
+
+// the address a request came from, and its node id when it came over TLS
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    pub node: Option<String>,
+}
+
+impl Client {
+    pub fn of<T>(req: &Request<T>) -> Client {
+        Client {
+            // clients over ipv4 are the same to a dual stack listener
+            ip: req.remote_addr().map(|addr| addr.ip().to_canonical()),
+            node: peer_id(req),
+        }
+    }
+
+    // a peer this node asked itself, by the address and id it advertised
+    pub fn peer(peer: &Peer) -> Client {
+        Client {
+            ip: peer.ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical()),
+            node: (!peer.id.is_empty()).then(|| peer.id.clone()),
+        }
+    }
+
+    fn offenders(&self) -> Vec<Offender> {
+        let ip = self.ip.map(Offender::Ip);
+        let node = self.node.clone().map(Offender::Node);
+        ip.into_iter().chain(node).collect()
+    }
+}
+
+// tokens left of a budget, refilled as time goes by
+#[derive(Debug, Clone, Copy)]
+struct Tokens {
+    left: f64,
+    updated: Instant,
+}
+
+impl Tokens {
+    fn full(budget: Budget, now: Instant) -> Tokens {
+        Tokens {
+            left: budget.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.left = (self.left + elapsed * budget.per_second as f64).min(budget.burst as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    points: u32,
+    // when points were last forgiven
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub offender: Offender,
+    pub remaining: Duration,
+    pub reason: String,
+}
+
+// Request budgets, misbehaviour scores and bans of the clients of a node.
+// Clients over budget have their requests refused, and clients whose score
+// reaches BAN_SCORE are refused everything for a while.
+#[derive(Debug, Clone)]
+pub struct PeerGuard {
+    ip_budget: Budget,
+    peer_budget: Budget,
+    ban_duration: Duration,
+    tokens: HashMap<Offender, Tokens>,
+    scores: HashMap<Offender, Score>,
+    // until when each offender is banned, and why
+    bans: HashMap<Offender, (Instant, String)>,
+}
+
+impl Default for PeerGuard {
+    fn default() -> Self {
+        PeerGuard::new()
+    }
+}
+
+impl PeerGuard {
+    pub fn new() -> PeerGuard {
+        PeerGuard {
+            ip_budget: IP_BUDGET,
+            peer_budget: PEER_BUDGET,
+            ban_duration: BAN_DURATION,
+            tokens: HashMap::new(),
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn with_ip_budget(mut self, budget: Budget) -> PeerGuard {
+        self.ip_budget = budget;
+        self
+    }
+
+    pub fn with_peer_budget(mut self, budget: Budget) -> PeerGuard {
+        self.peer_budget = budget;
+        self
+    }
+
+    pub fn with_ban_duration(mut self, ban_duration: Duration) -> PeerGuard {
+        self.ban_duration = ban_duration;
+        self
+    }
+
+    pub fn shared(self) -> Arc<Mutex<PeerGuard>> {
+        Arc::new(Mutex::new(self))
+    }
+
+    // Lets a request of `client` through unless it is banned or over budget.
+    // Requests over budget count as flooding.
+    pub fn admit(&mut self, client: &Client) -> Result<(), GuardError> {
+        let now = Instant::now();
+        let offenders = client.offenders();
+        for offender in &offenders {
+            if let Some(remaining) = self.banned_for(offender, now) {
+                return Err(GuardError::Banned {
+                    offender: offender.clone(),
+                    remaining,
+                });
+            }
+        }
+        self.forget_idle(now);
+        for offender in offenders {
+            let budget = match offender {
+                Offender::Ip(_) => self.ip_budget,
+                Offender::Node(_) => self.peer_budget,
+            };
+            let tokens = self
+                .tokens
+                .entry(offender.clone())
+                .or_insert_with(|| Tokens::full(budget, now));
+            tokens.refill(budget, now);
+            if tokens.left < 1.0 {
+                self.report(client, Misbehaviour::Flooding);
+                return Err(GuardError::OverBudget { offender });
+            }
+            tokens.left -= 1.0;
+        }
+        Ok(())
+    }
+
+    // adds to the score of `client`, banning it once the score is high enough
+    pub fn report(&mut self, client: &Client, misbehaviour: Misbehaviour) {
+        let now = Instant::now();
+        for offender in client.offenders() {
+            let score = self.scores.entry(offender.clone()).or_insert(Score {
+                points: 0,
+                updated: now,
+            });
+            let seconds = now.duration_since(score.updated).as_secs();
+            let forgiven = (seconds as u32).saturating_mul(FORGIVEN_PER_SECOND);
+            score.points = score.points.saturating_sub(forgiven) + misbehaviour.score();
+            score.updated += Duration::from_secs(seconds);
+            if score.points >= BAN_SCORE {
+                println!("Banned {}, it {}", offender, misbehaviour);
+                self.scores.remove(&offender);
+                self.bans.insert(
+                    offender,
+                    (now + self.ban_duration, misbehaviour.to_string()),
+                );
+            }
+        }
+    }
+
+    pub fn score(&self, offender: &Offender) -> u32 {
+        self.scores.get(offender).map_or(0, |score| score.points)
+    }
+
+    // the bans that haven't run out yet
+    pub fn bans(&mut self) -> Vec<Ban> {
+        let now = Instant::now();
+        self.bans.retain(|_, (until, _)| *until > now);
+        self.bans
+            .iter()
+            .map(|(offender, (until, reason))| Ban {
+                offender: offender.clone(),
+                remaining: *until - now,
+                reason: reason.clone(),
+            })
+            .collect()
+    }
+
+    fn banned_for(&mut self, offender: &Offender, now: Instant) -> Option<Duration> {
+        let until = self.bans.get(offender)?.0;
+        if until <= now {
+            self.bans.remove(offender);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    // keeps the bookkeeping bounded, clients that would be back to a full
+    // budget anyway can be forgotten
+    fn forget_idle(&mut self, now: Instant) {
+        if self.tokens.len() < MAX_TRACKED {
+            return;
+        }
+        let (ip_budget, peer_budget) = (self.ip_budget, self.peer_budget);
+        self.tokens.retain(|offender, tokens| {
+            let budget = match offender {
+                Offender::Ip(_) => ip_budget,
+                Offender::Node(_) => peer_budget,
+            };
+            tokens.refill(budget, now);
+            tokens.left < budget.burst as f64
+        });
+    }
+}
+
+// Refuses requests of banned clients and of clients over their budget, before
+// they reach any service.
 #[derive(Clone)]
-pub struct ClientAddressInterceptor {}
+pub struct ClientAddressInterceptor {
+    guard: Arc<Mutex<PeerGuard>>,
+}
 
 impl ClientAddressInterceptor {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(guard: Arc<Mutex<PeerGuard>>) -> Self {
+        Self { guard }
     }
 }
 
 impl Interceptor for ClientAddressInterceptor {
     fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
-        let _client_addr = request.remote_addr().unwrap();
+        let client = Client::of(&request);
+        match self.guard.lock().unwrap().admit(&client) {
+            Ok(()) => Ok(request),
+            Err(e @ GuardError::Banned { .. }) => Err(Status::permission_denied(e.to_string())),
+            Err(e @ GuardError::OverBudget { .. }) => {
+                Err(Status::resource_exhausted(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn client(ip: &str, node: Option<&str>) -> Client {
+        Client {
+            ip: Some(ip.parse().unwrap()),
+            node: node.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_budgets() {
+        let mut guard = PeerGuard::new()
+            .with_ip_budget(Budget::new(10, 2))
+            .with_peer_budget(Budget::new(10, 5));
+        let first = client("10.0.0.1", None);
+        assert_eq!(Ok(()), guard.admit(&first));
+        assert_eq!(Ok(()), guard.admit(&first));
+        assert_eq!(
+            Err(GuardError::OverBudget {
+                offender: Offender::Ip(first.ip.unwrap())
+            }),
+            guard.admit(&first)
+        );
+        // every address has a budget of its own
+        assert_eq!(Ok(()), guard.admit(&client("10.0.0.2", None)));
+        // refilled as time goes by
+        sleep(Duration::from_millis(120));
+        assert_eq!(Ok(()), guard.admit(&first));
+        // nodes are held to their own budget wherever they connect from
+        for i in 0..5 {
+            let ip = format!("10.0.1.{}", i);
+            assert_eq!(Ok(()), guard.admit(&client(&ip, Some("node"))));
+        }
+        assert!(guard.admit(&client("10.0.1.9", Some("node"))).is_err());
+    }
+
+    #[test]
+    fn test_misbehaving_clients_are_banned() {
+        let mut guard = PeerGuard::new().with_ban_duration(Duration::from_millis(100));
+        let offender = client("10.0.0.1", Some("node"));
+        guard.report(&offender, Misbehaviour::InvalidBlock);
+        assert_eq!(50, guard.score(&Offender::Node(String::from("node"))));
+        assert_eq!(Ok(()), guard.admit(&offender));
+        guard.report(&offender, Misbehaviour::InvalidBlock);
+        // by address and by node id
+        assert!(guard.admit(&client("10.0.0.1", None)).is_err());
+        assert!(guard.admit(&client("10.0.0.2", Some("node"))).is_err());
+        assert_eq!(Ok(()), guard.admit(&client("10.0.0.2", None)));
+        let bans = guard.bans();
+        assert_eq!(2, bans.len());
+        assert!(bans.iter().all(|ban| ban.reason == "sent an invalid block"));
+        // bans run out
+        sleep(Duration::from_millis(120));
+        assert!(guard.bans().is_empty());
+        assert_eq!(Ok(()), guard.admit(&offender));
+    }
 
-        Ok(request)
+    #[test]
+    fn test_floods_get_banned() {
+        let mut guard = PeerGuard::new().with_ip_budget(Budget::new(1, 1));
+        let flooder = client("10.0.0.1", None);
+        let refused = (0..200).filter(|_| guard.admit(&flooder).is_err()).count();
+        assert_eq!(199, refused);
+        match guard.admit(&flooder) {
+            Err(GuardError::Banned { .. }) => {}
+            other => panic!("flooder is not banned: {:?}", other),
+        }
     }
 }
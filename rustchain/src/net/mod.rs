@@ -1,6 +1,17 @@
 pub mod bootstrap_node;
+pub mod chord;
 pub mod client_stubs;
+pub mod connections;
+pub mod failure_detector;
+pub mod gossip;
+pub mod identity;
+pub mod kademlia;
+pub mod light_client;
 pub mod middleware;
 pub mod networking;
 pub mod p2p;
+pub mod peer_cache;
+pub mod reputation;
 pub mod server_stubs;
+pub mod sync;
+pub mod tls;
@@ -1,94 +1,244 @@
+use crate::blockchain::blockchain::Blockchain;
 use crate::event_bus::event_bus::EventBus;
 use crate::event_bus::events::RustchainEvent;
-use crate::protos::{Peer, PeerList};
+use crate::protos::{Heartbeat, Peer, PeerList};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 
-use super::client_stubs::PeerClient;
+use super::chord::Chord;
+use super::connections::ConnectionManager;
+use super::failure_detector::{FailureDetector, PeerState};
+use super::gossip::Gossip;
+use super::identity::NodeIdentity;
+use super::kademlia::{self, Insertion, NodeId, RoutingTable, K};
+use super::middleware::PeerGuard;
+use super::peer_cache::PeerCache;
+use super::reputation::{Interaction, Reputation};
 use super::server_stubs::PeerServer;
+use super::sync::BlockSync;
+use super::tls::TlsConfig;
 
-const MAX_PEERS_LEN: usize = 32;
+// buckets no lookup went through for this many heartbeats are refreshed
+const REFRESH_HEARTBEATS: u32 = 50;
+// most heartbeat intervals a node that can't reach any peer waits to retry
+const MAX_RETRY_HEARTBEATS: u32 = 32;
+// how long a peer may take to answer a FIND_NODE
+const FIND_NODE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// where a node is registered and how long the registration lasts
+#[derive(Debug, Clone)]
+struct Registration {
+    seed: Peer,
+    ttl: Duration,
+}
+
+// How a node joins the network: the seed nodes it registers with and the
+// cache of peers it saw last time it ran, tried again on every restart.
+#[derive(Debug, Clone)]
+pub struct P2pConfig {
+    addr: SocketAddr,
+    heartbeat_interval: Duration,
+    // tried in turn until one of them answers
+    seeds: Vec<Peer>,
+    peer_cache: Option<PeerCache>,
+    // a fresh one is generated when not given, so the id changes on restart
+    identity: Option<NodeIdentity>,
+    // plaintext without
+    tls: Option<TlsConfig>,
+    // request budgets and bans of the node's clients
+    guard: PeerGuard,
+    // node ids allowed to list the bans
+    admins: Vec<String>,
+}
+
+impl P2pConfig {
+    pub fn new(addr: SocketAddr, heartbeat_interval: Duration) -> P2pConfig {
+        P2pConfig {
+            addr,
+            heartbeat_interval,
+            seeds: vec![],
+            peer_cache: None,
+            identity: None,
+            tls: None,
+            guard: PeerGuard::new(),
+            admins: vec![],
+        }
+    }
+
+    pub fn with_seeds(mut self, seeds: Vec<Peer>) -> P2pConfig {
+        self.seeds = seeds;
+        self
+    }
+
+    pub fn with_peer_cache(mut self, path: impl AsRef<Path>) -> P2pConfig {
+        self.peer_cache = Some(PeerCache::new(path));
+        self
+    }
+
+    // the key pair the node's id is derived from, see NodeIdentity::load_or_generate
+    pub fn with_identity(mut self, identity: NodeIdentity) -> P2pConfig {
+        self.identity = Some(identity);
+        self
+    }
+
+    // Mutual TLS for everything the node serves and sends, with a
+    // certificate for the identity above. See TlsConfig::dev for local runs.
+    pub fn with_tls(mut self, tls: TlsConfig) -> P2pConfig {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_guard(mut self, guard: PeerGuard) -> P2pConfig {
+        self.guard = guard;
+        self
+    }
+
+    pub fn with_admin(mut self, id: String) -> P2pConfig {
+        self.admins.push(id);
+        self
+    }
+}
 
 pub struct P2p {
     event_bus: Arc<RwLock<EventBus>>,
     id: String,
+    node_id: NodeId,
+    identity: NodeIdentity,
     addr: SocketAddr,
+    // contents of the routing table, the peers heartbeats and gossip go to
     peers: Arc<RwLock<Vec<Peer>>>,
+    table: Arc<RwLock<RoutingTable>>,
+    // position on the ring key-based lookups are routed over
+    chord: Arc<RwLock<Chord>>,
+    detector: FailureDetector,
+    // shared with chord, gossip and sync
+    connections: Arc<RwLock<ConnectionManager>>,
+    // trust in peers, shared with gossip and sync
+    reputation: Arc<RwLock<Reputation>>,
 }
 
 impl P2p {
-    pub async fn new(
+    pub async fn new(event_bus: Arc<RwLock<EventBus>>, config: P2pConfig) -> Arc<RwLock<P2p>> {
+        P2p::start(event_bus, config, None).await
+    }
+
+    // A full node: serves its chain to peers, advertises its tip in heartbeats
+    // and syncs blocks from peers ahead of it.
+    pub async fn with_blockchain(
         event_bus: Arc<RwLock<EventBus>>,
-        boot_node: Peer,
-        addr: SocketAddr,
-        heartbeat_interval: Duration,
+        config: P2pConfig,
+        blockchain: Arc<RwLock<Blockchain>>,
     ) -> Arc<RwLock<P2p>> {
-        let fallback_p2p = Arc::new(RwLock::new(P2p {
+        P2p::start(event_bus, config, Some(blockchain)).await
+    }
+
+    // Starts serving and every background task right away. Joining the
+    // network happens in the background too, so a node whose seeds are all
+    // down still joins once any of them, or any cached peer, comes up.
+    async fn start(
+        event_bus: Arc<RwLock<EventBus>>,
+        config: P2pConfig,
+        blockchain: Option<Arc<RwLock<Blockchain>>>,
+    ) -> Arc<RwLock<P2p>> {
+        let addr = config.addr;
+        let heartbeat_interval = config.heartbeat_interval;
+        let identity = config
+            .identity
+            .clone()
+            .unwrap_or_else(NodeIdentity::generate);
+        let node_id = identity.node_id();
+        let id = node_id.to_hex();
+        let tls = config.tls.clone();
+        if let Some(tls) = &tls {
+            assert_eq!(
+                node_id,
+                tls.node_id(),
+                "TLS certificate is for another node"
+            );
+        }
+        let connections = ConnectionManager::new(tls.clone()).shared();
+        let reputation = Reputation::new(id.clone()).shared();
+        let guard = config.guard.clone().shared();
+        if let Some(blockchain) = &blockchain {
+            let sync = BlockSync::new(event_bus.clone(), blockchain.clone()).await;
+            let mut lock = sync.write().await;
+            lock.use_connections(connections.clone());
+            lock.use_reputation(reputation.clone());
+            lock.use_guard(guard.clone());
+        }
+        let peers = Arc::new(RwLock::new(vec![]));
+        let table = Arc::new(RwLock::new(RoutingTable::new(node_id)));
+        let self_peer = Peer {
+            id: id.clone(),
+            ip: addr.ip().to_string(),
+            port: addr.port() as u32,
+        };
+        let chord = Chord::new(node_id, self_peer).with_connections(connections.clone());
+        let chord = Arc::new(RwLock::new(chord));
+        let p2p = P2p {
             event_bus: event_bus.clone(),
-            id: String::from(""),
+            id,
+            node_id,
+            identity,
             addr,
-            peers: Arc::new(RwLock::new(vec![])),
-        }));
+            peers: peers.clone(),
+            table: table.clone(),
+            chord: chord.clone(),
+            detector: FailureDetector::new(heartbeat_interval),
+            connections: connections.clone(),
+            reputation: reputation.clone(),
+        };
+        let p2p_arc = Arc::new(RwLock::new(p2p));
+        // relay blocks and transactions to the peers of the membership table
+        let gossip = Gossip::new(event_bus.clone(), addr, peers.clone()).await;
+        {
+            let mut lock = gossip.write().await;
+            lock.use_connections(connections);
+            lock.use_reputation(reputation);
+            lock.use_guard(guard.clone());
+        }
         // listen to other peers
-        let server = PeerServer::new(event_bus.clone(), addr);
+        let mut server = PeerServer::new(event_bus.clone(), addr)
+            .with_gossip(gossip.clone())
+            .with_routing_table(table.clone())
+            .with_chord(chord.clone())
+            .with_guard(guard);
+        for admin in &config.admins {
+            server = server.with_admin(admin.clone());
+        }
+        if let Some(blockchain) = &blockchain {
+            server = server.with_blockchain(blockchain.clone());
+        }
+        if let Some(tls) = tls {
+            server = server.with_tls(tls);
+        }
         spawn(async { server.serve().await });
-        let peer_list: Vec<Peer> = vec![];
-        let bootstrap_conn = PeerClient::new(boot_node.ip.as_str(), boot_node.port as u16).await;
-        // gotta figure out a cleaner way of avoiding this ugly match nesting
-        match bootstrap_conn {
-            Ok(mut peer) => {
-                let resp = peer.register(addr).await;
-                match resp {
-                    Ok(register_response) => {
-                        let id = register_response.peer.unwrap().id;
-                        let registered_peers = register_response.peers.unwrap();
-                        event_bus
-                            .write()
-                            .await
-                            .publish(RustchainEvent::NewPeers(registered_peers))
-                            .await;
-                        let peers = Arc::new(RwLock::new(peer_list));
-                        let p2p = P2p {
-                            event_bus: event_bus.clone(),
-                            id: id.clone(),
-                            addr,
-                            peers: peers.clone(),
-                        };
-                        let p2p_arc = Arc::new(RwLock::new(p2p));
-                        let p2p_clone = p2p_arc.clone();
-                        let event_receiver: Receiver<RustchainEvent> =
-                        event_bus.write().await.subscribe().await;
-                        spawn(
-                            async move { P2p::listen_for_events(p2p_clone, event_receiver).await },
-                        );
-                        let self_peer = Arc::new(Peer {
-                            id: id.clone(),
-                            ip: addr.ip().to_string(),
-                            port: addr.port() as u32,
-                        });
-                        spawn(async move {
-                            loop {
-                                P2p::send_heartbeats(peers.clone(), self_peer.clone()).await;
-                                tokio::time::sleep(heartbeat_interval).await;
-                            }
-                        });
-                        return p2p_arc;
-                    }
-                    Err(_) => {
-                        println!(
-                            "Peer running at port {} could not register to bootstrap node",
-                            addr.port()
-                        );
-                        return fallback_p2p.clone();
-                    }
-                }
-            }
-            Err(_) => return fallback_p2p.clone(),
+        // peers cached by an earlier run are contacted like any other
+        if let Some(cache) = &config.peer_cache {
+            P2p::add_peers(p2p_arc.clone(), PeerList::from(cache.load())).await;
         }
+        let p2p_clone = p2p_arc.clone();
+        let event_receiver: Receiver<RustchainEvent> = event_bus.write().await.subscribe().await;
+        spawn(async move { P2p::listen_for_events(p2p_clone, event_receiver).await });
+        let p2p_clone = p2p_arc.clone();
+        spawn(async move { P2p::discover(p2p_clone, config).await });
+        spawn(async move { Chord::maintain(chord, heartbeat_interval).await });
+        let p2p_clone = p2p_arc.clone();
+        spawn(async move {
+            loop {
+                P2p::send_heartbeats(p2p_clone.clone(), blockchain.clone(), heartbeat_interval)
+                    .await;
+                tokio::time::sleep(heartbeat_interval).await;
+            }
+        });
+        p2p_arc
     }
 
     async fn listen_for_events(
@@ -98,122 +248,389 @@ impl P2p {
         while let Some(event) = event_receiver.recv().await {
             match event {
                 RustchainEvent::NewHeartbeat(heartbeat) => {
-                    // add membership table and peer who sent heartbeat
-                    P2p::add_peers(p2p.clone(), heartbeat.peers.unwrap()).await;
-                    P2p::add_peer(p2p.clone(), heartbeat.peer.unwrap()).await;
+                    P2p::on_heartbeat(p2p.clone(), heartbeat).await;
                 }
                 RustchainEvent::NewPeers(peer_list) => {
                     P2p::add_peers(p2p.clone(), peer_list).await;
                 }
-                _ => {
-                    unimplemented!();
+                // announced by the peer itself, so it's alive whatever the
+                // detector thought
+                RustchainEvent::PeerJoined(peer) => {
+                    p2p.write().await.detector.heard_from(&peer.id);
+                    P2p::add_peer(p2p.clone(), peer).await;
                 }
+                RustchainEvent::PeerLeft(peer) => {
+                    p2p.write().await.detector.quarantine(&peer.id);
+                    P2p::evict(p2p.clone(), peer).await;
+                }
+                _ => {}
             }
         }
     }
 
+    // add membership table and peer who sent heartbeat. The sender is alive,
+    // even if it was evicted before, and its trust in others is taken in.
+    async fn on_heartbeat(p2p: Arc<RwLock<P2p>>, heartbeat: Heartbeat) {
+        if let Some(peer) = heartbeat.peer {
+            let reputation = {
+                let mut lock = p2p.write().await;
+                lock.detector.heard_from(&peer.id);
+                lock.reputation.clone()
+            };
+            reputation.write().await.heard(&peer.id, &heartbeat.trust);
+            P2p::add_peer(p2p.clone(), peer).await;
+        }
+        if let Some(peers) = heartbeat.peers {
+            P2p::add_peers(p2p.clone(), peers).await;
+        }
+    }
+
+    // a peer that proved its id, from a signed heartbeat or announcement, so
+    // its address replaces the one known
     async fn add_peer(p2p: Arc<RwLock<P2p>>, peer: Peer) {
         let lock = p2p.write().await;
-        let self_id = lock.id.clone();
-        let curr_peer_list = lock.peers.clone();
-        let mut peer_lock = curr_peer_list.write().await;
-        if self_id == peer.id || peer_lock.iter().any(|x| x.id == peer.id) {
-            return;
-        }
-        peer_lock.push(peer);
+        let mut table = lock.table.write().await;
+        let reputation = lock.reputation.read().await;
+        insert_peer(&mut table, &reputation, peer, RoutingTable::update);
+        *lock.peers.write().await = table.peers();
     }
 
+    // Peers heard of from others are only added as new contacts, once they
+    // answered a FIND_NODE for this node. What others say can't change the
+    // address of a known contact.
     async fn add_peers(p2p: Arc<RwLock<P2p>>, new_peer_list: PeerList) {
-        let curr_peer_list = p2p.write().await.peers.clone();
-        let mut peer_lock = curr_peer_list.write().await;
-        let new_peers = new_peer_list.peers;
-        for peer in new_peers {
-            let self_id = p2p.read().await.id.clone();
-            if peer.id == self_id || peer_lock.iter().any(|x| x.id == peer.id) {
+        let (node_id, self_peer, connections, candidates) = {
+            let mut lock = p2p.write().await;
+            let table = lock.table.clone();
+            let table = table.read().await;
+            let candidates: Vec<Peer> = new_peer_list
+                .peers
+                .into_iter()
+                .filter(|peer| kademlia::peer_id(peer).is_some_and(|id| !table.contains(&id)))
+                // peers evicted lately may still be on other peers' lists
+                .filter(|peer| !lock.detector.is_quarantined(&peer.id))
+                .collect();
+            (
+                lock.node_id,
+                lock.self_peer(),
+                lock.connections.clone(),
+                candidates,
+            )
+        };
+        let mut pings = JoinSet::new();
+        for peer in candidates
+            .into_iter()
+            .filter(|peer| peer.id != self_peer.id)
+        {
+            let (self_peer, connections) = (self_peer.clone(), connections.clone());
+            pings.spawn(async move {
+                let answer = find_node(peer.clone(), node_id, self_peer, connections).await;
+                (peer, answer.is_ok())
+            });
+        }
+        let mut answered = vec![];
+        while let Some(Ok((peer, ok))) = pings.join_next().await {
+            if ok {
+                answered.push(peer);
+            }
+        }
+        P2p::add_answered(p2p, answered).await;
+    }
+
+    // Peers that answered go to the routing table, which ignores self. Full
+    // buckets keep their contacts unless a newcomer is trusted more than one
+    // of them.
+    async fn add_answered(p2p: Arc<RwLock<P2p>>, peers: Vec<Peer>) {
+        let mut lock = p2p.write().await;
+        let table = lock.table.clone();
+        let mut table = table.write().await;
+        let reputation = lock.reputation.clone();
+        let reputation = reputation.read().await;
+        for peer in peers {
+            if lock.detector.is_quarantined(&peer.id) {
                 continue;
             }
-            peer_lock.push(peer.clone());
+            insert_peer(&mut table, &reputation, peer, RoutingTable::insert);
         }
+        *lock.peers.write().await = table.peers();
     }
 
-    async fn send_heartbeats(peers: Arc<RwLock<Vec<Peer>>>, self_peer: Arc<Peer>) {
-        let peers_copy = {
-            let guard = peers.read().await;
-            (*guard).clone()
+    // Joins the network, then every heartbeat interval saves the peers it
+    // knows to the cache and refreshes buckets no lookup went through for a
+    // while by looking up a random id in them. The registration with the seed
+    // is kept alive along the way. A node that loses every peer and its
+    // registration goes back to joining, the first one in the network just
+    // waits to be found.
+    async fn discover(p2p: Arc<RwLock<P2p>>, config: P2pConfig) {
+        let (node_id, table) = {
+            let lock = p2p.read().await;
+            (lock.node_id, lock.table.clone())
         };
-        P2p::rebalance(peers.clone(), (*self_peer).clone()).await;
-        for remote_peer in peers.clone().read().await.iter() {
-            let conn = PeerClient::new(&remote_peer.ip, remote_peer.port as u16).await;
-            match conn {
-                Ok(mut client) => {
-                    let block_hashes = vec![];
-                    let peer_list = PeerList {
-                        peers: peers_copy.clone(),
-                    };
-                    let _ = client.send_heartbeat(peer_list, (*self_peer).clone(), block_hashes);
+        let refresh_interval = config.heartbeat_interval * REFRESH_HEARTBEATS;
+        let mut saved = vec![];
+        loop {
+            let mut registration = P2p::join(p2p.clone(), &config).await;
+            let mut next_keep_alive = Instant::now() + keep_alive_after(&registration, &config);
+            while registration.is_some() || !table.read().await.is_empty() {
+                tokio::time::sleep(config.heartbeat_interval).await;
+                if Instant::now() >= next_keep_alive {
+                    P2p::keep_registered(p2p.clone(), &config, &mut registration).await;
+                    next_keep_alive = Instant::now() + keep_alive_after(&registration, &config);
                 }
-                Err(_) => {
-                    unimplemented!()
+                let stale = table.read().await.stale_buckets(refresh_interval);
+                for index in stale {
+                    P2p::lookup(p2p.clone(), node_id.random_in_bucket(index)).await;
+                }
+                let known = table.read().await.peers();
+                if let Some(cache) = config.peer_cache.as_ref().filter(|_| known != saved) {
+                    if let Err(e) = cache.save(&known) {
+                        println!(
+                            "Could not save peer cache {}: {}",
+                            cache.path().display(),
+                            e
+                        );
+                    }
+                    saved = known;
                 }
             }
+            println!(
+                "Peer running at port {} lost every peer",
+                config.addr.port()
+            );
         }
     }
 
-    async fn rebalance(peers: Arc<RwLock<Vec<Peer>>>, peer: Peer) {
-        let mut sorted_peers = peers.read().await.clone();
-        let self_id = peer.id.clone();
-        // Sort peers by Id
-        sorted_peers.sort_by(sort_peers_by_id);
-        if sorted_peers.len() < MAX_PEERS_LEN {
-            *peers.write().await = sorted_peers;
-            return;
+    // Registers with the seeds in turn and looks its own id up through the
+    // peers known so far, which fills the buckets close to it. Until some peer
+    // answers it keeps retrying, backing off up to MAX_RETRY_HEARTBEATS.
+    async fn join(p2p: Arc<RwLock<P2p>>, config: &P2pConfig) -> Option<Registration> {
+        let (node_id, table, chord) = {
+            let lock = p2p.read().await;
+            (lock.node_id, lock.table.clone(), lock.chord.clone())
+        };
+        let mut retry = config.heartbeat_interval;
+        loop {
+            let registration = P2p::register(p2p.clone(), config).await;
+            let found = P2p::lookup(p2p.clone(), node_id).await;
+            if registration.is_some() || !found.is_empty() {
+                join_ring(chord, table.read().await.peers()).await;
+                return registration;
+            }
+            println!(
+                "Peer running at port {} could not reach any known peer, retrying in {:?}",
+                config.addr.port(),
+                retry
+            );
+            tokio::time::sleep(retry).await;
+            retry = (retry * 2).min(config.heartbeat_interval * MAX_RETRY_HEARTBEATS);
         }
+    }
+
+    // Registers with the first seed that answers and adds the peers it hands
+    // out.
+    async fn register(p2p: Arc<RwLock<P2p>>, config: &P2pConfig) -> Option<Registration> {
+        let (identity, event_bus, connections) = {
+            let lock = p2p.read().await;
+            (
+                lock.identity.clone(),
+                lock.event_bus.clone(),
+                lock.connections.clone(),
+            )
+        };
+        let (registration, peers) =
+            register(&config.seeds, &identity, config.addr, &connections).await?;
+        event_bus
+            .write()
+            .await
+            .publish(RustchainEvent::NewPeers(peers.clone()))
+            .await;
+        P2p::add_peers(p2p, peers).await;
+        Some(registration)
+    }
 
-        let mut self_index = 0;
-        for i in 0..sorted_peers.len() {
-            if sorted_peers.get(i).unwrap().id.parse::<u32>().unwrap()
-                > self_id.parse::<u32>().unwrap()
-            {
-                self_index = i;
-                break;
+    // Refreshes the registration before it expires. Once it lapsed, the seed
+    // is gone, or the node never got to register, it registers again.
+    async fn keep_registered(
+        p2p: Arc<RwLock<P2p>>,
+        config: &P2pConfig,
+        registration: &mut Option<Registration>,
+    ) {
+        if let Some(current) = registration.as_mut() {
+            let (identity, connections) = {
+                let lock = p2p.read().await;
+                (lock.identity.clone(), lock.connections.clone())
+            };
+            match keep_alive(&current.seed, &identity, config.addr, &connections).await {
+                Ok(ttl) => {
+                    current.ttl = ttl;
+                    return;
+                }
+                Err(e) => println!(
+                    "Keepalive to seed node {}:{} failed: {}",
+                    current.seed.ip, current.seed.port, e
+                ),
             }
         }
-        let mut closest_peers = vec![];
-        let mut i: usize; 
-        let mut j: usize;
-        if self_index == 0 {
-            i = self_index;
-            j = self_index + 1;
-        } else {
-            i = self_index - 1;
-            j = self_index;
-        }
-        let mut counter: usize = 0;
-        while counter < MAX_PEERS_LEN {
-            counter += 2;
-            closest_peers.push(sorted_peers.get(i).unwrap().clone());
-            closest_peers.push(sorted_peers.get(j).unwrap().clone());
-            if i == 0 {
-                i = sorted_peers.len() - 1;
-            } else {
-                i -= 1;
+        *registration = P2p::register(p2p, config).await;
+    }
+
+    // Iterative FIND_NODE for `target` starting from the closest peers in the
+    // routing table. The peers that answer are added to it.
+    pub async fn lookup(p2p: Arc<RwLock<P2p>>, target: NodeId) -> Vec<Peer> {
+        let (node_id, self_peer, table, connections) = {
+            let lock = p2p.read().await;
+            (
+                lock.node_id,
+                lock.self_peer(),
+                lock.table.clone(),
+                lock.connections.clone(),
+            )
+        };
+        let seeds = table.read().await.closest(&target, K);
+        let found = kademlia::lookup(node_id, target, seeds, |peer| {
+            find_node(peer, target, self_peer.clone(), connections.clone())
+        })
+        .await;
+        table.write().await.refreshed(&target);
+        P2p::add_answered(p2p, found.clone()).await;
+        found
+    }
+
+    // Heartbeats every peer of the membership table at once. Peers that can't
+    // be reached in time miss a heartbeat, and are evicted once the failure
+    // detector declares them dead. Heartbeats carry this node's local trust,
+    // and global trust is computed again once every peer was heartbeated.
+    async fn send_heartbeats(
+        p2p: Arc<RwLock<P2p>>,
+        blockchain: Option<Arc<RwLock<Blockchain>>>,
+        heartbeat_timeout: Duration,
+    ) {
+        // full nodes advertise their tip and locator so lagging peers can sync
+        let (tip, block_hashes) = match &blockchain {
+            Some(blockchain) => {
+                let lock = blockchain.read().await;
+                (Some(lock.chain_tip()), lock.locator())
+            }
+            None => (None, vec![]),
+        };
+        let (peers_copy, mut heartbeat, connections, reputation) = {
+            let lock = p2p.read().await;
+            let peers_copy = lock.peers.read().await.clone();
+            let heartbeat = Heartbeat {
+                peers: Some(PeerList {
+                    peers: peers_copy.clone(),
+                }),
+                peer: Some(lock.self_peer()),
+                block_hashes,
+                tip,
+                proof: None,
+                trust: lock.reputation.read().await.trust_values(),
             };
-            if j == sorted_peers.len() - 1 {
-                j = 0;
-            } else {
-                j += 1;
+            (
+                peers_copy,
+                heartbeat,
+                lock.connections.clone(),
+                lock.reputation.clone(),
+            )
+        };
+        // every peer gets the same heartbeat, signed once
+        p2p.read().await.identity.prove_heartbeat(&mut heartbeat);
+        let mut heartbeats = JoinSet::new();
+        for remote_peer in peers_copy.iter().cloned() {
+            let (heartbeat, connections) = (heartbeat.clone(), connections.clone());
+            heartbeats.spawn(async move {
+                let sent = send_heartbeat(&remote_peer, heartbeat, &connections);
+                let sent = timeout(heartbeat_timeout, sent)
+                    .await
+                    .unwrap_or_else(|_| Err(String::from("timed out")));
+                (remote_peer, sent)
+            });
+        }
+        while let Some(Ok((remote_peer, sent))) = heartbeats.join_next().await {
+            let interaction = match sent {
+                Ok(_) => Interaction::Up,
+                Err(_) => Interaction::Down,
+            };
+            reputation
+                .write()
+                .await
+                .record(&remote_peer.id, interaction);
+            let state = {
+                let mut lock = p2p.write().await;
+                match sent {
+                    Ok(_) => {
+                        lock.detector.heard_from(&remote_peer.id);
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("Heartbeat to peer {} failed: {}", remote_peer.id, e);
+                        lock.detector.missed_heartbeat(&remote_peer.id)
+                    }
+                }
+            };
+            match state {
+                PeerState::Suspect => println!("Peer {} is suspected to be down", remote_peer.id),
+                PeerState::Dead => P2p::evict(p2p.clone(), remote_peer).await,
+                PeerState::Alive => {}
             }
         }
-        closest_peers.sort_by(sort_peers_by_id);
+        reputation.write().await.compute();
+    }
 
-        // Update the peers field with the new list of closest peers
-        *peers.write().await = closest_peers.clone();
+    // drops a dead or departed peer from the membership table and lets the
+    // other components know
+    async fn evict(p2p: Arc<RwLock<P2p>>, peer: Peer) {
+        let (peers, table, event_bus, connections, reputation) = {
+            let lock = p2p.read().await;
+            (
+                lock.peers.clone(),
+                lock.table.clone(),
+                lock.event_bus.clone(),
+                lock.connections.clone(),
+                lock.reputation.clone(),
+            )
+        };
+        connections.write().await.forget(&peer);
+        reputation.write().await.forget(&peer.id);
+        {
+            let mut table = table.write().await;
+            if let Some(node_id) = NodeId::from_hex(&peer.id) {
+                table.remove(&node_id);
+            }
+            *peers.write().await = table.peers();
+        }
+        println!("Evicted peer {}", peer.id);
+        event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::PeerRemoved(peer))
+            .await;
+    }
+
+    fn self_peer(&self) -> Peer {
+        Peer {
+            id: self.id.clone(),
+            ip: self.addr.ip().to_string(),
+            port: self.addr.port() as u32,
+        }
     }
 
     pub fn id(&self) -> String {
         return self.id.clone();
     }
 
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn chord(&self) -> Arc<RwLock<Chord>> {
+        self.chord.clone()
+    }
+
+    pub fn reputation(&self) -> Arc<RwLock<Reputation>> {
+        self.reputation.clone()
+    }
+
     pub fn get_addr(&self) -> SocketAddr {
         return self.addr.clone();
     }
@@ -224,10 +641,127 @@ impl P2p {
     }
 }
 
-fn sort_peers_by_id(a: &Peer, b: &Peer) -> std::cmp::Ordering {
-    a.id.parse::<u32>()
-        .unwrap_or(0)
-        .cmp(&b.id.parse::<u32>().unwrap_or(0))
+// registers with the first seed that answers, returning some of the peers
+// registered there
+async fn register(
+    seeds: &[Peer],
+    identity: &NodeIdentity,
+    addr: SocketAddr,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Option<(Registration, PeerList)> {
+    for seed in seeds {
+        let registered = ConnectionManager::call(connections, seed, |mut client| async move {
+            client.register(identity, addr).await
+        })
+        .await;
+        match registered {
+            Ok(resp) => {
+                let registration = Registration {
+                    seed: seed.clone(),
+                    ttl: Duration::from_millis(resp.ttl_millis),
+                };
+                return Some((registration, resp.peers.unwrap_or_default()));
+            }
+            Err(e) => println!(
+                "Could not register to seed node {}:{}: {}",
+                seed.ip, seed.port, e
+            ),
+        }
+    }
+    None
+}
+
+// the registration's new ttl
+async fn keep_alive(
+    seed: &Peer,
+    identity: &NodeIdentity,
+    addr: SocketAddr,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<Duration, String> {
+    let resp = ConnectionManager::call(connections, seed, |mut client| async move {
+        client.keep_alive(identity, addr).await
+    })
+    .await?;
+    Ok(Duration::from_millis(resp.ttl_millis))
+}
+
+// keepalives go out a few times per ttl, so one lost on the way doesn't
+// expire the registration. Nodes that aren't registered retry rarely.
+fn keep_alive_after(registration: &Option<Registration>, config: &P2pConfig) -> Duration {
+    match registration {
+        Some(registration) => registration.ttl / 3,
+        None => config.heartbeat_interval * MAX_RETRY_HEARTBEATS,
+    }
+}
+
+// joins the ring through the first known peer that answers, a node that
+// knows none starts a ring of its own
+async fn join_ring(chord: Arc<RwLock<Chord>>, known: Vec<Peer>) {
+    let id = chord.read().await.id().to_hex();
+    for peer in known.into_iter().filter(|p| p.id != id) {
+        match Chord::join(chord.clone(), peer).await {
+            Ok(_) => return,
+            Err(e) => println!("Could not join the ring: {}", e),
+        }
+    }
+}
+
+// A full bucket makes room for the peer by dropping its least trusted
+// contact, if that one is trusted less than the peer.
+fn insert_peer(
+    table: &mut RoutingTable,
+    reputation: &Reputation,
+    peer: Peer,
+    insert: fn(&mut RoutingTable, Peer) -> Insertion,
+) {
+    if insert(table, peer.clone()) != Insertion::BucketFull {
+        return;
+    }
+    let contacts = match kademlia::peer_id(&peer) {
+        Some(id) => table.bucket_of(&id),
+        None => return,
+    };
+    let victim = reputation
+        .eviction_victim(&contacts, &peer)
+        .and_then(|victim| kademlia::peer_id(&victim).map(|id| (victim, id)));
+    if let Some((victim, victim_id)) = victim {
+        println!(
+            "Replacing peer {} by more trusted peer {}",
+            victim.id, peer.id
+        );
+        table.remove(&victim_id);
+        table.insert(peer);
+    }
+}
+
+async fn send_heartbeat(
+    peer: &Peer,
+    heartbeat: Heartbeat,
+    connections: &Arc<RwLock<ConnectionManager>>,
+) -> Result<(), String> {
+    ConnectionManager::call(connections, peer, |mut client| async move {
+        client.send_heartbeat(heartbeat).await
+    })
+    .await?;
+    Ok(())
+}
+
+async fn find_node(
+    peer: Peer,
+    target: NodeId,
+    sender: Peer,
+    connections: Arc<RwLock<ConnectionManager>>,
+) -> Result<Vec<Peer>, String> {
+    let request = async {
+        let found = ConnectionManager::call(&connections, &peer, |mut client| async move {
+            client.find_node(&target, sender).await
+        })
+        .await?;
+        Ok(found.peers)
+    };
+    timeout(FIND_NODE_TIMEOUT, request)
+        .await
+        .unwrap_or_else(|_| Err(String::from("timed out")))
 }
 
 pub fn print_membership_table(id: String, peers: Vec<Peer>) {
@@ -249,6 +783,10 @@ pub mod tests {
     use crate::{net::bootstrap_node::BootstrapNode, protos::Peer};
     use std::{net::SocketAddr, time::Duration};
 
+    use crate::net::client_stubs::PeerClient;
+    use crate::net::server_stubs::REMOVE_PEER;
+    use crate::protos::{p2p_client::P2pClient, rustchain_client::RustchainClient};
+    use crate::protos::{Block, PeerRequest, Transaction};
     use crate::{event_bus::event_bus::EventBus, net::networking::get_addr};
 
     fn addr_1() -> SocketAddr {
@@ -271,6 +809,10 @@ pub mod tests {
         Duration::from_millis(200)
     }
 
+    fn seeded(addr: SocketAddr, seed: &Peer) -> P2pConfig {
+        P2pConfig::new(addr, heartbeat_interval()).with_seeds(vec![seed.clone()])
+    }
+
     fn get_bootstrap_node() -> (Peer, BootstrapNode) {
         let boot_node_addr: SocketAddr = get_addr("127.0.0.1", 5000);
         let boot_node = Peer {
@@ -287,53 +829,86 @@ pub mod tests {
         spawn(async { bootstrap.serve().await });
         sleep(Duration::from_millis(100)).await;
         let event_bus = EventBus::new().await;
-        let peer_1 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_1(),
-            heartbeat_interval(),
-        )
-        .await;
-        let peer_2 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_2(),
-            heartbeat_interval(),
-        )
-        .await;
-        let peer_3 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_3(),
-            heartbeat_interval(),
-        )
-        .await;
-        let peer_4 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_4(),
-            heartbeat_interval(),
-        )
-        .await;
+        let peer_1 = P2p::new(event_bus.clone(), seeded(addr_1(), &boot_peer)).await;
+        let peer_2 = P2p::new(event_bus.clone(), seeded(addr_2(), &boot_peer)).await;
+        let peer_3 = P2p::new(event_bus.clone(), seeded(addr_3(), &boot_peer)).await;
+        let peer_4 = P2p::new(event_bus.clone(), seeded(addr_4(), &boot_peer)).await;
         sleep(Duration::from_secs(2)).await;
 
-        print_membership_table(1.to_string(), peer_1.read().await.peers.read().await.clone());
-        for i in 1..5 {
-            if i > 1 {
-                assert!(peer_1.read().await.peers.read().await.iter().any(|p| (*p.id).parse::<u32>().unwrap() == i));
-            }
-            if i == 1 || i > 3 {
-                assert!(peer_2.read().await.peers.read().await.iter().any(|p| (*p.id).parse::<u32>().unwrap() == i));
-            }
-            if (i >= 1 && i <= 2) || (i == 4) {
-                assert!(peer_3.read().await.peers.read().await.iter().any(|p| (*p.id).parse::<u32>().unwrap() == i));
-            }
-            if i < 4 {
-                assert!(peer_4.read().await.peers.read().await.iter().any(|p| (*p.id).parse::<u32>().unwrap() == i));
+        let nodes = [peer_1, peer_2, peer_3, peer_4];
+        let mut ids = vec![];
+        for node in nodes.iter() {
+            ids.push(node.read().await.id());
+        }
+        print_membership_table(ids[0].clone(), nodes[0].read().await.get_peers().await);
+        // few enough peers for every one to fit in the others' buckets
+        for (i, node) in nodes.iter().enumerate() {
+            let peers = node.read().await.get_peers().await;
+            assert_eq!(3, peers.len());
+            for (j, id) in ids.iter().enumerate() {
+                assert_eq!(i != j, peers.iter().any(|p| p.id == *id));
             }
         }
     }
 
+    #[tokio::test]
+    async fn test_evicts_dead_peer() {
+        let event_bus = EventBus::new().await;
+        let mut receiver = event_bus.write().await.subscribe().await;
+        let addr = get_addr("127.0.0.1", 5005);
+        let config = P2pConfig::new(addr, heartbeat_interval());
+        let p2p = P2p::new(event_bus, config).await;
+        // nothing listens on its port
+        let dead = Peer {
+            id: NodeId::from_key(b"dead").to_hex(),
+            ip: String::from("127.0.0.1"),
+            port: 5006,
+        };
+        P2p::add_peer(p2p.clone(), dead.clone()).await;
+        for _ in 0..4 {
+            P2p::send_heartbeats(p2p.clone(), None, heartbeat_interval()).await;
+        }
+        assert!(p2p.read().await.get_peers().await.is_empty());
+        let removed = loop {
+            if let Some(RustchainEvent::PeerRemoved(peer)) = receiver.recv().await {
+                break peer;
+            }
+        };
+        assert_eq!(dead, removed);
+
+        // other peers' lists don't bring it back, its own heartbeat does
+        P2p::add_peers(p2p.clone(), PeerList::from(vec![dead.clone()])).await;
+        assert!(p2p.read().await.get_peers().await.is_empty());
+        let heartbeat = Heartbeat {
+            peer: Some(dead.clone()),
+            peers: Some(PeerList::default()),
+            ..Heartbeat::default()
+        };
+        P2p::on_heartbeat(p2p.clone(), heartbeat).await;
+        assert_eq!(vec![dead], p2p.read().await.get_peers().await);
+    }
+
+    #[tokio::test]
+    async fn test_others_cannot_move_contacts() {
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5044), heartbeat_interval());
+        let p2p = P2p::new(EventBus::new().await, config).await;
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5045), heartbeat_interval());
+        let other = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(100)).await;
+        let contact = other.read().await.self_peer();
+        P2p::add_peer(p2p.clone(), contact.clone()).await;
+        // a list claiming it moved, and a peer that doesn't answer
+        let mut moved = contact.clone();
+        moved.port = 5046;
+        let silent = Peer {
+            id: NodeId::from_key(b"silent").to_hex(),
+            ip: String::from("127.0.0.1"),
+            port: 5046,
+        };
+        P2p::add_peers(p2p.clone(), PeerList::from(vec![moved, silent])).await;
+        assert_eq!(vec![contact], p2p.read().await.get_peers().await);
+    }
+
     #[tokio::test]
     async fn test_registration() {
         // create event_bus, bootstrap node
@@ -343,153 +918,327 @@ pub mod tests {
         // wait for bootstrap_node to start
         // create 2 P2P peers and register
         sleep(Duration::from_millis(100)).await;
-        let peer_1 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_1(),
-            heartbeat_interval(),
-        )
-        .await;
-        let peer_2 = P2p::new(
-            event_bus.clone(),
-            boot_peer.clone(),
-            addr_2(),
-            heartbeat_interval(),
-        )
-        .await;
-        sleep(Duration::from_millis(100)).await;
-        let id_1 = 1.to_string();
-        let id_2 = 2.to_string();
-        assert_eq!(id_1, peer_1.read().await.id()); // first assigned id should be 1
-        assert_eq!(id_2, peer_2.read().await.id()); // first assigned id should be 1
+        let peer_1 = P2p::new(event_bus.clone(), seeded(addr_1(), &boot_peer)).await;
+        let peer_2 = P2p::new(event_bus.clone(), seeded(addr_2(), &boot_peer)).await;
+        sleep(Duration::from_millis(300)).await;
+        // nodes register with the ids derived from their keys
+        let mut client = PeerClient::new(&boot_peer.ip, boot_peer.port as u16)
+            .await
+            .unwrap();
+        let identity = NodeIdentity::generate();
+        let registered = client.register(&identity, addr_3()).await.unwrap();
+        let registered = registered.peers.unwrap().peers;
+        for peer in [peer_1, peer_2] {
+            let lock = peer.read().await;
+            assert_eq!(lock.node_id().to_hex(), lock.id());
+            assert!(registered.iter().any(|p| p.id == lock.id()));
+        }
     }
 
     #[tokio::test]
-    async fn test_rebalance_below_max_size() {
+    async fn test_lookup_discovers_peers() {
         let event_bus = EventBus::new().await;
-        let peer_1 = P2p::new(event_bus, Peer::default(), addr_1(), heartbeat_interval()).await;
-        let mut expected = vec![];
-        for i in (0..5).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            // inserting nodes in different order
-            let node_lock = peer_1.write().await;
-            let mut peers_lock = node_lock.peers.write().await;
-            if i % 2 == 0 {
-                peers_lock.insert(0, peer.clone());
-            } else {
-                peers_lock.push(peer.clone());
-            }
-            expected.insert(0, peer.clone());
+        let mut nodes = vec![];
+        for port in 5007..5010 {
+            let addr = get_addr("127.0.0.1", port);
+            let config = P2pConfig::new(addr, heartbeat_interval());
+            nodes.push(P2p::new(event_bus.clone(), config).await);
+        }
+        sleep(Duration::from_millis(100)).await;
+        let mut peers = vec![];
+        for node in nodes.iter() {
+            peers.push(node.read().await.self_peer());
         }
-        let peer = Peer {
-            id: 5.to_string(),
-            ..Peer::default()
+        // a line: the first node only knows the second, which knows the third
+        P2p::add_peer(nodes[0].clone(), peers[1].clone()).await;
+        P2p::add_peer(nodes[1].clone(), peers[2].clone()).await;
+        let node_id = nodes[0].read().await.node_id();
+        let found = P2p::lookup(nodes[0].clone(), node_id).await;
+        assert_eq!(2, found.len());
+        let known = nodes[0].read().await.get_peers().await;
+        assert!(known.contains(&peers[1]));
+        assert!(known.contains(&peers[2]));
+    }
+
+    #[tokio::test]
+    async fn test_keeps_its_registration_alive() {
+        let boot_addr = get_addr("127.0.0.1", 5028);
+        let boot_peer = Peer {
+            id: String::new(),
+            ip: boot_addr.ip().to_string(),
+            port: boot_addr.port() as u32,
         };
-        P2p::rebalance(peer_1.read().await.peers.clone(), peer.clone()).await;
-        assert_eq!(expected, peer_1.read().await.peers.read().await.clone());
+        let bootstrap = BootstrapNode::new(boot_addr).with_ttl(Duration::from_millis(600));
+        spawn(async move { bootstrap.serve().await });
+        sleep(Duration::from_millis(100)).await;
+        let config = seeded(get_addr("127.0.0.1", 5029), &boot_peer);
+        let peer_1 = P2p::new(EventBus::new().await, config).await;
+        // well past the ttl, the only node in the network is still registered
+        sleep(Duration::from_millis(1500)).await;
+        let mut client = PeerClient::new(&boot_peer.ip, boot_peer.port as u16)
+            .await
+            .unwrap();
+        let registered = client
+            .register(&NodeIdentity::generate(), get_addr("127.0.0.1", 5030))
+            .await
+            .unwrap();
+        let id_1 = peer_1.read().await.id();
+        assert!(registered.peers.unwrap().peers.iter().any(|p| p.id == id_1));
     }
 
     #[tokio::test]
-    async fn test_rebalance_above_max_size() {
-        let event_bus = EventBus::new().await;
-        let peer_1 = P2p::new(event_bus, Peer::default(), addr_1(), heartbeat_interval()).await;
-        let target_peer_id = 13;
-        let num_peers = 32;
-        let target_peer = Peer {
-            id: target_peer_id.to_string(),
-            ..Peer::default()
+    async fn test_membership_rpcs() {
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5031), heartbeat_interval());
+        let p2p = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(100)).await;
+        let client = || PeerClient::new("127.0.0.1", 5031);
+        let identity = NodeIdentity::generate();
+        let addr = get_addr("127.0.0.1", 5032);
+        client()
+            .await
+            .unwrap()
+            .add_peer(&identity, addr)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let peers = client().await.unwrap().get_peers().await.unwrap().peers;
+        assert_eq!(
+            vec![identity.id()],
+            peers.iter().map(|p| p.id.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(peers, p2p.read().await.get_peers().await);
+
+        // nodes can't remove others
+        let impostor = NodeIdentity::generate();
+        let mut forged = PeerRequest {
+            peer: Some(peers[0].clone()),
+            proof: Some(impostor.prove_request(REMOVE_PEER, &peers[0])),
         };
+        let mut p2p_client = P2pClient::connect("http://127.0.0.1:5031").await.unwrap();
+        let status = p2p_client.remove_peer(forged.clone()).await.unwrap_err();
+        assert_eq!(tonic::Code::Unauthenticated, status.code());
+        forged.proof = None;
+        assert!(p2p_client.remove_peer(forged).await.is_err());
 
-        let mut all_peers = vec![];
-        for i in (0..num_peers).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            all_peers.push(peer.clone());
-        }
+        // a node that left stays out of the lists others pass around
+        client()
+            .await
+            .unwrap()
+            .remove_peer(&identity, addr)
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert!(client()
+            .await
+            .unwrap()
+            .get_peers()
+            .await
+            .unwrap()
+            .peers
+            .is_empty());
+        P2p::add_peers(p2p.clone(), PeerList::from(peers)).await;
+        assert!(p2p.read().await.get_peers().await.is_empty());
+    }
 
-        // The expected list should contain the closest 32 peers.
-        let mut expected = all_peers.into_iter().take(32).collect::<Vec<Peer>>();
-        expected.sort_by(sort_peers_by_id);
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let boot_addr = get_addr("127.0.0.1", 5033);
+        let boot_peer = Peer {
+            id: String::new(),
+            ip: boot_addr.ip().to_string(),
+            port: boot_addr.port() as u32,
+        };
+        let boot_tls = TlsConfig::dev(&NodeIdentity::generate());
+        let bootstrap = BootstrapNode::new(boot_addr).with_tls(boot_tls);
+        spawn(async move { bootstrap.serve().await });
+        sleep(Duration::from_millis(100)).await;
+        let mut nodes = vec![];
+        for port in [5034, 5035] {
+            let identity = NodeIdentity::generate();
+            let config = seeded(get_addr("127.0.0.1", port), &boot_peer)
+                .with_tls(TlsConfig::dev(&identity))
+                .with_identity(identity);
+            nodes.push(P2p::new(EventBus::new().await, config).await);
+        }
+        sleep(Duration::from_millis(1000)).await;
+        // nodes find each other and exchange heartbeats over TLS
+        let peer_1 = nodes[0].read().await.self_peer();
+        let peer_2 = nodes[1].read().await.self_peer();
+        assert!(nodes[0].read().await.get_peers().await.contains(&peer_2));
+        assert!(nodes[1].read().await.get_peers().await.contains(&peer_1));
 
-        // Insert peers into the peers list in a different order.
-        for i in (0..num_peers).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            let node_lock = peer_1.write().await;
-            let mut peers_lock = node_lock.peers.write().await;
-            if i % 2 == 0 {
-                peers_lock.insert(0, peer.clone());
-            } else {
-                peers_lock.push(peer.clone());
-            }
+        // plaintext clients are turned away
+        if let Ok(mut client) = PeerClient::new("127.0.0.1", 5034).await {
+            assert!(client.get_peers().await.is_err());
         }
+        // the node at an address has to be the one the client asks for
+        let identity = NodeIdentity::generate();
+        let tls = TlsConfig::dev(&identity);
+        let impostor = Peer {
+            id: peer_2.id.clone(),
+            ..peer_1.clone()
+        };
+        assert!(PeerClient::connect(&impostor, Some(&tls)).await.is_err());
+        // and clients can only speak for the node their certificate is for
+        let mut client = PeerClient::connect(&peer_1, Some(&tls)).await.unwrap();
+        let addr = get_addr("127.0.0.1", 5036);
+        let status = client
+            .add_peer(&NodeIdentity::generate(), addr)
+            .await
+            .unwrap_err();
+        assert!(status.to_string().contains("certificate"));
+        client.add_peer(&identity, addr).await.unwrap();
+    }
 
-        // Run the rebalance function.
-        P2p::rebalance(peer_1.read().await.peers.clone(), target_peer.clone()).await;
+    #[tokio::test]
+    async fn test_bans_misbehaving_clients() {
+        let admin = NodeIdentity::generate();
+        // both stacks, so clients over ipv4 and ipv6 are told apart
+        let config =
+            P2pConfig::new(get_addr("[::]", 5039), heartbeat_interval()).with_admin(admin.id());
+        let _p2p = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(100)).await;
+        let mut offender = RustchainClient::connect("http://127.0.0.1:5039")
+            .await
+            .unwrap();
+        let malformed = Transaction::default();
+        let status = offender.send_transaction(malformed).await.unwrap_err();
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        // two blocks without proof of work are one too many
+        for _ in 0..2 {
+            let status = offender.send_block(Block::default()).await.unwrap_err();
+            assert_eq!(tonic::Code::InvalidArgument, status.code());
+        }
+        let status = offender.send_block(Block::default()).await.unwrap_err();
+        assert_eq!(tonic::Code::PermissionDenied, status.code());
 
-        // Check the results.
-        let rebalanced = peer_1.read().await.peers.read().await.clone();
-        assert_eq!(expected, rebalanced);
+        let mut client = PeerClient::new("[::1]", 5039).await.unwrap();
+        let bans = client.list_bans(&admin).await.unwrap().bans;
+        assert_eq!(1, bans.len());
+        assert_eq!("127.0.0.1", bans[0].offender);
+        assert_eq!("sent an invalid block", bans[0].reason);
+        // nobody else can see them
+        assert!(client.list_bans(&NodeIdentity::generate()).await.is_err());
     }
 
     #[tokio::test]
-    async fn test_rebalance_above_max_size_cyclical() {
-        let event_bus = EventBus::new().await;
-        let peer_1 = P2p::new(event_bus, Peer::default(), addr_1(), heartbeat_interval()).await;
-        let target_peer_id = 53;
-        let num_peers = 33;
-        let target_peer = Peer {
-            id: target_peer_id.to_string(),
-            ..Peer::default()
+    async fn test_joins_once_a_known_peer_is_up() {
+        // separate buses, so nodes only learn about each other over the network
+        let boot_addr = get_addr("127.0.0.1", 5021);
+        let boot_peer = Peer {
+            id: String::new(),
+            ip: boot_addr.ip().to_string(),
+            port: boot_addr.port() as u32,
+        };
+        // the first seed never answers, the second one comes up late
+        let down = Peer {
+            port: 5025,
+            ..boot_peer.clone()
         };
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5022), heartbeat_interval())
+            .with_seeds(vec![down, boot_peer.clone()]);
+        let peer_1 = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(300)).await;
+        assert!(peer_1.read().await.get_peers().await.is_empty());
+        spawn(async move { BootstrapNode::new(boot_addr).serve().await });
+        sleep(Duration::from_millis(1000)).await;
+        let config = seeded(get_addr("127.0.0.1", 5023), &boot_peer);
+        let peer_2 = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(500)).await;
+        let id_1 = peer_1.read().await.id();
+        assert!(peer_2
+            .read()
+            .await
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.id == id_1));
 
-        let mut all_peers = vec![];
-        for i in (0..10).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            all_peers.push(peer.clone());
+        // a restarted node rejoins through its cached peers, no seeds needed
+        let cache_path = std::env::temp_dir().join(format!("rustchain-{}.peers", id_1));
+        let cache = PeerCache::new(&cache_path);
+        cache.save(&[peer_1.read().await.self_peer()]).unwrap();
+        let config = P2pConfig::new(get_addr("127.0.0.1", 5024), heartbeat_interval())
+            .with_peer_cache(&cache_path);
+        let peer_3 = P2p::new(EventBus::new().await, config).await;
+        sleep(Duration::from_millis(500)).await;
+        let id_2 = peer_2.read().await.id();
+        assert!(peer_3
+            .read()
+            .await
+            .get_peers()
+            .await
+            .iter()
+            .any(|p| p.id == id_2));
+        // and keeps the cache up to date
+        assert!(cache.load().iter().any(|p| p.id == id_2));
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peers_that_answer_are_trusted() {
+        // separate buses, so trust only comes from heartbeats
+        let boot_addr = get_addr("127.0.0.1", 5040);
+        let boot_peer = Peer {
+            id: String::new(),
+            ip: boot_addr.ip().to_string(),
+            port: boot_addr.port() as u32,
+        };
+        spawn(async move { BootstrapNode::new(boot_addr).serve().await });
+        sleep(Duration::from_millis(100)).await;
+        let mut nodes = vec![];
+        for port in [5041, 5042, 5043] {
+            let config = seeded(get_addr("127.0.0.1", port), &boot_peer);
+            nodes.push(P2p::new(EventBus::new().await, config).await);
         }
-        for i in (11..num_peers).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            all_peers.push(peer.clone());
+        sleep(Duration::from_secs(2)).await;
+        let mut ids = vec![];
+        for node in &nodes {
+            ids.push(node.read().await.id());
         }
-
-        // The expected list should contain the closest 32 peers.
-        let mut expected = all_peers.into_iter().take(32).collect::<Vec<Peer>>();
-        expected.sort_by(sort_peers_by_id);
-
-        // Insert peers into the peers list in a different order.
-        for i in (0..num_peers).rev() {
-            let peer = Peer {
-                id: (i * 2).to_string(),
-                ..Peer::default()
-            };
-            let node_lock = peer_1.write().await;
-            let mut peers_lock = node_lock.peers.write().await;
-            if i % 2 == 0 {
-                peers_lock.insert(0, peer.clone());
-            } else {
-                peers_lock.push(peer.clone());
+        for (i, node) in nodes.iter().enumerate() {
+            let reputation = node.read().await.reputation();
+            let reputation = reputation.read().await;
+            let mut peers = node.read().await.get_peers().await;
+            assert_eq!(2, peers.len());
+            for (j, id) in ids.iter().enumerate() {
+                assert_eq!(i != j, reputation.trust(id) > 0.0);
             }
+            let total: f64 = peers.iter().map(|p| reputation.trust(&p.id)).sum();
+            assert!(total <= 1.0);
+            reputation.rank(&mut peers);
+            assert!(reputation.trust(&peers[0].id) >= reputation.trust(&peers[1].id));
         }
+    }
 
-        // Run the rebalance function.
-        P2p::rebalance(peer_1.read().await.peers.clone(), target_peer.clone()).await;
-
-        // Check the results.
-        let rebalanced = peer_1.read().await.peers.read().await.clone();
-        assert_eq!(expected, rebalanced);
+    #[tokio::test]
+    async fn test_unsigned_heartbeats_are_refused() {
+        let event_bus = EventBus::new().await;
+        let mut receiver = event_bus.write().await.subscribe().await;
+        let addr = get_addr("127.0.0.1", 5026);
+        let server = PeerServer::new(event_bus, addr);
+        spawn(async move { server.serve().await });
+        sleep(Duration::from_millis(100)).await;
+        let identity = NodeIdentity::generate();
+        let mut heartbeat = Heartbeat {
+            peer: Some(Peer {
+                id: identity.id(),
+                ip: String::from("127.0.0.1"),
+                port: 5027,
+            }),
+            ..Heartbeat::default()
+        };
+        let mut client = PeerClient::new("127.0.0.1", 5026).await.unwrap();
+        assert!(client.send_heartbeat(heartbeat.clone()).await.is_err());
+        // nor can a node speak for another one
+        let mut impostor = heartbeat.clone();
+        NodeIdentity::generate().prove_heartbeat(&mut impostor);
+        assert!(client.send_heartbeat(impostor).await.is_err());
+        identity.prove_heartbeat(&mut heartbeat);
+        client.send_heartbeat(heartbeat.clone()).await.unwrap();
+        match receiver.recv().await {
+            Some(RustchainEvent::NewHeartbeat(received)) => assert_eq!(heartbeat, received),
+            _ => panic!("expected the signed heartbeat"),
+        }
     }
 }
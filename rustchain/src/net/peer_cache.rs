@@ -0,0 +1,96 @@
+use crate::protos::{Peer, PeerList};
+use prost::Message;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Peers known at the last save, so a restarted node can rejoin through them
+// even when no seed node is up. Stored as a prost-encoded PeerList written to
+// a temp file first and renamed over the old one, so a crash mid-write never
+// leaves a torn cache behind.
+#[derive(Debug, Clone)]
+pub struct PeerCache {
+    path: PathBuf,
+}
+
+impl PeerCache {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        PeerCache {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // a missing or unreadable cache is just empty, the seeds are still there
+    pub fn load(&self) -> Vec<Peer> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(_) => return vec![],
+        };
+        match PeerList::decode(bytes.as_slice()) {
+            Ok(peer_list) => peer_list.peers,
+            Err(e) => {
+                println!("Peer cache {} is corrupted: {}", self.path.display(), e);
+                vec![]
+            }
+        }
+    }
+
+    pub fn save(&self, peers: &[Peer]) -> io::Result<()> {
+        let peer_list = PeerList::from(peers.to_vec());
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&peer_list.encode_to_vec())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_cache_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        temp_dir().join(format!("rustchain-{}-{}.peers", name, nanos))
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let cache = PeerCache::new(temp_cache_path("save"));
+        assert!(cache.load().is_empty());
+        let peers = vec![
+            Peer {
+                id: String::from("a"),
+                ip: String::from("127.0.0.1"),
+                port: 1,
+            },
+            Peer {
+                id: String::from("b"),
+                ip: String::from("::1"),
+                port: 2,
+            },
+        ];
+        cache.save(&peers).unwrap();
+        assert_eq!(peers, cache.load());
+        cache.save(&peers[1..]).unwrap();
+        assert_eq!(peers[1..].to_vec(), cache.load());
+        fs::remove_file(cache.path()).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_cache_is_empty() {
+        let cache = PeerCache::new(temp_cache_path("corrupted"));
+        fs::write(cache.path(), [0xff, 0xff, 0xff]).unwrap();
+        assert!(cache.load().is_empty());
+        fs::remove_file(cache.path()).unwrap();
+    }
+}
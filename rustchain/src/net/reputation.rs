@@ -0,0 +1,276 @@
+use crate::protos::{Peer, TrustValue};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// share of trust that goes back to this node on every step of the iteration,
+// so peers only the untrusted vouch for stay untrusted
+const RESTART: f64 = 0.15;
+const MAX_ITERATIONS: usize = 50;
+// iteration stops once trust moves less than this in total
+const CONVERGED: f64 = 1e-6;
+// most trust values sent in a heartbeat, the highest ones
+pub const MAX_TRUST_VALUES: usize = 64;
+
+// What a node went through with a peer, and how much it counts towards its
+// local trust in the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    // relayed a block that joined the chain, or one that was rejected
+    ValidBlock,
+    InvalidBlock,
+    // answered a sync request, or didn't
+    Answered,
+    Unanswered,
+    // took a heartbeat, or couldn't be reached
+    Up,
+    Down,
+}
+
+impl Interaction {
+    // (satisfactory, unsatisfactory)
+    fn weight(&self) -> (u64, u64) {
+        match self {
+            Interaction::ValidBlock => (5, 0),
+            Interaction::InvalidBlock => (0, 20),
+            Interaction::Answered => (2, 0),
+            Interaction::Unanswered => (0, 2),
+            Interaction::Up => (1, 0),
+            Interaction::Down => (0, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Experience {
+    satisfactory: u64,
+    unsatisfactory: u64,
+}
+
+// EigenTrust reputation of peers, as seen from this node. Local trust in a
+// peer comes from this node's own interactions with it. Peers share their
+// local trust in heartbeats, and global trust weighs each peer's opinion by
+// the global trust in that peer, iterating from this node's own opinion
+// until it converges. Trust values are shares, they sum up to at most 1.
+#[derive(Debug, Clone)]
+pub struct Reputation {
+    // this node, where the iteration starts and restarts
+    id: String,
+    experience: HashMap<String, Experience>,
+    // local trust vectors peers sent, by peer id
+    opinions: HashMap<String, HashMap<String, f64>>,
+    global: HashMap<String, f64>,
+}
+
+impl Reputation {
+    pub fn new(id: String) -> Reputation {
+        Reputation {
+            id,
+            experience: HashMap::new(),
+            opinions: HashMap::new(),
+            global: HashMap::new(),
+        }
+    }
+
+    pub fn shared(self) -> Arc<RwLock<Reputation>> {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn record(&mut self, peer_id: &str, interaction: Interaction) {
+        if peer_id.is_empty() || peer_id == self.id {
+            return;
+        }
+        let (satisfactory, unsatisfactory) = interaction.weight();
+        let experience = self.experience.entry(peer_id.to_string()).or_default();
+        experience.satisfactory += satisfactory;
+        experience.unsatisfactory += unsatisfactory;
+    }
+
+    // Normalized local trust, c(i, j) in the paper. Peers that let this node
+    // down more often than not get none.
+    pub fn local_trust(&self) -> HashMap<String, f64> {
+        let trust = self.experience.iter().filter_map(|(id, e)| {
+            let score = e.satisfactory.saturating_sub(e.unsatisfactory);
+            (score > 0).then(|| (id.clone(), score as f64))
+        });
+        normalized(trust.collect())
+    }
+
+    // the local trust this node shares in its heartbeats
+    pub fn trust_values(&self) -> Vec<TrustValue> {
+        let mut values: Vec<TrustValue> = self
+            .local_trust()
+            .into_iter()
+            .map(|(id, value)| TrustValue { id, value })
+            .collect();
+        values.sort_by(|a, b| b.value.partial_cmp(&a.value).unwrap_or(Ordering::Equal));
+        values.truncate(MAX_TRUST_VALUES);
+        values
+    }
+
+    // takes in the local trust `peer_id` shared, replacing what it sent before
+    pub fn heard(&mut self, peer_id: &str, values: &[TrustValue]) {
+        if peer_id == self.id {
+            return;
+        }
+        let opinion = values
+            .iter()
+            .take(MAX_TRUST_VALUES)
+            .filter(|v| v.id != peer_id && v.value.is_finite() && v.value > 0.0)
+            .map(|v| (v.id.clone(), v.value))
+            .collect();
+        self.opinions
+            .insert(peer_id.to_string(), normalized(opinion));
+    }
+
+    // a peer that's gone has no say anymore, what this node went through with
+    // it is kept in case it comes back
+    pub fn forget(&mut self, peer_id: &str) {
+        self.opinions.remove(peer_id);
+    }
+
+    // Global trust, t = (1 - a) C^T t + a p, with p all on this node. Peers
+    // that shared no opinion hand their trust back to this node.
+    pub fn compute(&mut self) {
+        let own = self.local_trust();
+        let mut trust = HashMap::from([(self.id.clone(), 1.0)]);
+        for _ in 0..MAX_ITERATIONS {
+            let mut next: HashMap<String, f64> = HashMap::from([(self.id.clone(), RESTART)]);
+            for (i, t_i) in &trust {
+                let opinion = match i == &self.id {
+                    true => Some(&own),
+                    false => self.opinions.get(i),
+                };
+                match opinion.filter(|opinion| !opinion.is_empty()) {
+                    Some(opinion) => {
+                        for (j, c_ij) in opinion {
+                            *next.entry(j.clone()).or_default() += (1.0 - RESTART) * c_ij * t_i;
+                        }
+                    }
+                    None => *next.entry(self.id.clone()).or_default() += (1.0 - RESTART) * t_i,
+                }
+            }
+            let moved: f64 = next
+                .iter()
+                .map(|(id, t)| (t - trust.get(id).unwrap_or(&0.0)).abs())
+                .sum();
+            trust = next;
+            if moved < CONVERGED {
+                break;
+            }
+        }
+        trust.remove(&self.id);
+        self.global = trust;
+    }
+
+    // global trust in the peer as of the last compute
+    pub fn trust(&self, peer_id: &str) -> f64 {
+        self.global.get(peer_id).copied().unwrap_or(0.0)
+    }
+
+    // most trusted first, peers trusted the same keep their order
+    pub fn rank(&self, peers: &mut [Peer]) {
+        peers.sort_by(|a, b| {
+            self.trust(&b.id)
+                .partial_cmp(&self.trust(&a.id))
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    // the contact to drop for `candidate`, if any is trusted less than it
+    pub fn eviction_victim(&self, contacts: &[Peer], candidate: &Peer) -> Option<Peer> {
+        let least = contacts.iter().min_by(|a, b| {
+            self.trust(&a.id)
+                .partial_cmp(&self.trust(&b.id))
+                .unwrap_or(Ordering::Equal)
+        })?;
+        (self.trust(&least.id) < self.trust(&candidate.id)).then(|| least.clone())
+    }
+}
+
+fn normalized(values: HashMap<String, f64>) -> HashMap<String, f64> {
+    let total: f64 = values.values().sum();
+    if total <= 0.0 {
+        return HashMap::new();
+    }
+    values
+        .into_iter()
+        .map(|(id, value)| (id, value / total))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str) -> Peer {
+        Peer {
+            id: id.to_string(),
+            ip: String::from("127.0.0.1"),
+            port: 5000,
+        }
+    }
+
+    fn value(id: &str, value: f64) -> TrustValue {
+        TrustValue {
+            id: id.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_local_trust() {
+        let mut reputation = Reputation::new(String::from("self"));
+        reputation.record("a", Interaction::ValidBlock);
+        reputation.record("b", Interaction::Answered);
+        reputation.record("b", Interaction::Up);
+        reputation.record("c", Interaction::Up);
+        reputation.record("c", Interaction::InvalidBlock);
+        reputation.record("self", Interaction::ValidBlock);
+        let local = reputation.local_trust();
+        assert_eq!(2, local.len());
+        assert!((local["a"] - 5.0 / 8.0).abs() < 1e-9);
+        assert!((local["b"] - 3.0 / 8.0).abs() < 1e-9);
+        let values = reputation.trust_values();
+        assert_eq!(
+            vec!["a", "b"],
+            values.iter().map(|v| v.id.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_global_trust() {
+        let mut reputation = Reputation::new(String::from("self"));
+        reputation.record("a", Interaction::Answered);
+        reputation.record("b", Interaction::Down);
+        // a vouches for c, b and the never met d vouch for each other
+        reputation.heard("a", &[value("c", 1.0)]);
+        reputation.heard("b", &[value("d", 1.0)]);
+        reputation.heard("d", &[value("b", 1.0)]);
+        reputation.compute();
+        assert!(reputation.trust("a") > 0.0);
+        assert!(reputation.trust("c") > 0.0);
+        assert_eq!(0.0, reputation.trust("b"));
+        assert_eq!(0.0, reputation.trust("d"));
+        let total: f64 = reputation.global.values().sum();
+        assert!(total <= 1.0 + 1e-9);
+
+        let mut peers = vec![peer("d"), peer("c"), peer("a")];
+        reputation.rank(&mut peers);
+        assert_eq!("a", peers[0].id);
+        assert_eq!("c", peers[1].id);
+        // an untrusted contact makes room for a trusted peer, not the other way
+        let contacts = vec![peer("a"), peer("d")];
+        assert_eq!(
+            Some(peer("d")),
+            reputation.eviction_victim(&contacts, &peer("c"))
+        );
+        assert_eq!(None, reputation.eviction_victim(&contacts, &peer("b")));
+
+        // peers that left have no say anymore
+        reputation.forget("a");
+        reputation.compute();
+        assert_eq!(0.0, reputation.trust("c"));
+    }
+}
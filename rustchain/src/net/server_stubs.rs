@@ -1,64 +1,266 @@
 use crate::event_bus::events::RustchainEvent;
 use crate::{
     blockchain::block::Block,
+    blockchain::blockchain::Blockchain,
     event_bus::event_bus::EventBus,
     protos::{
+        chord_server::{Chord as ChordRpc, ChordServer},
         p2p_server::{P2p, P2pServer},
         response::Data,
         rustchain_server::{Rustchain, RustchainServer},
-        AddPeerResponse, GetPeersRequest, Heartbeat, Null, Peer, PeerList, RemovePeerResponse,
-        Response as RustchainResponse, Transaction, UtxoInputs, UtxoOutputs, ValidationRequest,
+        utxo_query::Query,
+        AddPeerResponse, AdminRequest, Ban, BanList, BlockList, BlocksRequest, ChordHop, ChordKey,
+        ChordNeighbours, FindNodeRequest, GetPeersRequest, HeaderList, HeadersRequest, Heartbeat,
+        Inventory, MerkleProof, MerkleProofRequest, Null, OutPoint, Peer, PeerList, PeerRequest,
+        RemovePeerResponse, Response as RustchainResponse, Transaction, TransactionList,
+        TransactionsRequest, Utxo, UtxoInputs, UtxoList, UtxoOutputs, UtxoQuery, ValidationRequest,
     },
 };
 
-use crate::net::middleware::ClientAddressInterceptor;
+use crate::blockchain::consensus::is_coinbase;
+use crate::blockchain::merkle::{IMerkleTree, MerkleEntry, MerkleTree};
+use crate::blockchain::validation::validate_proof_of_work;
+use crate::net::chord::Chord;
+use crate::net::gossip::Gossip;
+use crate::net::identity::{verify_heartbeat, verify_request, IdentityError, ReplayCache};
+use crate::net::kademlia::{NodeId, RoutingTable, K};
+use crate::net::middleware::{Client, ClientAddressInterceptor, Misbehaviour, Offender, PeerGuard};
+use crate::net::middleware::{MAX_CONCURRENT_STREAMS, MAX_MESSAGE_SIZE};
+use crate::net::tls::{check_peer, TlsConfig};
+use openssl::pkey::PKey;
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::Mutex;
 use std::{error::Error, sync::Arc};
 use tokio::sync::RwLock;
-use tonic::transport::server::Router;
+use tonic::service::interceptor::InterceptedService;
 pub use tonic::{transport::Server, Request, Response, Status};
 
+// most headers answered by a single GetHeaders
+pub const MAX_HEADERS: u32 = 2000;
+// most blocks answered by a single GetBlocks
+pub const MAX_BLOCKS: usize = 128;
+
+// actions signed by nodes announcing themselves
+pub const ADD_PEER: &str = "add-peer";
+pub const REMOVE_PEER: &str = "remove-peer";
+// signed by admins listing the clients a node bans
+pub const LIST_BANS: &str = "list-bans";
+
 #[derive(Debug)]
 struct RustchainService {
     event_bus: Arc<RwLock<EventBus>>,
+    // chain state queries are only answered by nodes that keep a chain
+    blockchain: Option<Arc<RwLock<Blockchain>>>,
+    // relayed transactions are only served by nodes that gossip
+    gossip: Option<Arc<RwLock<Gossip>>>,
+    guard: Arc<Mutex<PeerGuard>>,
+}
+
+fn no_blockchain() -> Status {
+    Status::unavailable("node does not keep a blockchain")
+}
+
+fn no_gossip() -> Status {
+    Status::unavailable("node does not relay transactions")
+}
+
+fn no_routing_table() -> Status {
+    Status::unavailable("node does not keep a routing table")
+}
+
+#[derive(Debug)]
+struct ChordService {
+    chord: Arc<RwLock<Chord>>,
 }
 
 #[derive(Debug)]
 struct P2pService {
     event_bus: Arc<RwLock<EventBus>>,
+    // membership queries and FIND_NODE are only answered by nodes taking part
+    // in discovery
+    routing_table: Option<Arc<RwLock<RoutingTable>>>,
+    guard: Arc<Mutex<PeerGuard>>,
+    admins: Arc<HashSet<String>>,
+    // signed requests accepted lately
+    replays: Arc<Mutex<ReplayCache>>,
+}
+
+// a client connected over TLS has to be the node it claims to be
+pub(crate) fn wrong_peer<T>(req: &Request<T>, peer: Option<&Peer>) -> Option<Status> {
+    let id = peer.map(|p| p.id.as_str()).unwrap_or_default();
+    check_peer(req, id)
+        .err()
+        .map(|e| Status::unauthenticated(e.to_string()))
+}
+
+// None when `req` is signed by one of the `admins` for `action`
+pub(crate) fn refuse_admin(
+    admins: &HashSet<String>,
+    req: &Request<AdminRequest>,
+    action: &str,
+    replays: &Mutex<ReplayCache>,
+) -> Option<Status> {
+    if let Err(e) = check_peer(req, &req.get_ref().admin_id) {
+        return Some(Status::unauthenticated(e.to_string()));
+    }
+    let req = req.get_ref();
+    if !admins.contains(&req.admin_id) {
+        return Some(Status::permission_denied("not an admin"));
+    }
+    let subject = req.peer.clone().unwrap_or_default();
+    verify_request(&req.admin_id, action, &subject, req.proof.as_ref(), replays)
+        .err()
+        .map(|e| Status::unauthenticated(e.to_string()))
+}
+
+// adds to the misbehaviour score of whoever sent `req`
+fn report<T>(guard: &Mutex<PeerGuard>, req: &Request<T>, misbehaviour: Misbehaviour) {
+    guard.lock().unwrap().report(&Client::of(req), misbehaviour);
+}
+
+// what makes a transaction malformed, rather than just invalid against the
+// chain the node keeps
+fn malformed(tx: &Transaction) -> Option<&'static str> {
+    if tx.inputs.is_empty() {
+        return Some("transaction has no inputs");
+    }
+    if tx.outputs.is_empty() {
+        return Some("transaction has no outputs");
+    }
+    if is_coinbase(tx) {
+        return Some("coinbase transactions are only valid in blocks");
+    }
+    let keys_parse = tx
+        .inputs
+        .iter()
+        .all(|input| PKey::public_key_from_pem(&input.public_key).is_ok());
+    if !keys_parse {
+        return Some("transaction input has a malformed public key");
+    }
+    None
+}
+
+// the peer in `req`, once it proved it asked for `action` itself
+fn announced_peer(
+    req: Request<PeerRequest>,
+    action: &str,
+    replays: &Mutex<ReplayCache>,
+) -> Result<Peer, IdentityError> {
+    let req = req.into_inner();
+    let peer = req.peer.unwrap_or_default();
+    verify_request(&peer.id, action, &peer, req.proof.as_ref(), replays)?;
+    Ok(peer)
 }
 
 pub struct PeerServer {
-    // server: Server, // do I really need this? simplify!
-    router: Router,
+    event_bus: Arc<RwLock<EventBus>>,
+    blockchain: Option<Arc<RwLock<Blockchain>>>,
+    gossip: Option<Arc<RwLock<Gossip>>>,
+    routing_table: Option<Arc<RwLock<RoutingTable>>>,
+    chord: Option<Arc<RwLock<Chord>>>,
+    // plaintext without
+    tls: Option<TlsConfig>,
+    guard: Arc<Mutex<PeerGuard>>,
+    // node ids allowed to list bans
+    admins: HashSet<String>,
     addr: SocketAddr,
 }
 
 impl PeerServer {
     pub fn new(event_bus: Arc<RwLock<EventBus>>, addr: SocketAddr) -> PeerServer {
-        let middleware = ClientAddressInterceptor::new();
-        let payment_service = RustchainServer::with_interceptor(
-            RustchainService {
-                event_bus: event_bus.clone(),
-            },
-            middleware,
-        );
-        let p2p_service = P2pServer::new(P2pService {
-            event_bus: event_bus.clone(),
-        });
-        let router = Server::builder()
-            .add_service(payment_service)
-            .add_service(p2p_service);
-        // add additional services to router here..
-        return PeerServer {
-            // server,
-            router,
+        PeerServer {
+            event_bus,
+            blockchain: None,
+            gossip: None,
+            routing_table: None,
+            chord: None,
+            tls: None,
+            guard: PeerGuard::new().shared(),
+            admins: HashSet::new(),
             addr,
-        };
+        }
+    }
+
+    pub fn with_blockchain(mut self, blockchain: Arc<RwLock<Blockchain>>) -> PeerServer {
+        self.blockchain = Some(blockchain);
+        self
+    }
+
+    pub fn with_gossip(mut self, gossip: Arc<RwLock<Gossip>>) -> PeerServer {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    pub fn with_routing_table(mut self, routing_table: Arc<RwLock<RoutingTable>>) -> PeerServer {
+        self.routing_table = Some(routing_table);
+        self
+    }
+
+    pub fn with_chord(mut self, chord: Arc<RwLock<Chord>>) -> PeerServer {
+        self.chord = Some(chord);
+        self
+    }
+
+    // serves over mutual TLS, clients need a certificate from the same CA
+    pub fn with_tls(mut self, tls: TlsConfig) -> PeerServer {
+        self.tls = Some(tls);
+        self
+    }
+
+    // budgets, scores and bans of the clients, shared with whoever else
+    // judges them
+    pub fn with_guard(mut self, guard: Arc<Mutex<PeerGuard>>) -> PeerServer {
+        self.guard = guard;
+        self
+    }
+
+    pub fn with_admin(mut self, id: String) -> PeerServer {
+        self.admins.insert(id);
+        self
     }
 
     pub async fn serve(self) -> Result<(), Box<dyn Error + Send>> {
-        self.router
+        // every service refuses banned clients and clients over budget
+        let middleware = ClientAddressInterceptor::new(self.guard.clone());
+        let payment_service = InterceptedService::new(
+            RustchainServer::new(RustchainService {
+                event_bus: self.event_bus.clone(),
+                blockchain: self.blockchain.clone(),
+                gossip: self.gossip.clone(),
+                guard: self.guard.clone(),
+            })
+            .max_decoding_message_size(MAX_MESSAGE_SIZE),
+            middleware.clone(),
+        );
+        let p2p_service = InterceptedService::new(
+            P2pServer::new(P2pService {
+                event_bus: self.event_bus.clone(),
+                routing_table: self.routing_table.clone(),
+                guard: self.guard.clone(),
+                admins: Arc::new(self.admins.clone()),
+                replays: ReplayCache::new().shared(),
+            })
+            .max_decoding_message_size(MAX_MESSAGE_SIZE),
+            middleware.clone(),
+        );
+        // only nodes that are part of the ring answer ring requests
+        let chord_service = self.chord.clone().map(|chord| {
+            let chord = ChordServer::new(ChordService { chord })
+                .max_decoding_message_size(MAX_MESSAGE_SIZE);
+            InterceptedService::new(chord, middleware)
+        });
+        let mut server = Server::builder().max_concurrent_streams(MAX_CONCURRENT_STREAMS);
+        if let Some(tls) = &self.tls {
+            server = server
+                .tls_config(tls.server())
+                .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
+        }
+        // add additional services to router here..
+        server
+            .add_service(payment_service)
+            .add_service(p2p_service)
+            .add_optional_service(chord_service)
             .serve::<_>(self.addr)
             .await
             .map_err(|e| -> Box<dyn std::error::Error + Send> { Box::new(e) })?;
@@ -68,11 +270,34 @@ impl PeerServer {
 
 #[tonic::async_trait]
 impl Rustchain for RustchainService {
+    // blocks pushed by a peer are handed to the blockchain, which validates
+    // them before they are relayed any further
     async fn send_block(
         &self,
-        _req: Request<Block>,
+        request: Request<Block>,
     ) -> Result<Response<RustchainResponse>, Status> {
-        unimplemented!();
+        // not relayed nor even queued without a valid proof of work
+        if let Err(e) = validate_proof_of_work(request.get_ref()) {
+            report(&self.guard, &request, Misbehaviour::InvalidBlock);
+            return Err(Status::invalid_argument(e.to_string()));
+        }
+        // whoever pushed it answers for it once it's validated
+        if let Some(gossip) = &self.gossip {
+            let client = Client::of(&request);
+            gossip.write().await.pushed_by(request.get_ref(), client);
+        }
+        let block = request.into_inner();
+        self.event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::NewBlock(block.clone()))
+            .await;
+        let reply = RustchainResponse {
+            successful: true,
+            message: format!("Received block {}.", hex::encode(&block.block_hash)),
+            data: Some(Data::Block(block)),
+        };
+        Ok(Response::new(reply))
     }
 
     async fn send_transaction(
@@ -80,6 +305,10 @@ impl Rustchain for RustchainService {
         request: Request<Transaction>,
     ) -> Result<Response<RustchainResponse>, Status> {
         println!("Got a request: {:?}", request);
+        if let Some(reason) = malformed(request.get_ref()) {
+            report(&self.guard, &request, Misbehaviour::MalformedTransaction);
+            return Err(Status::invalid_argument(reason));
+        }
         let tx = request.into_inner();
         self.event_bus
             .write()
@@ -104,29 +333,286 @@ impl Rustchain for RustchainService {
         println!("Got a validation request");
         Ok(Response::new(RustchainResponse::default()))
     }
+
+    async fn get_utxos(&self, request: Request<UtxoQuery>) -> Result<Response<UtxoList>, Status> {
+        let utxo_set = self
+            .blockchain
+            .as_ref()
+            .ok_or_else(no_blockchain)?
+            .read()
+            .await
+            .utxo_set();
+        let utxo_set = utxo_set.read().unwrap();
+        let utxos = match request.into_inner().query {
+            Some(Query::Address(address)) => utxo_set.by_address(&address),
+            Some(Query::Outpoint(outpoint)) => {
+                let outpoint = (outpoint.tx_hash, outpoint.output_index);
+                utxo_set
+                    .get(&outpoint)
+                    .map(|output| vec![(outpoint.clone(), output.clone())])
+                    .unwrap_or_default()
+            }
+            None => return Err(Status::invalid_argument("missing utxo query")),
+        };
+        let utxos = utxos
+            .into_iter()
+            .map(|((tx_hash, output_index), output)| Utxo {
+                outpoint: Some(OutPoint {
+                    tx_hash,
+                    output_index,
+                }),
+                output: Some(output),
+            })
+            .collect();
+        Ok(Response::new(UtxoList { utxos }))
+    }
+
+    async fn get_headers(
+        &self,
+        request: Request<HeadersRequest>,
+    ) -> Result<Response<HeaderList>, Status> {
+        let request = request.into_inner();
+        let locator: Vec<String> = request.locator.iter().map(hex::encode).collect();
+        let max = request.max_headers.clamp(1, MAX_HEADERS) as usize;
+        let headers = self
+            .blockchain
+            .as_ref()
+            .ok_or_else(no_blockchain)?
+            .read()
+            .await
+            .headers_after(&locator, max);
+        Ok(Response::new(HeaderList { headers }))
+    }
+
+    // known blocks among the requested ones, in the order they were asked for
+    async fn get_blocks(
+        &self,
+        request: Request<BlocksRequest>,
+    ) -> Result<Response<BlockList>, Status> {
+        let hashes = request.into_inner().hashes;
+        if hashes.len() > MAX_BLOCKS {
+            return Err(Status::invalid_argument(format!(
+                "at most {} blocks per request",
+                MAX_BLOCKS
+            )));
+        }
+        let blockchain = self
+            .blockchain
+            .as_ref()
+            .ok_or_else(no_blockchain)?
+            .read()
+            .await;
+        let blocks = hashes
+            .iter()
+            .filter_map(|hash| blockchain.get_block(&hex::encode(hash)))
+            .collect();
+        Ok(Response::new(BlockList { blocks }))
+    }
+
+    // transactions relayed lately among the requested ones
+    async fn get_transactions(
+        &self,
+        request: Request<TransactionsRequest>,
+    ) -> Result<Response<TransactionList>, Status> {
+        let gossip = self.gossip.as_ref().ok_or_else(no_gossip)?.read().await;
+        let transactions = request
+            .into_inner()
+            .hashes
+            .iter()
+            .filter_map(|hash| gossip.relayed_transaction(&hex::encode(hash)))
+            .collect();
+        Ok(Response::new(TransactionList { transactions }))
+    }
+
+    async fn announce(&self, request: Request<Inventory>) -> Result<Response<Null>, Status> {
+        let client = Client::of(&request);
+        self.event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::NewInventory(request.into_inner(), client))
+            .await;
+        Ok(Response::new(Null::default()))
+    }
+
+    // the transaction, the block confirming it and the merkle path from the
+    // transaction to the block's merkle root
+    async fn get_merkle_proof(
+        &self,
+        request: Request<MerkleProofRequest>,
+    ) -> Result<Response<MerkleProof>, Status> {
+        let tx_hash = request.into_inner().tx_hash;
+        let found = self
+            .blockchain
+            .as_ref()
+            .ok_or_else(no_blockchain)?
+            .read()
+            .await
+            .find_transaction(&tx_hash);
+        let (tx, block) = found.ok_or_else(|| Status::not_found("transaction is not confirmed"))?;
+        let path = MerkleTree::from_transactions(&block.transactions)
+            .generate_merkle_path(&MerkleEntry::from(&tx))
+            .map_err(Status::internal)?;
+        Ok(Response::new(MerkleProof {
+            transaction: Some(tx),
+            block_hash: block.block_hash.clone(),
+            block_index: block.header.as_ref().unwrap().block_index,
+            location: path.location as u64,
+            hashes: path.hashes.iter().map(|hash| hash.to_vec()).collect(),
+        }))
+    }
+}
+
+impl P2pService {
+    // Refuses a request whose proof doesn't hold up. Only a signature that
+    // doesn't match, or a key that isn't the sender's, is held against it: an
+    // honest node can send a stale or missing proof, with a clock that
+    // drifted or an older version.
+    fn forged(&self, client: &Client, e: IdentityError) -> Status {
+        if matches!(
+            e,
+            IdentityError::BadSignature { .. } | IdentityError::IdMismatch { .. }
+        ) {
+            self.guard
+                .lock()
+                .unwrap()
+                .report(client, Misbehaviour::BadSignature);
+        }
+        Status::unauthenticated(e.to_string())
+    }
 }
 
 #[tonic::async_trait]
 impl P2p for P2pService {
+    // the peers in the routing table
     async fn get_peers(
         &self,
         _req: Request<GetPeersRequest>,
     ) -> Result<Response<PeerList>, Status> {
-        Ok(Response::new(PeerList::default()))
+        let routing_table = self.routing_table.as_ref().ok_or_else(no_routing_table)?;
+        let peers = routing_table.read().await.peers();
+        Ok(Response::new(PeerList::from(peers)))
     }
 
-    async fn add_peer(&self, _req: Request<Peer>) -> Result<Response<AddPeerResponse>, Status> {
+    // nodes can only add and remove themselves
+    async fn add_peer(
+        &self,
+        req: Request<PeerRequest>,
+    ) -> Result<Response<AddPeerResponse>, Status> {
+        self.routing_table.as_ref().ok_or_else(no_routing_table)?;
+        if let Some(status) = wrong_peer(&req, req.get_ref().peer.as_ref()) {
+            return Err(status);
+        }
+        let client = Client::of(&req);
+        let peer =
+            announced_peer(req, ADD_PEER, &self.replays).map_err(|e| self.forged(&client, e))?;
+        self.event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::PeerJoined(peer))
+            .await;
         Ok(Response::new(AddPeerResponse::default()))
     }
 
     async fn remove_peer(
         &self,
-        _req: Request<Peer>,
+        req: Request<PeerRequest>,
     ) -> Result<Response<RemovePeerResponse>, Status> {
+        self.routing_table.as_ref().ok_or_else(no_routing_table)?;
+        if let Some(status) = wrong_peer(&req, req.get_ref().peer.as_ref()) {
+            return Err(status);
+        }
+        let client = Client::of(&req);
+        let peer =
+            announced_peer(req, REMOVE_PEER, &self.replays).map_err(|e| self.forged(&client, e))?;
+        self.event_bus
+            .read()
+            .await
+            .publish(RustchainEvent::PeerLeft(peer))
+            .await;
         Ok(Response::new(RemovePeerResponse::default()))
     }
 
+    // only heartbeats signed by the node they claim to come from get through
     async fn send_heartbeat(&self, req: Request<Heartbeat>) -> Result<Response<Null>, Status> {
+        if let Some(status) = wrong_peer(&req, req.get_ref().peer.as_ref()) {
+            return Err(status);
+        }
+        let client = Client::of(&req);
+        let heartbeat = req.into_inner();
+        verify_heartbeat(&heartbeat).map_err(|e| self.forged(&client, e))?;
+        self.event_bus
+            .write()
+            .await
+            .publish(RustchainEvent::NewHeartbeat(heartbeat))
+            .await;
+        Ok(Response::new(Null::default()))
+    }
+
+    // answers with the K closest peers known to the target, the asking node
+    // is a candidate for the routing table as well
+    async fn find_node(&self, req: Request<FindNodeRequest>) -> Result<Response<PeerList>, Status> {
+        let routing_table = self.routing_table.as_ref().ok_or_else(no_routing_table)?;
+        if let Some(sender) = req.get_ref().sender.as_ref() {
+            if let Some(status) = wrong_peer(&req, Some(sender)) {
+                return Err(status);
+            }
+        }
+        let req = req.into_inner();
+        let target = NodeId::from_bytes(&req.target)
+            .ok_or_else(|| Status::invalid_argument("target is not a node id"))?;
+        let closest = routing_table.read().await.closest(&target, K);
+        if let Some(sender) = req.sender {
+            self.event_bus
+                .read()
+                .await
+                .publish(RustchainEvent::NewPeers(PeerList::from(vec![sender])))
+                .await;
+        }
+        Ok(Response::new(PeerList::from(closest)))
+    }
+
+    // the clients this node refuses for misbehaving, for its admins
+    async fn list_bans(&self, req: Request<AdminRequest>) -> Result<Response<BanList>, Status> {
+        if let Some(status) = refuse_admin(&self.admins, &req, LIST_BANS, &self.replays) {
+            return Err(status);
+        }
+        let bans = self.guard.lock().unwrap().bans();
+        let bans = bans
+            .into_iter()
+            .map(|ban| Ban {
+                offender: match ban.offender {
+                    Offender::Ip(ip) => ip.to_string(),
+                    Offender::Node(id) => id,
+                },
+                remaining_millis: ban.remaining.as_millis() as u64,
+                reason: ban.reason,
+            })
+            .collect();
+        Ok(Response::new(BanList { bans }))
+    }
+}
+
+#[tonic::async_trait]
+impl ChordRpc for ChordService {
+    async fn find_successor(&self, req: Request<ChordKey>) -> Result<Response<ChordHop>, Status> {
+        let key = NodeId::from_bytes(&req.into_inner().key)
+            .ok_or_else(|| Status::invalid_argument("key is not a ring position"))?;
+        let hop = self.chord.read().await.next_hop(&key);
+        Ok(Response::new(ChordHop::from(hop)))
+    }
+
+    async fn get_neighbours(
+        &self,
+        _req: Request<Null>,
+    ) -> Result<Response<ChordNeighbours>, Status> {
+        Ok(Response::new(self.chord.read().await.neighbours()))
+    }
+
+    async fn notify(&self, req: Request<Peer>) -> Result<Response<Null>, Status> {
+        if let Some(status) = wrong_peer(&req, Some(req.get_ref())) {
+            return Err(status);
+        }
+        self.chord.write().await.notify(req.into_inner());
         Ok(Response::new(Null::default()))
     }
 }
@@ -0,0 +1,521 @@
+use crate::blockchain::blockchain::Blockchain;
+use crate::blockchain::validation::{validate_chained_header, BlockValidationError};
+use crate::event_bus::event_bus::EventBus;
+use crate::event_bus::events::RustchainEvent;
+use crate::net::connections::ConnectionManager;
+use crate::net::middleware::{Client, Misbehaviour, PeerGuard};
+use crate::net::reputation::{Interaction, Reputation};
+use crate::net::server_stubs::{MAX_BLOCKS, MAX_HEADERS};
+use crate::protos::{Block, BlockHeader, ChainTip, Heartbeat, Peer};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+// how long a peer gets to answer a sync request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// tries of a request before giving up, each one on the next peer
+const MAX_ATTEMPTS: usize = 3;
+// blocks asked for in a single GetBlocks, batches go to different peers
+const BLOCKS_PER_REQUEST: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncError {
+    // every attempt at a request failed or timed out
+    Unanswered {
+        request: String,
+    },
+    InvalidHeaders(BlockValidationError),
+    InvalidBlock {
+        hash: String,
+        reason: BlockValidationError,
+    },
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unanswered { request } => write!(
+                f,
+                "no peer answered {} after {} attempts",
+                request, MAX_ATTEMPTS
+            ),
+            Self::InvalidHeaders(reason) => write!(f, "peer sent invalid headers: {}", reason),
+            Self::InvalidBlock { hash, reason } => {
+                write!(f, "peer sent invalid block {}: {}", hash, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+// Catches up with peers whose heartbeats advertise more cumulative work. The
+// headers following the last block in common are asked for with a locator,
+// then their blocks are downloaded in batches spread over every peer ahead.
+// Peers ahead are asked most trusted first, and a peer that sends headers or
+// blocks that fail validation is reported and the next one is asked instead.
+#[derive(Debug)]
+pub struct BlockSync {
+    blockchain: Arc<RwLock<Blockchain>>,
+    // the chain's events are published here, once its lock is released
+    event_bus: Arc<RwLock<EventBus>>,
+    // last tip advertised by each peer, by address
+    peer_tips: HashMap<String, (Peer, ChainTip)>,
+    syncing: bool,
+    request_timeout: Duration,
+    connections: Arc<RwLock<ConnectionManager>>,
+    reputation: Arc<RwLock<Reputation>>,
+    guard: Arc<Mutex<PeerGuard>>,
+}
+
+impl BlockSync {
+    pub async fn new(
+        event_bus: Arc<RwLock<EventBus>>,
+        blockchain: Arc<RwLock<Blockchain>>,
+    ) -> Arc<RwLock<BlockSync>> {
+        BlockSync::with_timeout(event_bus, blockchain, REQUEST_TIMEOUT).await
+    }
+
+    pub async fn with_timeout(
+        event_bus: Arc<RwLock<EventBus>>,
+        blockchain: Arc<RwLock<Blockchain>>,
+        request_timeout: Duration,
+    ) -> Arc<RwLock<BlockSync>> {
+        let sync = Arc::new(RwLock::new(BlockSync {
+            blockchain,
+            event_bus: event_bus.clone(),
+            peer_tips: HashMap::new(),
+            syncing: false,
+            request_timeout,
+            connections: ConnectionManager::new(None).shared(),
+            reputation: Reputation::new(String::new()).shared(),
+            guard: PeerGuard::new().shared(),
+        }));
+        let event_receiver = event_bus.write().await.subscribe().await;
+        let sync_clone = sync.clone();
+        spawn(async move { BlockSync::listen_for_events(sync_clone, event_receiver).await });
+        sync
+    }
+
+    // blocks are downloaded over the node's connections to its peers
+    pub fn use_connections(&mut self, connections: Arc<RwLock<ConnectionManager>>) {
+        self.connections = connections;
+    }
+
+    // the most trusted peers ahead are asked first, and learn how they answered
+    pub fn use_reputation(&mut self, reputation: Arc<RwLock<Reputation>>) {
+        self.reputation = reputation;
+    }
+
+    // peers that send invalid headers or blocks are reported to the guard of
+    // the node's server
+    pub fn use_guard(&mut self, guard: Arc<Mutex<PeerGuard>>) {
+        self.guard = guard;
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.syncing
+    }
+
+    // peers that advertised more work than `work`, most work first
+    fn peers_ahead_of(&self, work: u128) -> Vec<Peer> {
+        let mut ahead: Vec<&(Peer, ChainTip)> = self
+            .peer_tips
+            .values()
+            .filter(|(_, tip)| tip.work() > work)
+            .collect();
+        ahead.sort_by_key(|(_, tip)| Reverse(tip.work()));
+        ahead.into_iter().map(|(peer, _)| peer.clone()).collect()
+    }
+
+    async fn listen_for_events(
+        sync: Arc<RwLock<BlockSync>>,
+        mut event_receiver: Receiver<RustchainEvent>,
+    ) {
+        while let Some(event) = event_receiver.recv().await {
+            match event {
+                RustchainEvent::NewHeartbeat(heartbeat) => {
+                    BlockSync::on_heartbeat(sync.clone(), heartbeat).await;
+                }
+                // dead peers are not asked for blocks anymore
+                RustchainEvent::PeerRemoved(peer) => {
+                    sync.write().await.peer_tips.remove(&peer_key(&peer));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // records the sender's tip and starts syncing if it is ahead of us
+    async fn on_heartbeat(sync: Arc<RwLock<BlockSync>>, heartbeat: Heartbeat) {
+        let (peer, tip) = match (heartbeat.peer, heartbeat.tip) {
+            (Some(peer), Some(tip)) => (peer, tip),
+            _ => return,
+        };
+        let blockchain = sync.read().await.blockchain.clone();
+        let work = blockchain.read().await.cumulative_work();
+        let ahead = tip.work() > work;
+        let mut lock = sync.write().await;
+        lock.peer_tips.insert(peer_key(&peer), (peer, tip));
+        if !ahead || lock.syncing {
+            return;
+        }
+        lock.syncing = true;
+        drop(lock);
+        let sync_clone = sync.clone();
+        spawn(async move {
+            match BlockSync::sync(sync_clone.clone()).await {
+                Ok(height) => println!("Synced blocks up to height {}", height),
+                Err(e) => println!("Block sync failed: {}", e),
+            }
+            sync_clone.write().await.syncing = false;
+        });
+    }
+
+    // Downloads blocks until no known peer advertises more work, or peers stop
+    // giving blocks that add any. Returns the height of the active chain, or
+    // why the last peer asked failed if none was left to ask.
+    pub async fn sync(sync: Arc<RwLock<BlockSync>>) -> Result<u64, SyncError> {
+        let (blockchain, event_bus, request_timeout, connections, reputation) = {
+            let lock = sync.read().await;
+            (
+                lock.blockchain.clone(),
+                lock.event_bus.clone(),
+                lock.request_timeout,
+                lock.connections.clone(),
+                lock.reputation.clone(),
+            )
+        };
+        let mut failure = None;
+        loop {
+            let (locator, work) = {
+                let lock = blockchain.read().await;
+                (lock.locator(), lock.cumulative_work())
+            };
+            let mut peers = sync.read().await.peers_ahead_of(work);
+            reputation.read().await.rank(&mut peers);
+            if peers.is_empty() {
+                return match failure {
+                    Some(e) => Err(e),
+                    None => Ok(blockchain.read().await.height()),
+                };
+            }
+            let locator: Vec<Vec<u8>> = locator
+                .iter()
+                .map(|hash| hex::decode(hash).unwrap())
+                .collect();
+            let (peer, headers) =
+                request(&peers, 0, request_timeout, &reputation, "headers", |peer| {
+                    get_headers(peer, locator.clone(), connections.clone())
+                })
+                .await?;
+            if headers.is_empty() {
+                return Ok(blockchain.read().await.height());
+            }
+            if let Err(reason) = check_headers(&*blockchain.read().await, &headers) {
+                let e = SyncError::InvalidHeaders(reason);
+                BlockSync::on_invalid(&sync, &peer, &e).await;
+                failure = Some(e);
+                continue;
+            }
+            reputation
+                .write()
+                .await
+                .record(&peer.id, Interaction::Answered);
+            let hashes: Vec<Vec<u8>> = headers.iter().map(|header| header.hash()).collect();
+            let batches =
+                fetch_blocks(&peers, hashes, request_timeout, &connections, &reputation).await?;
+            // each block is added under its own lock, and its events are
+            // published after it's released, the chain is needed to handle them
+            let mut invalid = None;
+            'batches: for (peer, blocks) in batches {
+                for block in blocks {
+                    let hash = hex::encode(&block.block_hash);
+                    let (status, events) = blockchain.write().await.add_block_deferred(block);
+                    let event_bus = event_bus.read().await;
+                    for event in events {
+                        event_bus.publish(event).await;
+                    }
+                    if let Err(reason) = status {
+                        let e = SyncError::InvalidBlock { hash, reason };
+                        BlockSync::on_invalid(&sync, &peer, &e).await;
+                        invalid = Some(e);
+                        break 'batches;
+                    }
+                }
+                reputation
+                    .write()
+                    .await
+                    .record(&peer.id, Interaction::Answered);
+            }
+            failure = invalid;
+            let lock = blockchain.read().await;
+            if failure.is_none() && lock.cumulative_work() <= work {
+                return Ok(lock.height());
+            }
+        }
+    }
+
+    // The peer isn't asked again until it advertises its tip anew, and is
+    // reported to the guard as well as in its reputation.
+    async fn on_invalid(sync: &Arc<RwLock<BlockSync>>, peer: &Peer, e: &SyncError) {
+        println!("Peer {}:{} {}", peer.ip, peer.port, e);
+        let (reputation, guard) = {
+            let mut lock = sync.write().await;
+            lock.peer_tips.remove(&peer_key(peer));
+            (lock.reputation.clone(), lock.guard.clone())
+        };
+        reputation
+            .write()
+            .await
+            .record(&peer.id, Interaction::InvalidBlock);
+        guard
+            .lock()
+            .unwrap()
+            .report(&Client::peer(peer), Misbehaviour::InvalidBlock);
+    }
+}
+
+fn peer_key(peer: &Peer) -> String {
+    format!("{}:{}", peer.ip, peer.port)
+}
+
+// Headers from a peer have to extend a block we know and be chained to each
+// other with valid proof of work, before their blocks are downloaded.
+fn check_headers(
+    blockchain: &Blockchain,
+    headers: &[BlockHeader],
+) -> Result<(), BlockValidationError> {
+    let first = &headers[0];
+    let mut parent = blockchain
+        .get_header(&hex::encode(&first.previous_hash))
+        .ok_or_else(|| BlockValidationError::PreviousHashMismatch {
+            expected: hex::encode(blockchain.tip().block_hash),
+            found: hex::encode(&first.previous_hash),
+        })?;
+    for header in headers {
+        validate_chained_header(header, &parent)?;
+        parent = header.clone();
+    }
+    Ok(())
+}
+
+// Asks for the blocks in batches, each one to another peer, and returns them
+// in the order of `hashes` along with the peer each batch came from.
+async fn fetch_blocks(
+    peers: &[Peer],
+    hashes: Vec<Vec<u8>>,
+    request_timeout: Duration,
+    connections: &Arc<RwLock<ConnectionManager>>,
+    reputation: &Arc<RwLock<Reputation>>,
+) -> Result<Vec<(Peer, Vec<Block>)>, SyncError> {
+    let mut batches = JoinSet::new();
+    for (index, batch) in hashes
+        .chunks(BLOCKS_PER_REQUEST.min(MAX_BLOCKS))
+        .enumerate()
+    {
+        let (peers, batch) = (peers.to_vec(), batch.to_vec());
+        let (connections, reputation) = (connections.clone(), reputation.clone());
+        batches.spawn(async move {
+            let blocks = request(
+                &peers,
+                index,
+                request_timeout,
+                &reputation,
+                "blocks",
+                |peer| get_blocks(peer, batch.clone(), connections.clone()),
+            )
+            .await;
+            (index, blocks)
+        });
+    }
+    let mut results = vec![];
+    while let Some(joined) = batches.join_next().await {
+        results.push(joined.expect("block download task panicked"));
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, batch)| batch).collect()
+}
+
+// Sends a request to the peers in turn, starting at `first`, until one of them
+// answers in time, and returns which one did. Peers that didn't answer lose
+// reputation, the one that did is only credited once its answer checks out.
+async fn request<T, F, Fut>(
+    peers: &[Peer],
+    first: usize,
+    request_timeout: Duration,
+    reputation: &Arc<RwLock<Reputation>>,
+    what: &str,
+    call: F,
+) -> Result<(Peer, T), SyncError>
+where
+    F: Fn(Peer) -> Fut,
+    Fut: Future<Output = Result<T, String>>,
+{
+    for attempt in 0..MAX_ATTEMPTS {
+        let peer = &peers[(first + attempt) % peers.len()];
+        let answer = timeout(request_timeout, call(peer.clone())).await;
+        if !matches!(answer, Ok(Ok(_))) {
+            reputation
+                .write()
+                .await
+                .record(&peer.id, Interaction::Unanswered);
+        }
+        match answer {
+            Ok(Ok(response)) => return Ok((peer.clone(), response)),
+            Ok(Err(e)) => println!(
+                "Request for {} to {}:{} failed: {}",
+                what, peer.ip, peer.port, e
+            ),
+            Err(_) => println!(
+                "Request for {} to {}:{} timed out",
+                what, peer.ip, peer.port
+            ),
+        }
+    }
+    Err(SyncError::Unanswered {
+        request: what.to_string(),
+    })
+}
+
+async fn get_headers(
+    peer: Peer,
+    locator: Vec<Vec<u8>>,
+    connections: Arc<RwLock<ConnectionManager>>,
+) -> Result<Vec<BlockHeader>, String> {
+    let headers = ConnectionManager::call(&connections, &peer, |mut client| async move {
+        client.get_headers(locator, MAX_HEADERS).await
+    })
+    .await?;
+    Ok(headers.headers)
+}
+
+// the peer has to answer with exactly the blocks asked for
+async fn get_blocks(
+    peer: Peer,
+    hashes: Vec<Vec<u8>>,
+    connections: Arc<RwLock<ConnectionManager>>,
+) -> Result<Vec<Block>, String> {
+    let asked = hashes.clone();
+    let blocks = ConnectionManager::call(&connections, &peer, |mut client| async move {
+        client.get_blocks(asked).await
+    })
+    .await?
+    .blocks;
+    let matches = blocks.len() == hashes.len()
+        && blocks
+            .iter()
+            .zip(&hashes)
+            .all(|(block, hash)| block.header.as_ref().map(|h| h.hash()).as_ref() == Some(hash));
+    if !matches {
+        return Err(String::from(
+            "answered with other blocks than the ones asked for",
+        ));
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::block::create_genesis_block;
+    use crate::blockchain::validation::tests::mined_block;
+    use crate::net::middleware::Offender;
+
+    fn tip_with_work(work: u128) -> ChainTip {
+        ChainTip {
+            cumulative_work: work.to_be_bytes().to_vec(),
+            ..ChainTip::default()
+        }
+    }
+
+    fn peer(port: u32) -> Peer {
+        Peer {
+            id: port.to_string(),
+            ip: String::from("127.0.0.1"),
+            port,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peers_ahead_by_most_work() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let sync = BlockSync::new(event_bus, blockchain).await;
+        let mut lock = sync.write().await;
+        for (port, work) in [(1, 10), (2, 30), (3, 5), (4, 20)] {
+            lock.peer_tips
+                .insert(port.to_string(), (peer(port), tip_with_work(work)));
+        }
+        let ports: Vec<u32> = lock.peers_ahead_of(9).iter().map(|p| p.port).collect();
+        assert_eq!(vec![2, 4, 1], ports);
+        assert!(lock.peers_ahead_of(30).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_peer_is_reported_and_skipped() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let sync = BlockSync::new(event_bus, blockchain).await;
+        let (reputation, guard) = {
+            let mut lock = sync.write().await;
+            for port in [1, 2] {
+                lock.peer_tips
+                    .insert(peer_key(&peer(port)), (peer(port), tip_with_work(10)));
+            }
+            (lock.reputation.clone(), lock.guard.clone())
+        };
+        reputation.write().await.record("1", Interaction::Answered);
+        let e = SyncError::InvalidHeaders(BlockValidationError::MissingHeader);
+        BlockSync::on_invalid(&sync, &peer(1), &e).await;
+        let ports: Vec<u32> = sync
+            .read()
+            .await
+            .peers_ahead_of(0)
+            .iter()
+            .map(|p| p.port)
+            .collect();
+        assert_eq!(vec![2], ports);
+        assert!(reputation.read().await.local_trust().is_empty());
+        let guard = guard.lock().unwrap();
+        assert_eq!(50, guard.score(&Offender::Node(String::from("1"))));
+        assert_eq!(50, guard.score(&Offender::Ip("127.0.0.1".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn test_check_headers() {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus).await;
+        let block_1 = mined_block(&create_genesis_block(), vec![]);
+        let block_2 = mined_block(&block_1, vec![]);
+        let headers = vec![block_1.header.unwrap(), block_2.header.unwrap()];
+        let b = blockchain.read().await;
+        assert_eq!(Ok(()), check_headers(&b, &headers));
+        // headers that don't extend a known block
+        assert!(matches!(
+            check_headers(&b, &headers[1..]),
+            Err(BlockValidationError::PreviousHashMismatch { .. })
+        ));
+        // or that are not chained
+        let unchained = vec![headers[0].clone(), headers[0].clone()];
+        assert!(check_headers(&b, &unchained).is_err());
+    }
+
+    #[test]
+    fn test_malformed_tip_work() {
+        assert_eq!(7, tip_with_work(7).work());
+        let malformed = ChainTip {
+            cumulative_work: vec![1, 2, 3],
+            ..ChainTip::default()
+        };
+        assert_eq!(0, malformed.work());
+    }
+}
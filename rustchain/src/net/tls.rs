@@ -0,0 +1,340 @@
+use crate::net::identity::NodeIdentity;
+use crate::net::kademlia::NodeId;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+use tonic::Request;
+
+// every node certificate names this, so a node whose id isn't known yet, like
+// a seed, can still be connected to
+pub const ANY_NODE: &str = "node.rustchain";
+// how long issued node certificates are good for
+const CERTIFICATE_DAYS: u32 = 365;
+// the dev CA key is derived from this, so every node has the same one
+const DEV_CA_SEED: &[u8] = b"rustchain insecure development CA";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsError {
+    // a certificate or key couldn't be read or made
+    BadCertificate(String),
+    // the certificate is for another key than the node's identity
+    KeyMismatch,
+    // or wasn't issued by the CA
+    NotIssuedByCa,
+    // a peer's certificate is for another node than the one it claims to be
+    WrongPeer { claimed: String, certified: String },
+    NoCertificate,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::BadCertificate(reason) => write!(f, "Bad certificate: {}", reason),
+            TlsError::KeyMismatch => write!(f, "Certificate is not for the node's key"),
+            TlsError::NotIssuedByCa => write!(f, "Certificate was not issued by the CA"),
+            TlsError::WrongPeer { claimed, certified } => write!(
+                f,
+                "Peer claims to be node {} but its certificate is for node {}",
+                claimed, certified
+            ),
+            TlsError::NoCertificate => write!(f, "Peer presented no certificate"),
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+impl From<ErrorStack> for TlsError {
+    fn from(e: ErrorStack) -> Self {
+        TlsError::BadCertificate(e.to_string())
+    }
+}
+
+// The name a node's certificate is issued for. Ids are 64 hex digits, one
+// more than a dns label can hold, so they are split in two labels.
+pub fn domain_name(id: &str) -> String {
+    match NodeId::from_hex(id) {
+        Some(_) => format!("{}.{}.{}", &id[..32], &id[32..], ANY_NODE),
+        None => ANY_NODE.to_string(),
+    }
+}
+
+// the node a DER certificate was issued for, its id is derived from the key
+fn certified_id(der: &[u8]) -> Result<NodeId, TlsError> {
+    let cert = X509::from_der(der)?;
+    Ok(NodeId::from_key(&cert.public_key()?.public_key_to_der()?))
+}
+
+// Checks that the client of `req` is node `id`. Requests that didn't come
+// over TLS have no certificate to check.
+pub fn check_peer<T>(req: &Request<T>, id: &str) -> Result<(), TlsError> {
+    let certs = match req.peer_certs() {
+        Some(certs) => certs,
+        None => return Ok(()),
+    };
+    let leaf = certs.first().ok_or(TlsError::NoCertificate)?;
+    let certified = certified_id(leaf.get_ref())?.to_hex();
+    if certified != id {
+        return Err(TlsError::WrongPeer {
+            claimed: id.to_string(),
+            certified,
+        });
+    }
+    Ok(())
+}
+
+// the node the client of `req` is certified as, None when it didn't come over
+// TLS
+pub fn peer_id<T>(req: &Request<T>) -> Option<String> {
+    let certs = req.peer_certs()?;
+    let leaf = certs.first()?;
+    certified_id(leaf.get_ref()).ok().map(|id| id.to_hex())
+}
+
+// The CA node certificates are issued by, trusted by every node of the network.
+#[derive(Clone)]
+pub struct CertificateAuthority {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl CertificateAuthority {
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<CertificateAuthority, TlsError> {
+        Ok(CertificateAuthority {
+            cert: X509::from_pem(cert)?,
+            key: PKey::private_key_from_pem(key)?,
+        })
+    }
+
+    // a fresh self-signed CA
+    pub fn generate(name: &str) -> Result<CertificateAuthority, TlsError> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        CertificateAuthority::self_signed(name, key)
+    }
+
+    // A self-signed CA whose key anyone can derive, so it can issue
+    // certificates for any node. Only good for tests and local networks.
+    pub fn dev() -> CertificateAuthority {
+        let key = dev_ca_key().expect("Failed to derive the dev CA key");
+        CertificateAuthority::self_signed("rustchain dev CA", key)
+            .expect("Failed to create the dev CA")
+    }
+
+    fn self_signed(name: &str, key: PKey<Private>) -> Result<CertificateAuthority, TlsError> {
+        let mut subject = X509NameBuilder::new()?;
+        subject.append_entry_by_nid(Nid::COMMONNAME, name)?;
+        let subject = subject.build();
+        let mut builder = certificate_builder(&key, 10 * CERTIFICATE_DAYS)?;
+        builder.set_subject_name(&subject)?;
+        builder.set_issuer_name(&subject)?;
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()?,
+        )?;
+        let key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(key_id)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(CertificateAuthority {
+            cert: builder.build(),
+            key,
+        })
+    }
+
+    pub fn certificate_pem(&self) -> Vec<u8> {
+        self.cert
+            .to_pem()
+            .expect("Failed to encode the CA certificate")
+    }
+
+    // Issues a PEM certificate for the node owning the DER `public_key`, good
+    // for both ends of a connection.
+    pub fn issue(&self, public_key: &[u8]) -> Result<Vec<u8>, TlsError> {
+        let key = PKey::public_key_from_der(public_key)?;
+        let id = NodeId::from_key(public_key).to_hex();
+        let mut subject = X509NameBuilder::new()?;
+        subject.append_entry_by_nid(Nid::COMMONNAME, &id)?;
+        let mut builder = certificate_builder(&key, CERTIFICATE_DAYS)?;
+        builder.set_subject_name(&subject.build())?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+        builder.append_extension(
+            ExtendedKeyUsage::new()
+                .server_auth()
+                .client_auth()
+                .build()?,
+        )?;
+        let names = SubjectAlternativeName::new()
+            .dns(&domain_name(&id))
+            .dns(ANY_NODE)
+            .build(&builder.x509v3_context(Some(&self.cert), None))?;
+        builder.append_extension(names)?;
+        builder.sign(&self.key, MessageDigest::sha256())?;
+        Ok(builder.build().to_pem()?)
+    }
+
+    // the TLS setup of `identity`, with a certificate issued right away
+    pub fn config_for(&self, identity: &NodeIdentity) -> Result<TlsConfig, TlsError> {
+        let certificate = self.issue(&identity.public_key())?;
+        TlsConfig::new(&self.certificate_pem(), &certificate, identity)
+    }
+}
+
+fn certificate_builder<T: openssl::pkey::HasPublic>(
+    key: &PKey<T>,
+    days: u32,
+) -> Result<X509Builder, TlsError> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_pubkey(key)?;
+    // an hour of leeway for peers whose clocks are behind
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let not_before = Asn1Time::from_unix(now - 3600)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    Ok(builder)
+}
+
+fn dev_ca_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+    let seed = BigNum::from_slice(&Sha256::digest(DEV_CA_SEED))?;
+    let mut private = BigNum::new()?;
+    private.nnmod(&seed, &order, &mut ctx)?;
+    let mut public = EcPoint::new(&group)?;
+    public.mul_generator(&group, &private, &ctx)?;
+    PKey::from_ec_key(EcKey::from_private_components(&group, &private, &public)?)
+}
+
+// What a node needs for mutual TLS: the CA it trusts and its own
+// certificate, issued by that CA for its identity key. PEM encoded.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    ca: Vec<u8>,
+    certificate: Vec<u8>,
+    key: Vec<u8>,
+    // the node the certificate is for
+    node_id: NodeId,
+}
+
+impl TlsConfig {
+    pub fn new(
+        ca: &[u8],
+        certificate: &[u8],
+        identity: &NodeIdentity,
+    ) -> Result<TlsConfig, TlsError> {
+        let ca_cert = X509::from_pem(ca)?;
+        let cert = X509::from_pem(certificate)?;
+        if cert.public_key()?.public_key_to_der()? != identity.public_key() {
+            return Err(TlsError::KeyMismatch);
+        }
+        let ca_key = ca_cert.public_key()?;
+        if !cert.verify(&ca_key)? {
+            return Err(TlsError::NotIssuedByCa);
+        }
+        Ok(TlsConfig {
+            ca: ca.to_vec(),
+            certificate: certificate.to_vec(),
+            key: identity.private_key_pem(),
+            node_id: identity.node_id(),
+        })
+    }
+
+    // certificate issued by the dev CA, see CertificateAuthority::dev
+    pub fn dev(identity: &NodeIdentity) -> TlsConfig {
+        CertificateAuthority::dev()
+            .config_for(identity)
+            .expect("Failed to issue a dev certificate")
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    // clients have to present a certificate from the CA as well
+    pub fn server(&self) -> ServerTlsConfig {
+        ServerTlsConfig::new()
+            .identity(Identity::from_pem(&self.certificate, &self.key))
+            .client_ca_root(Certificate::from_pem(&self.ca))
+    }
+
+    // for connecting to node `peer_id`, any node when the id isn't known
+    pub fn client(&self, peer_id: &str) -> ClientTlsConfig {
+        ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(&self.ca))
+            .identity(Identity::from_pem(&self.certificate, &self.key))
+            .domain_name(domain_name(peer_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_names() {
+        let id = NodeIdentity::generate().id();
+        let name = domain_name(&id);
+        assert_eq!(format!("{}.{}.{}", &id[..32], &id[32..], ANY_NODE), name);
+        assert!(name.split('.').all(|label| label.len() <= 63));
+        assert_eq!(ANY_NODE, domain_name(""));
+    }
+
+    #[test]
+    fn test_certificates_are_tied_to_the_identity() {
+        let identity = NodeIdentity::generate();
+        let ca = CertificateAuthority::dev();
+        let certificate = ca.issue(&identity.public_key()).unwrap();
+        let der = X509::from_pem(&certificate).unwrap().to_der().unwrap();
+        assert_eq!(identity.node_id(), certified_id(&der).unwrap());
+        assert!(TlsConfig::new(&ca.certificate_pem(), &certificate, &identity).is_ok());
+        // the dev CA is the same everywhere
+        let again = CertificateAuthority::dev().certificate_pem();
+        assert!(TlsConfig::new(&again, &certificate, &identity).is_ok());
+        // another node's certificate
+        assert_eq!(
+            TlsConfig::new(
+                &ca.certificate_pem(),
+                &certificate,
+                &NodeIdentity::generate()
+            )
+            .unwrap_err(),
+            TlsError::KeyMismatch
+        );
+        // or one from a CA the network doesn't trust
+        let other = CertificateAuthority::generate("other").unwrap();
+        let certificate = other.issue(&identity.public_key()).unwrap();
+        assert_eq!(
+            TlsConfig::new(&ca.certificate_pem(), &certificate, &identity).unwrap_err(),
+            TlsError::NotIssuedByCa
+        );
+    }
+}
@@ -16,7 +16,7 @@ impl BlockHeader {
         let mut hasher = Sha256::new();
         hasher.update(self.timestamp.to_be_bytes());
         hasher.update(self.nonce.to_be_bytes());
-        hasher.update(self.difficulty.to_be_bytes());
+        hasher.update(self.bits.to_be_bytes());
         hasher.update(self.previous_hash.clone());
         hasher.update(self.block_index.to_be_bytes());
         hasher.update(self.merkle_root.clone());
@@ -24,9 +24,19 @@ impl BlockHeader {
     }
 }
 
+impl ChainTip {
+    // malformed work counts as none
+    pub fn work(&self) -> u128 {
+        self.cumulative_work
+            .as_slice()
+            .try_into()
+            .map_or(0, u128::from_be_bytes)
+    }
+}
+
 impl Transaction {
     pub fn set_signature(&mut self, signature: Vec<u8>) {
-        for mut input in &mut self.inputs {
+        for input in &mut self.inputs {
             input.signature = signature.clone();
         }
     }
@@ -77,6 +87,15 @@ impl UtxoInput {
         self.signature = signature.clone();
     }
 
+    // the (tx hash, output index) pair this input spends. Wallets store the
+    // previous tx hash as its hex encoding, so that's what is returned.
+    pub fn outpoint(&self) -> (String, u32) {
+        (
+            String::from_utf8_lossy(&self.prev_tx_hash).to_string(),
+            self.output_index,
+        )
+    }
+
     pub fn hashable_content(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let mut pb = UtxoInput {
             from_addr: self.from_addr.clone(),
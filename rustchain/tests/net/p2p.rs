@@ -1,16 +1,45 @@
 #[cfg(test)]
 pub mod test {
     use protos::Transaction;
+    use rustchain::blockchain::block::next_block;
+    use rustchain::blockchain::blockchain::Blockchain;
+    use rustchain::blockchain::consensus::coinbase_transaction;
+    use rustchain::blockchain::merkle::merkle_root;
+    use rustchain::blockchain::utxo_set::UtxoSet;
     use rustchain::blockchain::wallet::Wallet;
     use rustchain::event_bus::event_bus::EventBus;
+    use rustchain::event_bus::events::RustchainEvent;
+    use rustchain::miner::miner::proof_of_work;
+    use rustchain::miner::miner::Miner;
     use rustchain::net::client_stubs::PeerClient;
+    use rustchain::net::gossip::Gossip;
+    use rustchain::net::light_client::LightClient;
     use rustchain::net::networking::get_addr;
     use rustchain::net::server_stubs::PeerServer;
+    use rustchain::net::sync::BlockSync;
     use rustchain::protos::response::Data;
-    use rustchain::protos::{self, UtxoInput, UtxoOutput};
+    use rustchain::protos::utxo_query::Query;
+    use rustchain::protos::{self, Heartbeat, OutPoint, Peer, UtxoInput, UtxoOutput, UtxoQuery};
     use std::error::Error;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
     use std::time::Duration;
-    use tokio::time::sleep;
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+    use tokio::time::{sleep, timeout};
+
+    // a block on top of the tip of `blockchain`, its coinbase paying `to_addr`
+    async fn mine_on(blockchain: &Arc<RwLock<Blockchain>>, to_addr: &str) -> protos::Block {
+        let (tip, bits) = {
+            let b = blockchain.read().await;
+            (b.tip(), b.next_bits())
+        };
+        let height = tip.header.as_ref().unwrap().block_index + 1;
+        let coinbase = coinbase_transaction(String::from(to_addr), height, 50);
+        let merkle_root = merkle_root(std::slice::from_ref(&coinbase));
+        let candidate = next_block(&tip, vec![coinbase], merkle_root, bits);
+        proof_of_work(candidate, &AtomicBool::new(false)).unwrap()
+    }
 
     #[tokio::test]
     async fn test_payment() -> Result<(), Box<dyn Error>> {
@@ -26,7 +55,8 @@ pub mod test {
 
         // setup client && send transaction
         let event_bus = EventBus::new().await;
-        let bob = Wallet::new(event_bus.clone()).await;
+        let utxo_set = Arc::new(std::sync::RwLock::new(UtxoSet::new()));
+        let bob = Wallet::new(event_bus.clone(), utxo_set.clone()).await;
         let (from_addr, public_key): (String, Vec<u8>) = {
             let bob_read = bob.read().await;
             (
@@ -45,7 +75,7 @@ pub mod test {
             signature: vec![],
         };
         let _ = bob.read().await.sign_transaction(&mut utxo_input.clone());
-        let alice = Wallet::new(event_bus.clone()).await;
+        let alice = Wallet::new(event_bus.clone(), utxo_set.clone()).await;
         let to_addr = alice.read().await.get_address();
         let utxo_output = UtxoOutput {
             to_addr: to_addr.clone(),
@@ -55,7 +85,7 @@ pub mod test {
         tx.outputs.push(utxo_output);
 
         // send tx
-        let peer_client: PeerClient = PeerClient::new(server_ip, server_port).await?;
+        let mut peer_client: PeerClient = PeerClient::new(server_ip, server_port).await?;
         let resp = peer_client.send_transaction(tx).await?;
 
         // buffer time
@@ -78,4 +108,264 @@ pub mod test {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_get_utxos() -> Result<(), Box<dyn Error>> {
+        // mine a block paying its coinbase to bob
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let block = mine_on(&blockchain, "bob").await;
+        let coinbase = block.transactions[0].clone();
+        blockchain.write().await.add_block(block).await?;
+
+        // setup server
+        let server_ip = "[::1]";
+        let server_port = 5010;
+        let server_addr = get_addr(server_ip, server_port);
+        let peer_server = PeerServer::new(event_bus, server_addr).with_blockchain(blockchain);
+        let server_handle = tokio::spawn(async { peer_server.serve().await });
+        sleep(Duration::from_millis(100)).await;
+
+        let mut peer_client = PeerClient::new(server_ip, server_port).await?;
+        let by_address = peer_client
+            .get_utxos(UtxoQuery {
+                query: Some(Query::Address(String::from("bob"))),
+            })
+            .await?;
+        let outpoint = OutPoint {
+            tx_hash: hex::encode(coinbase.hash()),
+            output_index: 0,
+        };
+        let by_outpoint = peer_client
+            .get_utxos(UtxoQuery {
+                query: Some(Query::Outpoint(outpoint.clone())),
+            })
+            .await?;
+        let unknown = peer_client
+            .get_utxos(UtxoQuery {
+                query: Some(Query::Address(String::from("alice"))),
+            })
+            .await?;
+        server_handle.abort();
+
+        assert_eq!(by_address, by_outpoint);
+        assert_eq!(1, by_address.utxos.len());
+        let utxo = &by_address.utxos[0];
+        assert_eq!(Some(outpoint), utxo.outpoint);
+        assert_eq!(50, utxo.output.as_ref().unwrap().amount);
+        assert!(unknown.utxos.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_light_client() -> Result<(), Box<dyn Error>> {
+        // full node with two blocks paying bob
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let mut coinbases = vec![];
+        for _ in 1..=2 {
+            let block = mine_on(&blockchain, "bob").await;
+            coinbases.push(block.transactions[0].clone());
+            blockchain.write().await.add_block(block).await?;
+        }
+
+        let server_ip = "[::1]";
+        let server_port = 5011;
+        let server_addr = get_addr(server_ip, server_port);
+        let peer_server = PeerServer::new(event_bus, server_addr).with_blockchain(blockchain);
+        let server_handle = tokio::spawn(async { peer_server.serve().await });
+        sleep(Duration::from_millis(100)).await;
+
+        let mut peer_client = PeerClient::new(server_ip, server_port).await?;
+        let mut light_client = LightClient::default();
+        let height = light_client.sync(&mut peer_client).await?;
+        let tx_hash = hex::encode(coinbases[0].hash());
+        let verified = light_client
+            .get_transaction(&mut peer_client, &tx_hash)
+            .await;
+        let unknown = light_client
+            .get_transaction(&mut peer_client, "unknown")
+            .await;
+        server_handle.abort();
+
+        assert_eq!(2, height);
+        let (tx, confirmations) = verified?;
+        assert_eq!(coinbases[0], tx);
+        assert_eq!(2, confirmations);
+        assert!(unknown.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_sync() -> Result<(), Box<dyn Error>> {
+        // full node three blocks ahead
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        for _ in 1..=3 {
+            let block = mine_on(&blockchain, "bob").await;
+            blockchain.write().await.add_block(block).await?;
+        }
+        let tip = blockchain.read().await.chain_tip();
+        let server_ip = "[::1]";
+        let server_addr = get_addr(server_ip, 5012);
+        let peer_server = PeerServer::new(event_bus, server_addr).with_blockchain(blockchain);
+        let server_handle = tokio::spawn(async { peer_server.serve().await });
+
+        // a peer claiming even more work that accepts connections but never
+        // answers, so every request to it times out
+        let silent = TcpListener::bind("[::1]:5013").await?;
+        let silent_handle = tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = silent.accept().await {
+                connections.push(stream);
+            }
+        });
+        let mut silent_tip = tip.clone();
+        silent_tip.cumulative_work = (tip.work() + 1).to_be_bytes().to_vec();
+        sleep(Duration::from_millis(100)).await;
+
+        // lagging node, learning about both tips from heartbeats
+        let event_bus = EventBus::new().await;
+        let lagging = Blockchain::new(event_bus.clone()).await;
+        let _sync = BlockSync::with_timeout(
+            event_bus.clone(),
+            lagging.clone(),
+            Duration::from_millis(300),
+        )
+        .await;
+        let heartbeat = |port: u32, tip: &protos::ChainTip| Heartbeat {
+            peer: Some(Peer {
+                id: port.to_string(),
+                ip: String::from(server_ip),
+                port,
+            }),
+            tip: Some(tip.clone()),
+            ..Heartbeat::default()
+        };
+        let synced = timeout(Duration::from_secs(10), async {
+            while lagging.read().await.height() < 3 {
+                for heartbeat in [heartbeat(5013, &silent_tip), heartbeat(5012, &tip)] {
+                    event_bus
+                        .read()
+                        .await
+                        .publish(RustchainEvent::NewHeartbeat(heartbeat))
+                        .await;
+                }
+                sleep(Duration::from_millis(200)).await;
+            }
+        })
+        .await;
+        server_handle.abort();
+        silent_handle.abort();
+
+        assert!(synced.is_ok());
+        assert_eq!(tip.hash, lagging.read().await.chain_tip().hash);
+        Ok(())
+    }
+
+    struct GossipNode {
+        blockchain: Arc<RwLock<Blockchain>>,
+        miner: Arc<RwLock<Miner>>,
+        server_handle: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send>>>,
+    }
+
+    // full node relaying to `neighbours`, with a miner only used for its mempool
+    async fn gossip_node(port: u16, neighbours: &[u16], funding: &Transaction) -> GossipNode {
+        let event_bus = EventBus::new().await;
+        let blockchain = Blockchain::new(event_bus.clone()).await;
+        let utxo_set = blockchain.read().await.utxo_set();
+        utxo_set.write().unwrap().apply_transaction(funding);
+        let miner = Miner::new(event_bus.clone(), blockchain.clone(), String::from("miner")).await;
+        let peers = neighbours
+            .iter()
+            .map(|port| protos::Peer {
+                id: port.to_string(),
+                ip: String::from("[::1]"),
+                port: *port as u32,
+            })
+            .collect();
+        let addr = get_addr("[::1]", port);
+        let gossip = Gossip::new(event_bus.clone(), addr, Arc::new(RwLock::new(peers))).await;
+        let peer_server = PeerServer::new(event_bus, addr)
+            .with_blockchain(blockchain.clone())
+            .with_gossip(gossip);
+        let server_handle = tokio::spawn(async { peer_server.serve().await });
+        GossipNode {
+            blockchain,
+            miner,
+            server_handle,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gossip() -> Result<(), Box<dyn Error>> {
+        // bob owns an output on every node
+        let bob = Wallet::new(
+            EventBus::new().await,
+            Arc::new(std::sync::RwLock::new(UtxoSet::new())),
+        )
+        .await
+        .read()
+        .await
+        .clone();
+        let funding = Transaction {
+            inputs: vec![],
+            outputs: vec![UtxoOutput {
+                to_addr: bob.get_address(),
+                amount: 100,
+            }],
+        };
+        // a line of nodes, the last one only hears from the middle one
+        let a = gossip_node(5014, &[5015], &funding).await;
+        let b = gossip_node(5015, &[5014, 5016], &funding).await;
+        let c = gossip_node(5016, &[5015], &funding).await;
+        sleep(Duration::from_millis(100)).await;
+
+        let mut tx = Transaction {
+            inputs: vec![UtxoInput {
+                from_addr: bob.get_address(),
+                public_key: bob.get_public_key().public_key_to_pem()?,
+                prev_tx_hash: hex::encode(funding.hash()).into_bytes(),
+                output_index: 0,
+                signature: vec![],
+            }],
+            outputs: vec![UtxoOutput {
+                to_addr: String::from("alice"),
+                amount: 90,
+            }],
+        };
+        bob.sign_tx(&mut tx)?;
+        PeerClient::new("[::1]", 5014)
+            .await?
+            .send_transaction(tx.clone())
+            .await?;
+
+        let block = mine_on(&a.blockchain, "miner").await;
+        a.blockchain.write().await.add_block(block.clone()).await?;
+
+        let tx_hash = hex::encode(tx.hash());
+        let mempool = c.miner.read().await.mempool();
+        let relayed = timeout(Duration::from_secs(5), async {
+            while !mempool.read().await.contains(&tx_hash) || c.blockchain.read().await.height() < 1
+            {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        for node in [&a, &b, &c] {
+            node.server_handle.abort();
+        }
+
+        assert!(relayed.is_ok());
+        assert!(b
+            .miner
+            .read()
+            .await
+            .mempool()
+            .read()
+            .await
+            .contains(&tx_hash));
+        assert_eq!(block, c.blockchain.read().await.tip());
+        Ok(())
+    }
 }